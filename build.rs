@@ -1,5 +1,216 @@
+use std::{collections::BTreeMap, env, fs, path::Path};
+
+#[path = "src/oui_format.rs"]
+mod oui_format;
+
+/// Source of the vendor table, in the IEEE registry's CSV format
+/// (https://standards-oui.ieee.org/oui/oui.csv). The checked-in copy is a
+/// sample of common vendors; `data/update-oui.sh --all` swaps in the whole
+/// registry.
+const OUI_CSV: &str = "data/oui.csv";
+
+/// Partition table flashed with the firmware; the table's budget comes from
+/// the size of its factory app partition.
+const PARTITIONS: &str = "partitions.csv";
+
 fn main() {
     println!("cargo::rustc-link-arg=-Trom_coexist.x");
     println!("cargo::rustc-link-arg=-Trom_functions.x");
     println!("cargo::rustc-link-arg=-Trom_phy.x");
+
+    println!("cargo::rerun-if-changed=build.rs");
+    println!("cargo::rerun-if-changed=src/oui_format.rs");
+    println!("cargo::rerun-if-changed={OUI_CSV}");
+    println!("cargo::rerun-if-changed={PARTITIONS}");
+
+    generate_oui_table(Path::new("."));
+}
+
+/// Write the vendor table into `OUT_DIR` for `oui.rs` to include. `root` is
+/// where `OUI_CSV` is found from; the simulator builds from `sim/`.
+pub fn generate_oui_table(root: &Path) {
+    let csv = fs::read_to_string(root.join(OUI_CSV)).expect("reading OUI registry snapshot");
+
+    // OUI -> vendor name, the registry has a few duplicate assignments
+    let mut entries: BTreeMap<[u8; 3], String> = BTreeMap::new();
+    for (number, line) in csv.lines().enumerate().skip(1) {
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let fields = split_csv(line);
+        let (Some(registry), Some(assignment), Some(name)) =
+            (fields.first(), fields.get(1), fields.get(2))
+        else {
+            panic!("{OUI_CSV}:{}: expected at least three fields", number + 1);
+        };
+
+        if registry != "MA-L" {
+            // MA-M/MA-S blocks need more than three octets to look up
+            continue;
+        }
+
+        let oui = parse_oui(assignment)
+            .unwrap_or_else(|| panic!("{OUI_CSV}:{}: bad assignment {assignment}", number + 1));
+        let name = name.trim();
+        assert!(
+            !name.bytes().any(|byte| byte < 0x20),
+            "{OUI_CSV}:{}: control character in vendor name",
+            number + 1
+        );
+
+        entries.insert(oui, name.to_string());
+    }
+
+    // Vendors with many assignments share one encoded name
+    let mut vendor_ids: BTreeMap<&str, u16> = BTreeMap::new();
+    let mut offsets: Vec<u8> = Vec::new();
+    let mut names: Vec<u8> = Vec::new();
+    let mut buckets = [0u16; 257];
+    let mut keys: Vec<u8> = Vec::new();
+    let mut vendors: Vec<u8> = Vec::new();
+
+    assert!(entries.len() < u16::MAX as usize, "too many OUIs for u16 buckets");
+
+    for (oui, name) in &entries {
+        let id = *vendor_ids.entry(name).or_insert_with(|| {
+            offsets.extend_from_slice(&(names.len() as u32).to_le_bytes());
+            names.extend_from_slice(&encode_name(name));
+            (offsets.len() / 4 - 1) as u16
+        });
+
+        buckets[oui[0] as usize + 1] += 1;
+        keys.extend_from_slice(&oui[1..]);
+        vendors.extend_from_slice(&id.to_le_bytes());
+    }
+    offsets.extend_from_slice(&(names.len() as u32).to_le_bytes());
+
+    for index in 1..buckets.len() {
+        buckets[index] += buckets[index - 1];
+    }
+    let buckets: Vec<u8> = buckets.iter().flat_map(|count| count.to_le_bytes()).collect();
+
+    let table = oui_format::Table {
+        buckets: &buckets,
+        keys: &keys,
+        vendors: &vendors,
+        offsets: &offsets,
+        names: &names,
+    };
+
+    // Every entry has to read back exactly as it went in
+    for (oui, name) in &entries {
+        let encoded = table
+            .find(*oui)
+            .unwrap_or_else(|| panic!("OUI {oui:02X?} missing from generated table"));
+        let mut decoded = String::new();
+        oui_format::expand(encoded, &mut decoded).unwrap();
+        assert_eq!(&decoded, name, "OUI {oui:02X?} decodes wrong");
+    }
+    assert_eq!(table.entries(), entries.len());
+    let budget = oui_format::budget(app_partition_size(root));
+    assert!(
+        table.size() <= budget,
+        "OUI table is {} bytes, over the {budget} byte budget",
+        table.size()
+    );
+    // For the tests, which check a sample would still fit as the full registry
+    println!("cargo::rustc-env=OUI_BUDGET={budget}");
+
+    let out_dir = env::var("OUT_DIR").unwrap();
+    let out = Path::new(&out_dir);
+    for (file, bytes) in [
+        ("oui_buckets.bin", &buckets),
+        ("oui_keys.bin", &keys),
+        ("oui_vendors.bin", &vendors),
+        ("oui_offsets.bin", &offsets),
+        ("oui_names.bin", &names),
+    ] {
+        fs::write(out.join(file), bytes).unwrap();
+    }
+}
+
+/// Size of the factory app partition in `PARTITIONS`.
+fn app_partition_size(root: &Path) -> usize {
+    let partitions = fs::read_to_string(root.join(PARTITIONS)).expect("reading partition table");
+    for line in partitions.lines() {
+        let fields: Vec<&str> = line.split(',').map(str::trim).collect();
+        if let ["factory", "app", "factory", _, size, ..] = fields[..] {
+            return parse_size(size)
+                .unwrap_or_else(|| panic!("{PARTITIONS}: bad factory size {size}"));
+        }
+    }
+    panic!("{PARTITIONS}: no factory app partition");
+}
+
+/// A partition size: hex, decimal, or with a K or M suffix.
+fn parse_size(size: &str) -> Option<usize> {
+    if let Some(hex) = size.strip_prefix("0x") {
+        return usize::from_str_radix(hex, 16).ok();
+    }
+    let (digits, unit) = match size.as_bytes().last()? {
+        b'K' | b'k' => (&size[..size.len() - 1], 1024),
+        b'M' | b'm' => (&size[..size.len() - 1], 1024 * 1024),
+        _ => (size, 1),
+    };
+    digits.parse::<usize>().ok().map(|value| value * unit)
+}
+
+/// Greedily swap the common words in `TOKENS` for their single byte codes.
+fn encode_name(name: &str) -> Vec<u8> {
+    let mut encoded = Vec::with_capacity(name.len());
+    let mut rest = name;
+
+    'outer: while !rest.is_empty() {
+        for (index, token) in oui_format::TOKENS.iter().enumerate() {
+            if let Some(after) = rest.strip_prefix(token) {
+                encoded.push(index as u8 + 1);
+                rest = after;
+                continue 'outer;
+            }
+        }
+
+        let next = rest.chars().next().unwrap();
+        let mut buffer = [0u8; 4];
+        encoded.extend_from_slice(next.encode_utf8(&mut buffer).as_bytes());
+        rest = &rest[next.len_utf8()..];
+    }
+
+    encoded
+}
+
+fn parse_oui(assignment: &str) -> Option<[u8; 3]> {
+    let hex: String = assignment
+        .chars()
+        .filter(|c| c.is_ascii_hexdigit())
+        .collect();
+    if hex.len() != 6 {
+        return None;
+    }
+
+    let value = u32::from_str_radix(&hex, 16).ok()?;
+    Some([(value >> 16) as u8, (value >> 8) as u8, value as u8])
+}
+
+/// Split one CSV line, honoring double quotes (names like "Apple, Inc.").
+fn split_csv(line: &str) -> Vec<String> {
+    let mut fields = Vec::new();
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = line.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                field.push('"');
+                chars.next();
+            }
+            '"' => quoted = !quoted,
+            ',' if !quoted => fields.push(std::mem::take(&mut field)),
+            c => field.push(c),
+        }
+    }
+
+    fields.push(field);
+    fields
 }
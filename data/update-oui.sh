@@ -0,0 +1,30 @@
+#!/bin/sh
+# Refresh oui.csv from the IEEE MA-L registry.
+#
+# By default only the assignments already in oui.csv are kept, so the
+# checked-in sample stays small and reviewable. Pass --all to take every
+# MA-L assignment; that's what a release build should ship, and build.rs
+# fails if it no longer fits the app partition.
+set -eu
+
+cd "$(dirname "$0")"
+
+all=0
+if [ "${1:-}" = "--all" ]; then
+    all=1
+fi
+
+registry=$(mktemp)
+trap 'rm -f "$registry" oui.csv.new' EXIT
+
+curl -fsSL https://standards-oui.ieee.org/oui/oui.csv | tr -d '\r' > "$registry"
+
+# First file: the assignments to keep. Second: the fresh registry, whose
+# header is kept as is.
+awk -F, -v all="$all" '
+    NR == FNR { if (FNR > 1) keep[$2] = 1; next }
+    FNR == 1 || ($1 == "MA-L" && (all || $2 in keep))
+' oui.csv "$registry" > oui.csv.new
+
+mv oui.csv.new oui.csv
+echo "oui.csv: $(($(wc -l < oui.csv) - 1)) assignments"
@@ -0,0 +1,99 @@
+//! Consistent Overhead Byte Stuffing: rewrites a frame so it contains no
+//! zero bytes, for one byte of overhead per 254.
+
+use alloc::vec::Vec;
+
+pub fn encode(data: &[u8]) -> Vec<u8> {
+    let mut encoded = Vec::with_capacity(data.len() + data.len() / 254 + 2);
+    let mut code_at = 0;
+    encoded.push(0);
+
+    for &byte in data {
+        if byte != 0 {
+            encoded.push(byte);
+        }
+
+        let run = encoded.len() - code_at;
+        if byte == 0 || run == 0xff {
+            encoded[code_at] = run as u8;
+            code_at = encoded.len();
+            encoded.push(0);
+        }
+    }
+
+    encoded[code_at] = (encoded.len() - code_at) as u8;
+    encoded
+}
+
+/// `None` if `data` isn't valid COBS, i.e. has a zero or a run that
+/// overshoots the end.
+pub fn decode(data: &[u8]) -> Option<Vec<u8>> {
+    let mut decoded = Vec::with_capacity(data.len());
+    let mut rest = data;
+
+    while let Some((&code, tail)) = rest.split_first() {
+        let run = (code as usize).checked_sub(1)?;
+        let bytes = tail.get(..run)?;
+        if bytes.contains(&0) {
+            return None;
+        }
+
+        decoded.extend_from_slice(bytes);
+        rest = &tail[run..];
+        if code != 0xff && !rest.is_empty() {
+            decoded.push(0);
+        }
+    }
+
+    Some(decoded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    fn round_trip(data: &[u8]) {
+        let encoded = encode(data);
+        assert!(!encoded.contains(&0), "zero in {encoded:?}");
+        assert_eq!(decode(&encoded).as_deref(), Some(data));
+    }
+
+    #[test]
+    fn round_trips_runs_around_the_longest_code() {
+        for len in [0, 1, 253, 254, 255, 256, 508, 509, 510] {
+            round_trip(&vec![0x41; len]);
+
+            let mut zero_ended = vec![0x41; len];
+            zero_ended.push(0);
+            round_trip(&zero_ended);
+
+            let mut zero_started = vec![0];
+            zero_started.extend(vec![0x41; len]);
+            round_trip(&zero_started);
+        }
+    }
+
+    #[test]
+    fn round_trips_zeros() {
+        round_trip(&[0]);
+        round_trip(&[0, 0, 0]);
+        round_trip(&[1, 0, 2, 0, 0, 3]);
+    }
+
+    #[test]
+    fn costs_a_byte_per_run() {
+        for len in [0, 1, 253, 254, 255, 1000] {
+            let encoded = encode(&vec![0x41; len]);
+            assert!(encoded.len() <= len + len / 254 + 2, "{len} bytes");
+        }
+    }
+
+    #[test]
+    fn rejects_what_isnt_cobs() {
+        // A zero inside, and a run past the end
+        assert_eq!(decode(&[3, 1, 0]), None);
+        assert_eq!(decode(&[5, 1, 2]), None);
+        assert_eq!(decode(&[0]), None);
+    }
+}
@@ -0,0 +1,20 @@
+//! CRC-16/CCITT-FALSE, which is plenty for frames of a few hundred bytes.
+
+const POLYNOMIAL: u16 = 0x1021;
+
+pub fn crc16(data: &[u8]) -> u16 {
+    let mut crc = 0xffff;
+
+    for &byte in data {
+        crc ^= (byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                crc << 1 ^ POLYNOMIAL
+            } else {
+                crc << 1
+            };
+        }
+    }
+
+    crc
+}
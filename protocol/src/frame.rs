@@ -0,0 +1,318 @@
+use alloc::{collections::VecDeque, string::String, vec::Vec};
+
+use crate::{cobs, crc::crc16, Message};
+
+/// Longest frame body we'll collect before deciding it's not a frame
+const MAX_FRAME: usize = 2048;
+
+/// Longest log line kept; the rest of it is dropped
+const MAX_LINE: usize = 512;
+
+/// `message` as a frame, zeros included, ready to write.
+pub fn encode(message: &Message) -> Vec<u8> {
+    // Messages are plain data, serializing into a Vec can't fail
+    let mut body = postcard::to_allocvec(message).unwrap();
+    body.extend_from_slice(&crc16(&body).to_le_bytes());
+
+    let mut frame = Vec::with_capacity(body.len() + body.len() / 254 + 4);
+    frame.push(0);
+    frame.extend_from_slice(&cobs::encode(&body));
+    frame.push(0);
+    frame
+}
+
+/// The message in what was between two zeros, if it is one.
+pub fn decode(frame: &[u8]) -> Option<Message> {
+    let body = cobs::decode(frame)?;
+    let (message, crc) = body.split_at(body.len().checked_sub(2)?);
+    if crc16(message).to_le_bytes() != crc {
+        return None;
+    }
+
+    postcard::from_bytes(message).ok()
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Item {
+    /// A line of log output, without its line ending
+    Log(String),
+    Message(Message),
+}
+
+/// Splits a byte stream into log lines and messages.
+///
+/// A zero might open a frame or close one, so what follows it is held back
+/// until it's clear which. Log text gives itself away by its second byte:
+/// a frame's body opens with the `Message` variant, a small number, where
+/// text has a printable character. Anything held back that turns out not to
+/// be a frame goes back to the log, lines and all.
+pub struct Decoder {
+    line: Vec<u8>,
+    /// Since the last zero, if we're maybe inside a frame
+    frame: Option<Vec<u8>>,
+    /// Finished, and not handed out yet
+    ready: VecDeque<Item>,
+}
+
+impl Decoder {
+    pub const fn new() -> Self {
+        Self {
+            line: Vec::new(),
+            frame: None,
+            ready: VecDeque::new(),
+        }
+    }
+
+    /// Take in the next byte, and hand out the next finished item if there
+    /// is one.
+    pub fn push(&mut self, byte: u8) -> Option<Item> {
+        self.take(byte);
+        self.ready.pop_front()
+    }
+
+    /// An item finished earlier that `push` hasn't handed out yet. A byte
+    /// that sends held back text to the log can finish more than one.
+    pub fn pop(&mut self) -> Option<Item> {
+        self.ready.pop_front()
+    }
+
+    fn take(&mut self, byte: u8) {
+        let Some(frame) = &mut self.frame else {
+            return self.text(byte);
+        };
+
+        match byte {
+            // Back to back frames, or the start of one after a bad one
+            0 if frame.is_empty() => (),
+            0 => {
+                let candidate = core::mem::take(frame);
+                match decode(&candidate) {
+                    Some(message) => {
+                        self.frame = None;
+                        self.ready.push_back(Item::Message(message));
+                    }
+                    None => {
+                        // Text that happened to sit between two zeros, or a
+                        // damaged frame; this zero may still open a real one
+                        for byte in candidate {
+                            self.text(byte);
+                        }
+                        self.cut_line();
+                    }
+                }
+            }
+            byte => {
+                frame.push(byte);
+                if frame.len() > MAX_FRAME || (frame.len() <= 2 && !could_be_frame(frame)) {
+                    let text = core::mem::take(frame);
+                    self.frame = None;
+                    for byte in text {
+                        self.text(byte);
+                    }
+                }
+            }
+        }
+    }
+
+    /// A byte outside any frame.
+    fn text(&mut self, byte: u8) {
+        match byte {
+            0 => {
+                self.cut_line();
+                self.frame = Some(Vec::new());
+            }
+            b'\n' => {
+                let line = self.take_line();
+                self.ready.push_back(line);
+            }
+            byte => {
+                if self.line.len() < MAX_LINE {
+                    self.line.push(byte);
+                }
+            }
+        }
+    }
+
+    /// Log lines end in a newline; at a zero anything pending is cut off.
+    fn cut_line(&mut self) {
+        if !self.line.is_empty() {
+            let line = self.take_line();
+            self.ready.push_back(line);
+        }
+    }
+
+    fn take_line(&mut self) -> Item {
+        let line = core::mem::take(&mut self.line);
+        let line = String::from_utf8_lossy(&line);
+        Item::Log(line.trim_end_matches('\r').into())
+    }
+}
+
+/// Whether a frame could start with `bytes`, going by the `Message` variant
+/// its body opens with.
+fn could_be_frame(bytes: &[u8]) -> bool {
+    let variant = match bytes {
+        // A run of no bytes before a zero: the body starts with zero
+        [1, ..] => 0,
+        [_, variant, ..] => *variant,
+        _ => return true,
+    };
+    // Every variant has fields, so with only the variant there's more to
+    // come; a variant that doesn't exist is an error of its own
+    matches!(
+        postcard::from_bytes::<Message>(&[variant]),
+        Err(postcard::Error::DeserializeUnexpectedEnd)
+    )
+}
+
+impl Default for Decoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Command, Record};
+    use alloc::{string::ToString, vec};
+
+    fn hello() -> Message {
+        Message::Hello {
+            min_version: 1,
+            version: 1,
+        }
+    }
+
+    /// A record long enough for its frame to need more than one COBS run.
+    fn record() -> Message {
+        Message::Record(Record {
+            index: Some(7),
+            stamp: None,
+            kind: "network".into(),
+            text: "+ CoffeeShop".into(),
+            bytes: vec![0x41; 300],
+        })
+    }
+
+    fn log(line: &str) -> Item {
+        Item::Log(line.to_string())
+    }
+
+    /// Everything `bytes` finishes, in order.
+    fn items(decoder: &mut Decoder, bytes: &[u8]) -> Vec<Item> {
+        let mut items = Vec::new();
+        for &byte in bytes {
+            items.extend(decoder.push(byte));
+            while let Some(item) = decoder.pop() {
+                items.push(item);
+            }
+        }
+        items
+    }
+
+    #[test]
+    fn round_trips_messages() {
+        for message in [hello(), record(), Message::Command(Command::Stats)] {
+            let frame = encode(&message);
+            assert_eq!(frame.iter().filter(|byte| **byte == 0).count(), 2);
+            assert_eq!(decode(&frame[1..frame.len() - 1]), Some(message));
+        }
+    }
+
+    #[test]
+    fn rejects_a_crc_mismatch() {
+        let frame = encode(&hello());
+        let body = &frame[1..frame.len() - 1];
+        for index in 0..body.len() {
+            let mut damaged = body.to_vec();
+            damaged[index] ^= 0x10;
+            // Still zero free, so still COBS, but not the frame that was sent
+            if !damaged.contains(&0) {
+                assert_eq!(decode(&damaged), None, "byte {index} changed");
+            }
+        }
+    }
+
+    #[test]
+    fn interleaves_log_lines_and_frames() {
+        let mut stream = b"boot\r\n".to_vec();
+        stream.extend(encode(&hello()));
+        stream.extend(b"storage: 3 records\r\n\r\n");
+        stream.extend(encode(&record()));
+        stream.extend(encode(&hello()));
+        stream.extend(b"done\n");
+
+        let mut decoder = Decoder::new();
+        assert_eq!(
+            items(&mut decoder, &stream),
+            [
+                log("boot"),
+                Item::Message(hello()),
+                log("storage: 3 records"),
+                log(""),
+                Item::Message(record()),
+                Item::Message(hello()),
+                log("done"),
+            ]
+        );
+    }
+
+    #[test]
+    fn cuts_off_a_line_a_frame_interrupts() {
+        let mut stream = b"half a li".to_vec();
+        stream.extend(encode(&hello()));
+        stream.extend(b"ne\n");
+
+        let mut decoder = Decoder::new();
+        assert_eq!(
+            items(&mut decoder, &stream),
+            [log("half a li"), Item::Message(hello()), log("ne")]
+        );
+    }
+
+    #[test]
+    fn recovers_right_after_a_damaged_frame() {
+        let mut damaged = encode(&record());
+        damaged[40] ^= 0x01;
+
+        let mut decoder = Decoder::new();
+        let garbage = items(&mut decoder, &damaged);
+        assert!(garbage.iter().all(|item| matches!(item, Item::Log(_))));
+
+        // Each line is its own as soon as it ends, not held back as a frame
+        assert_eq!(items(&mut decoder, b"first line\r\n"), [log("first line")]);
+        assert_eq!(items(&mut decoder, b"second\r\n"), [log("second")]);
+        assert_eq!(
+            items(&mut decoder, &encode(&hello())),
+            [Item::Message(hello())]
+        );
+    }
+
+    #[test]
+    fn finds_frames_when_started_part_way_through_one() {
+        // Joined in the middle of a frame: its tail, then its closing zero
+        let frame = encode(&record());
+        let mut stream = frame[frame.len() / 2..].to_vec();
+        stream.extend(b"log line\r\n");
+        stream.extend(encode(&hello()));
+        stream.extend(b"after\r\n");
+
+        let mut decoder = Decoder::new();
+        let items = items(&mut decoder, &stream);
+        let tail = items.len() - 3;
+        assert_eq!(
+            items[tail..],
+            [log("log line"), Item::Message(hello()), log("after")]
+        );
+    }
+
+    #[test]
+    fn keeps_the_start_of_a_long_line() {
+        let mut line = vec![b'x'; MAX_LINE + 100];
+        line.push(b'\n');
+
+        let mut decoder = Decoder::new();
+        assert_eq!(items(&mut decoder, &line), [log(&"x".repeat(MAX_LINE))]);
+    }
+}
@@ -0,0 +1,156 @@
+//! Talking to a device from a computer, over anything that reads and writes
+//! bytes; usually a serial port opened with the `serialport` crate.
+
+use std::io::{self, Read, Write};
+
+use crate::{encode, negotiate, Command, Decoder, Item, Message, Reply, MIN_VERSION, VERSION};
+
+pub struct Link<P> {
+    port: P,
+    decoder: Decoder,
+    version: Option<u16>,
+}
+
+impl<P: Read + Write> Link<P> {
+    pub fn new(port: P) -> Self {
+        Self {
+            port,
+            decoder: Decoder::new(),
+            version: None,
+        }
+    }
+
+    /// The version agreed on by `hello`.
+    pub fn version(&self) -> Option<u16> {
+        self.version
+    }
+
+    /// Open a session, handing anything that arrives first (log lines,
+    /// mostly) to `each`.
+    pub fn hello(&mut self, mut each: impl FnMut(Item)) -> io::Result<u16> {
+        self.send(&Message::Hello {
+            min_version: MIN_VERSION,
+            version: VERSION,
+        })?;
+
+        loop {
+            match self.read_item()? {
+                Item::Message(Message::Hello {
+                    min_version,
+                    version,
+                }) => {
+                    let version = negotiate(min_version, version).ok_or_else(|| {
+                        invalid(format!(
+                            "device speaks versions {min_version} to {version}, we speak {MIN_VERSION} to {VERSION}"
+                        ))
+                    })?;
+                    self.version = Some(version);
+                    return Ok(version);
+                }
+                item => each(item),
+            }
+        }
+    }
+
+    /// Run a command, handing what the device sends back for it (and any
+    /// log lines in between) to `each`.
+    pub fn command(&mut self, command: Command, mut each: impl FnMut(Item)) -> io::Result<Reply> {
+        if self.version.is_none() {
+            return Err(invalid("no session, call hello first".into()));
+        }
+
+        self.send(&Message::Command(command))?;
+        loop {
+            match self.read_item()? {
+                Item::Message(Message::Reply(reply)) => return Ok(reply),
+                item => each(item),
+            }
+        }
+    }
+
+    /// The next log line or message, waiting for it.
+    pub fn read_item(&mut self) -> io::Result<Item> {
+        if let Some(item) = self.decoder.pop() {
+            return Ok(item);
+        }
+
+        let mut byte = [0u8];
+        loop {
+            if self.port.read(&mut byte)? == 0 {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+            if let Some(item) = self.decoder.push(byte[0]) {
+                return Ok(item);
+            }
+        }
+    }
+
+    pub fn send(&mut self, message: &Message) -> io::Result<()> {
+        self.port.write_all(&encode(message))?;
+        self.port.flush()
+    }
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    /// A device that has already said everything in `output`.
+    struct Port {
+        output: Cursor<Vec<u8>>,
+        input: Vec<u8>,
+    }
+
+    impl Read for Port {
+        fn read(&mut self, bytes: &mut [u8]) -> io::Result<usize> {
+            self.output.read(bytes)
+        }
+    }
+
+    impl Write for Port {
+        fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+            self.input.write(bytes)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn opens_a_session_past_the_log() {
+        let mut output = b"booting\r\n".to_vec();
+        output.extend(encode(&Message::Hello {
+            min_version: 1,
+            version: 1,
+        }));
+        output.extend(b"ready\r\n");
+        output.extend(encode(&Message::Reply(Reply::Ok)));
+
+        let mut link = Link::new(Port {
+            output: Cursor::new(output),
+            input: Vec::new(),
+        });
+        let mut log = Vec::new();
+        assert_eq!(link.hello(|item| log.push(item)).unwrap(), 1);
+        assert_eq!(
+            link.command(Command::Stats, |item| log.push(item)).unwrap(),
+            Reply::Ok
+        );
+        assert_eq!(
+            log,
+            [Item::Log("booting".into()), Item::Log("ready".into())]
+        );
+
+        // And it ran out of things to say
+        assert_eq!(
+            link.read_item().unwrap_err().kind(),
+            io::ErrorKind::UnexpectedEof
+        );
+    }
+}
@@ -0,0 +1,37 @@
+//! What the firmware and host tools say to each other over USB serial.
+//!
+//! A frame is a postcard-encoded `Message` followed by its CRC-16, COBS
+//! encoded so it has no zero bytes, between two zeros. The firmware's log
+//! output never contains a zero, so frames and log lines share the link:
+//! whatever sits between zeros and checks out is a frame, everything else
+//! is text.
+//!
+//! Builds `no_std` for the firmware; the `std` feature adds `host`, for
+//! tools talking to a device over a serial port.
+
+#![cfg_attr(not(feature = "std"), no_std)]
+
+extern crate alloc;
+
+pub mod cobs;
+pub mod crc;
+mod frame;
+#[cfg(feature = "std")]
+pub mod host;
+mod message;
+
+pub use frame::{decode, encode, Decoder, Item};
+pub use message::*;
+
+/// The protocol version this side speaks
+pub const VERSION: u16 = 1;
+
+/// The oldest version this side still understands
+pub const MIN_VERSION: u16 = 1;
+
+/// The version to use with a peer that speaks `min_version..=version`: the
+/// newest we have in common.
+pub fn negotiate(min_version: u16, version: u16) -> Option<u16> {
+    let common = version.min(VERSION);
+    (common >= min_version.max(MIN_VERSION)).then_some(common)
+}
@@ -0,0 +1,113 @@
+use alloc::{string::String, vec::Vec};
+use serde::{Deserialize, Serialize};
+
+/// Only ever add variants (and fields) at the end, and bump `VERSION` when
+/// you do: postcard encodes variants by position.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Message {
+    /// Opens a session. The host sends its range; the device answers with the
+    /// version it picked, or its own range if there isn't one in common.
+    /// The device sends no frames until a session is open.
+    Hello {
+        min_version: u16,
+        version: u16,
+    },
+    /// A stored record: from a dump, or as it's stored
+    Record(Record),
+    Alert(Alert),
+    Stats(Stats),
+    /// From the host
+    Command(Command),
+    /// The device's answer to a command, after anything it sent for it
+    Reply(Reply),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Stamp {
+    pub boot: u32,
+    pub uptime_s: u32,
+    pub unix_s: Option<u32>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Record {
+    /// Position in the store, oldest first; `None` for a record sent live
+    pub index: Option<u32>,
+    pub stamp: Option<Stamp>,
+    /// "network", "alert" and so on, or "unknown" if the device couldn't
+    /// decode it either
+    pub kind: String,
+    /// How the device prints it
+    pub text: String,
+    /// The record as stored, stamp included
+    pub bytes: Vec<u8>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Alert {
+    /// Someone impersonating a trusted network
+    pub rogue: bool,
+    pub text: String,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Stats {
+    pub stamp: Stamp,
+    pub records: u32,
+    /// Clients heard recently, when sniffing
+    pub occupancy: Option<u16>,
+    pub battery: Option<Battery>,
+    /// Channels we've listened to
+    pub channels: Vec<Channel>,
+    pub recommended_channel: Option<u8>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Battery {
+    pub percent: u16,
+    pub millivolts: u16,
+    pub usb: bool,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Channel {
+    pub channel: u8,
+    pub frames: u32,
+    pub bytes: u32,
+    /// Out of 1000
+    pub busy: u16,
+    pub stations: u16,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Command {
+    /// Send up to `limit` stored records starting at `from`
+    Dump {
+        from: u32,
+        limit: u32,
+    },
+    Stats,
+    Erase,
+    SetTime {
+        unix_ms: u64,
+    },
+    /// Reboot into another mode
+    Mode(Mode),
+    Reboot,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Mode {
+    Sniff,
+    Bluetooth,
+    Ieee802154,
+    Share,
+    Upload,
+    Live,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Reply {
+    Ok,
+    Error(String),
+}
@@ -0,0 +1,16 @@
+use std::path::Path;
+
+/// The firmware's build script, for its vendor table; its `main` only adds
+/// the board's linker scripts on top.
+#[path = "../build.rs"]
+#[allow(dead_code)]
+mod firmware;
+
+fn main() {
+    println!("cargo::rerun-if-changed=../build.rs");
+    println!("cargo::rerun-if-changed=../src/oui_format.rs");
+    println!("cargo::rerun-if-changed=../data/oui.csv");
+    println!("cargo::rerun-if-changed=../partitions.csv");
+
+    firmware::generate_oui_table(Path::new(".."));
+}
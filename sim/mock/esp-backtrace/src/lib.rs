@@ -0,0 +1 @@
+//! Nothing to install on the host: std already prints panics.
@@ -0,0 +1,15 @@
+//! `esp-println` for the simulator: the console is stdout.
+
+#[macro_export]
+macro_rules! println {
+    ($($arg:tt)*) => {
+        ::std::println!($($arg)*)
+    };
+}
+
+#[macro_export]
+macro_rules! print {
+    ($($arg:tt)*) => {
+        ::std::print!($($arg)*)
+    };
+}
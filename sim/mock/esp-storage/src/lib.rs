@@ -0,0 +1,155 @@
+//! `esp-storage` for the simulator: flash is a buffer in RAM, erased at
+//! start. It keeps the rules of the real part that the log relies on:
+//! erases are whole sectors, and a NOR write can only clear bits.
+
+use std::sync::Mutex;
+
+use embedded_storage::{
+    nor_flash::{ErrorType, NorFlash, NorFlashError, NorFlashErrorKind, ReadNorFlash},
+    ReadStorage, Storage,
+};
+
+/// The size of the flash on the boards we ship
+const CAPACITY: usize = 4 * 1024 * 1024;
+
+const SECTOR_SIZE: usize = 4096;
+
+/// Every `FlashStorage` shares the one chip, like on the board
+static FLASH: Mutex<Vec<u8>> = Mutex::new(Vec::new());
+
+/// How many NOR writes to let through before one fails, for tests
+static FAILING_WRITE: Mutex<Option<usize>> = Mutex::new(None);
+
+/// Let `after` NOR writes through, then fail the next one without touching
+/// the flash.
+pub fn fail_write(after: usize) {
+    *FAILING_WRITE.lock().unwrap() = Some(after);
+}
+
+/// Whether the write `fail_write` asked to fail hasn't happened yet.
+pub fn failing_write() -> bool {
+    FAILING_WRITE.lock().unwrap().is_some()
+}
+
+fn with_flash<R>(f: impl FnOnce(&mut Vec<u8>) -> R) -> R {
+    let mut flash = FLASH.lock().unwrap();
+    if flash.is_empty() {
+        flash.resize(CAPACITY, 0xff);
+    }
+    f(&mut flash)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FlashStorageError {
+    IoError,
+    NotAligned,
+    OutOfBounds,
+}
+
+impl NorFlashError for FlashStorageError {
+    fn kind(&self) -> NorFlashErrorKind {
+        match self {
+            Self::NotAligned => NorFlashErrorKind::NotAligned,
+            Self::OutOfBounds => NorFlashErrorKind::OutOfBounds,
+            Self::IoError => NorFlashErrorKind::Other,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct FlashStorage;
+
+impl FlashStorage {
+    pub fn new() -> Self {
+        Self
+    }
+
+    fn range(offset: u32, len: usize) -> Result<std::ops::Range<usize>, FlashStorageError> {
+        let start = offset as usize;
+        match start.checked_add(len) {
+            Some(end) if end <= CAPACITY => Ok(start..end),
+            _ => Err(FlashStorageError::OutOfBounds),
+        }
+    }
+
+    fn aligned(offset: u32, len: usize, to: usize) -> Result<(), FlashStorageError> {
+        if !(offset as usize).is_multiple_of(to) || !len.is_multiple_of(to) {
+            return Err(FlashStorageError::NotAligned);
+        }
+        Ok(())
+    }
+}
+
+impl ReadStorage for FlashStorage {
+    type Error = FlashStorageError;
+
+    fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
+        let range = Self::range(offset, bytes.len())?;
+        with_flash(|flash| bytes.copy_from_slice(&flash[range]));
+        Ok(())
+    }
+
+    fn capacity(&self) -> usize {
+        CAPACITY
+    }
+}
+
+/// Like the real one: read the sectors, erase them, and write them back
+/// with `bytes` in place, so any byte can be changed.
+impl Storage for FlashStorage {
+    fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error> {
+        let range = Self::range(offset, bytes.len())?;
+        with_flash(|flash| flash[range].copy_from_slice(bytes));
+        Ok(())
+    }
+}
+
+impl ErrorType for FlashStorage {
+    type Error = FlashStorageError;
+}
+
+impl ReadNorFlash for FlashStorage {
+    const READ_SIZE: usize = 4;
+
+    fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
+        Self::aligned(offset, bytes.len(), Self::READ_SIZE)?;
+        ReadStorage::read(self, offset, bytes)
+    }
+
+    fn capacity(&self) -> usize {
+        CAPACITY
+    }
+}
+
+impl NorFlash for FlashStorage {
+    const WRITE_SIZE: usize = 4;
+    const ERASE_SIZE: usize = SECTOR_SIZE;
+
+    fn erase(&mut self, from: u32, to: u32) -> Result<(), Self::Error> {
+        let len = (to as usize).saturating_sub(from as usize);
+        Self::aligned(from, len, Self::ERASE_SIZE)?;
+        let range = Self::range(from, len)?;
+        with_flash(|flash| flash[range].fill(0xff));
+        Ok(())
+    }
+
+    fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error> {
+        Self::aligned(offset, bytes.len(), Self::WRITE_SIZE)?;
+        let range = Self::range(offset, bytes.len())?;
+        let mut failing = FAILING_WRITE.lock().unwrap();
+        match *failing {
+            Some(0) => {
+                *failing = None;
+                return Err(FlashStorageError::IoError);
+            }
+            Some(after) => *failing = Some(after - 1),
+            None => {}
+        }
+        with_flash(|flash| {
+            for (cell, byte) in flash[range].iter_mut().zip(bytes) {
+                *cell &= byte;
+            }
+        });
+        Ok(())
+    }
+}
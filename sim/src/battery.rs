@@ -0,0 +1,25 @@
+//! The simulated board has no fuel gauge, so there's no reading unless a
+//! test sets one.
+
+use core::cell::RefCell;
+
+use critical_section::Mutex;
+
+#[derive(Clone, Copy, Debug)]
+pub struct Level {
+    pub percent: u16,
+    pub millivolts: u16,
+    /// Running off USB power
+    pub usb: bool,
+}
+
+static LEVEL: Mutex<RefCell<Option<Level>>> = Mutex::new(RefCell::new(None));
+
+pub fn level() -> Option<Level> {
+    critical_section::with(|cs| *LEVEL.borrow_ref(cs))
+}
+
+/// What `level` reads from now on.
+pub fn set_level(level: Option<Level>) {
+    critical_section::with(|cs| *LEVEL.borrow_ref_mut(cs) = level);
+}
@@ -0,0 +1,37 @@
+//! The settings the shared modules read, at the firmware's defaults. The
+//! real module stores them with the network settings, none of which exist
+//! here; change them in `Config::new` to try others.
+
+use core::cell::RefCell;
+
+use critical_section::Mutex;
+
+#[derive(Clone, Debug)]
+pub struct Config {
+    /// How long the button has to be held for a long press
+    pub long_press_ms: u32,
+    /// LED brightness, percent
+    pub brightness: u8,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Config {
+    pub const fn new() -> Self {
+        Self {
+            long_press_ms: 1500,
+            brightness: 20,
+        }
+    }
+}
+
+static CONFIG: Mutex<RefCell<Config>> = Mutex::new(RefCell::new(Config::new()));
+
+/// Look at the settings without copying them all.
+pub fn with<R>(f: impl FnOnce(&Config) -> R) -> R {
+    critical_section::with(|cs| f(&CONFIG.borrow_ref(cs)))
+}
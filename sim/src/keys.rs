@@ -0,0 +1,102 @@
+//! The button, on the keyboard: Enter presses it, `l` and Enter holds it
+//! down for a long press, `q` and Enter quits.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+use std::{io::BufRead, thread, time::Duration};
+
+use embassy_time::Timer;
+use esp_println::println;
+
+use crate::{
+    button::{self, Button},
+    config,
+};
+
+/// How long a press holds the button down
+const PRESS_MS: u64 = 100;
+
+/// How often the button is looked at, standing in for the GPIO interrupt
+const POLL_MS: u64 = 5;
+
+/// Extra time a long press is held for. The button times it in 10 ms
+/// steps, and on a busy host each one can wake a little late.
+const HOLD_SLACK_MS: u64 = 500;
+
+static DOWN: AtomicBool = AtomicBool::new(false);
+
+/// Set while the button is let go and waiting for the next press, i.e. it's
+/// done with the last one
+static READY: AtomicBool = AtomicBool::new(false);
+
+pub struct Key;
+
+impl Button for Key {
+    async fn wait_for_press(&mut self) {
+        // An edge, like the GPIO: still held from last time doesn't count
+        while DOWN.load(Ordering::Relaxed) {
+            Timer::after_millis(POLL_MS).await;
+        }
+        READY.store(true, Ordering::Relaxed);
+        while !DOWN.load(Ordering::Relaxed) {
+            Timer::after_millis(POLL_MS).await;
+        }
+        READY.store(false, Ordering::Relaxed);
+    }
+
+    fn is_released(&mut self) -> bool {
+        !DOWN.load(Ordering::Relaxed)
+    }
+}
+
+#[embassy_executor::task]
+pub async fn start_button() {
+    button::watch(Key).await;
+}
+
+/// Read the keyboard on a thread of its own, so waiting for a line doesn't
+/// hold up the executor.
+pub fn listen() {
+    println!("keys: Enter presses the button, l holds it, q quits");
+
+    thread::spawn(|| {
+        for line in std::io::stdin().lock().lines() {
+            let Ok(line) = line else {
+                break;
+            };
+
+            match line.trim() {
+                "" => press(),
+                "l" => hold(),
+                "q" => std::process::exit(0),
+                _ => println!("keys: Enter presses the button, l holds it, q quits"),
+            }
+        }
+    });
+}
+
+/// Press the button and let go, blocking the calling thread until the press
+/// has been told apart and passed on.
+pub fn press() {
+    push(PRESS_MS);
+}
+
+/// Hold the button long enough for a long press.
+pub fn hold() {
+    push(config::with(|config| config.long_press_ms) as u64 + HOLD_SLACK_MS);
+}
+
+fn push(held_ms: u64) {
+    wait_until(true);
+    DOWN.store(true, Ordering::Relaxed);
+    // Timed from when the button noticed, as the board's would be
+    wait_until(false);
+    thread::sleep(Duration::from_millis(held_ms));
+    DOWN.store(false, Ordering::Relaxed);
+    wait_until(true);
+}
+
+fn wait_until(ready: bool) {
+    while READY.load(Ordering::Relaxed) != ready {
+        thread::sleep(Duration::from_millis(1));
+    }
+}
@@ -0,0 +1,75 @@
+//! The four lights, drawn on a line of their own whenever one changes:
+//! bottom to top, white, yellow, green and blue. Piped somewhere other than
+//! a terminal they're letters, so a run can be diffed against the last.
+
+use core::{cell::RefCell, convert::Infallible};
+use std::io::IsTerminal;
+
+use critical_section::Mutex;
+use embassy_time::{Duration, Instant};
+use esp_println::println;
+
+use crate::lights::{self, Lamp};
+
+/// Brightness of each light, in the order they're drawn
+static LEVELS: Mutex<RefCell<[u8; 4]>> = Mutex::new(RefCell::new([0; 4]));
+
+/// Letter and ANSI color of each light
+const LOOKS: [(char, u8); 4] = [('W', 97), ('Y', 93), ('G', 92), ('B', 94)];
+
+pub struct Led {
+    index: usize,
+    faded_at: Instant,
+}
+
+impl Led {
+    fn new(index: usize) -> Self {
+        Self {
+            index,
+            faded_at: Instant::now(),
+        }
+    }
+}
+
+impl Lamp for Led {
+    type Error = Infallible;
+
+    fn fading(&self) -> bool {
+        Instant::now() < self.faded_at
+    }
+
+    fn fade(&mut self, _from: u8, to: u8, duration: u16) -> Result<(), Infallible> {
+        self.faded_at = Instant::now() + Duration::from_millis(duration as u64);
+        let levels = critical_section::with(|cs| {
+            let mut levels = LEVELS.borrow_ref_mut(cs);
+            levels[self.index] = to;
+            *levels
+        });
+        draw(&levels);
+        Ok(())
+    }
+}
+
+/// Brightness of white, yellow, green and blue right now.
+pub fn levels() -> [u8; 4] {
+    critical_section::with(|cs| *LEVELS.borrow_ref(cs))
+}
+
+fn draw(levels: &[u8; 4]) {
+    let terminal = std::io::stdout().is_terminal();
+    let mut line = String::new();
+    for ((letter, color), level) in LOOKS.iter().zip(levels) {
+        match (*level > 0, terminal) {
+            (true, true) => line += &format!("\x1b[{color}m●\x1b[0m{level:>3} "),
+            (true, false) => line += &format!("{letter}{level:>3} "),
+            (false, true) => line += "○    ",
+            (false, false) => line += ".    ",
+        }
+    }
+    println!("lights: {}", line.trim_end());
+}
+
+#[embassy_executor::task]
+pub async fn start_leds() {
+    lights::show(Led::new(0), Led::new(1), Led::new(2), Led::new(3)).await;
+}
@@ -0,0 +1,100 @@
+//! The firmware's scenes, lights, button, storage and frame processing,
+//! built for the host against stand-ins for the board:
+//!
+//! - the lights are drawn in the terminal (`leds`)
+//! - the button is the keyboard (`keys`)
+//! - flash is a buffer in RAM (the `esp-storage` mock)
+//! - frames come from a capture instead of the radio (`replay`)
+//!
+//! The shared modules are compiled straight from `../src`. Those that only
+//! make sense on the board (the radios, the battery gauge, the supervisor
+//! and telemetry) are replaced by the few lines each here that the rest
+//! needs. The binary runs it all interactively; `tests/` runs it against
+//! the same capture.
+
+#[path = "../../src/alerts.rs"]
+pub mod alerts;
+#[path = "../../src/allowlist.rs"]
+pub mod allowlist;
+pub mod battery;
+#[path = "../../src/button.rs"]
+pub mod button;
+#[path = "../../src/channels.rs"]
+pub mod channels;
+#[path = "../../src/clock.rs"]
+pub mod clock;
+pub mod config;
+#[path = "../../src/config_format.rs"]
+pub mod config_format;
+#[path = "../../src/console.rs"]
+pub mod console;
+#[path = "../../src/dot15d4.rs"]
+pub mod dot15d4;
+#[path = "../../src/error.rs"]
+pub mod error;
+#[path = "../../src/export.rs"]
+pub mod export;
+#[path = "../../src/fingerprint.rs"]
+pub mod fingerprint;
+#[path = "../../src/foxhunt.rs"]
+pub mod foxhunt;
+#[path = "../../src/frame.rs"]
+pub mod frame;
+#[path = "../../src/http.rs"]
+pub mod http;
+#[path = "../../src/ingest.rs"]
+pub mod ingest;
+#[path = "../../src/journal.rs"]
+pub mod journal;
+pub mod keys;
+pub mod leds;
+#[path = "../../src/legacy.rs"]
+pub mod legacy;
+#[path = "../../src/lights.rs"]
+pub mod lights;
+#[path = "../../src/mode.rs"]
+pub mod mode;
+#[path = "../../src/mqtt.rs"]
+pub mod mqtt;
+#[path = "../../src/occupancy.rs"]
+pub mod occupancy;
+#[path = "../../src/oui.rs"]
+pub mod oui;
+#[path = "../../src/oui_format.rs"]
+pub mod oui_format;
+#[path = "../../src/query.rs"]
+pub mod query;
+#[path = "../../src/record.rs"]
+pub mod record;
+pub mod replay;
+#[path = "../../src/scene.rs"]
+pub mod scene;
+#[path = "../../src/spectrum.rs"]
+pub mod spectrum;
+#[path = "../../src/storage.rs"]
+pub mod storage;
+pub mod supervisor;
+pub mod telemetry;
+#[path = "../../src/upload.rs"]
+pub mod upload;
+#[path = "../../src/wids.rs"]
+pub mod wids;
+
+extern crate alloc;
+
+use embassy_executor::Spawner;
+
+/// Count the boot, open storage and start every task, replaying `capture`.
+pub fn start(spawner: Spawner, capture: String) {
+    clock::count_boot();
+    storage::init();
+    allowlist::load();
+
+    spawner.must_spawn(storage::start_storage());
+    spawner.must_spawn(leds::start_leds());
+    spawner.must_spawn(scene::setup_scene_manager());
+    spawner.must_spawn(keys::start_button());
+    spawner.must_spawn(ingest::start_frames());
+    spawner.must_spawn(alerts::start_alerts());
+    spawner.must_spawn(replay::start_replay(capture));
+}
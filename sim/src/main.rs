@@ -0,0 +1,16 @@
+//! The simulator, interactively: `cargo run` from this directory replays
+//! `frames.txt`; give it another capture to replay that instead.
+
+use embassy_executor::Spawner;
+use wif_sim::keys;
+
+/// The capture `cargo run` replays by default
+const CAPTURE: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/frames.txt");
+
+#[embassy_executor::main]
+async fn main(spawner: Spawner) {
+    let capture = std::env::args().nth(1).unwrap_or_else(|| CAPTURE.into());
+
+    wif_sim::start(spawner, capture);
+    keys::listen();
+}
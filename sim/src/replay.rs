@@ -0,0 +1,88 @@
+//! Frames played back from a capture, in place of the sniffer. See
+//! `frames.txt` for the format.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use embassy_time::Timer;
+use esp_println::println;
+
+use crate::{
+    channels::Phy,
+    ingest::{self, Sniffed},
+};
+
+/// One line of a capture: how long to wait, then the frame.
+fn parse(line: &str) -> Option<(u64, Sniffed)> {
+    let mut fields = line.split_whitespace();
+    let delay_ms = fields.next()?.parse().ok()?;
+    let channel = fields.next()?.parse().ok()?;
+    let rssi = fields.next()?.parse().ok()?;
+    let hex = fields.next()?;
+    if hex.len() % 2 != 0 || fields.next().is_some() {
+        return None;
+    }
+
+    let data = (0..hex.len())
+        .step_by(2)
+        .map(|index| u8::from_str_radix(hex.get(index..index + 2)?, 16).ok())
+        .collect::<Option<Vec<u8>>>()?;
+
+    Some((
+        delay_ms,
+        Sniffed {
+            len: data.len() as u16,
+            data,
+            channel,
+            rssi,
+            rate: 0,
+            phy: Phy::Legacy,
+            wide: false,
+        },
+    ))
+}
+
+/// Set once every frame in the capture has been handed to `ingest`
+static FINISHED: AtomicBool = AtomicBool::new(false);
+
+/// Whether the whole capture has been played and looked at.
+pub fn finished() -> bool {
+    FINISHED.load(Ordering::Relaxed)
+}
+
+#[embassy_executor::task]
+pub async fn start_replay(path: String) {
+    let capture = match std::fs::read_to_string(&path) {
+        Ok(capture) => capture,
+        Err(err) => {
+            println!("replay: couldn't read {path}: {err}");
+            return;
+        }
+    };
+
+    let mut frames = 0;
+    for (number, line) in capture.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let Some((delay_ms, rx)) = parse(line) else {
+            println!("replay: {path}:{}: not a frame", number + 1);
+            continue;
+        };
+
+        Timer::after_millis(delay_ms).await;
+        ingest::queue(rx);
+        frames += 1;
+    }
+
+    // The frame task was woken before us, so this lets it get through
+    // what's queued first
+    embassy_futures::yield_now().await;
+    FINISHED.store(true, Ordering::Relaxed);
+
+    println!(
+        "replay: {frames} frames from {path}, {} dropped",
+        ingest::dropped_frames()
+    );
+}
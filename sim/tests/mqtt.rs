@@ -0,0 +1,252 @@
+//! A live mode session against a broker stand-in, over a pair of pipes.
+
+use std::convert::Infallible;
+
+use embassy_futures::{
+    block_on,
+    join::join,
+    select::{select, Either},
+};
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, pipe::Pipe};
+use embassy_time::Timer;
+use embedded_io_async::{ErrorType, Read, Write};
+use wif_sim::{
+    alerts::Alert,
+    battery::{self, Level},
+    export,
+    mqtt::{self, MqttError, Settings},
+    record::Record,
+    storage,
+};
+
+type Wire = Pipe<CriticalSectionRawMutex, 1024>;
+
+/// The device's end of the connection.
+struct Client<'a> {
+    to_broker: &'a Wire,
+    from_broker: &'a Wire,
+}
+
+impl ErrorType for Client<'_> {
+    type Error = Infallible;
+}
+
+impl Read for Client<'_> {
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Infallible> {
+        Ok(self.from_broker.read(buf).await)
+    }
+}
+
+impl Write for Client<'_> {
+    async fn write(&mut self, buf: &[u8]) -> Result<usize, Infallible> {
+        Ok(self.to_broker.write(buf).await)
+    }
+}
+
+/// A packet as the broker reads it: the whole first byte, flags and all, and
+/// the body.
+async fn receive(mut wire: &Wire) -> (u8, Vec<u8>) {
+    let mut byte = [0u8];
+    wire.read_exact(&mut byte).await.unwrap();
+    let first = byte[0];
+
+    let mut len = 0;
+    for shift in 0..4 {
+        wire.read_exact(&mut byte).await.unwrap();
+        len |= ((byte[0] & 0x7f) as usize) << (7 * shift);
+        if byte[0] & 0x80 == 0 {
+            break;
+        }
+    }
+
+    let mut body = vec![0u8; len];
+    wire.read_exact(&mut body).await.unwrap();
+    (first, body)
+}
+
+async fn send(wire: &Wire, packet: &[u8]) {
+    wire.write_all(packet).await;
+}
+
+/// Pops length-prefixed strings and other fields off a packet body.
+struct Fields<'a>(&'a [u8]);
+
+impl<'a> Fields<'a> {
+    fn bytes(&mut self, len: usize) -> &'a [u8] {
+        let (bytes, rest) = self.0.split_at(len);
+        self.0 = rest;
+        bytes
+    }
+
+    fn u16(&mut self) -> u16 {
+        u16::from_be_bytes(self.bytes(2).try_into().unwrap())
+    }
+
+    fn string(&mut self) -> &'a str {
+        let len = self.u16() as usize;
+        std::str::from_utf8(self.bytes(len)).unwrap()
+    }
+}
+
+struct Connect<'a> {
+    flags: u8,
+    keep_alive: u16,
+    client_id: &'a str,
+    will_topic: &'a str,
+    will_payload: &'a str,
+}
+
+fn parse_connect(body: &[u8]) -> Connect<'_> {
+    let mut fields = Fields(body);
+    assert_eq!(fields.string(), "MQTT");
+    assert_eq!(fields.bytes(1), [4], "protocol level");
+    Connect {
+        flags: fields.bytes(1)[0],
+        keep_alive: fields.u16(),
+        client_id: fields.string(),
+        will_topic: fields.string(),
+        will_payload: fields.string(),
+    }
+}
+
+/// Topic, packet ID if it's QoS 1, and payload.
+fn parse_publish(first: u8, body: &[u8]) -> (&str, Option<u16>, &str) {
+    let mut fields = Fields(body);
+    let topic = fields.string();
+    let packet_id = (first & 0x06 != 0).then(|| fields.u16());
+    let payload = std::str::from_utf8(fields.0).unwrap();
+    (topic, packet_id, payload)
+}
+
+const CONNACK: [u8; 4] = [0x20, 2, 0, 0];
+/// CONNACK, "not authorized"
+const NOT_AUTHORIZED: [u8; 4] = [0x20, 2, 0, 5];
+
+fn puback(packet_id: u16) -> Vec<u8> {
+    let [high, low] = packet_id.to_be_bytes();
+    vec![0x40, 2, high, low]
+}
+
+#[test]
+fn publishes_under_the_prefix_with_a_last_will() {
+    let to_broker = Wire::new();
+    let from_broker = Wire::new();
+    let mut client = Client {
+        to_broker: &to_broker,
+        from_broker: &from_broker,
+    };
+
+    // Without a prefix set, topics go under the device ID. A refused login
+    // ends the session.
+    mqtt::configure(Settings::new());
+    let refused = async {
+        let (first, body) = receive(&to_broker).await;
+        assert_eq!(first, 0x10, "CONNECT");
+        assert_eq!(parse_connect(&body).will_topic, "wifblink/a1b2c3/status");
+        send(&from_broker, &NOT_AUTHORIZED).await;
+    };
+    let (session, ()) = block_on(join(mqtt::session(&mut client, "a1b2c3"), refused));
+    assert!(matches!(session, Err(MqttError::Refused)), "{session:?}");
+
+    mqtt::configure(Settings {
+        prefix: "home/board".into(),
+        qos: 1,
+        battery_secs: 1,
+        ..Settings::new()
+    });
+    let broker = async {
+        let (first, body) = receive(&to_broker).await;
+        assert_eq!(first, 0x10, "CONNECT");
+        let connect = parse_connect(&body);
+        // Clean session, a will, and the will retained
+        assert_eq!(connect.flags, 0x02 | 0x04 | 0x20);
+        assert_eq!(connect.keep_alive, 60);
+        assert_eq!(connect.client_id, "a1b2c3");
+        assert_eq!(connect.will_topic, "home/board/status");
+        assert_eq!(connect.will_payload, "offline");
+        send(&from_broker, &CONNACK).await;
+
+        // Retained, QoS 0
+        let (first, body) = receive(&to_broker).await;
+        assert_eq!(first, 0x31);
+        assert_eq!(
+            parse_publish(first, &body),
+            ("home/board/status", None, "online")
+        );
+
+        let network = Record::Network("CoffeeShop".into()).encode();
+        storage::APPENDED
+            .immediate_publisher()
+            .publish_immediate(network.clone());
+        let (first, body) = receive(&to_broker).await;
+        assert_eq!(first, 0x32, "QoS 1, not a duplicate");
+        let json = export::json_record(0, &network);
+        assert_eq!(
+            parse_publish(first, &body),
+            ("home/board/network", Some(1), json.as_str())
+        );
+
+        // Acknowledging something else gets it sent again, as a duplicate
+        send(&from_broker, &puback(9)).await;
+        let (first, resent) = receive(&to_broker).await;
+        assert_eq!(first, 0x3a, "QoS 1 duplicate");
+        assert_eq!(resent, body);
+        send(&from_broker, &puback(1)).await;
+
+        let alert = Record::Alert(Alert::DeauthFlood {
+            bssid: [0x3c, 0x84, 0x6a, 0x10, 0x20, 0x30],
+            frames: 40,
+        });
+        storage::APPENDED
+            .immediate_publisher()
+            .publish_immediate(alert.encode());
+        let (first, body) = receive(&to_broker).await;
+        assert_eq!(first, 0x32);
+        let (topic, packet_id, _) = parse_publish(first, &body);
+        assert_eq!((topic, packet_id), ("home/board/alert", Some(2)));
+        send(&from_broker, &puback(2)).await;
+
+        // Only moves on once that's been taken
+        let occupancy = Record::Occupancy {
+            count: 4,
+            window_s: 300,
+            min_rssi: -75,
+        };
+        storage::APPENDED
+            .immediate_publisher()
+            .publish_immediate(occupancy.encode());
+        let (first, body) = receive(&to_broker).await;
+        let (topic, packet_id, _) = parse_publish(first, &body);
+        assert_eq!((topic, packet_id), ("home/board/occupancy", Some(3)));
+        send(&from_broker, &puback(3)).await;
+
+        // Records coming in faster than the battery is checked don't keep
+        // it from going out
+        let level = Level {
+            percent: 80,
+            millivolts: 3950,
+            usb: false,
+        };
+        battery::set_level(Some(level));
+        for sent in 0.. {
+            assert!(sent < 20, "no battery reading among {sent} records");
+            storage::APPENDED
+                .immediate_publisher()
+                .publish_immediate(occupancy.encode());
+            let (first, body) = receive(&to_broker).await;
+            let (topic, packet_id, payload) = parse_publish(first, &body);
+            send(&from_broker, &puback(packet_id.unwrap())).await;
+            if topic == "home/board/battery" {
+                assert_eq!(payload, export::battery_json(&level));
+                break;
+            }
+            assert_eq!(topic, "home/board/occupancy");
+            Timer::after_millis(200).await;
+        }
+    };
+
+    match block_on(select(mqtt::session(&mut client, "a1b2c3"), broker)) {
+        Either::First(ended) => panic!("session ended: {ended:?}"),
+        Either::Second(()) => (),
+    }
+}
@@ -0,0 +1,129 @@
+//! Plays `frames.txt` through the simulator and checks what a person holding
+//! the board would: the alert strobe, what ends up stored, and the menu.
+//!
+//! Everything shares one flash and one executor, so it's a single test.
+
+use std::{
+    collections::BTreeSet,
+    thread,
+    time::{Duration, Instant},
+};
+
+use embassy_executor::Executor;
+use wif_sim::{
+    alerts::{self, Alert},
+    keys, leds,
+    query::Query,
+    record::Record,
+    replay,
+    storage::{self, Store},
+};
+
+const CAPTURE: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/frames.txt");
+
+/// Wait until `done`, for at most `timeout`.
+fn wait_for(timeout: Duration, mut done: impl FnMut() -> bool) -> bool {
+    let started = Instant::now();
+    while started.elapsed() < timeout {
+        if done() {
+            return true;
+        }
+        thread::sleep(Duration::from_millis(5));
+    }
+    false
+}
+
+/// The lights on right now.
+fn lit() -> BTreeSet<usize> {
+    leds::levels()
+        .into_iter()
+        .enumerate()
+        .filter(|(_, level)| *level > 0)
+        .map(|(index, _)| index)
+        .collect()
+}
+
+/// Every light that comes on over `period`, whichever order they blink in.
+fn lit_over(period: Duration) -> BTreeSet<usize> {
+    let mut seen = BTreeSet::new();
+    let started = Instant::now();
+    while started.elapsed() < period {
+        seen.extend(lit());
+        thread::sleep(Duration::from_millis(5));
+    }
+    seen
+}
+
+/// Wait for the menu to light up `option`, then check it blinks nothing
+/// else for a while: a couple of its 400 ms blinks.
+fn shows(option: &[usize]) -> BTreeSet<usize> {
+    let option = BTreeSet::from_iter(option.iter().copied());
+    if !wait_for(Duration::from_secs(10), || lit() == option) {
+        return lit();
+    }
+    lit_over(Duration::from_secs(1))
+}
+
+fn stored() -> Vec<Record> {
+    embassy_futures::block_on(storage::flush()).unwrap();
+    Store::new()
+        .unwrap()
+        .records(Query::default(), 0)
+        .unwrap()
+        .map(|record| Record::decode(&record.unwrap().1).unwrap())
+        .collect()
+}
+
+#[test]
+fn replay() {
+    thread::spawn(|| {
+        let executor: &'static mut Executor = Box::leak(Box::new(Executor::new()));
+        executor.run(|spawner| wif_sim::start(spawner, CAPTURE.into()));
+    });
+
+    // The deauth flood strobes every light at full brightness
+    assert!(
+        wait_for(Duration::from_secs(10), || leds::levels() == [100; 4]),
+        "no alert strobe"
+    );
+
+    assert!(
+        wait_for(Duration::from_secs(10), || {
+            stored()
+                .iter()
+                .any(|record| matches!(record, Record::Alert(Alert::DeauthFlood { .. })))
+        }),
+        "no deauth flood stored"
+    );
+
+    let networks: BTreeSet<String> = stored()
+        .into_iter()
+        .filter_map(|record| match record {
+            Record::Network(ssid) => Some(ssid),
+            _ => None,
+        })
+        .collect();
+    // The hidden network's SSID of zeros isn't one
+    assert_eq!(
+        networks,
+        BTreeSet::from(["CoffeeShop", "Home-5G", "Library Guest"].map(String::from))
+    );
+
+    // Nothing but the menu on the lights: the capture's done, and so are
+    // the alerts it raised
+    let settled = || replay::finished() && alerts::idle();
+    assert!(
+        wait_for(Duration::from_secs(30), settled),
+        "alerts still showing"
+    );
+
+    // Each press returns once the button's passed it on, so they reach the
+    // menu in order however slow the host is
+    keys::hold();
+    assert_eq!(shows(&[0]), BTreeSet::from([0]), "Sniff");
+
+    for _ in 0..5 {
+        keys::press();
+    }
+    assert_eq!(shows(&[1, 2]), BTreeSet::from([1, 2]), "Occupancy");
+}
@@ -0,0 +1,163 @@
+//! The storage task on its own, against the RAM flash.
+
+use std::{
+    future::ready,
+    thread,
+    time::{Duration, Instant},
+};
+
+use embassy_executor::Executor;
+use embassy_futures::{block_on, select::select};
+use wif_sim::{
+    alerts::Alert,
+    fingerprint::Sighting,
+    query::{Cursor, Query},
+    record::{self, Record},
+    storage::{self, Appended, Store},
+};
+
+fn start() {
+    storage::init();
+    thread::spawn(|| {
+        let executor: &'static mut Executor = Box::leak(Box::new(Executor::new()));
+        executor.run(|spawner| spawner.must_spawn(storage::start_storage()));
+    });
+}
+
+fn append(record: &Record) -> Appended {
+    block_on(storage::append(record.encode()))
+}
+
+/// Ask for an append, but stop waiting as soon as it's been asked for.
+fn give_up_on(record: &Record) {
+    block_on(select(storage::append(record.encode()), ready(())));
+}
+
+#[test]
+fn keeps_every_reading_but_only_the_first_sighting() {
+    start();
+
+    let sightings = [
+        Record::Network("CoffeeShop".into()),
+        // Looks like an alert if networks weren't tagged
+        Record::Network("\u{1}\u{3}\u{0}\u{1}\u{2}\u{3}\u{4}\u{5}(\u{0}".into()),
+        Record::Device(Sighting {
+            device: 7,
+            fingerprint: 0x1234_5678,
+            mac: [0x02, 0, 0, 0, 0, 1],
+            linked: false,
+        }),
+    ];
+    let readings = [
+        Record::Occupancy {
+            count: 4,
+            window_s: 300,
+            min_rssi: -75,
+        },
+        Record::Alert(Alert::DeauthFlood {
+            bssid: [0, 1, 2, 3, 4, 5],
+            frames: 40,
+        }),
+        Record::Spectrum(Vec::new()),
+    ];
+
+    // Only what's newly stored goes out live
+    let mut live = storage::APPENDED.subscriber().unwrap();
+    for record in &sightings {
+        assert_eq!(append(record), Appended::Stored, "{record}");
+        assert!(live.try_next_message_pure().is_some(), "{record}");
+        assert_eq!(append(record), Appended::Duplicate, "{record}");
+        assert!(live.try_next_message_pure().is_none(), "{record}");
+    }
+    for record in &readings {
+        assert_eq!(append(record), Appended::Stored, "{record}");
+        assert_eq!(append(record), Appended::Stored, "{record}");
+        assert_eq!(live.available(), 2, "{record}");
+        while live.try_next_message_pure().is_some() {}
+    }
+
+    // More networks than fit in the index still only go in once
+    let networks: Vec<Record> = (0..1100)
+        .map(|number| Record::Network(format!("Network {number}")))
+        .collect();
+    let batch = networks.iter().map(Record::encode).collect();
+    for appended in block_on(storage::append_batch(batch)) {
+        assert_eq!(appended, Appended::Stored);
+    }
+    for record in [&networks[0], &networks[1099], &sightings[0]] {
+        assert_eq!(append(record), Appended::Duplicate, "{record}");
+    }
+    assert_eq!(append(&Record::Network("Another".into())), Appended::Stored);
+    while live.try_next_message_pure().is_some() {}
+
+    // Replies nobody's waiting for anymore don't hold the task up. Holding
+    // the critical section keeps the task from answering until both callers
+    // have given up.
+    let stored = storage::counters().stored;
+    critical_section::with(|_| {
+        give_up_on(&readings[0]);
+        give_up_on(&readings[0]);
+    });
+    assert!(storage::try_append(readings[0].encode()));
+    let started = Instant::now();
+    while storage::counters().stored < stored + 3 {
+        assert!(started.elapsed() < Duration::from_secs(2), "stuck");
+        thread::sleep(Duration::from_millis(5));
+    }
+    assert_eq!(append(&readings[1]), Appended::Stored);
+
+    // A write failing part way through a batch loses nothing: what was
+    // held back for the page is written before the log is reopened. The
+    // first page written has just the start of the batch on it, the second
+    // has records waiting for it.
+    let from = Store::new().unwrap().len().unwrap() as u32;
+    let batch: Vec<Record> = (0..40)
+        .map(|count| Record::Occupancy {
+            count,
+            window_s: 60,
+            min_rssi: -80,
+        })
+        .collect();
+    esp_storage::fail_write(1);
+    let results = block_on(storage::append_batch(
+        batch.iter().map(Record::encode).collect(),
+    ));
+    assert!(!esp_storage::failing_write());
+    assert!(results.iter().all(|appended| *appended == Appended::Stored));
+    let after = Cursor {
+        generation: None,
+        id: from,
+    };
+    let page = Store::new()
+        .unwrap()
+        .page(Query::default(), after, 50)
+        .unwrap();
+    let stored: Vec<&[u8]> = page
+        .records
+        .iter()
+        .map(|(_, bytes)| record::unstamp(bytes).1)
+        .collect();
+    let expected: Vec<Vec<u8>> = batch.iter().map(Record::encode).collect();
+    assert_eq!(stored, expected);
+
+    // A page from before an erase starts over, IDs count from 0 again
+    let before = Store::new()
+        .unwrap()
+        .page(Query::default(), Cursor::default(), 2)
+        .unwrap();
+    block_on(storage::erase()).unwrap();
+    assert_eq!(append(&sightings[0]), Appended::Stored);
+    let stale = Cursor {
+        generation: Some(before.generation),
+        id: before.next.unwrap(),
+    };
+    let after = Store::new()
+        .unwrap()
+        .page(Query::default(), stale, 2)
+        .unwrap();
+    assert_ne!(after.generation, before.generation);
+    assert_eq!(after.records.len(), 1);
+    let (id, bytes) = &after.records[0];
+    assert_eq!(*id, 0);
+    assert_eq!(record::unstamp(bytes).1, sightings[0].encode());
+}
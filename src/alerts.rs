@@ -0,0 +1,200 @@
+use alloc::{string::String, vec::Vec};
+use core::{
+    fmt,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, pubsub::PubSubChannel};
+use esp_println::println;
+
+use crate::{
+    frame::{MacAddress, Security},
+    lights,
+    oui::Named,
+    record::{Escaped, Reader, Record},
+    storage, telemetry,
+};
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Alert {
+    /// Burst of deauthentication/disassociation frames for one BSSID
+    DeauthFlood { bssid: MacAddress, frames: u16 },
+    /// Channel Switch Announcement that doesn't line up with the real AP
+    ChannelSwitchSpoof { bssid: MacAddress, channel: u8 },
+    /// One transmitter beaconing lots of different SSIDs
+    BeaconFlood { transmitter: MacAddress, ssids: u16 },
+    /// Sequence numbers or TSF jumping around, i.e. two radios using one BSSID
+    BeaconSpoof { bssid: MacAddress, anomalies: u16 },
+    /// A trusted SSID from a BSSID that isn't on the allowlist
+    EvilTwin { bssid: MacAddress, ssid: String },
+    /// A trusted SSID advertising weaker security than it should
+    SecurityDowngrade {
+        bssid: MacAddress,
+        ssid: String,
+        security: Security,
+    },
+}
+
+impl Alert {
+    pub(crate) fn encode(&self, bytes: &mut Vec<u8>) {
+        match self {
+            Self::DeauthFlood { bssid, frames } => {
+                bytes.push(0);
+                bytes.extend_from_slice(bssid);
+                bytes.extend_from_slice(&frames.to_le_bytes());
+            }
+            Self::ChannelSwitchSpoof { bssid, channel } => {
+                bytes.push(1);
+                bytes.extend_from_slice(bssid);
+                bytes.push(*channel);
+            }
+            Self::BeaconFlood { transmitter, ssids } => {
+                bytes.push(2);
+                bytes.extend_from_slice(transmitter);
+                bytes.extend_from_slice(&ssids.to_le_bytes());
+            }
+            Self::BeaconSpoof { bssid, anomalies } => {
+                bytes.push(3);
+                bytes.extend_from_slice(bssid);
+                bytes.extend_from_slice(&anomalies.to_le_bytes());
+            }
+            Self::EvilTwin { bssid, ssid } => {
+                bytes.push(4);
+                bytes.extend_from_slice(bssid);
+                bytes.push(ssid.len() as u8);
+                bytes.extend_from_slice(ssid.as_bytes());
+            }
+            Self::SecurityDowngrade {
+                bssid,
+                ssid,
+                security,
+            } => {
+                bytes.push(5);
+                bytes.extend_from_slice(bssid);
+                bytes.push(*security as u8);
+                bytes.push(ssid.len() as u8);
+                bytes.extend_from_slice(ssid.as_bytes());
+            }
+        }
+    }
+
+    /// Someone impersonating one of our own networks, as opposed to an attack
+    /// on the air in general.
+    pub fn is_rogue(&self) -> bool {
+        matches!(self, Self::EvilTwin { .. } | Self::SecurityDowngrade { .. })
+    }
+
+    pub(crate) fn decode(bytes: &[u8]) -> Option<Self> {
+        let mut reader = Reader(bytes);
+        let kind = reader.u8()?;
+        let address = reader.array::<6>()?;
+
+        Some(match kind {
+            0 => Self::DeauthFlood {
+                bssid: address,
+                frames: reader.u16()?,
+            },
+            1 => Self::ChannelSwitchSpoof {
+                bssid: address,
+                channel: reader.u8()?,
+            },
+            2 => Self::BeaconFlood {
+                transmitter: address,
+                ssids: reader.u16()?,
+            },
+            3 => Self::BeaconSpoof {
+                bssid: address,
+                anomalies: reader.u16()?,
+            },
+            4 => Self::EvilTwin {
+                bssid: address,
+                ssid: read_ssid(&mut reader)?,
+            },
+            5 => Self::SecurityDowngrade {
+                bssid: address,
+                security: Security::from_u8(reader.u8()?)?,
+                ssid: read_ssid(&mut reader)?,
+            },
+            _ => return None,
+        })
+    }
+}
+
+fn read_ssid(reader: &mut Reader) -> Option<String> {
+    let len = reader.u8()? as usize;
+    String::from_utf8(reader.bytes(len)?.to_vec()).ok()
+}
+
+impl fmt::Display for Alert {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::DeauthFlood { bssid, frames } => {
+                write!(f, "deauth flood {} ({frames} frames)", Named(bssid))
+            }
+            Self::ChannelSwitchSpoof { bssid, channel } => {
+                write!(f, "spoofed channel switch {} -> {channel}", Named(bssid))
+            }
+            Self::BeaconFlood { transmitter, ssids } => {
+                write!(f, "beacon flood {} ({ssids} ssids)", Named(transmitter))
+            }
+            Self::BeaconSpoof { bssid, anomalies } => {
+                write!(f, "beacon spoofing {} ({anomalies} anomalies)", Named(bssid))
+            }
+            Self::EvilTwin { bssid, ssid } => {
+                write!(f, "evil twin of {} from {}", Escaped(ssid), Named(bssid))
+            }
+            Self::SecurityDowngrade {
+                bssid,
+                ssid,
+                security,
+            } => write!(
+                f,
+                "{} downgraded to {security:?} by {}",
+                Escaped(ssid),
+                Named(bssid)
+            ),
+        }
+    }
+}
+
+static ALERT_CHANNEL: PubSubChannel<CriticalSectionRawMutex, Alert, 8, 1, 4> =
+    PubSubChannel::<CriticalSectionRawMutex, Alert, 8, 1, 4>::new();
+
+/// Set from raising an alert until the alert task has none left to show
+static BUSY: AtomicBool = AtomicBool::new(false);
+
+/// Raise an alert from the sniffer callback. Never blocks: if the alert task
+/// is behind, the oldest pending alert is dropped instead.
+pub fn raise(alert: Alert) {
+    ALERT_CHANNEL.immediate_publisher().publish_immediate(alert);
+    BUSY.store(true, Ordering::Relaxed);
+}
+
+/// Every alert raised so far has been stored and shown. The simulator's
+/// tests wait on this rather than for however long the lights might take.
+#[cfg(not(target_os = "none"))]
+pub fn idle() -> bool {
+    !BUSY.load(Ordering::Relaxed)
+}
+
+#[embassy_executor::task]
+pub async fn start_alerts() {
+    let mut subscriber = ALERT_CHANNEL.subscriber().unwrap();
+
+    loop {
+        let alert = subscriber.next_message_pure().await;
+        println!("ALERT: {}", alert);
+        telemetry::alert(&alert);
+
+        let rogue = alert.is_rogue();
+        storage::append(Record::Alert(alert).encode()).await;
+
+        if rogue {
+            lights::warning().await;
+        } else {
+            lights::alert().await;
+        }
+
+        BUSY.store(!ALERT_CHANNEL.is_empty(), Ordering::Relaxed);
+    }
+}
@@ -0,0 +1,334 @@
+//! Our own networks, so we can spot somebody impersonating them.
+//!
+//! Each trusted SSID lists the BSSIDs that legitimately serve it and the
+//! security it is configured with. A beacon for a trusted SSID from any other
+//! BSSID is an evil twin; one that advertises weaker security than we expect is
+//! a downgrade (e.g. an open copy of our WPA2 network).
+
+use core::cell::RefCell;
+
+use alloc::{
+    string::{String, ToString},
+    vec::Vec,
+};
+use critical_section::Mutex;
+use embedded_storage::{ReadStorage, Storage};
+use esp_println::println;
+use esp_storage::FlashStorage;
+
+use crate::{
+    alerts::Alert,
+    frame::{Mac, MacAddress, Security},
+    record::{Escaped, Reader},
+    storage,
+};
+
+const MAGIC: &[u8; 4] = b"ALW1";
+
+/// The allowlist has one sector to itself
+const MAX_LEN: usize = storage::SECTOR_SIZE as usize;
+
+const MAX_SSID_LEN: usize = 32;
+
+#[derive(Clone, Debug)]
+pub struct Trusted {
+    pub ssid: String,
+    pub security: Security,
+    pub bssids: Vec<MacAddress>,
+}
+
+pub struct Allowlist {
+    networks: Vec<Trusted>,
+}
+
+static ALLOWLIST: Mutex<RefCell<Allowlist>> = Mutex::new(RefCell::new(Allowlist::new()));
+
+/// Read the allowlist out of flash. Call once before sniffing starts.
+pub fn load() {
+    let allowlist = Allowlist::load();
+
+    println!("Trusted networks:");
+    for network in allowlist.networks() {
+        println!("  {} ({:?})", Escaped(&network.ssid), network.security);
+        for bssid in &network.bssids {
+            println!("    {}", Mac(bssid));
+        }
+    }
+
+    critical_section::with(|cs| *ALLOWLIST.borrow_ref_mut(cs) = allowlist);
+}
+
+/// Every trusted network.
+pub fn trusted() -> Vec<Trusted> {
+    critical_section::with(|cs| ALLOWLIST.borrow_ref(cs).networks().to_vec())
+}
+
+/// Trust `bssid` as serving `ssid` with `security`, and persist it.
+pub fn trust(ssid: &str, bssid: MacAddress, security: Security) -> Result<(), &'static str> {
+    let bytes = critical_section::with(|cs| {
+        let mut allowlist = ALLOWLIST.borrow_ref_mut(cs);
+        allowlist.trust(ssid, bssid, security)?;
+        Ok(allowlist.encode())
+    })?;
+
+    Allowlist::write(&bytes);
+    Ok(())
+}
+
+/// Stop trusting `ssid` altogether. Returns false if it wasn't trusted.
+pub fn forget(ssid: &str) -> bool {
+    let bytes = critical_section::with(|cs| {
+        let mut allowlist = ALLOWLIST.borrow_ref_mut(cs);
+        allowlist.forget(ssid).then(|| allowlist.encode())
+    });
+
+    match bytes {
+        Some(bytes) => {
+            Allowlist::write(&bytes);
+            true
+        }
+        None => false,
+    }
+}
+
+/// Check a beacon against the allowlist. Safe to call from the sniffer.
+pub fn check(ssid: &str, bssid: MacAddress, security: Security) -> Option<Alert> {
+    critical_section::with(|cs| ALLOWLIST.borrow_ref(cs).check(ssid, bssid, security))
+}
+
+impl Default for Allowlist {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Allowlist {
+    pub const fn new() -> Self {
+        Self {
+            networks: Vec::new(),
+        }
+    }
+
+    fn load() -> Self {
+        let mut storage = FlashStorage::new();
+        let mut bytes = alloc::vec![0u8; MAX_LEN];
+        if let Err(err) =
+            storage.read(storage::reserved_sector(storage::ALLOWLIST_SECTOR), &mut bytes)
+        {
+            println!("allowlist: couldn't read: {:?}", err);
+            return Self::new();
+        }
+
+        Self::decode(&bytes).unwrap_or_default()
+    }
+
+    fn write(bytes: &[u8]) {
+        let mut storage = FlashStorage::new();
+        if let Err(err) = storage.write(storage::reserved_sector(storage::ALLOWLIST_SECTOR), bytes) {
+            println!("allowlist: couldn't save: {:?}", err);
+        }
+    }
+
+    pub fn networks(&self) -> &[Trusted] {
+        &self.networks
+    }
+
+    /// Add `bssid` to `ssid`'s network, unless the allowlist would no longer
+    /// fit its sector or its one-byte counts.
+    pub fn trust(
+        &mut self,
+        ssid: &str,
+        bssid: MacAddress,
+        security: Security,
+    ) -> Result<(), &'static str> {
+        if ssid.len() > MAX_SSID_LEN {
+            return Err("SSIDs are at most 32 bytes");
+        }
+
+        let mut networks = self.networks.clone();
+        match networks.iter_mut().find(|network| network.ssid == ssid) {
+            Some(network) => {
+                network.security = security;
+                if !network.bssids.contains(&bssid) {
+                    if network.bssids.len() >= u8::MAX as usize {
+                        return Err("too many BSSIDs for one network");
+                    }
+                    network.bssids.push(bssid);
+                }
+            }
+            None => {
+                if networks.len() >= u8::MAX as usize {
+                    return Err("too many trusted networks");
+                }
+                networks.push(Trusted {
+                    ssid: ssid.to_string(),
+                    security,
+                    bssids: alloc::vec![bssid],
+                });
+            }
+        }
+
+        let allowlist = Self { networks };
+        if allowlist.encode().len() > MAX_LEN {
+            return Err("allowlist is full");
+        }
+        *self = allowlist;
+        Ok(())
+    }
+
+    pub fn forget(&mut self, ssid: &str) -> bool {
+        let before = self.networks.len();
+        self.networks.retain(|network| network.ssid != ssid);
+        self.networks.len() != before
+    }
+
+    pub fn check(&self, ssid: &str, bssid: MacAddress, security: Security) -> Option<Alert> {
+        let network = self.networks.iter().find(|network| network.ssid == ssid)?;
+
+        if !network.bssids.contains(&bssid) {
+            return Some(Alert::EvilTwin {
+                bssid,
+                ssid: ssid.to_string(),
+            });
+        }
+
+        if security < network.security {
+            return Some(Alert::SecurityDowngrade {
+                bssid,
+                ssid: ssid.to_string(),
+                security,
+            });
+        }
+
+        None
+    }
+
+    /// `trust` keeps every count to a byte.
+    fn encode(&self) -> Vec<u8> {
+        let mut bytes = MAGIC.to_vec();
+        bytes.push(self.networks.len() as u8);
+
+        for network in &self.networks {
+            bytes.push(network.security as u8);
+            bytes.push(network.ssid.len() as u8);
+            bytes.extend_from_slice(network.ssid.as_bytes());
+            bytes.push(network.bssids.len() as u8);
+            for bssid in &network.bssids {
+                bytes.extend_from_slice(bssid);
+            }
+        }
+
+        bytes
+    }
+
+    fn decode(bytes: &[u8]) -> Option<Self> {
+        let mut reader = Reader(bytes);
+        if reader.bytes(MAGIC.len())? != MAGIC {
+            // Erased or never written
+            return None;
+        }
+
+        let mut networks = Vec::new();
+        for _ in 0..reader.u8()? {
+            let security = Security::from_u8(reader.u8()?)?;
+            let len = reader.u8()? as usize;
+            let ssid = String::from_utf8(reader.bytes(len)?.to_vec()).ok()?;
+
+            let mut bssids = Vec::new();
+            for _ in 0..reader.u8()? {
+                bssids.push(reader.array::<6>()?);
+            }
+
+            networks.push(Trusted {
+                ssid,
+                security,
+                bssids,
+            });
+        }
+
+        Some(Self { networks })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bssid(index: usize) -> MacAddress {
+        let [.., a, b] = (index as u32).to_be_bytes();
+        [0x3c, 0x84, 0x6a, 0x10, a, b]
+    }
+
+    #[test]
+    fn reads_back_what_it_wrote() {
+        let mut allowlist = Allowlist::new();
+        allowlist.trust("Home", bssid(1), Security::Wpa2).unwrap();
+        allowlist.trust("Home", bssid(2), Security::Wpa3).unwrap();
+        allowlist.trust("Home", bssid(2), Security::Wpa3).unwrap();
+        let clear = "\u{1b}[2J";
+        allowlist.trust(clear, bssid(3), Security::Open).unwrap();
+
+        let decoded = Allowlist::decode(&allowlist.encode()).unwrap();
+        let networks = decoded.networks();
+        assert_eq!(networks.len(), 2);
+        assert_eq!(networks[0].ssid, "Home");
+        assert_eq!(networks[0].security, Security::Wpa3);
+        assert_eq!(networks[0].bssids, [bssid(1), bssid(2)]);
+        assert_eq!(networks[1].ssid, clear);
+        assert_eq!(Escaped(&networks[1].ssid).to_string(), "\\u{1b}[2J");
+    }
+
+    #[test]
+    fn refuses_what_would_not_read_back() {
+        let mut allowlist = Allowlist::new();
+        assert!(allowlist
+            .trust(&"x".repeat(33), bssid(0), Security::Wpa2)
+            .is_err());
+        allowlist
+            .trust(&"x".repeat(32), bssid(0), Security::Wpa2)
+            .unwrap();
+
+        // Counts are a byte each
+        for index in 0..255 {
+            allowlist
+                .trust("Office", bssid(index), Security::Wpa2)
+                .unwrap();
+        }
+        assert_eq!(
+            allowlist.trust("Office", bssid(255), Security::Wpa2),
+            Err("too many BSSIDs for one network")
+        );
+        // Already trusted, so nothing to add
+        allowlist.trust("Office", bssid(1), Security::Wpa3).unwrap();
+
+        // Nor past its sector
+        let mut added = 0;
+        let err = loop {
+            match allowlist.trust(&format!("network {added}"), bssid(added), Security::Wpa2) {
+                Ok(()) => added += 1,
+                Err(err) => break err,
+            }
+        };
+        assert_eq!(err, "allowlist is full");
+        let encoded = allowlist.encode();
+        assert!(encoded.len() <= MAX_LEN);
+        let decoded = Allowlist::decode(&encoded).unwrap();
+        assert_eq!(decoded.networks().len(), added + 2);
+        assert_eq!(decoded.networks()[1].bssids.len(), 255);
+
+        let mut allowlist = Allowlist::new();
+        for index in 0..255 {
+            let ssid = format!("{index:02x}");
+            allowlist
+                .trust(&ssid, bssid(index), Security::Wpa2)
+                .unwrap();
+        }
+        assert_eq!(
+            allowlist.trust("ff!", bssid(255), Security::Wpa2),
+            Err("too many trusted networks")
+        );
+        let decoded = Allowlist::decode(&allowlist.encode()).unwrap();
+        assert_eq!(decoded.networks().len(), 255);
+        assert_eq!(decoded.networks()[254].ssid, "fe");
+    }
+}
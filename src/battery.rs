@@ -1,3 +1,6 @@
+use core::cell::Cell;
+
+use critical_section::Mutex;
 use embassy_futures::{join, select};
 use embassy_time::Timer;
 use esp32c6::lp_aon::usb;
@@ -8,16 +11,68 @@ use esp_hal::{
 };
 use esp_println::println;
 
+use crate::{
+    config,
+    error::{self, Subsystem},
+    supervisor::{self, Task},
+};
+
 const DEFAULT_RCOMP: u8 = 0x97;
 
+/// MAX17048 fuel gauge I2C address
+const GAUGE_ADDRESS: u8 = 0x36;
+
+/// Readings in a row the gauge can miss before it's reported missing
+const MAX_MISSED: u32 = 3;
+
+#[derive(Clone, Copy, Debug)]
+pub struct Level {
+    pub percent: u16,
+    pub millivolts: u16,
+    /// Running off USB power
+    pub usb: bool,
+}
+
+static LEVEL: Mutex<Cell<Option<Level>>> = Mutex::new(Cell::new(None));
+
+/// The most recent gauge reading, if it's answered yet.
+pub fn level() -> Option<Level> {
+    critical_section::with(|cs| LEVEL.borrow(cs).get())
+}
+
 type AsyncI2C = I2c<'static, esp_hal::peripherals::I2C0, Async>;
 
 #[embassy_executor::task]
 pub async fn start_battery(i2c: AsyncI2C, usb_pin: GpioPin<16>) {
     let mut usb = Input::new(usb_pin, Pull::Down);
+    let mut gauge = Max17048::new(i2c, GAUGE_ADDRESS).await;
+    let mut missed = 0;
 
     loop {
-        select::select(Timer::after_secs(5), usb.wait_for_any_edge()).await;
+        supervisor::beat(Task::Battery);
+        match (gauge.soc().await, gauge.vcell().await) {
+            (Ok(percent), Ok(volts)) => {
+                let level = Level {
+                    percent,
+                    millivolts: (volts * 1000.0) as u16,
+                    usb: usb.is_high(),
+                };
+                critical_section::with(|cs| LEVEL.borrow(cs).set(Some(level)));
+                missed = 0;
+                error::enable(Subsystem::Battery);
+            }
+            (Err(err), _) | (_, Err(err)) => {
+                missed += 1;
+                if missed == MAX_MISSED {
+                    critical_section::with(|cs| LEVEL.borrow(cs).set(None));
+                    error::disable(Subsystem::Battery, err.into()).await;
+                }
+            }
+        }
+
+        let period = config::with(|config| config.battery_secs);
+        supervisor::wait(Task::Battery);
+        select::select(Timer::after_secs(period as u64), usb.wait_for_any_edge()).await;
     }
 }
 
@@ -1,4 +1,6 @@
-use alloc::{string::String, vec::Vec};
+use core::{cell::RefCell, fmt::Write};
+
+use alloc::string::String;
 use bleps::{
     ad_structure::{
         create_advertising_data, AdStructure, BR_EDR_NOT_SUPPORTED, LE_GENERAL_DISCOVERABLE,
@@ -6,7 +8,7 @@ use bleps::{
     async_attribute_server::AttributeServer,
     asynch::Ble,
     attribute_server::WorkResult,
-    gatt, HciConnector,
+    gatt,
 };
 use embassy_time::{Duration, Timer};
 use esp_hal::{
@@ -18,7 +20,20 @@ use esp_hal::{
 use esp_println::println;
 use esp_wifi::{ble::controller::asynch::BleConnector, init, EspWifiInitFor};
 
-use crate::storage;
+use crate::{
+    channels,
+    clock::{self, Source},
+    config,
+    console::Filter,
+    crash,
+    error::{self, Error, Subsystem},
+    query::Cursor,
+    record::{self, Record},
+    storage::Store,
+};
+
+/// Records per page of the records characteristic
+const PAGE: usize = 50;
 
 #[embassy_executor::task]
 pub async fn start_bluetooth(
@@ -26,82 +41,205 @@ pub async fn start_bluetooth(
     rng: Rng,
     radio_clock: RADIO_CLK,
     mut bluetooth: BT,
-) -> ! {
-    // let init = init(EspWifiInitFor::Ble, timer, rng, radio_clock).unwrap();
+) {
+    let init = match init(EspWifiInitFor::Ble, timer, rng, radio_clock) {
+        Ok(init) => init,
+        Err(err) => return error::disable(Subsystem::Radio, err.into()).await,
+    };
     println!("ble initialized");
 
     loop {
-        // let connector = BleConnector::new(&init, &mut bluetooth);
-
-        // let now = || time::now().duration_since_epoch().to_millis();
-        // let mut ble = Ble::new(connector, now);
-
-        // ble.init().await;
-        // ble.cmd_set_le_advertising_parameters().await;
-
-        // ble.cmd_set_le_advertising_data(
-        //     create_advertising_data(&[
-        //         AdStructure::CompleteLocalName("wifblink"),
-        //         AdStructure::Flags(LE_GENERAL_DISCOVERABLE | BR_EDR_NOT_SUPPORTED),
-        //         AdStructure::ServiceUuids16(&[Uuid::Uuid16(0x1809)]),
-        //     ])
-        //     .unwrap(),
-        // )
-        // .await;
-        // // println!("{:?}", ble.cmd_set_le_advertise_enable(true).await);
-        // println!("started advertising");
-
-        // let mut store = storage::Store::new();
-        // let entries_vec = store
-        //     .entries()
-        //     .into_iter()
-        //     .map(|bytes| {
-        //         let string = String::from_utf8(bytes).unwrap();
-        //         println!("entry: {:?}", string);
-        //         return string;
-        //     })
-        //     .collect::<Vec<String>>();
-        // let entries_string = entries_vec.join("\n");
-
-        // println!("entries string: {:?}", entries_string);
-
-        // let entries = entries_string.as_bytes();
-
-        // let mut rf = |_offset: usize, data: &mut [u8]| {
-        //     data[..entries.len()].copy_from_slice(entries);
-        //     entries.len()
-        // };
-
-        // let mut wf = |offset: usize, data: &[u8]| {
-        //     println!("RECEIVED: {} {:?}", offset, data);
-        // };
-
-        // gatt!([service {
-        //     uuid: "e6a0ea50-6a66-013d-0514-061a78fcc099",
-        //     characteristics: [
-        //         // Count of entries
-        //         characteristic {
-        //             uuid: "4194bb90-6a6c-013d-0514-061a78fcc099",
-        //             read: rf,
-        //             write: wf,
-        //         },
-        //     ],
-        // },]);
-
-        // let mut rng = bleps::no_rng::NoRng;
-        // let mut srv = AttributeServer::new(&mut ble, &mut gatt_attributes, &mut rng);
-
-        // let mut notification = None;
-        // match srv.do_work_with_notification(notification).await {
-        //     Ok(res) => {
-        //         if let WorkResult::GotDisconnected = res {
-        //             break;
-        //         }
-        //     }
-        //     Err(err) => {
-        //         println!("{:?}", err);
-        //     }
-        // }
-        Timer::after(Duration::from_secs(10)).await;
+        let connector = BleConnector::new(&init, &mut bluetooth);
+
+        let now = || time::now().duration_since_epoch().to_millis();
+        let mut ble = Ble::new(connector, now);
+
+        // A controller that didn't come up gets a fresh connector next time
+        if let Err(err) = advertise(&mut ble).await {
+            println!("ble: {err}, trying again");
+            Timer::after(Duration::from_secs(1)).await;
+            continue;
+        }
+        println!("started advertising");
+
+        // A page of records at a time; writing a console style filter line
+        // ("pan channel 15", "from 120") picks which
+        let records = RefCell::new(render(&Filter::default()));
+
+        // The survey from the sniffing session before we reset into BLE mode
+        let channels_string = channels::persisted()
+            .map(|survey| survey.summary())
+            .unwrap_or_default();
+        let channels = channels_string.as_bytes();
+
+        let mut records_rf =
+            |offset: usize, data: &mut [u8]| read_at(records.borrow().as_bytes(), offset, data);
+        let mut records_wf = |_offset: usize, data: &[u8]| {
+            let Ok(line) = core::str::from_utf8(data) else {
+                return;
+            };
+            *records.borrow_mut() = match Filter::parse(line.split_whitespace()) {
+                Ok(filter) => render(&filter),
+                Err(err) => alloc::format!("{err}\n"),
+            };
+        };
+        let mut channels_rf = |offset: usize, data: &mut [u8]| read_at(channels, offset, data);
+
+        // Unset reads as all zeroes, which the spec allows for "unknown"
+        let mut time_rf = |offset: usize, data: &mut [u8]| {
+            let value = clock::unix_ms().map_or([0; 10], clock::current_time);
+            read_at(&value, offset, data)
+        };
+        let mut time_wf = |_offset: usize, data: &[u8]| {
+            if let Some(unix_ms) = clock::from_current_time(data) {
+                clock::set_unix_ms(unix_ms, Source::Bluetooth);
+            }
+        };
+
+        // "key = value" lines to read, one "key value" to write (just the
+        // key clears it)
+        let mut config_rf = |offset: usize, data: &mut [u8]| {
+            let settings = config::get()
+                .shown()
+                .into_iter()
+                .map(|(key, value)| alloc::format!("{key} = {value}\n"))
+                .collect::<String>();
+            read_at(settings.as_bytes(), offset, data)
+        };
+        let mut config_wf = |_offset: usize, data: &[u8]| {
+            let Ok(line) = core::str::from_utf8(data) else {
+                return;
+            };
+            let line = line.trim();
+            let (key, value) = line.split_once(' ').unwrap_or((line, ""));
+            if let Err(err) = config::set(key, value.trim()) {
+                println!("config {key}: {err}");
+            }
+        };
+
+        // The last crash on record, empty if there isn't one
+        let crash_string = crash::last()
+            .map(|report| alloc::format!("{report}"))
+            .unwrap_or_default();
+        let crash_report = crash_string.as_bytes();
+        let mut crash_rf = |offset: usize, data: &mut [u8]| read_at(crash_report, offset, data);
+
+        gatt!([service {
+            uuid: "e6a0ea50-6a66-013d-0514-061a78fcc099",
+            characteristics: [
+                // A page of stored records, one per line
+                characteristic {
+                    uuid: "4194bb90-6a6c-013d-0514-061a78fcc099",
+                    read: records_rf,
+                },
+                // Per-channel utilization summary
+                characteristic {
+                    uuid: "4194bb91-6a6c-013d-0514-061a78fcc099",
+                    read: channels_rf,
+                },
+                // Settings
+                characteristic {
+                    uuid: "4194bb92-6a6c-013d-0514-061a78fcc099",
+                    read: config_rf,
+                    write: config_wf,
+                },
+                // Last crash
+                characteristic {
+                    uuid: "4194bb93-6a6c-013d-0514-061a78fcc099",
+                    read: crash_rf,
+                },
+                // Which records the page shows
+                characteristic {
+                    uuid: "4194bb94-6a6c-013d-0514-061a78fcc099",
+                    write: records_wf,
+                },
+            ],
+        },
+        // Current Time Service, so a phone can set our clock
+        service {
+            uuid: "1805",
+            characteristics: [characteristic {
+                uuid: "2a2b",
+                read: time_rf,
+                write: time_wf,
+            },],
+        },]);
+
+        let mut rng = bleps::no_rng::NoRng;
+        let mut srv = AttributeServer::new(&mut ble, &mut gatt_attributes, &mut rng);
+
+        loop {
+            match srv.do_work_with_notification(None).await {
+                Ok(WorkResult::GotDisconnected) => break,
+                Ok(WorkResult::DidWork) => (),
+                Err(err) => {
+                    println!("{:?}", err);
+                }
+            }
+        }
+
+        Timer::after(Duration::from_millis(100)).await;
     }
 }
+
+async fn advertise(ble: &mut Ble<BleConnector<'_>>) -> Result<(), Error> {
+    ble.init().await?;
+    ble.cmd_set_le_advertising_parameters().await?;
+    ble.cmd_set_le_advertising_data(
+        create_advertising_data(&[
+            AdStructure::CompleteLocalName("wifblink"),
+            AdStructure::Flags(LE_GENERAL_DISCOVERABLE | BR_EDR_NOT_SUPPORTED),
+        ])
+        .map_err(|_| Error::Radio)?,
+    )
+    .await?;
+    ble.cmd_set_le_advertise_enable(true).await?;
+    Ok(())
+}
+
+/// Up to `PAGE` of the records `filter` picks, "id stamp record" per line,
+/// then "next id generation g" if there are more: written back after the
+/// filters, that's the next page, or the first if the store's been erased
+/// since.
+fn render(filter: &Filter) -> String {
+    page(filter).unwrap_or_else(|err| alloc::format!("storage: {err}\n"))
+}
+
+fn page(filter: &Filter) -> Result<String, Error> {
+    let mut store = Store::new()?;
+    let mut from = filter.from;
+    if let Some(last) = filter.last {
+        // Start at the first of the newest `last`, in the log as it is now
+        let skip = store.count(&filter.query, from)?.saturating_sub(last);
+        let start = store.resolve(from)?;
+        from = match store.records(filter.query.clone(), start)?.nth(skip) {
+            Some(first) => Cursor {
+                generation: None,
+                id: first?.0,
+            },
+            None => return Ok(String::new()),
+        };
+    }
+
+    let page = store.page(filter.query.clone(), from, PAGE)?;
+    let mut text = String::new();
+    for (id, bytes) in &page.records {
+        let _ = match (record::unstamp(bytes).0, Record::decode(bytes)) {
+            (Some(stamp), Some(record)) => writeln!(text, "{id} {stamp} {record}"),
+            (None, Some(record)) => writeln!(text, "{id} {record}"),
+            (_, None) => writeln!(text, "{id} ?"),
+        };
+    }
+    if let Some(next) = page.next {
+        let _ = writeln!(text, "next {next} generation {}", page.generation);
+    }
+    Ok(text)
+}
+
+/// Serve a long value in chunks: copy what fits from `offset` on.
+fn read_at(source: &[u8], offset: usize, data: &mut [u8]) -> usize {
+    let remaining = source.get(offset..).unwrap_or_default();
+    let len = remaining.len().min(data.len());
+    data[..len].copy_from_slice(&remaining[..len]);
+    len
+}
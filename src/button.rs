@@ -1,12 +1,11 @@
-use embassy_sync::{
-    blocking_mutex::raw::CriticalSectionRawMutex,
-    pubsub::{publisher, PubSubChannel},
-};
+use core::future::Future;
+
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, pubsub::PubSubChannel};
 use embassy_time::{Duration, Timer};
+#[cfg(target_os = "none")]
 use esp_hal::gpio::{GpioPin, Input};
-use esp_println::println;
 
-use crate::lights;
+use crate::{config, error};
 
 #[derive(Clone)]
 pub enum ButtonPress {
@@ -19,16 +18,43 @@ pub enum ButtonPress {
 pub static BUTTON_CHANNEL: PubSubChannel<CriticalSectionRawMutex, ButtonPress, 4, 4, 4> =
     PubSubChannel::<CriticalSectionRawMutex, ButtonPress, 4, 4, 4>::new();
 
+/// What `watch` needs from the button: the GPIO on the board, or a key in
+/// the simulator.
+pub trait Button {
+    /// Wait until it's pushed down
+    fn wait_for_press(&mut self) -> impl Future<Output = ()>;
+
+    /// Whether it's been let go
+    fn is_released(&mut self) -> bool;
+}
+
+#[cfg(target_os = "none")]
+impl Button for Input<'static, GpioPin<17>> {
+    async fn wait_for_press(&mut self) {
+        self.wait_for_rising_edge().await;
+    }
+
+    fn is_released(&mut self) -> bool {
+        self.is_low()
+    }
+}
+
+#[cfg(target_os = "none")]
 #[embassy_executor::task]
-pub async fn button_task(mut button: Input<'static, GpioPin<17>>) {
-    let publisher = BUTTON_CHANNEL.publisher().unwrap();
+pub async fn button_task(button: Input<'static, GpioPin<17>>) {
+    watch(button).await;
+}
+
+/// Turn presses of `button` into `ButtonPress`es.
+pub async fn watch(mut button: impl Button) -> ! {
     loop {
-        button.wait_for_rising_edge().await;
-        publisher.publish(ButtonPress::Down).await;
+        button.wait_for_press().await;
+        error::publish(&BUTTON_CHANNEL, ButtonPress::Down).await;
 
         let mut is_long_press = true;
-        for _ in 0..=150 {
-            if button.is_low() {
+        let long_press_ms = config::with(|config| config.long_press_ms);
+        for _ in 0..=long_press_ms / 10 {
+            if button.is_released() {
                 is_long_press = false;
                 break;
             }
@@ -36,12 +62,12 @@ pub async fn button_task(mut button: Input<'static, GpioPin<17>>) {
             Timer::after(Duration::from_millis(10)).await;
         }
 
-        publisher.publish(ButtonPress::Up).await;
+        error::publish(&BUTTON_CHANNEL, ButtonPress::Up).await;
 
         if is_long_press {
-            publisher.publish(ButtonPress::Long).await;
+            error::publish(&BUTTON_CHANNEL, ButtonPress::Long).await;
         } else {
-            publisher.publish(ButtonPress::Single).await;
+            error::publish(&BUTTON_CHANNEL, ButtonPress::Single).await;
         }
 
         Timer::after(Duration::from_millis(100)).await;
@@ -0,0 +1,471 @@
+//! Per-channel utilization, gathered while the sniffer hops.
+//!
+//! Every received frame adds to its channel's frame/byte counters and to an
+//! estimate of the airtime it took, kept apart for management, control and
+//! data frames so it's clear which kind is using the channel, and beacons carrying a QBSS Load element
+//! add what the AP itself thinks of the channel. Dividing airtime by how long
+//! we actually listened on the channel gives a rough busy fraction, which is
+//! what the recommended channel is based on.
+//!
+//! The summary is copied into RTC memory so it survives the reset into BLE
+//! mode and can be read from there.
+
+use core::{cell::RefCell, fmt::Write};
+
+use alloc::string::String;
+use critical_section::Mutex;
+#[cfg(target_os = "none")]
+use esp_hal::macros::ram;
+
+use crate::frame::FrameType;
+
+/// 2.4 GHz channels 1 through 14
+pub const CHANNELS: usize = 14;
+
+/// Channels we hop over and can recommend (14 is Japan-only 802.11b)
+pub const HOP_CHANNELS: core::ops::RangeInclusive<u8> = 1..=13;
+
+/// Two 2.4 GHz channels interfere unless they're at least this far apart
+const OVERLAP_SPAN: u32 = 5;
+
+const SNAPSHOT_MAGIC: u32 = 0x4348_4e32;
+
+/// Which generation of PHY a frame was sent with, as far as working out its
+/// airtime goes.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Phy {
+    /// 11b/11g
+    Legacy,
+    /// 11n
+    Ht,
+    /// 11ax
+    He,
+}
+
+/// What the radio told us about one received frame.
+pub struct Rx {
+    pub channel: u8,
+    pub kind: FrameType,
+    pub len: u16,
+    /// `rx_ctrl.rate` for legacy frames, the MCS for HT and HE ones
+    pub rate: u8,
+    pub phy: Phy,
+    pub wide: bool,
+}
+
+/// The PHY and rate of a frame, from `rx_ctrl`'s `cur_bb_format`, `rate`
+/// and `he_siga1`. The last holds the HT-SIG for HT frames and HE-SIG-A1 for
+/// HE ones; HE MU and trigger-based frames don't carry an MCS there, so
+/// they're taken to be at MCS 0.
+pub fn phy_rate(bb_format: u32, rate: u32, sig: u32) -> (Phy, u8) {
+    match bb_format {
+        // 11b/11g
+        0 | 1 => (Phy::Legacy, rate as u8),
+        // HT-SIG1 bits 0-6. VHT is 5 GHz only, but it's the closest match
+        2 | 3 => (Phy::Ht, (sig & 0x7f) as u8),
+        // HE SU and extended range SU: HE-SIG-A1 bits 3-6
+        4 | 5 => (Phy::He, (sig >> 3 & 0xf) as u8),
+        _ => (Phy::He, 0),
+    }
+}
+
+/// What one kind of frame added up to on a channel.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Traffic {
+    pub frames: u32,
+    pub bytes: u32,
+    pub airtime_us: u32,
+}
+
+impl Traffic {
+    const fn new() -> Self {
+        Self {
+            frames: 0,
+            bytes: 0,
+            airtime_us: 0,
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ChannelStats {
+    pub management: Traffic,
+    pub control: Traffic,
+    /// Extension frames count here too
+    pub data: Traffic,
+    pub dwell_ms: u32,
+    /// Highest station count any AP on the channel reported
+    pub stations: u16,
+    /// Highest QBSS channel utilization reported, out of 255
+    pub utilization: u8,
+    _padding: u8,
+}
+
+impl ChannelStats {
+    const fn new() -> Self {
+        Self {
+            management: Traffic::new(),
+            control: Traffic::new(),
+            data: Traffic::new(),
+            dwell_ms: 0,
+            stations: 0,
+            utilization: 0,
+            _padding: 0,
+        }
+    }
+
+    fn kinds(&self) -> [(&'static str, &Traffic); 3] {
+        [
+            ("mgmt", &self.management),
+            ("ctrl", &self.control),
+            ("data", &self.data),
+        ]
+    }
+
+    pub fn frames(&self) -> u32 {
+        self.kinds().iter().map(|(_, traffic)| traffic.frames).sum()
+    }
+
+    pub fn bytes(&self) -> u32 {
+        self.kinds()
+            .iter()
+            .fold(0, |bytes, (_, traffic)| bytes.saturating_add(traffic.bytes))
+    }
+
+    pub fn airtime_us(&self) -> u32 {
+        self.kinds().iter().fold(0, |airtime, (_, traffic)| {
+            airtime.saturating_add(traffic.airtime_us)
+        })
+    }
+
+    /// How busy the channel is in per-mille: the larger of what we measured
+    /// and what the APs on it report.
+    pub fn busy(&self) -> u32 {
+        let measured = if self.dwell_ms == 0 {
+            0
+        } else {
+            (self.airtime_us() as u64 / self.dwell_ms as u64).min(1000) as u32
+        };
+
+        let reported = self.utilization as u32 * 1000 / 255;
+        measured.max(reported)
+    }
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct Survey {
+    magic: u32,
+    pub channels: [ChannelStats; CHANNELS],
+}
+
+impl Default for Survey {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Survey {
+    pub const fn new() -> Self {
+        Self {
+            magic: SNAPSHOT_MAGIC,
+            channels: [ChannelStats::new(); CHANNELS],
+        }
+    }
+
+    fn get_mut(&mut self, channel: u8) -> Option<&mut ChannelStats> {
+        self.channels.get_mut((channel as usize).checked_sub(1)?)
+    }
+
+    pub fn get(&self, channel: u8) -> Option<&ChannelStats> {
+        self.channels.get((channel as usize).checked_sub(1)?)
+    }
+
+    pub fn record(&mut self, rx: &Rx) {
+        let Some(stats) = self.get_mut(rx.channel) else {
+            return;
+        };
+
+        let traffic = match rx.kind {
+            FrameType::Management => &mut stats.management,
+            FrameType::Control => &mut stats.control,
+            FrameType::Data | FrameType::Extension => &mut stats.data,
+        };
+
+        traffic.frames = traffic.frames.saturating_add(1);
+        traffic.bytes = traffic.bytes.saturating_add(rx.len as u32);
+        traffic.airtime_us = traffic.airtime_us.saturating_add(airtime_us(rx));
+    }
+
+    pub fn bss_load(&mut self, channel: u8, stations: u16, utilization: u8) {
+        if let Some(stats) = self.get_mut(channel) {
+            stats.stations = stats.stations.max(stations);
+            stats.utilization = stats.utilization.max(utilization);
+        }
+    }
+
+    pub fn dwell(&mut self, channel: u8, ms: u32) {
+        if let Some(stats) = self.get_mut(channel) {
+            stats.dwell_ms = stats.dwell_ms.saturating_add(ms);
+        }
+    }
+
+    /// Interference on `channel` from everything we heard, weighting each
+    /// other channel by how much its 20 MHz overlaps this one.
+    pub fn interference(&self, channel: u8) -> u32 {
+        (1..=CHANNELS as u8)
+            .filter_map(|other| {
+                let distance = (channel as i32 - other as i32).unsigned_abs();
+                let overlap = OVERLAP_SPAN.checked_sub(distance)?;
+                Some(self.get(other)?.busy() * overlap / OVERLAP_SPAN)
+            })
+            .sum()
+    }
+
+    /// The least interfered channel, preferring the non-overlapping 1/6/11 on
+    /// a tie. `None` until we've listened to every channel at least once.
+    pub fn recommended(&self) -> Option<u8> {
+        if HOP_CHANNELS
+            .clone()
+            .any(|channel| self.get(channel).is_none_or(|stats| stats.dwell_ms == 0))
+        {
+            return None;
+        }
+
+        HOP_CHANNELS
+            .clone()
+            .min_by_key(|channel| {
+                let preferred = matches!(channel, 1 | 6 | 11);
+                (self.interference(*channel), !preferred)
+            })
+    }
+
+    pub fn summary(&self) -> String {
+        let mut summary = String::new();
+        let _ = writeln!(
+            summary,
+            "ch       frames     bytes  air ms   busy  sta  util"
+        );
+
+        for channel in 1..=CHANNELS as u8 {
+            let Some(stats) = self.get(channel).filter(|stats| stats.dwell_ms > 0) else {
+                continue;
+            };
+
+            // The channel's own figures go on its first line, after the
+            // management frames
+            for (index, (kind, traffic)) in stats.kinds().into_iter().enumerate() {
+                if index == 0 {
+                    let _ = write!(summary, "{channel:>2} ");
+                } else {
+                    summary.push_str("   ");
+                }
+                let _ = write!(
+                    summary,
+                    "{kind} {:>6}{:>10}{:>8}",
+                    traffic.frames,
+                    traffic.bytes,
+                    traffic.airtime_us / 1000,
+                );
+                if index == 0 {
+                    let busy = stats.busy();
+                    let _ = write!(
+                        summary,
+                        " {:>3}.{}% {:>4} {:>4}",
+                        busy / 10,
+                        busy % 10,
+                        stats.stations,
+                        stats.utilization,
+                    );
+                }
+                summary.push('\n');
+            }
+        }
+
+        match self.recommended() {
+            Some(channel) => {
+                let _ = writeln!(summary, "recommended channel: {channel}");
+            }
+            None => {
+                let _ = writeln!(summary, "recommended channel: not enough data yet");
+            }
+        }
+
+        summary
+    }
+}
+
+static SURVEY: Mutex<RefCell<Survey>> = Mutex::new(RefCell::new(Survey::new()));
+
+#[cfg_attr(target_os = "none", ram(rtc_fast, persistent))]
+static mut SNAPSHOT: Survey = Survey::new();
+
+/// Count a frame. Called from the sniffer callback.
+pub fn record(rx: &Rx) {
+    critical_section::with(|cs| SURVEY.borrow_ref_mut(cs).record(rx));
+}
+
+pub fn bss_load(channel: u8, stations: u16, utilization: u8) {
+    critical_section::with(|cs| {
+        SURVEY
+            .borrow_ref_mut(cs)
+            .bss_load(channel, stations, utilization)
+    });
+}
+
+/// Account for having listened on `channel` for `ms`.
+pub fn dwell(channel: u8, ms: u32) {
+    critical_section::with(|cs| SURVEY.borrow_ref_mut(cs).dwell(channel, ms));
+}
+
+pub fn survey() -> Survey {
+    critical_section::with(|cs| *SURVEY.borrow_ref(cs))
+}
+
+/// Copy the current survey somewhere that survives a software reset.
+pub fn persist() {
+    let survey = survey();
+    critical_section::with(|_| unsafe { SNAPSHOT = survey });
+}
+
+/// The survey from before the last reset, if there was one.
+pub fn persisted() -> Option<Survey> {
+    let snapshot = critical_section::with(|_| unsafe { SNAPSHOT });
+    (snapshot.magic == SNAPSHOT_MAGIC).then_some(snapshot)
+}
+
+/// Rough time on air for a frame: preamble/header plus payload at its rate.
+fn airtime_us(rx: &Rx) -> u32 {
+    let bits = 8 * rx.len as u32;
+
+    // 40 MHz roughly doubles the bits per symbol
+    let width = if rx.wide { 2 } else { 1 };
+    match rx.phy {
+        // HT-mixed preamble and 4us symbols, single stream
+        Phy::Ht => {
+            let per_symbol = HT_BITS_PER_SYMBOL[rx.rate as usize % 8] * width;
+            return 36 + 4 * (16 + bits + 6).div_ceil(per_symbol);
+        }
+        // HE SU preamble with one 4x HE-LTF, and 13.6us symbols
+        Phy::He => {
+            let per_symbol = HE_BITS_PER_SYMBOL[(rx.rate as usize).min(11)] * width;
+            return 44 + ((16 + bits + 6).div_ceil(per_symbol) * 136).div_ceil(10);
+        }
+        Phy::Legacy => {}
+    }
+
+    match rx.rate {
+        // DSSS/CCK, long preamble: rate in 100 kbps
+        0 => 192 + bits,
+        1 => 192 + bits / 2,
+        2 => 192 + bits * 10 / 55,
+        3 => 192 + bits / 11,
+        // Short preamble variants
+        5 => 96 + bits / 2,
+        6 => 96 + bits * 10 / 55,
+        7 => 96 + bits / 11,
+        // OFDM: 20us preamble, 4us symbols
+        rate => {
+            let per_symbol = match rate {
+                8 => 192,  // 48 Mbps
+                9 => 96,   // 24
+                10 => 48,  // 12
+                11 => 24,  // 6
+                12 => 216, // 54
+                13 => 144, // 36
+                14 => 72,  // 18
+                _ => 36,   // 9
+            };
+            20 + 4 * (16 + bits + 6).div_ceil(per_symbol)
+        }
+    }
+}
+
+/// Data bits per 4us symbol for HT MCS 0-7 on 20 MHz
+const HT_BITS_PER_SYMBOL: [u32; 8] = [26, 52, 78, 104, 156, 208, 234, 260];
+
+/// Data bits per 13.6us symbol for HE MCS 0-11 on 20 MHz
+const HE_BITS_PER_SYMBOL: [u32; 12] = [
+    117, 234, 351, 468, 702, 936, 1053, 1170, 1404, 1560, 1755, 1950,
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rx(phy: Phy, rate: u8) -> Rx {
+        Rx {
+            channel: 6,
+            kind: FrameType::Data,
+            len: 1500,
+            rate,
+            phy,
+            wide: false,
+        }
+    }
+
+    #[test]
+    fn keeps_each_kind_of_frame_apart() {
+        let mut survey = Survey::new();
+        let beacon = Rx {
+            kind: FrameType::Management,
+            len: 300,
+            rate: 11,
+            ..rx(Phy::Legacy, 0)
+        };
+        survey.record(&beacon);
+        survey.record(&beacon);
+        survey.record(&rx(Phy::Ht, 7));
+        survey.record(&Rx {
+            kind: FrameType::Extension,
+            ..rx(Phy::Ht, 7)
+        });
+        survey.dwell(6, 1000);
+
+        let stats = survey.get(6).unwrap();
+        assert_eq!(stats.management.frames, 2);
+        assert_eq!(stats.management.bytes, 600);
+        assert_eq!(stats.control.frames, 0);
+        assert_eq!(stats.data.frames, 2);
+        assert_eq!(stats.data.bytes, 3000);
+        assert_eq!(stats.frames(), 4);
+        assert_eq!(stats.bytes(), 3600);
+        assert_eq!(
+            stats.airtime_us(),
+            stats.management.airtime_us + stats.data.airtime_us
+        );
+        // 6 Mbps beacons take longer than 65 Mbps data of twice the size
+        assert!(stats.management.airtime_us > stats.data.airtime_us);
+
+        let summary = survey.summary();
+        let lines: Vec<&str> = summary.lines().skip(1).take(3).collect();
+        assert!(lines[0].starts_with(" 6 mgmt      2       600"));
+        assert!(lines[1].starts_with("   ctrl      0         0"));
+        assert!(lines[2].starts_with("   data      2      3000"));
+    }
+
+    #[test]
+    fn finds_the_mcs_for_each_format() {
+        assert_eq!(phy_rate(0, 11, 0xffff_ffff), (Phy::Legacy, 11));
+        // HT-SIG1 for MCS 7 at 40 MHz
+        assert_eq!(phy_rate(2, 0, 0x87), (Phy::Ht, 7));
+        // HE-SIG-A1 for MCS 9 with DCM off, BSS color 5
+        assert_eq!(phy_rate(4, 0, 9 << 3 | 5 << 8), (Phy::He, 9));
+        assert_eq!(phy_rate(5, 0, 2 << 3 | 1 << 7), (Phy::He, 2));
+        assert_eq!(phy_rate(6, 0, 0xffff_ffff), (Phy::He, 0));
+    }
+
+    #[test]
+    fn keeps_he_frames_out_of_the_ht_table() {
+        // MCS 9 is one of the fastest HE rates, and a two-stream one for HT
+        let he = airtime_us(&rx(Phy::He, 9));
+        let ht = airtime_us(&rx(Phy::Ht, 9));
+        assert!(he < ht, "{he} {ht}");
+        assert!(airtime_us(&rx(Phy::He, 0)) > airtime_us(&rx(Phy::He, 11)));
+        // Nothing out of range indexes past the tables
+        airtime_us(&rx(Phy::He, 15));
+        airtime_us(&rx(Phy::Ht, 76));
+    }
+}
@@ -0,0 +1,430 @@
+//! Wall-clock time, when something has told us what it is.
+//!
+//! The clock can be set over SNTP, the BLE Current Time characteristic or
+//! the serial console. What's kept is the offset from the RTC timer, which
+//! keeps counting through deep sleep and software resets, in RTC memory that
+//! survives both. Until it's set, records are stamped with the boot counter
+//! and uptime instead, which still sorts them.
+
+#[cfg(target_os = "none")]
+use core::cell::RefCell;
+use core::{
+    fmt,
+    sync::atomic::{AtomicU32, Ordering},
+};
+
+use alloc::vec::Vec;
+#[cfg(target_os = "none")]
+use critical_section::Mutex;
+use embassy_time::Instant;
+use embedded_storage::{ReadStorage, Storage};
+#[cfg(target_os = "none")]
+use esp_hal::{macros::ram, rtc_cntl::Rtc};
+use esp_println::println;
+use esp_storage::FlashStorage;
+
+use crate::{record::Reader, storage};
+
+const PERSISTED_MAGIC: u32 = 0x434c_4b31;
+const BOOT_MAGIC: &[u8; 4] = b"BOOT";
+
+/// Seconds from 1900 (NTP) to 1970 (Unix)
+const NTP_EPOCH_OFFSET: u64 = 2_208_988_800;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Source {
+    Sntp,
+    Bluetooth,
+    Serial,
+}
+
+/// When a record was made.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Stamp {
+    pub boot: u32,
+    pub uptime_s: u32,
+    /// Unix time, if the clock was set
+    pub unix_s: Option<u32>,
+}
+
+impl Stamp {
+    pub(crate) fn encode(&self, bytes: &mut Vec<u8>) {
+        bytes.extend_from_slice(&self.boot.to_le_bytes());
+        bytes.extend_from_slice(&self.uptime_s.to_le_bytes());
+        bytes.extend_from_slice(&self.unix_s.unwrap_or(0).to_le_bytes());
+    }
+
+    pub(crate) fn decode(reader: &mut Reader) -> Option<Self> {
+        Some(Self {
+            boot: reader.u32()?,
+            uptime_s: reader.u32()?,
+            unix_s: Some(reader.u32()?).filter(|unix_s| *unix_s != 0),
+        })
+    }
+}
+
+impl fmt::Display for Stamp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.unix_s {
+            Some(unix_s) => {
+                let (year, month, day) = civil_from_days(unix_s as i64 / 86_400);
+                let seconds = unix_s % 86_400;
+                write!(
+                    f,
+                    "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}Z",
+                    seconds / 3600,
+                    seconds / 60 % 60,
+                    seconds % 60
+                )
+            }
+            None => write!(f, "boot {} +{}s", self.boot, self.uptime_s),
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct Persisted {
+    magic: u32,
+    /// Unix time minus RTC time, in microseconds
+    offset_us: i64,
+}
+
+#[cfg_attr(target_os = "none", ram(rtc_fast, persistent))]
+static mut PERSISTED: Persisted = Persisted {
+    magic: 0,
+    offset_us: 0,
+};
+
+#[cfg(target_os = "none")]
+static RTC: Mutex<RefCell<Option<Rtc<'static>>>> = Mutex::new(RefCell::new(None));
+
+static BOOT: AtomicU32 = AtomicU32::new(0);
+
+/// Take the RTC and count this boot.
+#[cfg(target_os = "none")]
+pub fn init(rtc: Rtc<'static>) {
+    critical_section::with(|cs| *RTC.borrow_ref_mut(cs) = Some(rtc));
+    count_boot();
+}
+
+/// Count this boot. Done by `init` on the board; the simulator has no RTC
+/// to hand over.
+pub fn count_boot() {
+    let boot = next_boot();
+    BOOT.store(boot, Ordering::Relaxed);
+
+    match now().unix_s {
+        Some(_) => println!("boot {boot}, clock {}", now()),
+        None => println!("boot {boot}, clock not set"),
+    }
+}
+
+/// The boot counted last, before `init` counts this one; `None` if it
+/// never was, or it can't be read.
+pub fn last_boot() -> Option<u32> {
+    let mut bytes = [0u8; 8];
+    FlashStorage::new()
+        .read(storage::reserved_sector(storage::BOOT_SECTOR), &mut bytes)
+        .ok()?;
+    (&bytes[..4] == BOOT_MAGIC).then(|| u32::from_le_bytes(bytes[4..].try_into().unwrap()))
+}
+
+/// Bump the boot counter kept in flash; RTC memory doesn't survive losing
+/// power, and the counter has to keep going up across that too.
+fn next_boot() -> u32 {
+    // Unreadable counts as never booted, which only makes stamps ambiguous
+    let boot = last_boot().map_or(0, |boot| boot + 1);
+
+    let offset = storage::reserved_sector(storage::BOOT_SECTOR);
+    let mut bytes = BOOT_MAGIC.to_vec();
+    bytes.extend_from_slice(&boot.to_le_bytes());
+    if let Err(err) = FlashStorage::new().write(offset, &bytes) {
+        println!("clock: couldn't save the boot count: {:?}", err);
+    }
+    boot
+}
+
+/// Borrow the RTC, for its watchdog.
+#[cfg(target_os = "none")]
+pub fn with_rtc<R>(f: impl FnOnce(&mut Rtc<'static>) -> R) -> Option<R> {
+    critical_section::with(|cs| RTC.borrow_ref_mut(cs).as_mut().map(f))
+}
+
+#[cfg(target_os = "none")]
+fn rtc_us() -> u64 {
+    critical_section::with(|cs| {
+        RTC.borrow_ref(cs)
+            .as_ref()
+            .map_or(0, |rtc| rtc.time_since_boot().to_micros())
+    })
+}
+
+/// Off the board uptime stands in for the RTC; it only has to keep going
+/// for as long as the process does.
+#[cfg(not(target_os = "none"))]
+fn rtc_us() -> u64 {
+    Instant::now().as_micros()
+}
+
+pub fn set_unix_ms(unix_ms: u64, source: Source) {
+    let offset_us = (unix_ms * 1000) as i64 - rtc_us() as i64;
+    critical_section::with(|_| unsafe {
+        PERSISTED = Persisted {
+            magic: PERSISTED_MAGIC,
+            offset_us,
+        }
+    });
+
+    println!("clock set from {:?}: {}", source, now());
+}
+
+pub fn unix_ms() -> Option<u64> {
+    let persisted = critical_section::with(|_| unsafe { PERSISTED });
+    if persisted.magic != PERSISTED_MAGIC {
+        return None;
+    }
+
+    Some(((rtc_us() as i64 + persisted.offset_us) / 1000) as u64)
+}
+
+pub fn now() -> Stamp {
+    Stamp {
+        boot: BOOT.load(Ordering::Relaxed),
+        uptime_s: Instant::now().as_secs() as u32,
+        unix_s: unix_ms().map(|unix_ms| (unix_ms / 1000) as u32),
+    }
+}
+
+/// An SNTP client request: version 3, mode 3.
+pub fn sntp_request() -> [u8; 48] {
+    let mut request = [0u8; 48];
+    request[0] = 0x1b;
+    request
+}
+
+/// Unix time in ms from an SNTP server's reply (its transmit timestamp).
+pub fn sntp_unix_ms(reply: &[u8]) -> Option<u64> {
+    let mode = reply.first()? & 0b111;
+    if reply.len() < 48 || mode != 4 {
+        return None;
+    }
+
+    let seconds = u32::from_be_bytes(reply[40..44].try_into().ok()?) as u64;
+    let fraction = u32::from_be_bytes(reply[44..48].try_into().ok()?) as u64;
+    // The seconds wrap in February 2036. As RFC 4330 suggests, anything
+    // without the top bit set is taken to be after that.
+    let seconds = if seconds & 0x8000_0000 == 0 {
+        seconds + (1 << 32)
+    } else {
+        seconds
+    };
+    if seconds < NTP_EPOCH_OFFSET {
+        return None;
+    }
+
+    Some((seconds - NTP_EPOCH_OFFSET) * 1000 + ((fraction * 1000) >> 32))
+}
+
+/// Unix time in ms from a Current Time characteristic (0x2A2B) value.
+pub fn from_current_time(value: &[u8]) -> Option<u64> {
+    let mut reader = Reader(value);
+    let year = reader.u16()? as u32;
+    let [month, day, hours, minutes, seconds, _weekday, fraction] = reader.array()?;
+
+    let unix_ms = unix_ms_from_civil(
+        year,
+        month as u32,
+        day as u32,
+        hours as u32,
+        minutes as u32,
+        seconds as u32,
+    )?;
+    Some(unix_ms + fraction as u64 * 1000 / 256)
+}
+
+/// Unix time in ms for a UTC date and time, if it's a plausible one.
+pub fn unix_ms_from_civil(
+    year: u32,
+    month: u32,
+    day: u32,
+    hours: u32,
+    minutes: u32,
+    seconds: u32,
+) -> Option<u64> {
+    if !(1970..=2105).contains(&year)
+        || !(1..=12).contains(&month)
+        || !(1..=days_in_month(year, month)).contains(&day)
+        || hours > 23
+        || minutes > 59
+        || seconds > 60
+    {
+        return None;
+    }
+
+    let days = days_from_civil(year as i64, month, day);
+    let seconds = days * 86_400 + hours as i64 * 3600 + minutes as i64 * 60 + seconds as i64;
+    Some(seconds as u64 * 1000)
+}
+
+fn days_in_month(year: u32, month: u32) -> u32 {
+    let leap = year.is_multiple_of(4) && (!year.is_multiple_of(100) || year.is_multiple_of(400));
+    match month {
+        2 if leap => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+/// The Current Time characteristic value for `unix_ms`.
+pub fn current_time(unix_ms: u64) -> [u8; 10] {
+    let seconds = unix_ms / 1000;
+    let days = (seconds / 86_400) as i64;
+    let (year, month, day) = civil_from_days(days);
+    let of_day = seconds % 86_400;
+    // 1970-01-01 was a Thursday; the characteristic counts Monday as 1
+    let weekday = ((days + 3) % 7 + 1) as u8;
+
+    let [year_low, year_high] = (year as u16).to_le_bytes();
+    [
+        year_low,
+        year_high,
+        month as u8,
+        day as u8,
+        (of_day / 3600) as u8,
+        (of_day / 60 % 60) as u8,
+        (of_day % 60) as u8,
+        weekday,
+        ((unix_ms % 1000) * 256 / 1000) as u8,
+        0,
+    ]
+}
+
+/// Days since 1970-01-01 to (year, month, day), proleptic Gregorian.
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z.rem_euclid(146_097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year.rem_euclid(400);
+    let mp = (month as i64 + 9) % 12;
+    let day_of_year = (153 * mp + 2) / 5 + day as i64 - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Unix seconds when the NTP seconds wrap, 2036-02-07 06:28:16
+    const NTP_ROLLOVER: u64 = (1 << 32) - NTP_EPOCH_OFFSET;
+
+    fn reply(seconds: u32, fraction: u32) -> [u8; 48] {
+        let mut reply = [0u8; 48];
+        // Version 3, server
+        reply[0] = 0x1c;
+        reply[40..44].copy_from_slice(&seconds.to_be_bytes());
+        reply[44..48].copy_from_slice(&fraction.to_be_bytes());
+        reply
+    }
+
+    #[test]
+    fn reads_sntp_replies() {
+        let epoch = NTP_EPOCH_OFFSET as u32;
+        assert_eq!(sntp_unix_ms(&reply(epoch, 0)), Some(0));
+        assert_eq!(sntp_unix_ms(&reply(epoch, 0x8000_0000)), Some(500));
+        assert_eq!(sntp_unix_ms(&reply(epoch + 1, u32::MAX)), Some(1999));
+        assert_eq!(sntp_unix_ms(&reply(epoch - 1, 0)), None);
+
+        let mut request = reply(epoch, 0);
+        request[0] = sntp_request()[0];
+        assert_eq!(sntp_unix_ms(&request), None);
+        assert_eq!(sntp_unix_ms(&reply(epoch, 0)[..47]), None);
+        assert_eq!(sntp_unix_ms(&[]), None);
+    }
+
+    #[test]
+    fn carries_on_past_2036() {
+        let before = sntp_unix_ms(&reply(u32::MAX, 0x8000_0000));
+        assert_eq!(before, Some(NTP_ROLLOVER * 1000 - 500));
+        assert_eq!(sntp_unix_ms(&reply(0, 0)), Some(NTP_ROLLOVER * 1000));
+        assert_eq!(sntp_unix_ms(&reply(1, 0)), Some(NTP_ROLLOVER * 1000 + 1000));
+
+        let time = current_time(NTP_ROLLOVER * 1000);
+        assert_eq!(&time[..8], &[0xf4, 0x07, 2, 7, 6, 28, 16, 4]);
+    }
+
+    #[test]
+    fn counts_days_across_leap_years() {
+        assert_eq!(days_from_civil(1970, 1, 1), 0);
+        assert_eq!(days_from_civil(1969, 12, 31), -1);
+        assert_eq!(days_from_civil(2000, 2, 29), 11_016);
+        assert_eq!(days_from_civil(2000, 3, 1), 11_017);
+        assert_eq!(days_from_civil(2024, 2, 29), 19_782);
+        // Centuries are only leap years every 400
+        let february = |year| days_from_civil(year, 3, 1) - days_from_civil(year, 2, 1);
+        assert_eq!(february(1900), 28);
+        assert_eq!(february(2000), 29);
+        assert_eq!(february(2100), 28);
+
+        assert_eq!(civil_from_days(0), (1970, 1, 1));
+        assert_eq!(civil_from_days(-1), (1969, 12, 31));
+        assert_eq!(civil_from_days(11_016), (2000, 2, 29));
+        assert_eq!(civil_from_days(19_782), (2024, 2, 29));
+
+        for days in (-800_000..800_000).step_by(97) {
+            let (year, month, day) = civil_from_days(days);
+            assert_eq!(days_from_civil(year, month, day), days);
+        }
+    }
+
+    #[test]
+    fn checks_civil_dates() {
+        assert_eq!(unix_ms_from_civil(1970, 1, 1, 0, 0, 0), Some(0));
+        assert_eq!(unix_ms_from_civil(1969, 12, 31, 23, 59, 59), None);
+        let leap_day = Some(19_782 * 86_400_000);
+        assert_eq!(unix_ms_from_civil(2024, 2, 29, 0, 0, 0), leap_day);
+        assert_eq!(unix_ms_from_civil(2023, 2, 29, 0, 0, 0), None);
+        assert_eq!(unix_ms_from_civil(2100, 2, 29, 0, 0, 0), None);
+        assert_eq!(unix_ms_from_civil(2024, 13, 1, 0, 0, 0), None);
+        assert_eq!(unix_ms_from_civil(2024, 4, 31, 0, 0, 0), None);
+        assert_eq!(unix_ms_from_civil(2024, 1, 1, 24, 0, 0), None);
+        assert!(unix_ms_from_civil(2016, 12, 31, 23, 59, 60).is_some());
+    }
+
+    #[test]
+    fn round_trips_ble_current_time() {
+        // 1970-01-01 was a Thursday
+        assert_eq!(current_time(0), [0xb2, 0x07, 1, 1, 0, 0, 0, 4, 0, 0]);
+
+        // Thursday 2024-02-29 12:34:56.5
+        let unix_ms = (19_782 * 86_400 + 45_296) * 1000 + 500;
+        let value = current_time(unix_ms);
+        assert_eq!(value, [0xe8, 0x07, 2, 29, 12, 34, 56, 4, 128, 0]);
+        assert_eq!(from_current_time(&value), Some(unix_ms));
+
+        // Fractions are 1/256 s, and both ways round down
+        for unix_ms in [1, 999, 1_700_000_000_123, NTP_ROLLOVER * 1000 + 7] {
+            let back = from_current_time(&current_time(unix_ms)).unwrap();
+            assert!(unix_ms - back < 5, "{unix_ms} came back as {back}");
+        }
+
+        assert_eq!(from_current_time(&value[..8]), None);
+        let mut leap = value;
+        leap[0..2].copy_from_slice(&2023u16.to_le_bytes());
+        assert_eq!(from_current_time(&leap), None);
+    }
+}
@@ -0,0 +1,295 @@
+//! Device settings, kept in their own flash sector so erasing or migrating
+//! the survey leaves them alone.
+//!
+//! Settings are stored as key/value text, the same keys the console, BLE
+//! and web UI use, so a layout change only needs `config_format::migrate`
+//! to know about renamed or reinterpreted keys; anything missing comes back
+//! as its default. Each module still keeps its own copy of its settings, handed to
+//! it through its `configure` whenever they change.
+
+use core::cell::RefCell;
+
+use alloc::{format, string::String, vec::Vec};
+use critical_section::Mutex;
+use embedded_storage::{ReadStorage, Storage};
+use esp_println::println;
+use esp_storage::FlashStorage;
+
+use crate::{
+    config_format::{self, Unreadable},
+    mqtt, occupancy, share, station, storage, supervisor, upload,
+};
+
+/// Leaves room for the header in the sector
+const MAX_LEN: usize = 4000;
+
+/// Shown instead of passwords
+const HIDDEN: &str = "********";
+
+#[derive(Clone, Debug)]
+pub struct Config {
+    /// How long the button has to be held for a long press
+    pub long_press_ms: u32,
+    /// LED brightness, percent
+    pub brightness: u8,
+    /// How often to read the fuel gauge
+    pub battery_secs: u32,
+    /// Heap size in KB, the part above `supervisor::BOOT_HEAP` taken from
+    /// the stack at the next boot
+    pub heap_kb: u32,
+    pub occupancy: occupancy::Settings,
+    pub share: share::Settings,
+    pub station: station::Settings,
+    pub upload: upload::Settings,
+    pub mqtt: mqtt::Settings,
+}
+
+impl Config {
+    pub const fn new() -> Self {
+        Self {
+            long_press_ms: 1500,
+            brightness: 20,
+            battery_secs: 5,
+            heap_kb: 64,
+            occupancy: occupancy::Settings::new(),
+            share: share::Settings::new(),
+            station: station::Settings::new(),
+            upload: upload::Settings::new(),
+            mqtt: mqtt::Settings::new(),
+        }
+    }
+
+    /// Every setting as (key, value), passwords included.
+    pub fn entries(&self) -> Vec<(&'static str, String)> {
+        alloc::vec![
+            ("button.long_press_ms", format!("{}", self.long_press_ms)),
+            ("lights.brightness", format!("{}", self.brightness)),
+            ("battery.sample_secs", format!("{}", self.battery_secs)),
+            ("heap.kb", format!("{}", self.heap_kb)),
+            (
+                "occupancy.window_ms",
+                format!("{}", self.occupancy.window_ms)
+            ),
+            ("occupancy.min_rssi", format!("{}", self.occupancy.min_rssi)),
+            ("share.ssid", self.share.ssid.clone()),
+            ("share.password", self.share.password.clone()),
+            ("station.ssid", self.station.ssid.clone()),
+            ("station.password", self.station.password.clone()),
+            ("upload.server", address(self.upload.server)),
+            ("upload.port", format!("{}", self.upload.port)),
+            ("upload.path", self.upload.path.clone()),
+            ("upload.retry_ms", format!("{}", self.upload.retry_ms)),
+            ("mqtt.broker", address(self.mqtt.broker)),
+            ("mqtt.port", format!("{}", self.mqtt.port)),
+            ("mqtt.prefix", self.mqtt.prefix.clone()),
+            ("mqtt.qos", format!("{}", self.mqtt.qos)),
+            ("mqtt.battery_secs", format!("{}", self.mqtt.battery_secs)),
+        ]
+    }
+
+    /// `entries` with the passwords blanked out, for showing to people.
+    pub fn shown(&self) -> Vec<(&'static str, String)> {
+        self.entries()
+            .into_iter()
+            .map(|(key, value)| {
+                if key.ends_with(".password") && !value.is_empty() {
+                    (key, HIDDEN.into())
+                } else {
+                    (key, value)
+                }
+            })
+            .collect()
+    }
+
+    /// Check and change one setting.
+    pub fn set(&mut self, key: &str, value: &str) -> Result<(), &'static str> {
+        match key {
+            "button.long_press_ms" => self.long_press_ms = ranged(value, 200, 10_000)?,
+            "lights.brightness" => self.brightness = ranged(value, 1, 100)?,
+            "battery.sample_secs" => self.battery_secs = ranged(value, 1, 3600)?,
+            "heap.kb" => {
+                self.heap_kb = ranged(value, (supervisor::BOOT_HEAP / 1024) as u32, 256)?
+            }
+            "occupancy.window_ms" => {
+                self.occupancy.window_ms = ranged(value, 1000, 60 * 60 * 1000)?
+            }
+            "occupancy.min_rssi" => self.occupancy.min_rssi = ranged(value, -100, 0)?,
+            "share.ssid" => self.share.ssid = ssid(value)?,
+            "share.password" => {
+                if !value.is_empty() && !(8..=63).contains(&value.len()) {
+                    return Err("WPA2 passwords are 8 to 63 characters, or empty to make one up");
+                }
+                self.share.password = value.into();
+            }
+            "station.ssid" => self.station.ssid = ssid(value)?,
+            "station.password" => {
+                if value.len() > 64 {
+                    return Err("too long");
+                }
+                self.station.password = value.into();
+            }
+            "upload.server" => self.upload.server = parse_address(value)?,
+            "upload.port" => self.upload.port = ranged(value, 1, u16::MAX)?,
+            "upload.path" => {
+                if !value.is_empty() && !value.starts_with('/') {
+                    return Err("paths start with /");
+                }
+                if value.len() > 128 {
+                    return Err("too long");
+                }
+                self.upload.path = value.into();
+            }
+            "upload.retry_ms" => self.upload.retry_ms = ranged(value, 1, 60_000)?,
+            "mqtt.broker" => self.mqtt.broker = parse_address(value)?,
+            "mqtt.port" => self.mqtt.port = ranged(value, 1, u16::MAX)?,
+            "mqtt.prefix" => {
+                if value.contains(['+', '#']) {
+                    return Err("no wildcards in topics");
+                }
+                if value.len() > 128 {
+                    return Err("too long");
+                }
+                self.mqtt.prefix = value.into();
+            }
+            "mqtt.qos" => self.mqtt.qos = ranged(value, 0, 1)?,
+            "mqtt.battery_secs" => self.mqtt.battery_secs = ranged(value, 1, 3600)?,
+            _ => return Err("no such setting"),
+        }
+
+        Ok(())
+    }
+
+    /// Only what differs from the defaults is stored.
+    pub fn encode(&self) -> Vec<u8> {
+        let defaults = Self::new().entries();
+        let changed: Vec<_> = self
+            .entries()
+            .into_iter()
+            .zip(defaults)
+            .filter(|((_, value), (_, default))| value != default)
+            .map(|(entry, _)| entry)
+            .collect();
+        config_format::encode(&changed)
+    }
+
+    /// Settings that no longer check out are left at their defaults.
+    pub fn decode(bytes: &[u8]) -> Result<Self, Unreadable> {
+        let mut config = Self::new();
+        for (key, value) in config_format::decode(bytes)? {
+            if let Err(err) = config.set(&key, &value) {
+                println!("config: dropping {key}: {err}");
+            }
+        }
+        Ok(config)
+    }
+}
+
+fn ranged<T: core::str::FromStr + PartialOrd>(
+    value: &str,
+    min: T,
+    max: T,
+) -> Result<T, &'static str> {
+    let value: T = value.parse().map_err(|_| "expected a number")?;
+    if value < min || value > max {
+        return Err("out of range");
+    }
+    Ok(value)
+}
+
+fn ssid(value: &str) -> Result<String, &'static str> {
+    if value.len() > 32 {
+        return Err("SSIDs are at most 32 bytes");
+    }
+    Ok(value.into())
+}
+
+fn address([a, b, c, d]: [u8; 4]) -> String {
+    format!("{a}.{b}.{c}.{d}")
+}
+
+fn parse_address(value: &str) -> Result<[u8; 4], &'static str> {
+    let mut address = [0u8; 4];
+    let mut octets = value.split('.');
+    for octet in address.iter_mut() {
+        *octet = octets
+            .next()
+            .and_then(|octet| octet.parse().ok())
+            .ok_or("expected an IPv4 address")?;
+    }
+    if octets.next().is_some() {
+        return Err("expected an IPv4 address");
+    }
+    Ok(address)
+}
+
+static CONFIG: Mutex<RefCell<Config>> = Mutex::new(RefCell::new(Config::new()));
+
+/// Read the stored settings, or start from the defaults, and hand them out.
+pub fn load() {
+    let mut bytes = [0u8; storage::SECTOR_SIZE as usize];
+    if let Err(err) =
+        FlashStorage::new().read(storage::reserved_sector(storage::CONFIG_SECTOR), &mut bytes)
+    {
+        println!("config: couldn't read: {:?}", err);
+    }
+
+    let config = match Config::decode(&bytes) {
+        Ok(config) => config,
+        Err(Unreadable::Missing) => {
+            println!("config: nothing stored, using defaults");
+            Config::new()
+        }
+        // Its keys may not mean what they do here; changing a setting
+        // replaces them
+        Err(Unreadable::Newer(version)) => {
+            println!("config: stored by newer firmware (layout {version}), using defaults");
+            Config::new()
+        }
+    };
+    apply(&config);
+    critical_section::with(|cs| *CONFIG.borrow_ref_mut(cs) = config);
+}
+
+pub fn get() -> Config {
+    critical_section::with(|cs| CONFIG.borrow_ref(cs).clone())
+}
+
+/// Look at the settings without copying them all.
+pub fn with<R>(f: impl FnOnce(&Config) -> R) -> R {
+    critical_section::with(|cs| f(&CONFIG.borrow_ref(cs)))
+}
+
+/// Change one setting, store it and pass it on.
+pub fn set(key: &str, value: &str) -> Result<(), &'static str> {
+    let mut config = get();
+    config.set(key, value)?;
+    save(&config)
+}
+
+/// Back to the defaults.
+pub fn reset() -> Result<(), &'static str> {
+    save(&Config::new())
+}
+
+fn save(config: &Config) -> Result<(), &'static str> {
+    let bytes = config.encode();
+    if bytes.len() > MAX_LEN {
+        return Err("too much to store");
+    }
+
+    FlashStorage::new()
+        .write(storage::reserved_sector(storage::CONFIG_SECTOR), &bytes)
+        .map_err(|_| "couldn't write flash")?;
+
+    apply(config);
+    critical_section::with(|cs| *CONFIG.borrow_ref_mut(cs) = config.clone());
+    Ok(())
+}
+
+fn apply(config: &Config) {
+    occupancy::configure(config.occupancy);
+    share::configure(config.share.clone());
+    station::configure(config.station.clone());
+    upload::configure(config.upload.clone());
+    mqtt::configure(config.mqtt.clone());
+}
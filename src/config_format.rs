@@ -0,0 +1,165 @@
+//! Layout of the settings sector.
+//!
+//! A header (magic, layout version, body length, CRC-16 of the body), then
+//! the body: each key and value as a length byte and UTF-8 text. Kept apart
+//! from `config`, which needs the board's network modules, so the simulator
+//! can check it.
+
+use alloc::{string::String, vec::Vec};
+use wif_protocol::crc::crc16;
+
+use crate::record::Reader;
+
+const MAGIC: &[u8; 4] = b"CONF";
+
+/// Bump when a key is renamed or changes meaning, and teach `migrate` how
+/// to bring the old one forward.
+pub const VERSION: u16 = 2;
+
+/// Why `decode` came back empty handed.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Unreadable {
+    /// Blank, or not intact
+    Missing,
+    /// Written by newer firmware, in a layout whose keys may mean something
+    /// else by now
+    Newer(u16),
+}
+
+/// `entries` as (key, value), each at most 255 bytes.
+pub fn encode(entries: &[(&str, String)]) -> Vec<u8> {
+    let mut body = Vec::new();
+    for (key, value) in entries {
+        body.push(key.len() as u8);
+        body.extend_from_slice(key.as_bytes());
+        body.push(value.len() as u8);
+        body.extend_from_slice(value.as_bytes());
+    }
+
+    let mut bytes = MAGIC.to_vec();
+    bytes.extend_from_slice(&VERSION.to_le_bytes());
+    bytes.extend_from_slice(&(body.len() as u16).to_le_bytes());
+    bytes.extend_from_slice(&crc16(&body).to_le_bytes());
+    bytes.extend_from_slice(&body);
+    bytes
+}
+
+/// The stored (key, value)s, brought up to the current layout.
+pub fn decode(bytes: &[u8]) -> Result<Vec<(String, String)>, Unreadable> {
+    let mut reader = Reader(bytes);
+    if reader.array::<4>() != Some(*MAGIC) {
+        return Err(Unreadable::Missing);
+    }
+
+    let (version, body) = header(&mut reader).ok_or(Unreadable::Missing)?;
+    if version > VERSION {
+        return Err(Unreadable::Newer(version));
+    }
+
+    let mut entries = Vec::new();
+    let mut reader = Reader(body);
+    while !reader.0.is_empty() {
+        let (key, value) = entry(&mut reader).ok_or(Unreadable::Missing)?;
+        if let Some(entry) = migrate(version, key, value) {
+            entries.push(entry);
+        }
+    }
+    Ok(entries)
+}
+
+/// Version and body, if the body checks out.
+fn header<'a>(reader: &mut Reader<'a>) -> Option<(u16, &'a [u8])> {
+    let version = reader.u16()?;
+    let len = reader.u16()? as usize;
+    let crc = reader.u16()?;
+    let body = reader.bytes(len)?;
+    (crc16(body) == crc).then_some((version, body))
+}
+
+fn entry<'a>(reader: &mut Reader<'a>) -> Option<(&'a str, &'a str)> {
+    let len = reader.u8()? as usize;
+    let key = core::str::from_utf8(reader.bytes(len)?).ok()?;
+    let len = reader.u8()? as usize;
+    let value = core::str::from_utf8(reader.bytes(len)?).ok()?;
+    Some((key, value))
+}
+
+/// Bring a setting stored in layout `version` up to date, or drop it.
+///
+/// Layout 2 renamed `battery.secs`, how often the fuel gauge is read, to
+/// `battery.sample_secs` so it isn't mistaken for `mqtt.battery_secs`.
+fn migrate(version: u16, key: &str, value: &str) -> Option<(String, String)> {
+    debug_assert!(version <= VERSION);
+    let key = match key {
+        "battery.secs" if version < 2 => "battery.sample_secs",
+        key => key,
+    };
+    Some((key.into(), value.into()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entries() -> Vec<(&'static str, String)> {
+        alloc::vec![
+            ("lights.brightness", "40".into()),
+            ("station.ssid", " Joe's Cafe ".into()),
+            ("station.password", "".into()),
+            ("mqtt.prefix", "home/ünïcode".into()),
+        ]
+    }
+
+    #[test]
+    fn reads_back_what_it_wrote() {
+        let decoded = decode(&encode(&entries())).unwrap();
+        let expected: Vec<(String, String)> = entries()
+            .into_iter()
+            .map(|(key, value)| (key.into(), value))
+            .collect();
+        assert_eq!(decoded, expected);
+        assert_eq!(decode(&encode(&[])), Ok(Vec::new()));
+    }
+
+    #[test]
+    fn ignores_what_isnt_intact() {
+        assert_eq!(decode(&[0xff; 64]), Err(Unreadable::Missing));
+        assert_eq!(decode(&[]), Err(Unreadable::Missing));
+
+        let bytes = encode(&entries());
+        let mut flipped = bytes.clone();
+        *flipped.last_mut().unwrap() ^= 1;
+        assert_eq!(decode(&flipped), Err(Unreadable::Missing));
+        assert_eq!(decode(&bytes[..bytes.len() - 1]), Err(Unreadable::Missing));
+    }
+
+    /// `entries` in a header claiming layout `version`
+    fn encode_as(version: u16, entries: &[(&str, String)]) -> Vec<u8> {
+        let mut bytes = encode(entries);
+        bytes[4..6].copy_from_slice(&version.to_le_bytes());
+        bytes
+    }
+
+    #[test]
+    fn renames_keys_from_older_layouts() {
+        let old = [
+            ("battery.secs", "30".into()),
+            ("lights.brightness", "40".into()),
+        ];
+        let expected: Vec<(String, String)> = alloc::vec![
+            ("battery.sample_secs".into(), "30".into()),
+            ("lights.brightness".into(), "40".into()),
+        ];
+        assert_eq!(decode(&encode_as(1, &old)), Ok(expected));
+
+        // Only layouts from before the rename are rewritten
+        let decoded = decode(&encode_as(VERSION, &old)).unwrap();
+        assert_eq!(decoded[0].0, "battery.secs");
+    }
+
+    #[test]
+    fn refuses_newer_layouts() {
+        let bytes = encode_as(VERSION + 1, &entries());
+        assert_eq!(decode(&bytes), Err(Unreadable::Newer(VERSION + 1)));
+    }
+}
@@ -0,0 +1,931 @@
+//! The serial console's commands: parsing a line and carrying it out.
+//!
+//! Everything the commands touch goes through `Device`, so the console can
+//! be driven on a host with a fake one. `serial` wires it to the real thing.
+
+use alloc::{string::String, vec::Vec};
+use core::fmt::Write;
+
+use crate::{
+    allowlist::Trusted,
+    battery::Level,
+    clock::{self, Stamp},
+    frame::{self, Mac, MacAddress, Security},
+    mode::Mode,
+    query::{Cursor, Page, Query},
+    record::{self, Escaped, Record},
+};
+
+pub const HELP: &str = "\
+help                      this list
+dump [filters] [from id [generation g]] [last n]
+                          stored records, from a record ID on, or the
+                          newest n. IDs start over when the store is
+                          erased; from an ID of an older generation
+                          starts at the beginning. Filters:
+                            network alert device occupancy pan spectrum
+                            match text    ssid prefix
+                            channel n     rssi dbm
+                            since when    until when
+                          Only pans, spectrum and alerts have a
+                          channel, and only spectrum a level.
+count [filters]           how many records match
+stats                     channel, spectrum and occupancy summaries
+erase                     erase the store (asks first)
+config get [key]          show one setting, or all of them
+config set key value      change a setting; quote a value to keep
+                          spaces at its ends
+config reset              back to the defaults
+trust                     our networks, watched for evil twins
+trust bssid security ssid trust an AP for a network; security is one of
+                          open wep wpa wpa2 wpa3
+forget ssid               stop trusting a network
+mode name                 reboot into sniff bluetooth 802154 share upload live
+time                      show the clock
+time set when             set it: unix seconds or 2024-01-31T12:00:00
+                          (UTC; a space for the T works too)
+battery                   fuel gauge reading
+crash [clear]             the last crash on record, or forget it
+health                    task check-ins, stack and heap use
+reboot                    restart in the current mode
+";
+
+/// Records `dump` reads from the store at a time
+const DUMP_PAGE: usize = 16;
+
+/// What the console can see and do.
+pub trait Device {
+    /// Up to `limit` raw stored entries matching `query` from `from` on,
+    /// oldest first. A cursor from an older generation starts over.
+    fn page(&mut self, query: &Query, from: Cursor, limit: usize) -> Page;
+    fn count(&mut self, query: &Query, from: Cursor) -> usize;
+    fn stats(&mut self) -> String;
+    fn battery(&self) -> Option<Level>;
+    fn erase(&mut self);
+    /// Every setting as (key, value).
+    fn settings(&self) -> Vec<(&'static str, String)>;
+    fn set(&mut self, key: &str, value: &str) -> Result<(), &'static str>;
+    fn reset_settings(&mut self) -> Result<(), &'static str>;
+    fn trusted(&self) -> Vec<Trusted>;
+    fn trust(
+        &mut self,
+        ssid: &str,
+        bssid: MacAddress,
+        security: Security,
+    ) -> Result<(), &'static str>;
+    /// False if `ssid` wasn't trusted.
+    fn forget(&mut self, ssid: &str) -> bool;
+    fn now(&self) -> Stamp;
+    fn set_time(&mut self, unix_ms: u64);
+    fn switch(&mut self, mode: Mode);
+    fn reboot(&mut self);
+    /// The last crash on record, as text.
+    fn crash(&self) -> Option<String>;
+    fn clear_crash(&mut self) -> Result<(), &'static str>;
+    /// Task, stack and heap health, as text.
+    fn health(&self) -> String;
+}
+
+/// Which records `dump` shows: those `query` matches from `from` on, or
+/// only the newest `last` of them.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Filter {
+    pub query: Query,
+    pub from: Cursor,
+    pub last: Option<usize>,
+}
+
+impl Filter {
+    pub fn parse<'a>(mut words: impl Iterator<Item = &'a str>) -> Result<Self, &'static str> {
+        let mut filter = Self::default();
+        let query = &mut filter.query;
+
+        while let Some(word) = words.next() {
+            let mut value = |what| words.next().ok_or(what);
+            match word {
+                "match" => query.text = Some(value("match what?")?.into()),
+                "ssid" => query.ssid_prefix = Some(value("ssid starting with what?")?.into()),
+                "channel" => {
+                    let channel = value("which channel?")?;
+                    query.channel = Some(channel.parse().map_err(|_| "channel takes a number")?);
+                }
+                "rssi" => {
+                    let rssi = value("rssi of at least what?")?;
+                    query.min_rssi = Some(rssi.parse().map_err(|_| "rssi takes dBm, like -70")?);
+                }
+                "since" => query.since = Some((parse_time(value("since when?")?)? / 1000) as u32),
+                "until" => query.until = Some((parse_time(value("until when?")?)? / 1000) as u32),
+                "from" => {
+                    let id = value("from which record?")?;
+                    filter.from.id = id.parse().map_err(|_| "from takes a record ID")?;
+                }
+                "generation" => {
+                    let generation = value("which generation?")?;
+                    let generation = generation
+                        .parse()
+                        .map_err(|_| "generation takes a number")?;
+                    filter.from.generation = Some(generation);
+                }
+                "last" => {
+                    let count = value("last how many?")?;
+                    filter.last = Some(count.parse().map_err(|_| "last takes a number")?);
+                }
+                "network" | "alert" | "device" | "occupancy" | "pan" | "spectrum" => {
+                    query.kind = Some(word.into())
+                }
+                _ => return Err("unknown filter, try help"),
+            }
+        }
+        Ok(filter)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Command {
+    Help,
+    Dump(Filter),
+    Count(Filter),
+    Stats,
+    Erase,
+    ConfigGet(Option<String>),
+    ConfigSet(String, String),
+    ConfigReset,
+    Trusted,
+    Trust {
+        ssid: String,
+        bssid: MacAddress,
+        security: Security,
+    },
+    Forget(String),
+    Mode(Mode),
+    Time,
+    SetTime(u64),
+    Battery,
+    Reboot,
+    Crash,
+    ClearCrash,
+    Health,
+}
+
+impl Command {
+    pub fn parse(line: &str) -> Result<Self, &'static str> {
+        let mut words = line.split_whitespace();
+        let Some(command) = words.next() else {
+            return Err("empty");
+        };
+
+        let command = match command {
+            "help" | "?" => Self::Help,
+            "stats" => Self::Stats,
+            "erase" => Self::Erase,
+            "battery" => Self::Battery,
+            "reboot" => Self::Reboot,
+            "health" => Self::Health,
+            "dump" => Self::Dump(Filter::parse(words)?),
+            "count" => Self::Count(Filter::parse(words)?),
+            "config" => match words.next() {
+                Some("get") => Self::ConfigGet(words.next().map(Into::into)),
+                Some("set") => {
+                    let key = words.next().ok_or("set which key?")?;
+                    Self::ConfigSet(key.into(), value_after(line, 3).into())
+                }
+                Some("reset") => Self::ConfigReset,
+                _ => return Err("config get, set or reset"),
+            },
+            "trust" => match words.next() {
+                None => Self::Trusted,
+                Some(bssid) => {
+                    let bssid = frame::parse_mac(bssid).ok_or("bssid like aa:bb:cc:dd:ee:ff")?;
+                    let security = words.next().ok_or("trust with which security?")?;
+                    let security =
+                        Security::parse(security).ok_or("open, wep, wpa, wpa2 or wpa3")?;
+                    let ssid = value_after(line, 3);
+                    if ssid.is_empty() {
+                        return Err("trust it for which ssid?");
+                    }
+                    Self::Trust {
+                        ssid: ssid.into(),
+                        bssid,
+                        security,
+                    }
+                }
+            },
+            "forget" => match value_after(line, 1) {
+                "" => return Err("forget which ssid?"),
+                ssid => Self::Forget(ssid.into()),
+            },
+            "mode" => Self::Mode(match words.next() {
+                Some("sniff") => Mode::Wifi,
+                Some("bluetooth") => Mode::Bluetooth,
+                Some("802154") => Mode::Ieee802154,
+                Some("share") => Mode::Share,
+                Some("upload") => Mode::Upload,
+                Some("live") => Mode::Live,
+                _ => return Err("unknown mode"),
+            }),
+            "time" => match words.next() {
+                None => Self::Time,
+                Some("set") => match after_words(line, 2) {
+                    "" => return Err("set it to what?"),
+                    when => Self::SetTime(parse_time(when)?),
+                },
+                _ => return Err("time or time set"),
+            },
+            "crash" => match words.next() {
+                None => Self::Crash,
+                Some("clear") => Self::ClearCrash,
+                _ => return Err("crash or crash clear"),
+            },
+            _ => return Err("unknown command, try help"),
+        };
+
+        Ok(command)
+    }
+}
+
+/// The rest of `line` after its first `count` words.
+fn after_words(line: &str, count: usize) -> &str {
+    let mut rest = line.trim_start();
+    for _ in 0..count {
+        let end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+        rest = rest[end..].trim_start();
+    }
+    rest.trim_end()
+}
+
+/// The rest of `line` after its first `count` words, as one value: SSIDs and
+/// the like may have spaces in them. Quotes around it are dropped, so it can
+/// start or end with spaces too, or be empty.
+fn value_after(line: &str, count: usize) -> &str {
+    let value = after_words(line, count);
+    value
+        .strip_prefix('"')
+        .and_then(|value| value.strip_suffix('"'))
+        .unwrap_or(value)
+}
+
+/// Unix seconds, or an ISO 8601 UTC date and time; as Unix ms.
+pub(crate) fn parse_time(value: &str) -> Result<u64, &'static str> {
+    if let Ok(seconds) = value.parse::<u64>() {
+        return Ok(seconds * 1000);
+    }
+
+    const FORMAT: &str = "unix seconds or YYYY-MM-DDTHH:MM:SS";
+    let value = value.strip_suffix('Z').unwrap_or(value);
+    let (date, time) = value.split_once(['T', ' ']).ok_or(FORMAT)?;
+    let [year, month, day] = numbers(date, '-').ok_or(FORMAT)?;
+    let [hours, minutes, seconds] = numbers(time, ':').ok_or(FORMAT)?;
+
+    clock::unix_ms_from_civil(year, month, day, hours, minutes, seconds).ok_or("bad date")
+}
+
+/// Exactly `N` numbers with `separator` between them.
+fn numbers<const N: usize>(text: &str, separator: char) -> Option<[u32; N]> {
+    let mut numbers = [0; N];
+    let mut parts = text.split(separator);
+    for number in &mut numbers {
+        *number = parts.next()?.parse().ok()?;
+    }
+    parts.next().is_none().then_some(numbers)
+}
+
+/// One console session's state.
+pub struct Console {
+    /// `erase` was typed and we're waiting for a yes
+    confirming_erase: bool,
+}
+
+impl Default for Console {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Console {
+    pub const fn new() -> Self {
+        Self {
+            confirming_erase: false,
+        }
+    }
+
+    /// Carry out one line of input, writing the response to `out`.
+    pub fn handle(&mut self, line: &str, device: &mut impl Device, out: &mut impl Write) {
+        let line = line.trim();
+
+        if core::mem::take(&mut self.confirming_erase) {
+            if line == "yes" {
+                device.erase();
+                let _ = writeln!(out, "erased");
+            } else {
+                let _ = writeln!(out, "not erased");
+            }
+            return;
+        }
+
+        if line.is_empty() {
+            return;
+        }
+
+        let command = match Command::parse(line) {
+            Ok(command) => command,
+            Err(err) => {
+                let _ = writeln!(out, "error: {err}");
+                return;
+            }
+        };
+
+        match command {
+            Command::Help => {
+                let _ = out.write_str(HELP);
+            }
+            Command::Dump(filter) => dump(&filter, device, out),
+            Command::Count(filter) => {
+                let _ = writeln!(out, "{} records", device.count(&filter.query, filter.from));
+            }
+            Command::Stats => {
+                let _ = out.write_str(&device.stats());
+            }
+            Command::Erase => {
+                self.confirming_erase = true;
+                let _ = writeln!(out, "erase every stored record? type yes to confirm");
+            }
+            Command::ConfigGet(key) => {
+                let mut found = false;
+                for (name, value) in device.settings() {
+                    if key.as_deref().is_none_or(|key| key == name) {
+                        found = true;
+                        let _ = writeln!(out, "{name} = {value}");
+                    }
+                }
+                if !found {
+                    let _ = writeln!(out, "error: no such setting");
+                }
+            }
+            Command::ConfigSet(key, value) => match device.set(&key, &value) {
+                Ok(()) => {
+                    let _ = writeln!(out, "ok");
+                }
+                Err(err) => {
+                    let _ = writeln!(out, "error: {err}");
+                }
+            },
+            Command::ConfigReset => match device.reset_settings() {
+                Ok(()) => {
+                    let _ = writeln!(out, "ok");
+                }
+                Err(err) => {
+                    let _ = writeln!(out, "error: {err}");
+                }
+            },
+            Command::Trusted => {
+                let trusted = device.trusted();
+                if trusted.is_empty() {
+                    let _ = writeln!(out, "no trusted networks");
+                }
+                for network in trusted {
+                    let ssid = Escaped(&network.ssid);
+                    let _ = writeln!(out, "{ssid} ({:?})", network.security);
+                    for bssid in &network.bssids {
+                        let _ = writeln!(out, "  {}", Mac(bssid));
+                    }
+                }
+            }
+            Command::Trust {
+                ssid,
+                bssid,
+                security,
+            } => {
+                let _ = match device.trust(&ssid, bssid, security) {
+                    Ok(()) => writeln!(out, "ok"),
+                    Err(err) => writeln!(out, "error: {err}"),
+                };
+            }
+            Command::Forget(ssid) => {
+                let _ = if device.forget(&ssid) {
+                    writeln!(out, "ok")
+                } else {
+                    writeln!(out, "error: {ssid} isn't trusted")
+                };
+            }
+            Command::Mode(mode) => {
+                let _ = writeln!(out, "switching to {mode:?}");
+                device.switch(mode);
+            }
+            Command::Time => {
+                let _ = writeln!(out, "{}", device.now());
+            }
+            Command::SetTime(unix_ms) => {
+                device.set_time(unix_ms);
+                let _ = writeln!(out, "{}", device.now());
+            }
+            Command::Battery => {
+                let _ = match device.battery() {
+                    Some(level) => writeln!(
+                        out,
+                        "{}% {} mV{}",
+                        level.percent,
+                        level.millivolts,
+                        if level.usb { " on usb" } else { "" }
+                    ),
+                    None => writeln!(out, "no reading yet"),
+                };
+            }
+            Command::Reboot => {
+                let _ = writeln!(out, "rebooting");
+                device.reboot();
+            }
+            Command::Crash => {
+                let _ = match device.crash() {
+                    Some(report) => out.write_str(&report),
+                    None => writeln!(out, "no crash on record"),
+                };
+            }
+            Command::ClearCrash => match device.clear_crash() {
+                Ok(()) => {
+                    let _ = writeln!(out, "ok");
+                }
+                Err(err) => {
+                    let _ = writeln!(out, "error: {err}");
+                }
+            },
+            Command::Health => {
+                let _ = out.write_str(&device.health());
+            }
+        }
+    }
+}
+
+fn dump(filter: &Filter, device: &mut impl Device, out: &mut impl Write) {
+    // For the newest n, count them first and pass over the rest
+    let skip = filter.last.map_or(0, |last| {
+        device
+            .count(&filter.query, filter.from)
+            .saturating_sub(last)
+    });
+
+    let mut from = filter.from;
+    let mut seen = 0;
+    loop {
+        let page = device.page(&filter.query, from, DUMP_PAGE);
+        let erased = from
+            .generation
+            .is_some_and(|generation| generation != page.generation);
+        if erased {
+            // Part way through, what's left isn't what was asked for
+            if seen > 0 {
+                let _ = writeln!(out, "erased part way through");
+                break;
+            }
+            let _ = writeln!(out, "erased since, from the beginning");
+        }
+
+        for (id, bytes) in &page.records {
+            seen += 1;
+            if seen <= skip {
+                continue;
+            }
+
+            let (stamp, _) = record::unstamp(bytes);
+            let _ = match (stamp, Record::decode(bytes)) {
+                (Some(stamp), Some(record)) => writeln!(out, "{id} {stamp} {record}"),
+                (None, Some(record)) => writeln!(out, "{id} {record}"),
+                (_, None) => writeln!(out, "{id} ? {:?}", bytes),
+            };
+        }
+
+        match page.next {
+            Some(id) => {
+                from = Cursor {
+                    generation: Some(page.generation),
+                    id,
+                }
+            }
+            None => {
+                let _ = writeln!(
+                    out,
+                    "{} records, generation {}",
+                    seen.saturating_sub(skip),
+                    page.generation
+                );
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::{borrow::ToOwned, format};
+
+    use super::*;
+
+    /// 2024-01-31T12:00:00Z
+    const UNIX_MS: u64 = 1_706_702_400_000;
+
+    #[derive(Default)]
+    struct Fake {
+        records: Vec<Vec<u8>>,
+        settings: Vec<(&'static str, String)>,
+        trusted: Vec<Trusted>,
+        erased: bool,
+        /// Bumped by `erase`
+        generation: u32,
+        unix_ms: Option<u64>,
+    }
+
+    impl Fake {
+        fn resolve(&self, from: Cursor) -> u32 {
+            match from.generation {
+                Some(generation) if generation != self.generation => 0,
+                _ => from.id,
+            }
+        }
+    }
+
+    impl Device for Fake {
+        fn page(&mut self, query: &Query, from: Cursor, limit: usize) -> Page {
+            let mut page = Page {
+                generation: self.generation,
+                ..Page::default()
+            };
+            let matching = self
+                .records
+                .iter()
+                .enumerate()
+                .skip(self.resolve(from) as usize)
+                .filter(|(_, bytes)| query.matches(bytes));
+            for (id, bytes) in matching {
+                if page.records.len() == limit {
+                    page.next = Some(id as u32);
+                    break;
+                }
+                page.records.push((id as u32, bytes.clone()));
+            }
+            page
+        }
+
+        fn count(&mut self, query: &Query, from: Cursor) -> usize {
+            let records = self.records.iter().skip(self.resolve(from) as usize);
+            records.filter(|bytes| query.matches(bytes)).count()
+        }
+
+        fn stats(&mut self) -> String {
+            String::new()
+        }
+
+        fn battery(&self) -> Option<Level> {
+            None
+        }
+
+        fn erase(&mut self) {
+            self.records.clear();
+            self.generation += 1;
+            self.erased = true;
+        }
+
+        fn settings(&self) -> Vec<(&'static str, String)> {
+            self.settings.clone()
+        }
+
+        fn set(&mut self, key: &str, value: &str) -> Result<(), &'static str> {
+            let setting = self.settings.iter_mut().find(|(name, _)| *name == key);
+            setting.ok_or("no such setting")?.1 = value.into();
+            Ok(())
+        }
+
+        fn reset_settings(&mut self) -> Result<(), &'static str> {
+            Ok(())
+        }
+
+        fn trusted(&self) -> Vec<Trusted> {
+            self.trusted
+                .iter()
+                .map(|network| Trusted {
+                    ssid: network.ssid.clone(),
+                    security: network.security,
+                    bssids: network.bssids.clone(),
+                })
+                .collect()
+        }
+
+        fn trust(
+            &mut self,
+            ssid: &str,
+            bssid: MacAddress,
+            security: Security,
+        ) -> Result<(), &'static str> {
+            if ssid.len() > 32 {
+                return Err("SSIDs are at most 32 bytes");
+            }
+            self.trusted.push(Trusted {
+                ssid: ssid.into(),
+                security,
+                bssids: alloc::vec![bssid],
+            });
+            Ok(())
+        }
+
+        fn forget(&mut self, ssid: &str) -> bool {
+            let before = self.trusted.len();
+            self.trusted.retain(|network| network.ssid != ssid);
+            self.trusted.len() < before
+        }
+
+        fn now(&self) -> Stamp {
+            Stamp {
+                boot: 1,
+                uptime_s: 5,
+                unix_s: self.unix_ms.map(|unix_ms| (unix_ms / 1000) as u32),
+            }
+        }
+
+        fn set_time(&mut self, unix_ms: u64) {
+            self.unix_ms = Some(unix_ms);
+        }
+
+        fn switch(&mut self, _mode: Mode) {}
+
+        fn reboot(&mut self) {}
+
+        fn crash(&self) -> Option<String> {
+            None
+        }
+
+        fn clear_crash(&mut self) -> Result<(), &'static str> {
+            Ok(())
+        }
+
+        fn health(&self) -> String {
+            String::new()
+        }
+    }
+
+    /// Type `lines` in one session; what the console said back.
+    fn session(device: &mut Fake, lines: &[&str]) -> String {
+        let mut console = Console::new();
+        let mut out = String::new();
+        for line in lines {
+            console.handle(line, device, &mut out);
+        }
+        out
+    }
+
+    fn config_set(key: &str, value: &str) -> Result<Command, &'static str> {
+        Ok(Command::ConfigSet(key.into(), value.into()))
+    }
+
+    #[test]
+    fn keeps_spaces_in_setting_values() {
+        assert_eq!(
+            Command::parse("config set ssid Cafe"),
+            config_set("ssid", "Cafe")
+        );
+        assert_eq!(
+            Command::parse("config set ssid My Home  Network"),
+            config_set("ssid", "My Home  Network")
+        );
+        assert_eq!(
+            Command::parse("config   set\tssid   Library Guest  "),
+            config_set("ssid", "Library Guest")
+        );
+        assert_eq!(
+            Command::parse("config set ssid \" padded \""),
+            config_set("ssid", " padded ")
+        );
+        assert_eq!(
+            Command::parse("config set password \"\""),
+            config_set("password", "")
+        );
+        // Only quotes around the whole value go
+        assert_eq!(
+            Command::parse("config set ssid Joe's \"Cafe\" 2"),
+            config_set("ssid", "Joe's \"Cafe\" 2")
+        );
+        assert!(Command::parse("config set").is_err());
+    }
+
+    #[test]
+    fn sets_what_it_was_given() {
+        let mut device = Fake {
+            settings: alloc::vec![("ssid", "Home".into()), ("brightness", "20".into())],
+            ..Default::default()
+        };
+        let out = session(
+            &mut device,
+            &[
+                "config set ssid \"  Coffee Shop\"",
+                "config get ssid",
+                "config get nothing",
+                "config set nothing 1",
+            ],
+        );
+        assert_eq!(
+            out,
+            "ok\nssid =   Coffee Shop\nerror: no such setting\nerror: no such setting\n"
+        );
+        assert_eq!(device.settings[0].1, "  Coffee Shop");
+    }
+
+    #[test]
+    fn parses_times() {
+        for when in [
+            "2024-01-31T12:00:00Z",
+            "2024-01-31T12:00:00",
+            "2024-01-31 12:00:00",
+            "1706702400",
+        ] {
+            let line = format!("time set {when}");
+            assert_eq!(
+                Command::parse(&line),
+                Ok(Command::SetTime(UNIX_MS)),
+                "{when}"
+            );
+        }
+        assert_eq!(
+            parse_time("2024-02-29T00:00:00Z"),
+            Ok(1_709_164_800_000),
+            "leap day"
+        );
+        assert_eq!(parse_time("1970-01-01T00:00:00Z"), Ok(0));
+
+        for bad in [
+            "",
+            "tomorrow",
+            "2024-01-31",
+            "2024-01-31T12:00",
+            "2024-01-31T12:00:00:00",
+            "2024-01-31-01T12:00:00",
+            "2024-01-31T12:00:00.5Z",
+            "2024-1-xxT12:00:00",
+            "2023-02-29T12:00:00",
+            "2024-04-31T12:00:00",
+            "2024-13-01T12:00:00",
+            "2024-01-31T24:00:00",
+            "1969-12-31T23:59:59",
+        ] {
+            assert!(parse_time(bad).is_err(), "{bad}");
+        }
+        assert!(Command::parse("time set").is_err());
+        assert!(Command::parse("time set 2024-01-31T12:00:00 later").is_err());
+    }
+
+    #[test]
+    fn shows_the_time_it_was_set_to() {
+        let mut device = Fake::default();
+        let out = session(
+            &mut device,
+            &["time", "time set 2024-01-31 12:00:00", "time"],
+        );
+        assert_eq!(
+            out,
+            "boot 1 +5s\n2024-01-31T12:00:00Z\n2024-01-31T12:00:00Z\n"
+        );
+        assert_eq!(device.unix_ms, Some(UNIX_MS));
+    }
+
+    #[test]
+    fn only_erases_on_yes() {
+        let mut device = Fake {
+            records: alloc::vec![Record::Network("Home".into()).encode()],
+            ..Default::default()
+        };
+
+        let out = session(&mut device, &["erase", "y"]);
+        assert_eq!(
+            out,
+            "erase every stored record? type yes to confirm\nnot erased\n"
+        );
+        assert!(!device.erased);
+
+        // Anything at all in between calls it off, even a command
+        let out = session(&mut device, &["erase", "count", "yes"]);
+        assert!(out.ends_with("not erased\nerror: unknown command, try help\n"));
+        assert!(!device.erased);
+
+        let out = session(&mut device, &["erase", "  yes ", "count"]);
+        assert_eq!(
+            out,
+            "erase every stored record? type yes to confirm\nerased\n0 records\n"
+        );
+        assert!(device.erased);
+    }
+
+    #[test]
+    fn trusts_and_forgets_networks() {
+        let mut device = Fake::default();
+        let out = session(
+            &mut device,
+            &[
+                "trust",
+                "trust 3c:84:6a:10:20:30 wpa2 Home Network",
+                "trust 3c:84:6a:10:20:31 wpa3 \" Spaced \"",
+                "trust 3c:84:6a:10:20:32 wpa3 A network name too long for any AP",
+                "trust 3c:84:6a:10:20:33 wpa2 Bell\x07",
+                "trust",
+                "forget Home Network",
+                "forget Home Network",
+                "forget \" Spaced \"",
+                "forget Bell\x07",
+            ],
+        );
+        assert_eq!(
+            out,
+            "no trusted networks\n\
+             ok\n\
+             ok\n\
+             error: SSIDs are at most 32 bytes\n\
+             ok\n\
+             Home Network (Wpa2)\n  3c:84:6a:10:20:30\n\
+             \x20Spaced  (Wpa3)\n  3c:84:6a:10:20:31\n\
+             Bell\\u{7} (Wpa2)\n  3c:84:6a:10:20:33\n\
+             ok\n\
+             error: Home Network isn't trusted\n\
+             ok\n\
+             ok\n"
+        );
+        assert!(device.trusted.is_empty());
+
+        for bad in [
+            "trust 3c:84:6a:10:20 wpa2 Home",
+            "trust 3c:84:6a:10:20:30 wpa9 Home",
+            "trust 3c:84:6a:10:20:30 wpa2",
+            "trust 3c:84:6a:10:20:30 wpa2 \"\"",
+            "forget",
+        ] {
+            assert!(Command::parse(bad).is_err(), "{bad}");
+        }
+    }
+
+    #[test]
+    fn dumps_the_newest_matching_records() {
+        let mut device = Fake::default();
+        for ssid in ["Home", "Cafe", "Home-5G", "Hotel"] {
+            device
+                .records
+                .push(Record::Network(ssid.to_owned()).encode());
+        }
+        device.records.push(
+            Record::Occupancy {
+                count: 2,
+                window_s: 300,
+                min_rssi: -75,
+            }
+            .encode(),
+        );
+
+        let out = session(
+            &mut device,
+            &["dump ssid Ho last 2", "count network from 2"],
+        );
+        assert_eq!(
+            out,
+            "2 + Home-5G\n3 + Hotel\n2 records, generation 0\n2 records\n"
+        );
+
+        // More than a page of them, all the way through
+        for count in 0..DUMP_PAGE as u16 * 2 {
+            device.records.push(
+                Record::Occupancy {
+                    count,
+                    window_s: 300,
+                    min_rssi: -75,
+                }
+                .encode(),
+            );
+        }
+        let out = session(&mut device, &["dump occupancy from 6"]);
+        assert!(out.starts_with("6 # 1 clients"), "{out}");
+        assert!(out.ends_with("36 # 31 clients in 300s above -75 dBm\n31 records, generation 0\n"));
+    }
+
+    #[test]
+    fn starts_over_from_an_id_before_an_erase() {
+        let mut device = Fake::default();
+        for ssid in ["Home", "Cafe", "Hotel"] {
+            device
+                .records
+                .push(Record::Network(ssid.to_owned()).encode());
+        }
+        assert_eq!(
+            session(&mut device, &["dump from 2 generation 0"]),
+            "2 + Hotel\n1 records, generation 0\n"
+        );
+
+        device.erase();
+        device
+            .records
+            .push(Record::Network("Lobby".to_owned()).encode());
+        assert_eq!(
+            session(
+                &mut device,
+                &["dump from 2 generation 0", "count from 2 generation 0"]
+            ),
+            "erased since, from the beginning\n0 + Lobby\n1 records, generation 1\n1 records\n"
+        );
+        assert_eq!(
+            session(&mut device, &["dump from 2 generation 1"]),
+            "0 records, generation 1\n"
+        );
+        assert!(Command::parse("dump generation new").is_err());
+    }
+}
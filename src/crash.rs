@@ -0,0 +1,376 @@
+//! What happened the last time the firmware fell over.
+//!
+//! The panic handler, and esp-backtrace's exception handler through its
+//! `custom_halt` hook, note what they can in RTC memory and reset; so does
+//! the supervisor before it lets the watchdog reset a stalled task. At the
+//! next boot that's moved into a reserved flash sector, where it survives
+//! losing power, and stays readable over serial and BLE until the next crash
+//! replaces it or someone clears it.
+
+use core::{
+    arch::asm,
+    fmt::{self, Write},
+    panic::PanicInfo,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use alloc::{format, string::String, vec::Vec};
+use embedded_storage::{ReadStorage, Storage};
+use esp_hal::{macros::ram, reset::software_reset};
+use esp_println::println;
+use esp_storage::FlashStorage;
+
+use crate::{
+    clock::{self, Stamp},
+    error::Error,
+    lights,
+    record::Reader,
+    storage, supervisor,
+};
+
+const CAPTURED_MAGIC: u32 = 0x4352_5348;
+const REPORT_MAGIC: &[u8; 4] = b"CRS1";
+
+const MAX_TEXT: usize = 192;
+const MAX_FRAMES: usize = 8;
+
+/// Where the stack lives, for walking frame pointers without faulting
+const RAM: core::ops::Range<u32> = 0x4080_0000..0x4088_0000;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Kind {
+    Panic,
+    Exception,
+    Watchdog,
+}
+
+/// Written by the handlers, so nothing in it needs the heap.
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct Captured {
+    magic: u32,
+    /// `Kind`, as a number
+    kind: u32,
+    boot: u32,
+    uptime_s: u32,
+    unix_s: u32,
+    /// mcause, mepc and mtval, for exceptions
+    cause: u32,
+    address: u32,
+    value: u32,
+    frames: [u32; MAX_FRAMES],
+    frame_count: u32,
+    len: u32,
+    text: [u8; MAX_TEXT],
+}
+
+impl Captured {
+    const EMPTY: Self = Self {
+        magic: 0,
+        kind: 0,
+        boot: 0,
+        uptime_s: 0,
+        unix_s: 0,
+        cause: 0,
+        address: 0,
+        value: 0,
+        frames: [0; MAX_FRAMES],
+        frame_count: 0,
+        len: 0,
+        text: [0; MAX_TEXT],
+    };
+}
+
+impl Write for Captured {
+    /// Keeps what fits.
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for byte in s.bytes() {
+            if self.len as usize == MAX_TEXT {
+                break;
+            }
+            self.text[self.len as usize] = byte;
+            self.len += 1;
+        }
+        Ok(())
+    }
+}
+
+#[ram(rtc_fast, persistent)]
+static mut CAPTURED: Captured = Captured::EMPTY;
+
+/// Set once we're handling a crash, in case handling it crashes too
+static CRASHING: AtomicBool = AtomicBool::new(false);
+
+/// The stored report is from the boot just before this one
+static FRESH: AtomicBool = AtomicBool::new(false);
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Report {
+    pub kind: Kind,
+    pub stamp: Stamp,
+    /// The panic message and where it came from
+    pub text: String,
+    pub cause: u32,
+    pub address: u32,
+    pub value: u32,
+    /// Return addresses, innermost first
+    pub frames: Vec<u32>,
+    /// Why the chip says it reset afterwards
+    pub reset: String,
+}
+
+impl Report {
+    fn encode(&self) -> Vec<u8> {
+        let mut bytes = REPORT_MAGIC.to_vec();
+        bytes.push(self.kind as u8);
+        self.stamp.encode(&mut bytes);
+        for word in [self.cause, self.address, self.value] {
+            bytes.extend_from_slice(&word.to_le_bytes());
+        }
+        bytes.push(self.frames.len() as u8);
+        for frame in &self.frames {
+            bytes.extend_from_slice(&frame.to_le_bytes());
+        }
+        for text in [&self.text, &self.reset] {
+            bytes.push(text.len() as u8);
+            bytes.extend_from_slice(text.as_bytes());
+        }
+        bytes
+    }
+
+    fn decode(bytes: &[u8]) -> Option<Self> {
+        let mut reader = Reader(bytes);
+        if reader.array::<4>()? != *REPORT_MAGIC {
+            return None;
+        }
+
+        let kind = match reader.u8()? {
+            0 => Kind::Panic,
+            1 => Kind::Exception,
+            2 => Kind::Watchdog,
+            _ => return None,
+        };
+        let stamp = Stamp::decode(&mut reader)?;
+        let cause = reader.u32()?;
+        let address = reader.u32()?;
+        let value = reader.u32()?;
+        let frames = (0..reader.u8()?)
+            .map(|_| reader.u32())
+            .collect::<Option<Vec<_>>>()?;
+        let len = reader.u8()? as usize;
+        let text = String::from_utf8_lossy(reader.bytes(len)?).into();
+        let len = reader.u8()? as usize;
+        let reset = String::from_utf8_lossy(reader.bytes(len)?).into();
+
+        Some(Self {
+            kind,
+            stamp,
+            text,
+            cause,
+            address,
+            value,
+            frames,
+            reset,
+        })
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.kind {
+            Kind::Panic => writeln!(f, "panic at {}: {}", self.stamp, self.text)?,
+            Kind::Watchdog => writeln!(f, "watchdog reset at {}: {}", self.stamp, self.text)?,
+            Kind::Exception => writeln!(
+                f,
+                "exception at {}: {} (mcause {}) at {:#010x}, mtval {:#010x}",
+                self.stamp,
+                exception_name(self.cause),
+                self.cause,
+                self.address,
+                self.value
+            )?,
+        }
+        if !self.frames.is_empty() {
+            write!(f, "backtrace:")?;
+            for frame in &self.frames {
+                write!(f, " {frame:#010x}")?;
+            }
+            writeln!(f)?;
+        }
+        writeln!(f, "reset: {}", self.reset)
+    }
+}
+
+fn exception_name(cause: u32) -> &'static str {
+    match cause {
+        0 => "instruction address misaligned",
+        1 => "instruction access fault",
+        2 => "illegal instruction",
+        3 => "breakpoint",
+        4 => "load address misaligned",
+        5 => "load access fault",
+        6 => "store address misaligned",
+        7 => "store access fault",
+        11 => "environment call",
+        _ => "unknown exception",
+    }
+}
+
+/// Move a crash from the last boot into flash. Run first thing at boot,
+/// with only the heap up, so a panic while bringing up the rest can't
+/// capture over it before it's kept.
+pub fn init() {
+    let mut captured = critical_section::with(|_| unsafe { CAPTURED });
+    if captured.magic != CAPTURED_MAGIC {
+        // Wedged too hard for the supervisor to say anything
+        if !supervisor::watchdog_reset() {
+            return;
+        }
+        captured = Captured::EMPTY;
+        captured.kind = Kind::Watchdog as u32;
+        match supervisor::last_heard() {
+            Some((task, stamp)) => {
+                let _ = write!(captured, "hung, {} checked in last", task.name());
+                captured.boot = stamp.boot;
+                captured.uptime_s = stamp.uptime_s;
+            }
+            None => {
+                let _ = write!(captured, "hung, no task checked in");
+                captured.boot = clock::last_boot().unwrap_or(0);
+            }
+        }
+    }
+
+    let report = Report {
+        kind: match captured.kind {
+            1 => Kind::Exception,
+            2 => Kind::Watchdog,
+            _ => Kind::Panic,
+        },
+        stamp: Stamp {
+            boot: captured.boot,
+            uptime_s: captured.uptime_s,
+            unix_s: Some(captured.unix_s).filter(|unix_s| *unix_s != 0),
+        },
+        text: String::from_utf8_lossy(&captured.text[..(captured.len as usize).min(MAX_TEXT)])
+            .into(),
+        cause: captured.cause,
+        address: captured.address,
+        value: captured.value,
+        frames: captured.frames[..(captured.frame_count as usize).min(MAX_FRAMES)].to_vec(),
+        reset: esp_hal::reset::get_reset_reason()
+            .map_or_else(|| "unknown".into(), |reason| format!("{reason:?}")),
+    };
+
+    // Left in RTC memory to try again next boot
+    let sector = storage::reserved_sector(storage::CRASH_SECTOR);
+    if let Err(err) = FlashStorage::new().write(sector, &report.encode()) {
+        println!("crash: couldn't keep the report: {:?}", err);
+        println!("{report}");
+        return;
+    }
+    critical_section::with(|_| unsafe { CAPTURED.magic = 0 });
+    FRESH.store(true, Ordering::Relaxed);
+
+    println!("crash: the last boot crashed");
+    println!("{report}");
+}
+
+/// The most recent crash on record.
+pub fn last() -> Option<Report> {
+    let mut bytes = [0u8; 512];
+    FlashStorage::new()
+        .read(storage::reserved_sector(storage::CRASH_SECTOR), &mut bytes)
+        .ok()?;
+    Report::decode(&bytes)
+}
+
+pub fn clear() -> Result<(), Error> {
+    FlashStorage::new().write(storage::reserved_sector(storage::CRASH_SECTOR), &[0u8; 4])?;
+    Ok(())
+}
+
+/// Blink the fault pattern if the last boot crashed.
+#[embassy_executor::task]
+pub async fn start_crash_report() {
+    if FRESH.load(Ordering::Relaxed) {
+        lights::fault().await;
+    }
+}
+
+/// Note why the watchdog is about to reset us, for the next boot.
+pub fn stalled(text: &str) {
+    let mut captured = Captured::EMPTY;
+    captured.kind = Kind::Watchdog as u32;
+    let _ = captured.write_str(text);
+    note(&mut captured);
+}
+
+fn note(captured: &mut Captured) {
+    let stamp = clock::now();
+    captured.magic = CAPTURED_MAGIC;
+    captured.boot = stamp.boot;
+    captured.uptime_s = stamp.uptime_s;
+    captured.unix_s = stamp.unix_s.unwrap_or(0);
+
+    critical_section::with(|_| unsafe { CAPTURED = *captured });
+}
+
+/// Note the crash, then reset to get going again.
+fn capture(captured: &mut Captured) -> ! {
+    note(captured);
+    software_reset();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    if CRASHING.swap(true, Ordering::Relaxed) {
+        software_reset();
+        loop {}
+    }
+
+    println!("\n\n!! {info}");
+
+    let mut captured = Captured::EMPTY;
+    let _ = write!(captured, "{}", info.message());
+    if let Some(location) = info.location() {
+        let _ = write!(captured, " at {}:{}", location.file(), location.line());
+    }
+
+    // Needs -C force-frame-pointers, which .cargo/config.toml sets
+    let mut fp: u32;
+    unsafe { asm!("mv {}, s0", out(reg) fp) };
+    while (captured.frame_count as usize) < MAX_FRAMES && fp % 4 == 0 && RAM.contains(&fp) {
+        let (ra, next) = unsafe { (*((fp - 4) as *const u32), *((fp - 8) as *const u32)) };
+        if ra == 0 {
+            break;
+        }
+        captured.frames[captured.frame_count as usize] = ra;
+        captured.frame_count += 1;
+        fp = next;
+    }
+
+    capture(&mut captured)
+}
+
+/// esp-backtrace has printed the exception and its backtrace by now. The
+/// trap registers still say what it was.
+#[no_mangle]
+fn custom_halt() -> ! {
+    if CRASHING.swap(true, Ordering::Relaxed) {
+        software_reset();
+        loop {}
+    }
+
+    let mut captured = Captured::EMPTY;
+    captured.kind = Kind::Exception as u32;
+    unsafe {
+        asm!("csrr {}, mcause", out(reg) captured.cause);
+        asm!("csrr {}, mepc", out(reg) captured.address);
+        asm!("csrr {}, mtval", out(reg) captured.value);
+    }
+    captured.cause &= 0xff;
+
+    capture(&mut captured)
+}
@@ -0,0 +1,146 @@
+//! Just enough of a DHCP server to hand out addresses on our own access
+//! point, so phones can join the share network without manual setup.
+
+use alloc::vec::Vec;
+
+use crate::record::Reader;
+
+/// Our address on the share network, a /24
+pub const SERVER: [u8; 4] = [192, 168, 4, 1];
+const NETMASK: [u8; 4] = [255, 255, 255, 0];
+
+/// Clients get .2 upwards
+const FIRST_HOST: u8 = 2;
+const MAX_LEASES: usize = 32;
+const LEASE_SECS: u32 = 60 * 60;
+
+const BOOTREQUEST: u8 = 1;
+const BOOTREPLY: u8 = 2;
+const MAGIC: [u8; 4] = [99, 130, 83, 99];
+
+const DISCOVER: u8 = 1;
+const OFFER: u8 = 2;
+const REQUEST: u8 = 3;
+const ACK: u8 = 5;
+const NAK: u8 = 6;
+
+const OPTION_NETMASK: u8 = 1;
+const OPTION_REQUESTED_ADDRESS: u8 = 50;
+const OPTION_LEASE_TIME: u8 = 51;
+const OPTION_MESSAGE_TYPE: u8 = 53;
+const OPTION_SERVER: u8 = 54;
+const OPTION_PAD: u8 = 0;
+const OPTION_END: u8 = 255;
+
+/// Client hardware addresses, by host number. Leases never expire; the pool
+/// is recycled oldest first once it's full.
+pub struct Leases {
+    clients: Vec<[u8; 6]>,
+}
+
+impl Leases {
+    pub const fn new() -> Self {
+        Self {
+            clients: Vec::new(),
+        }
+    }
+
+    fn address_for(&mut self, client: [u8; 6]) -> [u8; 4] {
+        let index = match self.clients.iter().position(|known| *known == client) {
+            Some(index) => index,
+            None if self.clients.len() < MAX_LEASES => {
+                self.clients.push(client);
+                self.clients.len() - 1
+            }
+            None => {
+                self.clients.remove(0);
+                self.clients.push(client);
+                self.clients.len() - 1
+            }
+        };
+
+        [SERVER[0], SERVER[1], SERVER[2], FIRST_HOST + index as u8]
+    }
+}
+
+/// The reply to a client's DHCP message, if it needs one.
+pub fn reply(request: &[u8], leases: &mut Leases) -> Option<Vec<u8>> {
+    let mut reader = Reader(request);
+    let [op, _htype, hlen, _hops] = reader.array()?;
+    if op != BOOTREQUEST || hlen != 6 {
+        return None;
+    }
+
+    let xid: [u8; 4] = reader.array()?;
+    reader.u16()?; // secs
+    let flags: [u8; 2] = reader.array()?;
+    let client_address: [u8; 4] = reader.array()?;
+    reader.bytes(8)?; // yiaddr, siaddr
+    let relay: [u8; 4] = reader.array()?;
+    let chaddr: [u8; 16] = reader.array()?;
+    reader.bytes(64 + 128)?; // sname, file
+    if reader.array::<4>()? != MAGIC {
+        return None;
+    }
+
+    let mut message_type = None;
+    let mut requested = None;
+    loop {
+        match reader.u8()? {
+            OPTION_PAD => continue,
+            OPTION_END => break,
+            option => {
+                let len = reader.u8()? as usize;
+                let value = reader.bytes(len)?;
+                match option {
+                    OPTION_MESSAGE_TYPE => message_type = value.first().copied(),
+                    OPTION_REQUESTED_ADDRESS => requested = value.try_into().ok(),
+                    _ => (),
+                }
+            }
+        }
+    }
+
+    let mut client = [0u8; 6];
+    client.copy_from_slice(&chaddr[..6]);
+    let address = leases.address_for(client);
+
+    let response = match message_type? {
+        DISCOVER => OFFER,
+        REQUEST => {
+            let asked = requested.unwrap_or(client_address);
+            if asked == address {
+                ACK
+            } else {
+                NAK
+            }
+        }
+        _ => return None,
+    };
+
+    let mut reply = Vec::with_capacity(300);
+    reply.extend_from_slice(&[BOOTREPLY, 1, 6, 0]);
+    reply.extend_from_slice(&xid);
+    reply.extend_from_slice(&[0, 0]);
+    reply.extend_from_slice(&flags);
+    reply.extend_from_slice(&[0; 4]);
+    reply.extend_from_slice(if response == NAK { &[0; 4] } else { &address });
+    reply.extend_from_slice(&SERVER);
+    reply.extend_from_slice(&relay);
+    reply.extend_from_slice(&chaddr);
+    reply.extend_from_slice(&[0; 64 + 128]);
+    reply.extend_from_slice(&MAGIC);
+
+    reply.extend_from_slice(&[OPTION_MESSAGE_TYPE, 1, response]);
+    reply.extend_from_slice(&[OPTION_SERVER, 4]);
+    reply.extend_from_slice(&SERVER);
+    if response != NAK {
+        reply.extend_from_slice(&[OPTION_LEASE_TIME, 4]);
+        reply.extend_from_slice(&LEASE_SECS.to_be_bytes());
+        reply.extend_from_slice(&[OPTION_NETMASK, 4]);
+        reply.extend_from_slice(&NETMASK);
+    }
+    reply.push(OPTION_END);
+
+    Some(reply)
+}
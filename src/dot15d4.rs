@@ -0,0 +1,610 @@
+//! IEEE 802.15.4 MAC frame parsing, plus the two kinds of network
+//! advertisement we care about: Zigbee beacons and Thread MLE discovery
+//! responses.
+
+use alloc::{string::String, vec::Vec};
+use core::fmt;
+
+use crate::record::Reader;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FrameKind {
+    Beacon,
+    Data,
+    Ack,
+    Command,
+    Other(u8),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Address {
+    None,
+    Short(u16),
+    Extended(u64),
+}
+
+pub struct Frame<'a> {
+    pub kind: FrameKind,
+    pub secured: bool,
+    pub dst_pan: Option<u16>,
+    pub dst: Address,
+    pub src_pan: Option<u16>,
+    pub src: Address,
+    pub payload: &'a [u8],
+}
+
+impl<'a> Frame<'a> {
+    /// Parse a frame as received, FCS included.
+    pub fn parse(data: &'a [u8]) -> Option<Self> {
+        let body = data.get(..data.len().checked_sub(2)?)?;
+        let mut reader = Reader(body);
+
+        let fcf = reader.u16()?;
+        let kind = match fcf & 0b111 {
+            0 => FrameKind::Beacon,
+            1 => FrameKind::Data,
+            2 => FrameKind::Ack,
+            3 => FrameKind::Command,
+            other => FrameKind::Other(other as u8),
+        };
+        let secured = fcf & (1 << 3) != 0;
+        let pan_compression = fcf & (1 << 6) != 0;
+        let sequence_suppressed = fcf & (1 << 8) != 0;
+        let dst_mode = (fcf >> 10) & 0b11;
+        let version = (fcf >> 12) & 0b11;
+        let src_mode = (fcf >> 14) & 0b11;
+
+        if version > 1 {
+            // 2015 frames move the PAN IDs around and carry IEs; not worth it
+            // for a sniffer that only wants beacons and discovery.
+            return None;
+        }
+
+        if !sequence_suppressed {
+            reader.u8()?;
+        }
+
+        let dst_pan = (dst_mode != 0).then(|| reader.u16()).flatten();
+        let dst = read_address(&mut reader, dst_mode)?;
+
+        let src_pan = if src_mode != 0 && !pan_compression {
+            Some(reader.u16()?)
+        } else {
+            dst_pan
+        };
+        let src = read_address(&mut reader, src_mode)?;
+
+        Some(Self {
+            kind,
+            secured,
+            dst_pan,
+            dst,
+            src_pan,
+            src,
+            payload: reader.0,
+        })
+    }
+
+    /// The network a beacon or discovery response advertises, if any.
+    pub fn network(&self) -> Option<Network> {
+        if self.secured {
+            return None;
+        }
+
+        match self.kind {
+            FrameKind::Beacon => zigbee_beacon(self.payload),
+            FrameKind::Data => thread_discovery(self.payload),
+            _ => None,
+        }
+    }
+}
+
+fn read_address(reader: &mut Reader, mode: u16) -> Option<Address> {
+    Some(match mode {
+        2 => Address::Short(reader.u16()?),
+        3 => Address::Extended(u64::from_le_bytes(reader.array()?)),
+        _ => Address::None,
+    })
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Network {
+    Zigbee {
+        extended_pan_id: u64,
+        stack_profile: u8,
+        protocol_version: u8,
+        permit_joining: bool,
+    },
+    Thread {
+        name: String,
+        extended_pan_id: u64,
+    },
+}
+
+/// Zigbee NWK beacon payload, after the MAC beacon's superframe/GTS/pending
+/// address fields.
+fn zigbee_beacon(payload: &[u8]) -> Option<Network> {
+    let mut reader = Reader(payload);
+
+    let superframe = reader.u16()?;
+    let gts = reader.u8()?;
+    let gts_count = (gts & 0b111) as usize;
+    if gts_count > 0 {
+        // direction mask plus three bytes per descriptor
+        reader.bytes(1 + 3 * gts_count)?;
+    }
+    let pending = reader.u8()?;
+    let short = (pending & 0b111) as usize;
+    let extended = ((pending >> 4) & 0b111) as usize;
+    reader.bytes(2 * short + 8 * extended)?;
+
+    // Protocol ID 0 is Zigbee
+    if reader.u8()? != 0 {
+        return None;
+    }
+    let profile = reader.u8()?;
+    reader.u8()?; // router/end device capacity, depth
+    let extended_pan_id = u64::from_le_bytes(reader.array()?);
+
+    Some(Network::Zigbee {
+        extended_pan_id,
+        stack_profile: profile & 0x0f,
+        protocol_version: profile >> 4,
+        permit_joining: superframe & (1 << 15) != 0,
+    })
+}
+
+const MLE_PORT: u16 = 19788;
+const MLE_NO_SECURITY: u8 = 255;
+const MLE_DISCOVERY_RESPONSE: u8 = 17;
+const MLE_TLV_DISCOVERY: u8 = 26;
+const MESHCOP_EXTENDED_PAN_ID: u8 = 2;
+const MESHCOP_NETWORK_NAME: u8 = 3;
+
+/// A Thread MLE Discovery Response: 6LoWPAN IPHC, UDP to port 19788, then an
+/// unsecured MLE message whose Discovery TLV carries MeshCoP TLVs.
+fn thread_discovery(payload: &[u8]) -> Option<Network> {
+    let mle = udp_payload(payload, MLE_PORT)?;
+    let mut reader = Reader(mle);
+
+    if reader.u8()? != MLE_NO_SECURITY || reader.u8()? != MLE_DISCOVERY_RESPONSE {
+        return None;
+    }
+
+    let discovery = tlvs(reader.0).find(|(kind, _)| *kind == MLE_TLV_DISCOVERY)?.1;
+
+    let mut name = None;
+    let mut extended_pan_id = None;
+    for (kind, value) in tlvs(discovery) {
+        match kind {
+            MESHCOP_NETWORK_NAME => name = String::from_utf8(value.to_vec()).ok(),
+            MESHCOP_EXTENDED_PAN_ID => {
+                extended_pan_id = value.try_into().ok().map(u64::from_be_bytes);
+            }
+            _ => (),
+        }
+    }
+
+    Some(Network::Thread {
+        name: name?,
+        extended_pan_id: extended_pan_id?,
+    })
+}
+
+/// Single-byte type/length TLVs.
+fn tlvs(bytes: &[u8]) -> impl Iterator<Item = (u8, &[u8])> {
+    let mut reader = Reader(bytes);
+    core::iter::from_fn(move || {
+        let kind = reader.u8()?;
+        let len = reader.u8()? as usize;
+        Some((kind, reader.bytes(len)?))
+    })
+}
+
+/// Walk a 6LoWPAN IPHC header (RFC 6282) far enough to find a UDP payload
+/// sent to `port`.
+fn udp_payload(payload: &[u8], port: u16) -> Option<&[u8]> {
+    let mut reader = Reader(payload);
+    let [first, second] = reader.array::<2>()?;
+
+    if first >> 5 != 0b011 {
+        return None;
+    }
+
+    let tf = (first >> 3) & 0b11;
+    let inline_next_header = first & 0b100 == 0;
+    let hop_limit = first & 0b11;
+    let cid = second & 0x80 != 0;
+    let sac = second & 0x40 != 0;
+    let sam = (second >> 4) & 0b11;
+    let multicast = second & 0x08 != 0;
+    let dac = second & 0x04 != 0;
+    let dam = second & 0b11;
+
+    if cid {
+        reader.u8()?;
+    }
+
+    reader.bytes(match tf {
+        0 => 4,
+        1 => 3,
+        2 => 1,
+        _ => 0,
+    })?;
+
+    let next_header = if inline_next_header {
+        Some(reader.u8()?)
+    } else {
+        None
+    };
+
+    if hop_limit == 0 {
+        reader.u8()?;
+    }
+
+    reader.bytes(match (sac, sam) {
+        (false, 0) => 16,
+        (_, 1) => 8,
+        (_, 2) => 2,
+        _ => 0,
+    })?;
+
+    reader.bytes(match (multicast, dac, dam) {
+        (false, false, 0) => 16,
+        (false, _, 1) => 8,
+        (false, _, 2) => 2,
+        (true, false, 0) => 16,
+        (true, false, 1) => 6,
+        (true, false, 2) => 4,
+        (true, false, 3) => 1,
+        (true, true, 0) => 6,
+        _ => 0,
+    })?;
+
+    let destination = match next_header {
+        // UDP header carried inline
+        Some(17) => {
+            reader.u16()?;
+            let destination = u16::from_be_bytes(reader.array()?);
+            reader.bytes(4)?;
+            destination
+        }
+        Some(_) => return None,
+        // NHC compressed UDP: 11110CPP
+        None => {
+            let nhc = reader.u8()?;
+            if nhc & 0xf8 != 0xf0 {
+                return None;
+            }
+
+            let destination = match nhc & 0b11 {
+                0 => {
+                    reader.u16()?;
+                    u16::from_be_bytes(reader.array()?)
+                }
+                1 => {
+                    reader.u16()?;
+                    0xf000 | reader.u8()? as u16
+                }
+                2 => {
+                    reader.u8()?;
+                    u16::from_be_bytes(reader.array()?)
+                }
+                _ => 0xf0b0 | (reader.u8()? & 0x0f) as u16,
+            };
+
+            if nhc & 0b100 == 0 {
+                // checksum inline
+                reader.u16()?;
+            }
+            destination
+        }
+    };
+
+    (destination == port).then_some(reader.0)
+}
+
+/// An 802.15.4 network (or just a node in one) we heard on some channel.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PanSighting {
+    pub channel: u8,
+    pub pan_id: u16,
+    pub address: Address,
+    pub network: Option<Network>,
+}
+
+impl PanSighting {
+    pub fn from_frame(channel: u8, frame: &Frame) -> Option<Self> {
+        Some(Self {
+            channel,
+            pan_id: frame.src_pan?,
+            address: frame.src,
+            network: frame.network(),
+        })
+    }
+
+    pub(crate) fn encode(&self, bytes: &mut Vec<u8>) {
+        bytes.push(self.channel);
+        bytes.extend_from_slice(&self.pan_id.to_le_bytes());
+
+        match self.address {
+            Address::None => bytes.push(0),
+            Address::Short(short) => {
+                bytes.push(2);
+                bytes.extend_from_slice(&short.to_le_bytes());
+            }
+            Address::Extended(extended) => {
+                bytes.push(3);
+                bytes.extend_from_slice(&extended.to_le_bytes());
+            }
+        }
+
+        match &self.network {
+            None => bytes.push(0),
+            Some(Network::Zigbee {
+                extended_pan_id,
+                stack_profile,
+                protocol_version,
+                permit_joining,
+            }) => {
+                bytes.push(1);
+                bytes.extend_from_slice(&extended_pan_id.to_le_bytes());
+                bytes.push(*stack_profile);
+                bytes.push(*protocol_version);
+                bytes.push(*permit_joining as u8);
+            }
+            Some(Network::Thread {
+                name,
+                extended_pan_id,
+            }) => {
+                bytes.push(2);
+                bytes.extend_from_slice(&extended_pan_id.to_le_bytes());
+                bytes.push(name.len() as u8);
+                bytes.extend_from_slice(name.as_bytes());
+            }
+        }
+    }
+
+    pub(crate) fn decode(bytes: &[u8]) -> Option<Self> {
+        let mut reader = Reader(bytes);
+        let channel = reader.u8()?;
+        let pan_id = reader.u16()?;
+        let address = match reader.u8()? {
+            0 => Address::None,
+            mode => read_address(&mut reader, mode as u16)?,
+        };
+
+        let network = match reader.u8()? {
+            0 => None,
+            1 => Some(Network::Zigbee {
+                extended_pan_id: u64::from_le_bytes(reader.array()?),
+                stack_profile: reader.u8()?,
+                protocol_version: reader.u8()?,
+                permit_joining: reader.u8()? != 0,
+            }),
+            2 => {
+                let extended_pan_id = u64::from_le_bytes(reader.array()?);
+                let len = reader.u8()? as usize;
+                Some(Network::Thread {
+                    name: String::from_utf8(reader.bytes(len)?.to_vec()).ok()?,
+                    extended_pan_id,
+                })
+            }
+            _ => return None,
+        };
+
+        Some(Self {
+            channel,
+            pan_id,
+            address,
+            network,
+        })
+    }
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::None => f.write_str("-"),
+            Self::Short(short) => write!(f, "{short:04x}"),
+            Self::Extended(extended) => write!(f, "{extended:016x}"),
+        }
+    }
+}
+
+impl fmt::Display for PanSighting {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "ch {} pan {:04x} node {}",
+            self.channel, self.pan_id, self.address
+        )?;
+
+        match &self.network {
+            None => Ok(()),
+            Some(Network::Zigbee {
+                extended_pan_id,
+                stack_profile,
+                protocol_version,
+                permit_joining,
+            }) => write!(
+                f,
+                " zigbee {extended_pan_id:016x} profile {stack_profile} v{protocol_version}{}",
+                if *permit_joining { " joinable" } else { "" }
+            ),
+            Some(Network::Thread {
+                name,
+                extended_pan_id,
+            }) => write!(f, " thread \"{name}\" {extended_pan_id:016x}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::{string::ToString, vec};
+
+    use super::*;
+
+    /// A Zigbee coordinator's beacon as the radio hands it over: short
+    /// source 0x0000 on PAN 0x1234, permitting joins, then the FCS.
+    const ZIGBEE_BEACON: [u8; 28] = [
+        0x00, 0x80, 0x8c, 0x34, 0x12, 0x00, 0x00, // MAC header
+        0xff, 0xcf, 0x00, 0x00, // superframe, no GTS, nothing pending
+        0x00, 0x22, 0x84, // protocol ID, stack profile 2 v2, capacity
+        0xef, 0xbe, 0xad, 0xde, 0x00, 0x4b, 0x12, 0x00, // extended PAN ID
+        0xff, 0xff, 0xff, 0x00, // tx offset, update ID
+        0x5a, 0x31, // FCS
+    ];
+
+    /// An OpenThread MLE Discovery Response, between extended addresses on
+    /// PAN 0xface: IPHC with link-local addresses elided, compressed UDP
+    /// 19788 to 19788, and MeshCoP TLVs in the Discovery TLV.
+    const THREAD_DISCOVERY: [u8; 66] = [
+        0x41, 0xdc, 0xa7, 0xce, 0xfa, // MAC header
+        0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x88, // destination
+        0x2a, 0x3b, 0x4c, 0x5d, 0x6e, 0x7f, 0x80, 0x91, // source
+        0x7f, 0x33, // IPHC
+        0xf0, 0x4d, 0x4c, 0x4d, 0x4c, 0x5e, 0x1a, // UDP ports, checksum
+        0xff, 0x11, // no security, Discovery Response
+        0x1a, 0x1e, // Discovery TLV
+        0x81, 0x02, 0x20, 0x00, // Discovery Response TLV
+        0x02, 0x08, 0xde, 0xad, 0x00, 0xbe, 0xef, 0x00, 0xca, 0xfe, // extended PAN ID
+        0x03, 0x0a, b'O', b'p', b'e', b'n', b'T', b'h', b'r', b'e', b'a', b'd', // name
+        0x12, 0x02, 0x03, 0xe8, // Joiner UDP port
+        0x9c, 0x3f, // FCS
+    ];
+
+    /// Where the MLE message starts in `THREAD_DISCOVERY`.
+    const MLE: usize = 30;
+
+    fn network(data: &[u8]) -> Option<Network> {
+        Frame::parse(data)?.network()
+    }
+
+    #[test]
+    fn reads_a_zigbee_beacon() {
+        let frame = Frame::parse(&ZIGBEE_BEACON).unwrap();
+        assert_eq!(frame.kind, FrameKind::Beacon);
+        assert!(!frame.secured);
+        assert_eq!(frame.dst_pan, None);
+        assert_eq!(frame.dst, Address::None);
+        assert_eq!(frame.src_pan, Some(0x1234));
+        assert_eq!(frame.src, Address::Short(0));
+
+        let zigbee = Network::Zigbee {
+            extended_pan_id: 0x0012_4b00_dead_beef,
+            stack_profile: 2,
+            protocol_version: 2,
+            permit_joining: true,
+        };
+        assert_eq!(frame.network(), Some(zigbee));
+
+        let sighting = PanSighting::from_frame(15, &frame).unwrap();
+        assert_eq!(
+            sighting.to_string(),
+            "ch 15 pan 1234 node 0000 zigbee 00124b00deadbeef profile 2 v2 joinable"
+        );
+        let mut bytes = Vec::new();
+        sighting.encode(&mut bytes);
+        assert_eq!(PanSighting::decode(&bytes), Some(sighting));
+    }
+
+    #[test]
+    fn reads_a_thread_discovery_response() {
+        let frame = Frame::parse(&THREAD_DISCOVERY).unwrap();
+        assert_eq!(frame.kind, FrameKind::Data);
+        assert_eq!(frame.dst_pan, Some(0xface));
+        assert_eq!(frame.dst, Address::Extended(0x8877_6655_4433_2211));
+        // PAN ID compression: the source shares the destination's
+        assert_eq!(frame.src_pan, Some(0xface));
+        assert_eq!(frame.src, Address::Extended(0x9180_7f6e_5d4c_3b2a));
+
+        let sighting = PanSighting::from_frame(25, &frame).unwrap();
+        assert_eq!(
+            sighting.to_string(),
+            "ch 25 pan face node 91807f6e5d4c3b2a thread \"OpenThread\" dead00beef00cafe"
+        );
+        let mut bytes = Vec::new();
+        sighting.encode(&mut bytes);
+        assert_eq!(PanSighting::decode(&bytes), Some(sighting));
+    }
+
+    #[test]
+    fn gives_up_on_truncated_frames() {
+        assert!(Frame::parse(&[]).is_none());
+        assert!(Frame::parse(&[0x00]).is_none());
+        // Just a frame control field and FCS: no room for the sequence number
+        assert!(Frame::parse(&[0x00, 0x80, 0x5a, 0x31]).is_none());
+
+        // The last two bytes are always taken as the FCS, so a cut frame
+        // loses its tail. The beacon's tx offset and update ID aren't needed;
+        // any less and there's no network.
+        for len in 0..ZIGBEE_BEACON.len() {
+            let expected = len >= ZIGBEE_BEACON.len() - 4;
+            assert_eq!(network(&ZIGBEE_BEACON[..len]).is_some(), expected, "{len}");
+        }
+        for len in 0..THREAD_DISCOVERY.len() {
+            assert_eq!(network(&THREAD_DISCOVERY[..len]), None, "{len}");
+        }
+    }
+
+    #[test]
+    fn gives_up_on_malformed_beacons() {
+        let with = |index: usize, value: u8| {
+            let mut beacon = ZIGBEE_BEACON;
+            beacon[index] = value;
+            network(&beacon)
+        };
+
+        // 2015 frames aren't parsed, and secured ones aren't looked into
+        assert!(Frame::parse(&{
+            let mut beacon = ZIGBEE_BEACON;
+            beacon[1] |= 0x20;
+            beacon
+        })
+        .is_none());
+        assert_eq!(with(0, 0x08), None);
+        // Not Zigbee
+        assert_eq!(with(11, 0x01), None);
+        // GTS descriptors or pending addresses that run off the end
+        assert_eq!(with(9, 0x07), None);
+        assert_eq!(with(10, 0x77), None);
+        // Fewer of them than that, and the payload is read from the wrong
+        // place: whatever it is, it isn't Zigbee
+        assert_eq!(with(10, 0x01), None);
+    }
+
+    #[test]
+    fn gives_up_on_malformed_discovery_responses() {
+        let with = |index: usize, value: u8| {
+            let mut discovery = THREAD_DISCOVERY;
+            discovery[index] = value;
+            network(&discovery)
+        };
+
+        // Not IPHC, or a next header that isn't UDP
+        assert_eq!(with(21, 0x41), None);
+        assert_eq!(with(23, 0xe0), None);
+        // Sent to another port
+        assert_eq!(with(27, 0x4d), None);
+        // Secured MLE, or some other command
+        assert_eq!(with(MLE, 0x00), None);
+        assert_eq!(with(MLE + 1, 16), None);
+        // A Discovery TLV longer than what's left
+        assert_eq!(with(MLE + 3, 0x1f), None);
+        // An extended PAN ID of the wrong size swallows the name after it
+        assert_eq!(with(MLE + 9, 0x07), None);
+        // A name that isn't UTF-8
+        assert_eq!(with(MLE + 20, 0xff), None);
+
+        // And with no name at all
+        let mut unnamed = vec![];
+        unnamed.extend_from_slice(&THREAD_DISCOVERY[..MLE + 2]);
+        unnamed.extend_from_slice(&[0x1a, 0x0e]);
+        unnamed.extend_from_slice(&THREAD_DISCOVERY[MLE + 4..MLE + 18]);
+        unnamed.extend_from_slice(&[0x9c, 0x3f]);
+        assert_eq!(network(&unnamed), None);
+        assert!(Frame::parse(&unnamed).is_some());
+    }
+}
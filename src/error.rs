@@ -0,0 +1,165 @@
+//! What goes wrong with the hardware, and what each part does about it.
+//!
+//! One flaky peripheral shouldn't take the whole device down, so instead of
+//! panicking each subsystem has its own way out:
+//!
+//! - Storage reopens the log and tries a failed write once more. If that
+//!   fails too it stops storing; records are dropped and everything else
+//!   keeps going.
+//! - The radio gets a few tries to start. After that it's left off for this
+//!   boot, and the console, lights and battery carry on without it.
+//! - The fuel gauge is asked again next round. One that keeps failing is
+//!   reported missing until it answers again.
+//! - Lights that can't be set up stay dark; nothing waits on them.
+//!
+//! Giving up on a subsystem blinks its color, so it shows without a console.
+
+use core::{
+    fmt,
+    sync::atomic::{AtomicU8, Ordering},
+};
+
+use alloc::vec::Vec;
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, pubsub::PubSubChannel};
+use embassy_time::Timer;
+use esp_println::println;
+use esp_storage::FlashStorageError;
+
+use crate::{journal, lights};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Error {
+    /// A flash read, write or erase failed
+    Flash,
+    /// No room left in the store
+    Full,
+    /// A record longer than the store takes
+    TooLong,
+    /// The radio wouldn't initialize, start or configure
+    Radio,
+    /// The fuel gauge didn't answer
+    Battery,
+    /// The LED controller couldn't be set up
+    Lights,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Flash => "flash failed",
+            Self::Full => "store full",
+            Self::TooLong => "record too long",
+            Self::Radio => "radio failed",
+            Self::Battery => "fuel gauge not answering",
+            Self::Lights => "LED controller failed",
+        })
+    }
+}
+
+impl From<FlashStorageError> for Error {
+    fn from(_: FlashStorageError) -> Self {
+        Self::Flash
+    }
+}
+
+impl<E> From<journal::Error<E>> for Error {
+    fn from(err: journal::Error<E>) -> Self {
+        match err {
+            journal::Error::Full => Self::Full,
+            journal::Error::TooLong => Self::TooLong,
+            journal::Error::Flash(_) => Self::Flash,
+        }
+    }
+}
+
+#[cfg(target_os = "none")]
+impl From<esp_wifi::InitializationError> for Error {
+    fn from(_: esp_wifi::InitializationError) -> Self {
+        Self::Radio
+    }
+}
+
+#[cfg(target_os = "none")]
+impl From<esp_wifi::wifi::WifiError> for Error {
+    fn from(_: esp_wifi::wifi::WifiError) -> Self {
+        Self::Radio
+    }
+}
+
+#[cfg(target_os = "none")]
+impl From<bleps::Error> for Error {
+    fn from(_: bleps::Error) -> Self {
+        Self::Radio
+    }
+}
+
+#[cfg(target_os = "none")]
+impl From<esp_hal::i2c::Error> for Error {
+    fn from(_: esp_hal::i2c::Error) -> Self {
+        Self::Battery
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[repr(u8)]
+pub enum Subsystem {
+    Storage = 1,
+    Radio = 2,
+    Battery = 4,
+    Lights = 8,
+}
+
+const SUBSYSTEMS: [Subsystem; 4] = [
+    Subsystem::Storage,
+    Subsystem::Radio,
+    Subsystem::Battery,
+    Subsystem::Lights,
+];
+
+/// One bit per subsystem we've given up on
+static DISABLED: AtomicU8 = AtomicU8::new(0);
+
+/// Give up on `subsystem` until it says it's back, and show it.
+pub async fn disable(subsystem: Subsystem, err: Error) {
+    println!("{:?} disabled: {err}", subsystem);
+
+    let was = DISABLED.fetch_or(subsystem as u8, Ordering::Relaxed);
+    if was & subsystem as u8 == 0 && subsystem != Subsystem::Lights {
+        lights::failed(subsystem).await;
+    }
+}
+
+/// `subsystem` is working again.
+pub fn enable(subsystem: Subsystem) {
+    let was = DISABLED.fetch_and(!(subsystem as u8), Ordering::Relaxed);
+    if was & subsystem as u8 != 0 {
+        println!("{:?} working again", subsystem);
+    }
+}
+
+pub fn disabled(subsystem: Subsystem) -> bool {
+    DISABLED.load(Ordering::Relaxed) & subsystem as u8 != 0
+}
+
+/// Everything we've given up on.
+pub fn all_disabled() -> Vec<Subsystem> {
+    SUBSYSTEMS
+        .into_iter()
+        .filter(|subsystem| disabled(*subsystem))
+        .collect()
+}
+
+/// Publish `message`, waiting for a publisher if the channel's are all in
+/// use rather than failing.
+pub async fn publish<T: Clone, const CAP: usize, const SUBS: usize, const PUBS: usize>(
+    channel: &PubSubChannel<CriticalSectionRawMutex, T, CAP, SUBS, PUBS>,
+    message: T,
+) {
+    loop {
+        if let Ok(publisher) = channel.publisher() {
+            publisher.publish(message).await;
+            return;
+        }
+        Timer::after_millis(10).await;
+    }
+}
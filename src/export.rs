@@ -0,0 +1,102 @@
+//! Records as CSV rows and JSON objects, for anything that ships the store
+//! off the device.
+
+use alloc::{format, string::String, vec::Vec};
+use core::fmt::Write;
+
+use crate::{
+    battery::Level,
+    record::{self, Record},
+};
+
+pub const CSV_HEADER: &str = "index,time,kind,record\r\n";
+
+/// One CSV row. `bytes` that don't decode are exported as hex so nothing is
+/// silently dropped.
+pub fn csv_row(index: usize, bytes: &[u8]) -> String {
+    let (time, kind, text) = describe(bytes);
+    let time = time.unwrap_or_default();
+
+    let mut row = format!("{index},{time},{kind},\"");
+    for c in text.chars() {
+        if c == '"' {
+            row.push('"');
+        }
+        row.push(c);
+    }
+    row.push_str("\"\r\n");
+    row
+}
+
+/// One JSON object: `{"index":0,"time":"..","kind":"network","record":"+ ssid"}`.
+pub fn json_record(index: usize, bytes: &[u8]) -> String {
+    let (time, kind, text) = describe(bytes);
+    let time = match time {
+        Some(time) => format!("{}", JsonString(&time)),
+        None => "null".into(),
+    };
+
+    format!(
+        "{{\"index\":{index},\"time\":{time},\"kind\":\"{kind}\",\"record\":{}}}",
+        JsonString(&text)
+    )
+}
+
+/// A batch for the collection server: `{"device":..,"records":[..]}`, with
+/// indexes counting from `first`.
+pub fn json_batch(device: &str, first: usize, records: &[Vec<u8>]) -> String {
+    let mut batch = format!("{{\"device\":{},\"records\":[", JsonString(device));
+    for (offset, bytes) in records.iter().enumerate() {
+        if offset > 0 {
+            batch.push(',');
+        }
+        batch.push_str(&json_record(first + offset, bytes));
+    }
+    batch.push_str("]}");
+    batch
+}
+
+pub fn battery_json(level: &Level) -> String {
+    format!(
+        "{{\"percent\":{},\"millivolts\":{},\"usb\":{}}}",
+        level.percent, level.millivolts, level.usb
+    )
+}
+
+/// When (if known), what kind, and the record as text.
+fn describe(bytes: &[u8]) -> (Option<String>, &'static str, String) {
+    let (stamp, record_bytes) = record::unstamp(bytes);
+    let time = stamp.map(|stamp| format!("{stamp}"));
+
+    match Record::decode(record_bytes) {
+        Some(record) => (time, record.kind(), format!("{record}")),
+        None => {
+            let mut hex = String::new();
+            for byte in record_bytes {
+                let _ = write!(hex, "{byte:02x}");
+            }
+            (time, "unknown", hex)
+        }
+    }
+}
+
+/// A quoted, escaped JSON string.
+pub struct JsonString<'a>(pub &'a str);
+
+impl core::fmt::Display for JsonString<'_> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_char('"')?;
+        for c in self.0.chars() {
+            match c {
+                '"' => f.write_str("\\\"")?,
+                '\\' => f.write_str("\\\\")?,
+                '\n' => f.write_str("\\n")?,
+                '\r' => f.write_str("\\r")?,
+                '\t' => f.write_str("\\t")?,
+                c if (c as u32) < 0x20 => write!(f, "\\u{:04x}", c as u32)?,
+                c => f.write_char(c)?,
+            }
+        }
+        f.write_char('"')
+    }
+}
@@ -0,0 +1,464 @@
+//! Probe request fingerprinting.
+//!
+//! Phones randomize their source MAC, so counting MACs counts the same phone
+//! over and over. What they don't change nearly as often is *how* they probe:
+//! which information elements they send, in what order, and what rates and
+//! HT/VHT capabilities they advertise. That gives a fingerprint; clients that
+//! share one are told apart (or stitched together across MAC changes) by
+//! whether their 802.11 sequence numbers carry on where the other left off.
+
+use core::cell::RefCell;
+
+use alloc::vec::Vec;
+use critical_section::Mutex;
+
+use crate::frame::{
+    fnv1a, Elements, MacAddress, Management, ManagementKind, IE_DS_PARAMETER, IE_SSID, IE_VENDOR,
+};
+
+/// Elements whose contents say something about the hardware/driver, rather
+/// than about what it's looking for right now
+const HASHED_BODIES: [u8; 6] = [
+    1,   // Supported rates
+    45,  // HT capabilities
+    50,  // Extended supported rates
+    127, // Extended capabilities
+    191, // VHT capabilities
+    255, // Extension (HE capabilities and friends)
+];
+
+/// A new MAC continues a device if its first sequence number is at most this
+/// far past where the device's previous MAC stopped...
+const SEQUENCE_WINDOW: u16 = 64;
+
+/// ...and it shows up within this long
+const CONTINUITY_MS: u64 = 60_000;
+
+/// Devices to keep in memory, oldest are forgotten first
+const MAX_DEVICES: usize = 64;
+
+/// MACs remembered per device
+const MAX_MACS: usize = 4;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Probe {
+    pub source: MacAddress,
+    pub sequence: u16,
+    pub fingerprint: u32,
+}
+
+impl Probe {
+    pub fn parse(data: &[u8]) -> Option<Self> {
+        let frame = Management::parse(data)?;
+        if frame.kind != ManagementKind::ProbeRequest {
+            return None;
+        }
+
+        Some(Self {
+            source: frame.transmitter,
+            sequence: frame.sequence,
+            fingerprint: fingerprint(Elements(frame.body)),
+        })
+    }
+
+    /// Locally administered, i.e. (almost certainly) randomized.
+    pub fn randomized(&self) -> bool {
+        self.source[0] & 0x02 != 0
+    }
+}
+
+/// Hash the order of elements plus the contents of the capability ones.
+pub fn fingerprint(elements: Elements) -> u32 {
+    let mut bytes: Vec<u8> = Vec::new();
+
+    for (id, body) in elements {
+        bytes.push(id);
+
+        match id {
+            // What's being probed for and on which channel vary per frame
+            IE_SSID | IE_DS_PARAMETER => (),
+            // Vendor elements: OUI and type identify them, the rest can be
+            // per-frame state (WPS UUIDs, P2P device names)
+            IE_VENDOR => bytes.extend_from_slice(body.get(..4).unwrap_or(body)),
+            id if HASHED_BODIES.contains(&id) => {
+                bytes.push(body.len() as u8);
+                bytes.extend_from_slice(body);
+            }
+            _ => (),
+        }
+    }
+
+    fnv1a(&bytes)
+}
+
+#[derive(Clone, Debug)]
+pub struct Device {
+    /// Pseudo-device ID: fingerprint and first MAC, so it doesn't depend on
+    /// the order devices happen to be seen in
+    pub id: u32,
+    pub fingerprint: u32,
+    pub macs: Vec<MacAddress>,
+    sequence: u16,
+    last_seen: u64,
+}
+
+/// A MAC we hadn't seen before, and which device it belongs to.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Sighting {
+    pub device: u32,
+    pub fingerprint: u32,
+    pub mac: MacAddress,
+    /// The MAC was added to a device we already knew
+    pub linked: bool,
+}
+
+pub struct Clusters {
+    devices: Vec<Device>,
+}
+
+impl Default for Clusters {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clusters {
+    pub const fn new() -> Self {
+        Self {
+            devices: Vec::new(),
+        }
+    }
+
+    pub fn devices(&self) -> &[Device] {
+        &self.devices
+    }
+
+    /// The pseudo-device ID for `mac`, if we know it.
+    pub fn device_for(&self, mac: &MacAddress) -> Option<u32> {
+        self.devices
+            .iter()
+            .find(|device| device.macs.contains(mac))
+            .map(|device| device.id)
+    }
+
+    /// Assign a probe to a device. Returns a sighting only when the probe
+    /// came from a MAC we hadn't seen yet.
+    pub fn observe(&mut self, probe: &Probe, now: u64) -> Option<Sighting> {
+        if let Some(device) = self
+            .devices
+            .iter_mut()
+            .find(|device| device.macs.contains(&probe.source))
+        {
+            device.sequence = probe.sequence;
+            device.last_seen = now;
+            return None;
+        }
+
+        // Real MACs are their own device; only randomized ones get stitched
+        let continued = probe
+            .randomized()
+            .then(|| {
+                self.devices.iter_mut().find(|device| {
+                    device.fingerprint == probe.fingerprint
+                        && now.saturating_sub(device.last_seen) < CONTINUITY_MS
+                        && continues(device.sequence, probe.sequence)
+                })
+            })
+            .flatten();
+
+        if let Some(device) = continued {
+            if device.macs.len() >= MAX_MACS {
+                device.macs.remove(0);
+            }
+            device.macs.push(probe.source);
+            device.sequence = probe.sequence;
+            device.last_seen = now;
+
+            return Some(Sighting {
+                device: device.id,
+                fingerprint: device.fingerprint,
+                mac: probe.source,
+                linked: true,
+            });
+        }
+
+        if self.devices.len() >= MAX_DEVICES {
+            let oldest = self
+                .devices
+                .iter()
+                .enumerate()
+                .min_by_key(|(_, device)| device.last_seen)
+                .map(|(index, _)| index);
+
+            if let Some(index) = oldest {
+                self.devices.swap_remove(index);
+            }
+        }
+
+        let mut seed = probe.fingerprint.to_le_bytes().to_vec();
+        seed.extend_from_slice(&probe.source);
+        let id = fnv1a(&seed);
+
+        self.devices.push(Device {
+            id,
+            fingerprint: probe.fingerprint,
+            macs: alloc::vec![probe.source],
+            sequence: probe.sequence,
+            last_seen: now,
+        });
+
+        Some(Sighting {
+            device: id,
+            fingerprint: probe.fingerprint,
+            mac: probe.source,
+            linked: false,
+        })
+    }
+}
+
+static CLUSTERS: Mutex<RefCell<Clusters>> = Mutex::new(RefCell::new(Clusters::new()));
+
+/// Feed a frame from the sniffer; anything but a probe request is ignored.
+pub fn observe(data: &[u8], now: u64) -> Option<Sighting> {
+    let probe = Probe::parse(data)?;
+    critical_section::with(|cs| CLUSTERS.borrow_ref_mut(cs).observe(&probe, now))
+}
+
+/// The pseudo-device ID for `mac`, if we've clustered it.
+pub fn device_for(mac: &MacAddress) -> Option<u32> {
+    critical_section::with(|cs| CLUSTERS.borrow_ref(cs).device_for(mac))
+}
+
+/// Whether `next` plausibly follows `previous` in the same 12-bit counter.
+fn continues(previous: u16, next: u16) -> bool {
+    let gap = next.wrapping_sub(previous) & 0x0fff;
+    (1..=SEQUENCE_WINDOW).contains(&gap)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn element(id: u8, body: &[u8]) -> Vec<u8> {
+        let mut element = alloc::vec![id, body.len() as u8];
+        element.extend_from_slice(body);
+        element
+    }
+
+    const IPHONE_HT: [u8; 26] = [
+        0x2d, 0x40, 0x17, 0xff, 0xff, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+    ];
+    const IPHONE_EXTENDED: [u8; 10] = [0x04, 0x00, 0x0a, 0x02, 0x01, 0x40, 0x40, 0x40, 0x00, 0x21];
+    const IPHONE_VHT: [u8; 12] = [
+        0xb2, 0x79, 0x91, 0x33, 0xfa, 0xff, 0x0c, 0x03, 0xfa, 0xff, 0x0c, 0x03,
+    ];
+
+    const PIXEL_HT: [u8; 26] = [
+        0xef, 0x01, 0x1b, 0xff, 0xff, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+    ];
+    const PIXEL_EXTENDED: [u8; 8] = [0x00, 0x00, 0x08, 0x80, 0x00, 0x00, 0x00, 0x40];
+    const PIXEL_HE: [u8; 12] = [
+        0x23, 0x01, 0x08, 0x08, 0x18, 0x00, 0x80, 0x20, 0x30, 0x02, 0x00, 0x0d,
+    ];
+
+    /// An iPhone's broadcast probe: it names `ssid` and `channel`, the rest
+    /// stays the same from frame to frame.
+    fn iphone(ssid: &str, channel: u8) -> Vec<u8> {
+        [
+            element(IE_SSID, ssid.as_bytes()),
+            element(1, &[0x02, 0x04, 0x0b, 0x16]),
+            element(50, &[0x0c, 0x12, 0x18, 0x24, 0x30, 0x48, 0x60, 0x6c]),
+            element(IE_DS_PARAMETER, &[channel]),
+            element(45, &IPHONE_HT),
+            element(127, &IPHONE_EXTENDED),
+            element(191, &IPHONE_VHT),
+            element(IE_VENDOR, &[0x00, 0x17, 0xf2, 0x0a, 0x00, 0x01, 0x04, 0x00]),
+        ]
+        .concat()
+    }
+
+    /// An Android phone's probe, with a WPS element whose UUID it changes
+    /// along with its MAC.
+    fn pixel(ssid: &str, channel: u8, uuid: u8) -> Vec<u8> {
+        [
+            element(IE_SSID, ssid.as_bytes()),
+            element(1, &[0x82, 0x84, 0x8b, 0x96, 0x0c, 0x12, 0x18, 0x24]),
+            element(50, &[0x30, 0x48, 0x60, 0x6c]),
+            element(IE_DS_PARAMETER, &[channel]),
+            element(45, &PIXEL_HT),
+            element(127, &PIXEL_EXTENDED),
+            element(255, &PIXEL_HE),
+            element(
+                IE_VENDOR,
+                &[0x00, 0x50, 0xf2, 0x04, 0x10, 0x47, 0x00, 0x10, uuid],
+            ),
+        ]
+        .concat()
+    }
+
+    fn probe_request(source: MacAddress, sequence: u16, elements: &[u8]) -> Vec<u8> {
+        let mut frame = alloc::vec![0x40, 0x00, 0x00, 0x00];
+        frame.extend_from_slice(&[0xff; 6]);
+        frame.extend_from_slice(&source);
+        frame.extend_from_slice(&[0xff; 6]);
+        frame.extend_from_slice(&(sequence << 4).to_le_bytes());
+        frame.extend_from_slice(elements);
+        frame
+    }
+
+    fn observe(clusters: &mut Clusters, frame: &[u8], now: u64) -> Option<Sighting> {
+        clusters.observe(&Probe::parse(frame).unwrap(), now)
+    }
+
+    const IPHONE_MACS: [MacAddress; 3] = [
+        [0xda, 0xa1, 0x19, 0x0a, 0x0b, 0x0c],
+        [0x3e, 0x22, 0xfb, 0x11, 0x12, 0x13],
+        [0x86, 0x5f, 0x30, 0x7c, 0x01, 0xe2],
+    ];
+
+    const PIXEL_MACS: [MacAddress; 3] = [
+        [0x7a, 0x2b, 0x00, 0x91, 0x44, 0xfe],
+        [0xf6, 0x13, 0x8a, 0x02, 0x5d, 0x70],
+        [0x0e, 0xc4, 0x61, 0xb9, 0x33, 0x28],
+    ];
+
+    #[test]
+    fn ignores_what_changes_between_probes() {
+        let fingerprint = |frame: Vec<u8>| Probe::parse(&frame).unwrap().fingerprint;
+
+        let iphone_probes = [
+            probe_request(IPHONE_MACS[0], 100, &iphone("", 1)),
+            probe_request(IPHONE_MACS[1], 900, &iphone("CoffeeShop", 6)),
+            probe_request(IPHONE_MACS[2], 40, &iphone("Home-5G", 11)),
+        ];
+        let pixel_probes = [
+            probe_request(PIXEL_MACS[0], 3000, &pixel("", 1, 0x11)),
+            probe_request(PIXEL_MACS[1], 12, &pixel("Library Guest", 6, 0x22)),
+            probe_request(PIXEL_MACS[2], 512, &pixel("CoffeeShop", 11, 0x33)),
+        ];
+
+        let iphone = fingerprint(iphone_probes[0].clone());
+        let pixel = fingerprint(pixel_probes[0].clone());
+        assert_ne!(iphone, pixel);
+        assert!(iphone_probes
+            .into_iter()
+            .all(|probe| fingerprint(probe) == iphone));
+        assert!(pixel_probes
+            .into_iter()
+            .all(|probe| fingerprint(probe) == pixel));
+    }
+
+    #[test]
+    fn follows_a_phone_across_randomized_macs() {
+        let mut clusters = Clusters::new();
+        let mut sequence = 4080;
+        let mut now = 0;
+        let mut sightings = Vec::new();
+
+        // A burst of probes from each MAC, the next MAC picking up the
+        // sequence counter where the last left off, wrapping through 4095
+        for (mac, ssid) in IPHONE_MACS.into_iter().zip(["", "CoffeeShop", "Home-5G"]) {
+            for channel in [1, 6, 11] {
+                sightings.extend(observe(
+                    &mut clusters,
+                    &probe_request(mac, sequence, &iphone(ssid, channel)),
+                    now,
+                ));
+                sequence = (sequence + 3) & 0x0fff;
+                now += 100;
+            }
+            sequence = (sequence + 20) & 0x0fff;
+            now += 15_000;
+        }
+
+        assert_eq!(sightings.len(), 3);
+        assert!(!sightings[0].linked);
+        assert!(sightings[1..].iter().all(|sighting| sighting.linked));
+        assert!(sightings
+            .iter()
+            .all(|sighting| sighting.device == sightings[0].device));
+        assert_eq!(clusters.devices().len(), 1);
+        assert_eq!(clusters.devices()[0].macs, IPHONE_MACS);
+        assert_eq!(
+            clusters.device_for(&IPHONE_MACS[2]),
+            Some(sightings[0].device)
+        );
+    }
+
+    #[test]
+    fn keeps_two_phones_apart() {
+        let mut clusters = Clusters::new();
+
+        // Both rotate their MACs at the same moments, interleaved on air
+        for round in 0..3 {
+            let (iphone_mac, pixel_mac) = (IPHONE_MACS[round], PIXEL_MACS[round]);
+            let now = round as u64 * 20_000;
+            let round = round as u16;
+            observe(
+                &mut clusters,
+                &probe_request(iphone_mac, 100 + 10 * round, &iphone("", 6)),
+                now,
+            );
+            observe(
+                &mut clusters,
+                &probe_request(pixel_mac, 3000 + 10 * round, &pixel("", 6, round as u8)),
+                now + 5,
+            );
+        }
+
+        let iphone = clusters.device_for(&IPHONE_MACS[0]).unwrap();
+        let pixel = clusters.device_for(&PIXEL_MACS[0]).unwrap();
+        assert_ne!(iphone, pixel);
+        assert!(IPHONE_MACS
+            .iter()
+            .all(|mac| clusters.device_for(mac) == Some(iphone)));
+        assert!(PIXEL_MACS
+            .iter()
+            .all(|mac| clusters.device_for(mac) == Some(pixel)));
+    }
+
+    #[test]
+    fn tells_identical_phones_apart_by_sequence_number() {
+        let mut clusters = Clusters::new();
+        let [first, second, third] = IPHONE_MACS;
+
+        observe(&mut clusters, &probe_request(first, 100, &iphone("", 6)), 0);
+        observe(
+            &mut clusters,
+            &probe_request(second, 2900, &iphone("", 6)),
+            50,
+        );
+        // Picks up from the first phone, not the second
+        observe(
+            &mut clusters,
+            &probe_request(third, 110, &iphone("", 6)),
+            100,
+        );
+
+        assert_ne!(clusters.device_for(&first), clusters.device_for(&second));
+        assert_eq!(clusters.device_for(&first), clusters.device_for(&third));
+    }
+
+    #[test]
+    fn only_stitches_randomized_macs_seen_soon_after() {
+        let mut clusters = Clusters::new();
+        let [first, second, _] = IPHONE_MACS;
+        let burned_in = [0xa4, 0x83, 0xe7, 0x01, 0x02, 0x03];
+
+        observe(&mut clusters, &probe_request(first, 100, &iphone("", 6)), 0);
+        let late = observe(
+            &mut clusters,
+            &probe_request(second, 101, &iphone("", 6)),
+            CONTINUITY_MS,
+        );
+        assert!(!late.unwrap().linked);
+
+        let real = observe(
+            &mut clusters,
+            &probe_request(burned_in, 102, &iphone("", 6)),
+            CONTINUITY_MS + 10,
+        );
+        assert!(!real.unwrap().linked);
+        assert_eq!(clusters.devices().len(), 3);
+    }
+}
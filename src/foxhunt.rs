@@ -0,0 +1,221 @@
+//! Fox hunting: walk towards one BSSID by watching its signal strength.
+//!
+//! While sniffing we keep a short list of the networks we've heard recently and
+//! how strong they were, so the fox hunt scene can offer the strongest ones as
+//! targets. Once a target is chosen the sniffer stops hopping, stays on the
+//! target's channel and smooths the RSSI of every frame it transmits.
+
+use core::cell::RefCell;
+
+use alloc::{string::String, vec::Vec};
+use critical_section::Mutex;
+
+use crate::frame::MacAddress;
+
+/// How many networks to remember
+const MAX_NEARBY: usize = 16;
+
+/// Forget networks we haven't heard from in this long
+const NEARBY_TTL_MS: u64 = 30_000;
+
+/// The target is lost if it's been quiet this long
+const LOST_AFTER_MS: u64 = 3_000;
+
+/// Weight of a new sample in the moving average, as 1/2^SMOOTHING
+const SMOOTHING: u32 = 2;
+
+#[derive(Clone, Debug)]
+pub struct Candidate {
+    pub bssid: MacAddress,
+    pub ssid: String,
+    pub channel: u8,
+    pub rssi: i8,
+    last_seen: u64,
+}
+
+#[derive(Clone, Debug)]
+struct Target {
+    bssid: MacAddress,
+    channel: u8,
+    /// RSSI in 1/16 dBm so the average doesn't stall on integer rounding
+    smoothed: Option<i32>,
+    last_heard: u64,
+}
+
+pub struct FoxHunt {
+    nearby: Vec<Candidate>,
+    target: Option<Target>,
+}
+
+impl Default for FoxHunt {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FoxHunt {
+    pub const fn new() -> Self {
+        Self {
+            nearby: Vec::new(),
+            target: None,
+        }
+    }
+
+    pub fn observe_beacon(&mut self, bssid: MacAddress, ssid: &str, channel: u8, rssi: i8, now: u64) {
+        if let Some(candidate) = self.nearby.iter_mut().find(|c| c.bssid == bssid) {
+            candidate.channel = channel;
+            candidate.rssi = rssi;
+            candidate.last_seen = now;
+            return;
+        }
+
+        self.nearby
+            .retain(|candidate| now.saturating_sub(candidate.last_seen) < NEARBY_TTL_MS);
+
+        if self.nearby.len() >= MAX_NEARBY {
+            // Make room by dropping the weakest
+            let weakest = self
+                .nearby
+                .iter()
+                .enumerate()
+                .min_by_key(|(_, candidate)| candidate.rssi)
+                .map(|(index, _)| index);
+
+            match weakest {
+                Some(index) if self.nearby[index].rssi < rssi => {
+                    self.nearby.swap_remove(index);
+                }
+                _ => return,
+            }
+        }
+
+        self.nearby.push(Candidate {
+            bssid,
+            ssid: String::from(ssid),
+            channel,
+            rssi,
+            last_seen: now,
+        });
+    }
+
+    /// Any frame from the target moves the average.
+    pub fn observe_frame(&mut self, transmitter: MacAddress, rssi: i8, now: u64) {
+        let Some(target) = self.target.as_mut().filter(|t| t.bssid == transmitter) else {
+            return;
+        };
+
+        let sample = (rssi as i32) << 4;
+        target.smoothed = Some(match target.smoothed {
+            Some(smoothed) => smoothed + ((sample - smoothed) >> SMOOTHING),
+            None => sample,
+        });
+        target.last_heard = now;
+    }
+
+    /// Recently heard networks, strongest first.
+    pub fn strongest(&self, count: usize, now: u64) -> Vec<Candidate> {
+        let mut candidates: Vec<Candidate> = self
+            .nearby
+            .iter()
+            .filter(|candidate| now.saturating_sub(candidate.last_seen) < NEARBY_TTL_MS)
+            .cloned()
+            .collect();
+
+        candidates.sort_by_key(|candidate| -(candidate.rssi as i16));
+        candidates.truncate(count);
+        candidates
+    }
+
+    pub fn hunt(&mut self, candidate: &Candidate, now: u64) {
+        self.target = Some(Target {
+            bssid: candidate.bssid,
+            channel: candidate.channel,
+            smoothed: None,
+            last_heard: now,
+        });
+    }
+
+    pub fn stop(&mut self) {
+        self.target = None;
+    }
+
+    /// The channel to stay on while hunting.
+    pub fn channel(&self) -> Option<u8> {
+        self.target.as_ref().map(|target| target.channel)
+    }
+
+    /// Smoothed RSSI of the target, `None` if we've lost it.
+    pub fn signal(&self, now: u64) -> Option<i8> {
+        let target = self.target.as_ref()?;
+        if now.saturating_sub(target.last_heard) > LOST_AFTER_MS {
+            return None;
+        }
+
+        target.smoothed.map(|smoothed| (smoothed >> 4) as i8)
+    }
+}
+
+static FOX_HUNT: Mutex<RefCell<FoxHunt>> = Mutex::new(RefCell::new(FoxHunt::new()));
+
+pub fn observe_beacon(bssid: MacAddress, ssid: &str, channel: u8, rssi: i8, now: u64) {
+    critical_section::with(|cs| {
+        FOX_HUNT
+            .borrow_ref_mut(cs)
+            .observe_beacon(bssid, ssid, channel, rssi, now)
+    });
+}
+
+pub fn observe_frame(transmitter: MacAddress, rssi: i8, now: u64) {
+    critical_section::with(|cs| {
+        FOX_HUNT
+            .borrow_ref_mut(cs)
+            .observe_frame(transmitter, rssi, now)
+    });
+}
+
+pub fn strongest(count: usize, now: u64) -> Vec<Candidate> {
+    critical_section::with(|cs| FOX_HUNT.borrow_ref(cs).strongest(count, now))
+}
+
+pub fn hunt(candidate: &Candidate, now: u64) {
+    critical_section::with(|cs| FOX_HUNT.borrow_ref_mut(cs).hunt(candidate, now));
+}
+
+pub fn stop() {
+    critical_section::with(|cs| FOX_HUNT.borrow_ref_mut(cs).stop());
+}
+
+pub fn channel() -> Option<u8> {
+    critical_section::with(|cs| FOX_HUNT.borrow_ref(cs).channel())
+}
+
+pub fn signal(now: u64) -> Option<i8> {
+    critical_section::with(|cs| FOX_HUNT.borrow_ref(cs).signal(now))
+}
+
+/// What the LEDs should show for a given signal strength.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Meter {
+    /// How many of the four lights are fully on
+    pub lit: usize,
+    /// Brightness of the next light up, for in-between readings
+    pub partial: u8,
+    /// Blink period, faster as we get closer
+    pub period_ms: u64,
+}
+
+const WEAKEST_DBM: i32 = -90;
+const STRONGEST_DBM: i32 = -30;
+
+impl Meter {
+    pub fn from_rssi(rssi: i8) -> Self {
+        let range = STRONGEST_DBM - WEAKEST_DBM;
+        let scaled = ((rssi as i32 - WEAKEST_DBM).clamp(0, range) * 400 / range) as usize;
+
+        Self {
+            lit: scaled / 100,
+            partial: (scaled % 100) as u8,
+            period_ms: 1_000 - (scaled as u64 * 900 / 400),
+        }
+    }
+}
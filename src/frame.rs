@@ -0,0 +1,360 @@
+//! Minimal raw 802.11 management frame parsing.
+//!
+//! `ieee80211::match_frames!` is enough to pull an SSID out of a beacon, but the
+//! detectors need the bits it doesn't expose (sequence numbers, TSF, raw
+//! information elements), so we read those straight out of the buffer.
+
+pub type MacAddress = [u8; 6];
+
+const HEADER_LEN: usize = 24;
+
+const CAPABILITY_PRIVACY: u16 = 0x0010;
+const WPA_OUI_TYPE: [u8; 4] = [0x00, 0x50, 0xf2, 0x01];
+const AKM_SAE: [u8; 4] = [0x00, 0x0f, 0xac, 0x08];
+
+// Information element IDs
+pub const IE_SSID: u8 = 0;
+pub const IE_SUPPORTED_RATES: u8 = 1;
+pub const IE_DS_PARAMETER: u8 = 3;
+pub const IE_BSS_LOAD: u8 = 11;
+pub const IE_CHANNEL_SWITCH: u8 = 37;
+pub const IE_RSN: u8 = 48;
+pub const IE_EXTENDED_CHANNEL_SWITCH: u8 = 60;
+pub const IE_VENDOR: u8 = 221;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FrameType {
+    Management,
+    Control,
+    Data,
+    Extension,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ManagementKind {
+    AssociationRequest,
+    AssociationResponse,
+    ReassociationRequest,
+    ReassociationResponse,
+    ProbeRequest,
+    ProbeResponse,
+    Beacon,
+    Disassociation,
+    Authentication,
+    Deauthentication,
+    Action,
+    Other(u8),
+}
+
+impl ManagementKind {
+    fn from_subtype(subtype: u8) -> Self {
+        match subtype {
+            0 => Self::AssociationRequest,
+            1 => Self::AssociationResponse,
+            2 => Self::ReassociationRequest,
+            3 => Self::ReassociationResponse,
+            4 => Self::ProbeRequest,
+            5 => Self::ProbeResponse,
+            8 => Self::Beacon,
+            10 => Self::Disassociation,
+            11 => Self::Authentication,
+            12 => Self::Deauthentication,
+            13 => Self::Action,
+            other => Self::Other(other),
+        }
+    }
+}
+
+pub fn frame_type(data: &[u8]) -> Option<FrameType> {
+    let fc = *data.first()?;
+
+    Some(match (fc >> 2) & 0b11 {
+        0 => FrameType::Management,
+        1 => FrameType::Control,
+        2 => FrameType::Data,
+        _ => FrameType::Extension,
+    })
+}
+
+/// The transmitter address (addr2) of a management or data frame.
+pub fn transmitter(data: &[u8]) -> Option<MacAddress> {
+    match frame_type(data)? {
+        FrameType::Management | FrameType::Data if data.len() >= 16 => {
+            Some(address(&data[10..16]))
+        }
+        _ => None,
+    }
+}
+
+/// A management frame header plus the body that follows it.
+pub struct Management<'a> {
+    pub kind: ManagementKind,
+    pub receiver: MacAddress,
+    pub transmitter: MacAddress,
+    pub bssid: MacAddress,
+    pub sequence: u16,
+    pub body: &'a [u8],
+}
+
+impl<'a> Management<'a> {
+    pub fn parse(data: &'a [u8]) -> Option<Self> {
+        if data.len() < HEADER_LEN || frame_type(data)? != FrameType::Management {
+            return None;
+        }
+
+        let subtype = data[0] >> 4;
+        let sequence_control = u16::from_le_bytes([data[22], data[23]]);
+
+        Some(Self {
+            kind: ManagementKind::from_subtype(subtype),
+            receiver: address(&data[4..10]),
+            transmitter: address(&data[10..16]),
+            bssid: address(&data[16..22]),
+            sequence: sequence_control >> 4,
+            body: &data[HEADER_LEN..],
+        })
+    }
+
+    /// Fixed fields of a beacon or probe response. `None` for other frames.
+    pub fn beacon(&self) -> Option<Beacon<'a>> {
+        if !matches!(
+            self.kind,
+            ManagementKind::Beacon | ManagementKind::ProbeResponse
+        ) || self.body.len() < 12
+        {
+            return None;
+        }
+
+        let mut tsf = [0u8; 8];
+        tsf.copy_from_slice(&self.body[0..8]);
+
+        Some(Beacon {
+            tsf: u64::from_le_bytes(tsf),
+            interval: u16::from_le_bytes([self.body[8], self.body[9]]),
+            capabilities: u16::from_le_bytes([self.body[10], self.body[11]]),
+            elements: Elements(&self.body[12..]),
+        })
+    }
+
+    /// Reason code of a deauthentication or disassociation frame.
+    pub fn reason(&self) -> Option<u16> {
+        if !matches!(
+            self.kind,
+            ManagementKind::Deauthentication | ManagementKind::Disassociation
+        ) || self.body.len() < 2
+        {
+            return None;
+        }
+
+        Some(u16::from_le_bytes([self.body[0], self.body[1]]))
+    }
+
+    /// The new channel of a spectrum management Channel Switch action frame.
+    pub fn action_channel_switch(&self) -> Option<u8> {
+        if self.kind != ManagementKind::Action {
+            return None;
+        }
+
+        // category 0 (spectrum management), action 4 (channel switch)
+        match self.body {
+            [0, 4, elements @ ..] => Beacon::channel_switch_in(Elements(elements)),
+            _ => None,
+        }
+    }
+}
+
+pub struct Beacon<'a> {
+    pub tsf: u64,
+    pub interval: u16,
+    pub capabilities: u16,
+    pub elements: Elements<'a>,
+}
+
+impl Beacon<'_> {
+    pub fn ssid(&self) -> Option<&[u8]> {
+        self.elements.find(IE_SSID)
+    }
+
+    /// The channel from the DS Parameter Set element, if present.
+    pub fn channel(&self) -> Option<u8> {
+        self.elements.find(IE_DS_PARAMETER)?.first().copied()
+    }
+
+    /// Station count and channel utilization (out of 255) from the QBSS Load
+    /// element, if the AP advertises one.
+    pub fn bss_load(&self) -> Option<(u16, u8)> {
+        match self.elements.find(IE_BSS_LOAD)? {
+            [stations_lo, stations_hi, utilization, ..] => Some((
+                u16::from_le_bytes([*stations_lo, *stations_hi]),
+                *utilization,
+            )),
+            _ => None,
+        }
+    }
+
+    /// The new channel announced by a (extended) channel switch element.
+    pub fn channel_switch(&self) -> Option<u8> {
+        Self::channel_switch_in(self.elements)
+    }
+
+    fn channel_switch_in(elements: Elements<'_>) -> Option<u8> {
+        if let Some(csa) = elements.find(IE_CHANNEL_SWITCH) {
+            // mode, new channel, count
+            return csa.get(1).copied();
+        }
+
+        // mode, new operating class, new channel, count
+        elements.find(IE_EXTENDED_CHANNEL_SWITCH)?.get(2).copied()
+    }
+}
+
+/// How a network protects itself, weakest first so they can be compared.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Security {
+    Open,
+    Wep,
+    Wpa,
+    Wpa2,
+    Wpa3,
+}
+
+impl Security {
+    pub fn from_u8(value: u8) -> Option<Self> {
+        Some(match value {
+            0 => Self::Open,
+            1 => Self::Wep,
+            2 => Self::Wpa,
+            3 => Self::Wpa2,
+            4 => Self::Wpa3,
+            _ => return None,
+        })
+    }
+
+    /// By name, as typed on the console: open, wep, wpa, wpa2 or wpa3.
+    pub fn parse(name: &str) -> Option<Self> {
+        Some(match name {
+            "open" => Self::Open,
+            "wep" => Self::Wep,
+            "wpa" => Self::Wpa,
+            "wpa2" => Self::Wpa2,
+            "wpa3" => Self::Wpa3,
+            _ => return None,
+        })
+    }
+}
+
+impl Beacon<'_> {
+    pub fn security(&self) -> Security {
+        if let Some(rsn) = self.elements.find(IE_RSN) {
+            // version (2), group cipher (4), pairwise count + suites, AKM count + suites
+            let pairwise = rsn
+                .get(6..8)
+                .map_or(0, |count| u16::from_le_bytes([count[0], count[1]]) as usize);
+            let akm_start = 8 + 4 * pairwise;
+            let akms = rsn
+                .get(akm_start + 2..)
+                .unwrap_or_default()
+                .chunks_exact(4);
+
+            if akms.into_iter().any(|akm| akm == AKM_SAE) {
+                return Security::Wpa3;
+            }
+
+            return Security::Wpa2;
+        }
+
+        let wpa = self
+            .elements
+            .into_iter()
+            .any(|(id, body)| id == IE_VENDOR && body.starts_with(&WPA_OUI_TYPE));
+
+        if wpa {
+            Security::Wpa
+        } else if self.capabilities & CAPABILITY_PRIVACY != 0 {
+            Security::Wep
+        } else {
+            Security::Open
+        }
+    }
+}
+
+/// Iterator over the tagged information elements of a frame body.
+#[derive(Clone, Copy)]
+pub struct Elements<'a>(pub &'a [u8]);
+
+impl<'a> Elements<'a> {
+    pub fn find(&self, id: u8) -> Option<&'a [u8]> {
+        self.into_iter()
+            .find(|(element, _)| *element == id)
+            .map(|(_, body)| body)
+    }
+}
+
+impl<'a> IntoIterator for Elements<'a> {
+    type Item = (u8, &'a [u8]);
+    type IntoIter = ElementIter<'a>;
+
+    fn into_iter(self) -> ElementIter<'a> {
+        ElementIter(self.0)
+    }
+}
+
+pub struct ElementIter<'a>(&'a [u8]);
+
+impl<'a> Iterator for ElementIter<'a> {
+    type Item = (u8, &'a [u8]);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let [id, len, rest @ ..] = self.0 else {
+            return None;
+        };
+
+        let len = *len as usize;
+        if rest.len() < len {
+            // Truncated element (or the FCS trailing the frame), stop here
+            self.0 = &[];
+            return None;
+        }
+
+        let body = &rest[..len];
+        self.0 = &rest[len..];
+        Some((*id, body))
+    }
+}
+
+fn address(bytes: &[u8]) -> MacAddress {
+    let mut address = [0u8; 6];
+    address.copy_from_slice(bytes);
+    address
+}
+
+pub struct Mac<'a>(pub &'a MacAddress);
+
+impl core::fmt::Display for Mac<'_> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let [a, b, c, d, e, g] = self.0;
+        write!(f, "{a:02x}:{b:02x}:{c:02x}:{d:02x}:{e:02x}:{g:02x}")
+    }
+}
+
+/// A MAC address written the way `Mac` prints one, aa:bb:cc:dd:ee:ff.
+pub fn parse_mac(text: &str) -> Option<MacAddress> {
+    let mut mac = [0u8; 6];
+    let mut parts = text.split(':');
+    for byte in &mut mac {
+        let part = parts.next()?;
+        if part.len() != 2 {
+            return None;
+        }
+        *byte = u8::from_str_radix(part, 16).ok()?;
+    }
+    parts.next().is_none().then_some(mac)
+}
+
+/// FNV-1a, good enough to tell SSIDs and element sets apart without keeping
+/// the bytes around.
+pub fn fnv1a(bytes: &[u8]) -> u32 {
+    bytes.iter().fold(0x811c_9dc5, |hash, byte| {
+        (hash ^ *byte as u32).wrapping_mul(0x0100_0193)
+    })
+}
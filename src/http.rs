@@ -0,0 +1,755 @@
+//! The share mode's HTTP routes, and the bits of client side HTTP uploads
+//! need.
+//!
+//! Everything here works on parsed requests and a `Source` of data, not on
+//! sockets or flash, so the handlers run the same on a host as on the device.
+
+use alloc::{format, string::String, vec::Vec};
+use core::{fmt::Write, str::FromStr};
+
+use crate::{
+    battery::Level,
+    channels::Survey,
+    console,
+    export::{self, JsonString},
+    query::{Cursor, Page, Query},
+};
+
+/// Records per page of `/api/records` when the client doesn't say
+const DEFAULT_LIMIT: usize = 50;
+const MAX_LIMIT: usize = 200;
+
+/// What the handlers read from.
+pub trait Source {
+    fn len(&mut self) -> usize;
+    fn is_empty(&mut self) -> bool {
+        self.len() == 0
+    }
+    /// How many records match `query`.
+    fn count(&mut self, query: &Query) -> usize;
+    /// Up to `limit` records matching `query`, from `from` on.
+    fn page(&mut self, query: &Query, from: Cursor, limit: usize) -> Page;
+    fn battery(&self) -> Option<Level>;
+    fn survey(&self) -> Option<Survey>;
+    /// Settings as (key, value), passwords hidden.
+    fn settings(&self) -> Vec<(&'static str, String)>;
+    fn set(&mut self, key: &str, value: &str) -> Result<(), &'static str>;
+    /// Whether anyone in range can join the network this is served on.
+    fn open(&self) -> bool;
+}
+
+pub struct Request<'a> {
+    pub method: &'a str,
+    pub path: &'a str,
+    pub query: &'a str,
+}
+
+impl<'a> Request<'a> {
+    /// Parse the request line out of a request head.
+    pub fn parse(head: &'a [u8]) -> Option<Self> {
+        let line = head.split(|byte| *byte == b'\n').next()?;
+        let line = core::str::from_utf8(line).ok()?.trim_end_matches('\r');
+
+        let mut parts = line.split(' ');
+        let method = parts.next()?;
+        let target = parts.next()?;
+        parts.next().filter(|version| version.starts_with("HTTP/"))?;
+
+        let (path, query) = target.split_once('?').unwrap_or((target, ""));
+        Some(Self {
+            method,
+            path,
+            query,
+        })
+    }
+
+    pub fn param(&self, name: &str) -> Option<&'a str> {
+        self.query
+            .split('&')
+            .filter_map(|pair| pair.split_once('='))
+            .find(|(key, _)| *key == name)
+            .map(|(_, value)| value)
+    }
+}
+
+/// Where the request head ends, if we've got all of it.
+pub fn head_end(bytes: &[u8]) -> Option<usize> {
+    bytes
+        .windows(4)
+        .position(|window| window == b"\r\n\r\n")
+        .map(|position| position + 4)
+}
+
+/// Head of a JSON POST; the body follows it as is.
+pub fn post_head(host: &str, path: &str, len: usize) -> String {
+    format!(
+        "POST {path} HTTP/1.1\r\nHost: {host}\r\nContent-Type: application/json\r\n\
+         Content-Length: {len}\r\nConnection: close\r\n\r\n"
+    )
+}
+
+/// The status code from a response's status line.
+pub fn response_status(head: &[u8]) -> Option<u16> {
+    let line = head.split(|byte| *byte == b'\n').next()?;
+    let line = core::str::from_utf8(line).ok()?;
+
+    let mut parts = line.split(' ');
+    parts.next().filter(|version| version.starts_with("HTTP/"))?;
+    parts.next()?.parse().ok()
+}
+
+pub enum Body {
+    Text(String),
+    /// The records a query matches as CSV, written row by row by the
+    /// server, see [`csv_rows`]
+    Csv(Query),
+}
+
+/// The rows of a CSV body after `export::CSV_HEADER`, read from `source` a
+/// page at a time.
+pub fn csv_rows<'a>(
+    source: &'a mut impl Source,
+    query: &'a Query,
+) -> impl Iterator<Item = String> + 'a {
+    let mut from = Some(Cursor::default());
+    let mut rows = Page::default().records.into_iter();
+
+    core::iter::from_fn(move || loop {
+        if let Some((id, bytes)) = rows.next() {
+            return Some(export::csv_row(id as usize, &bytes));
+        }
+        let cursor = from?;
+        let page = source.page(query, cursor, MAX_LIMIT);
+        // Erased part way through, what's there now is another log
+        if cursor
+            .generation
+            .is_some_and(|generation| generation != page.generation)
+        {
+            return None;
+        }
+        from = page.next.map(|id| Cursor {
+            generation: Some(page.generation),
+            id,
+        });
+        rows = page.records.into_iter();
+    })
+}
+
+pub struct Response {
+    pub status: u16,
+    pub content_type: &'static str,
+    pub body: Body,
+}
+
+impl Response {
+    fn text(status: u16, content_type: &'static str, body: String) -> Self {
+        Self {
+            status,
+            content_type,
+            body: Body::Text(body),
+        }
+    }
+
+    fn json(body: String) -> Self {
+        Self::text(200, "application/json", body)
+    }
+
+    /// Status line and headers. Streamed bodies have no length and end when
+    /// the connection closes.
+    pub fn head(&self) -> String {
+        let reason = match self.status {
+            200 => "OK",
+            400 => "Bad Request",
+            403 => "Forbidden",
+            404 => "Not Found",
+            405 => "Method Not Allowed",
+            _ => "",
+        };
+
+        let mut head = format!(
+            "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nConnection: close\r\n",
+            self.status, reason, self.content_type
+        );
+
+        match &self.body {
+            Body::Text(body) => {
+                let _ = write!(head, "Content-Length: {}\r\n", body.len());
+            }
+            Body::Csv(_) => {
+                head.push_str("Content-Disposition: attachment; filename=\"survey.csv\"\r\n");
+            }
+        }
+
+        head.push_str("\r\n");
+        head
+    }
+}
+
+pub fn bad_request() -> Response {
+    Response::text(400, "text/plain", "bad request\n".into())
+}
+
+pub fn handle(request: &Request, source: &mut impl Source) -> Response {
+    if request.method == "POST" && request.path == "/api/config" {
+        return set_config(request, source);
+    }
+    if request.method != "GET" {
+        return Response::text(405, "text/plain", "GET only\n".into());
+    }
+
+    match request.path {
+        "/" => Response::text(200, "text/html; charset=utf-8", status_page(source)),
+        "/api/records" => records(request, source),
+        "/api/stats" => Response::json(stats(source)),
+        "/api/config" => Response::json(config(source)),
+        "/api/battery" => Response::json(
+            source
+                .battery()
+                .map_or("null".into(), |level| export::battery_json(&level)),
+        ),
+        "/records.csv" => match query(request) {
+            Some(query) => Response {
+                status: 200,
+                content_type: "text/csv",
+                body: Body::Csv(query),
+            },
+            None => bad_request(),
+        },
+        _ => Response::text(404, "text/plain", "not found\n".into()),
+    }
+}
+
+fn status_page(source: &mut impl Source) -> String {
+    let mut page = String::from(
+        "<!doctype html><html><head><meta name=viewport content=\"width=device-width\">\
+         <title>wifblink</title></head><body><h1>wifblink</h1><ul>",
+    );
+
+    let _ = write!(page, "<li>{} records</li>", source.len());
+
+    match source.battery() {
+        Some(level) => {
+            let _ = write!(
+                page,
+                "<li>battery {}% ({} mV{})</li>",
+                level.percent,
+                level.millivolts,
+                if level.usb { ", on usb" } else { "" }
+            );
+        }
+        None => page.push_str("<li>battery unknown</li>"),
+    }
+
+    if let Some(channel) = source.survey().and_then(|survey| survey.recommended()) {
+        let _ = write!(page, "<li>recommended channel {channel}</li>");
+    }
+
+    page.push_str(
+        "</ul><p><a href=\"/records.csv\">download csv</a> \
+         <a href=\"/api/records\">records</a> <a href=\"/api/stats\">stats</a> \
+         <a href=\"/api/battery\">battery</a> <a href=\"/api/config\">settings</a></p>\
+         </body></html>",
+    );
+    page
+}
+
+/// The filters in a request's parameters, named as the console's are;
+/// None if one doesn't parse.
+fn query(request: &Request) -> Option<Query> {
+    Some(Query {
+        kind: request.param("kind").map(Into::into),
+        since: time(request.param("since"))?,
+        until: time(request.param("until"))?,
+        channel: parsed(request.param("channel"))?,
+        min_rssi: parsed(request.param("rssi"))?,
+        ssid_prefix: request.param("ssid").map(percent_decode),
+        text: request.param("match").map(percent_decode),
+    })
+}
+
+/// An optional parameter: Some(None) when it's missing, None when it's bad.
+fn parsed<T: FromStr>(value: Option<&str>) -> Option<Option<T>> {
+    match value {
+        None => Some(None),
+        Some(value) => value.parse().ok().map(Some),
+    }
+}
+
+/// An optional time parameter, Unix seconds or ISO 8601, as Unix seconds.
+fn time(value: Option<&str>) -> Option<Option<u32>> {
+    match value {
+        None => Some(None),
+        Some(value) => {
+            let unix_ms = console::parse_time(&percent_decode(value)).ok()?;
+            Some(Some((unix_ms / 1000) as u32))
+        }
+    }
+}
+
+/// A page of records. `next` is where the page after starts, or null at
+/// the end; `offset` is still taken for `from`. IDs count in `generation`,
+/// passed back along with `from`: a page asked for from before the log was
+/// erased starts again at its beginning.
+fn records(request: &Request, source: &mut impl Source) -> Response {
+    let from = request.param("from").or(request.param("offset"));
+    let from = match from.map(str::parse::<u32>) {
+        None => 0,
+        Some(Ok(from)) => from,
+        Some(Err(_)) => return bad_request(),
+    };
+    let Some(generation) = parsed(request.param("generation")) else {
+        return bad_request();
+    };
+    let limit = match request.param("limit").map(str::parse::<usize>) {
+        None => DEFAULT_LIMIT,
+        Some(Ok(limit)) => limit.min(MAX_LIMIT),
+        Some(Err(_)) => return bad_request(),
+    };
+    let Some(query) = query(request) else {
+        return bad_request();
+    };
+
+    let total = source.count(&query);
+    let cursor = Cursor {
+        generation,
+        id: from,
+    };
+    let page = source.page(&query, cursor, limit);
+    let from = match generation {
+        Some(generation) if generation != page.generation => 0,
+        _ => from,
+    };
+    let mut body = format!(
+        "{{\"total\":{total},\"generation\":{},\"from\":{from},\"next\":",
+        page.generation
+    );
+    match page.next {
+        Some(next) => {
+            let _ = write!(body, "{next}");
+        }
+        None => body.push_str("null"),
+    }
+    body.push_str(",\"records\":[");
+
+    for (index, (id, bytes)) in page.records.iter().enumerate() {
+        if index > 0 {
+            body.push(',');
+        }
+        body.push_str(&export::json_record(*id as usize, bytes));
+    }
+
+    body.push_str("]}");
+    Response::json(body)
+}
+
+/// `{"key":"value",..}`
+fn config(source: &mut impl Source) -> String {
+    let mut body = String::from("{");
+    for (index, (key, value)) in source.settings().iter().enumerate() {
+        if index > 0 {
+            body.push(',');
+        }
+        let _ = write!(body, "{}:{}", JsonString(key), JsonString(value));
+    }
+    body.push('}');
+    body
+}
+
+/// `POST /api/config?key=..&value=..`. Not on an open network, where
+/// anyone passing could point the uploads somewhere else.
+fn set_config(request: &Request, source: &mut impl Source) -> Response {
+    if source.open() {
+        return Response::text(
+            403,
+            "text/plain",
+            "settings can't be changed over an open network\n".into(),
+        );
+    }
+    let (Some(key), Some(value)) = (request.param("key"), request.param("value")) else {
+        return bad_request();
+    };
+
+    match source.set(&percent_decode(key), &percent_decode(value)) {
+        Ok(()) => Response::json(config(source)),
+        Err(err) => Response::text(400, "text/plain", format!("{err}\n")),
+    }
+}
+
+/// Undo URL encoding; `+` is a space, as forms send it.
+fn percent_decode(value: &str) -> String {
+    let mut bytes = Vec::with_capacity(value.len());
+    let mut rest = value.as_bytes();
+
+    while let Some((&byte, tail)) = rest.split_first() {
+        rest = tail;
+        match byte {
+            b'+' => bytes.push(b' '),
+            b'%' => {
+                let hex = rest
+                    .get(..2)
+                    .and_then(|hex| core::str::from_utf8(hex).ok())
+                    .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+                match hex {
+                    Some(decoded) => {
+                        bytes.push(decoded);
+                        rest = &rest[2..];
+                    }
+                    None => bytes.push(byte),
+                }
+            }
+            byte => bytes.push(byte),
+        }
+    }
+
+    String::from_utf8_lossy(&bytes).into()
+}
+
+fn stats(source: &mut impl Source) -> String {
+    let Some(survey) = source.survey() else {
+        return "{\"channels\":[],\"recommended\":null}".into();
+    };
+
+    let mut body = String::from("{\"channels\":[");
+    let mut first = true;
+    for (index, stats) in survey.channels.iter().enumerate() {
+        if stats.dwell_ms == 0 {
+            continue;
+        }
+        if !first {
+            body.push(',');
+        }
+        first = false;
+
+        let _ = write!(
+            body,
+            "{{\"channel\":{},\"frames\":{},\"bytes\":{},\"busy_permille\":{},\"stations\":{}}}",
+            index + 1,
+            stats.frames(),
+            stats.bytes(),
+            stats.busy(),
+            stats.stations
+        );
+    }
+
+    match survey.recommended() {
+        Some(channel) => {
+            let _ = write!(body, "],\"recommended\":{channel}}}");
+        }
+        None => body.push_str("],\"recommended\":null}"),
+    }
+    body
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        channels::HOP_CHANNELS,
+        clock::Stamp,
+        record::{self, Record},
+    };
+
+    /// Records in a `Vec`, paged the way the store pages them.
+    #[derive(Default)]
+    struct Fake {
+        records: Vec<Vec<u8>>,
+        /// Bumped by `erase`
+        generation: u32,
+        battery: Option<Level>,
+        survey: Option<Survey>,
+        brightness: u8,
+        open: bool,
+    }
+
+    impl Source for Fake {
+        fn len(&mut self) -> usize {
+            self.records.len()
+        }
+
+        fn count(&mut self, query: &Query) -> usize {
+            self.records
+                .iter()
+                .filter(|bytes| query.matches(bytes))
+                .count()
+        }
+
+        fn page(&mut self, query: &Query, from: Cursor, limit: usize) -> Page {
+            let from = match from.generation {
+                Some(generation) if generation != self.generation => 0,
+                _ => from.id,
+            };
+            let mut page = Page {
+                generation: self.generation,
+                ..Page::default()
+            };
+            let matching = (from..self.records.len() as u32)
+                .filter(|id| query.matches(&self.records[*id as usize]));
+            for id in matching {
+                if page.records.len() == limit {
+                    page.next = Some(id);
+                    break;
+                }
+                page.records.push((id, self.records[id as usize].clone()));
+            }
+            page
+        }
+
+        fn battery(&self) -> Option<Level> {
+            self.battery
+        }
+
+        fn survey(&self) -> Option<Survey> {
+            self.survey
+        }
+
+        fn settings(&self) -> Vec<(&'static str, String)> {
+            alloc::vec![
+                ("brightness", format!("{}", self.brightness)),
+                ("password", "***".into()),
+            ]
+        }
+
+        fn set(&mut self, key: &str, value: &str) -> Result<(), &'static str> {
+            match key {
+                "brightness" => {
+                    self.brightness = value.parse().map_err(|_| "brightness is 0-100")?;
+                    Ok(())
+                }
+                _ => Err("no such setting"),
+            }
+        }
+
+        fn open(&self) -> bool {
+            self.open
+        }
+    }
+
+    /// 2023-11-14T22:13:20Z
+    const UNIX_S: u32 = 1_700_000_000;
+
+    fn stamped(record: Record) -> Vec<u8> {
+        let stamp = Stamp {
+            boot: 1,
+            uptime_s: 5,
+            unix_s: Some(UNIX_S),
+        };
+        record::stamp(&stamp, &record.encode())
+    }
+
+    fn survey() -> Fake {
+        Fake {
+            records: alloc::vec![
+                stamped(Record::Network("CoffeeShop".into())),
+                stamped(Record::Network("Say \"hi\"".into())),
+                Record::Occupancy {
+                    count: 4,
+                    window_s: 300,
+                    min_rssi: -75,
+                }
+                .encode(),
+                stamped(Record::Network("Home-5G".into())),
+            ],
+            generation: 3,
+            brightness: 20,
+            ..Default::default()
+        }
+    }
+
+    fn request(source: &mut Fake, head: &str) -> Response {
+        handle(&Request::parse(head.as_bytes()).unwrap(), source)
+    }
+
+    fn get(source: &mut Fake, target: &str) -> Response {
+        request(source, &format!("GET {target} HTTP/1.1\r\n\r\n"))
+    }
+
+    fn text(response: &Response) -> &str {
+        match &response.body {
+            Body::Text(text) => text,
+            Body::Csv(_) => panic!("expected a text body"),
+        }
+    }
+
+    #[test]
+    fn serves_the_status_page() {
+        let mut source = survey();
+        let response = get(&mut source, "/");
+        assert_eq!(response.status, 200);
+        assert_eq!(response.content_type, "text/html; charset=utf-8");
+        assert!(text(&response).contains("<li>4 records</li>"));
+        assert!(text(&response).contains("<li>battery unknown</li>"));
+        assert!(!text(&response).contains("recommended channel"));
+
+        let mut survey = Survey::new();
+        for channel in HOP_CHANNELS {
+            survey.dwell(channel, 1000);
+        }
+        source.survey = Some(survey);
+        source.battery = Some(Level {
+            percent: 80,
+            millivolts: 3950,
+            usb: true,
+        });
+        let response = get(&mut source, "/");
+        assert!(text(&response).contains("<li>battery 80% (3950 mV, on usb)</li>"));
+        assert!(text(&response).contains("<li>recommended channel 1</li>"));
+    }
+
+    #[test]
+    fn pages_through_records_as_json() {
+        let mut source = survey();
+
+        let first = get(&mut source, "/api/records?limit=2");
+        assert_eq!(first.status, 200);
+        assert_eq!(first.content_type, "application/json");
+        assert_eq!(
+            text(&first),
+            "{\"total\":4,\"generation\":3,\"from\":0,\"next\":2,\"records\":[\
+             {\"index\":0,\"time\":\"2023-11-14T22:13:20Z\",\"kind\":\"network\",\"record\":\"+ CoffeeShop\"},\
+             {\"index\":1,\"time\":\"2023-11-14T22:13:20Z\",\"kind\":\"network\",\"record\":\"+ Say \\\"hi\\\"\"}]}"
+        );
+
+        let last = get(&mut source, "/api/records?limit=2&from=2");
+        assert!(text(&last).starts_with("{\"total\":4,\"generation\":3,\"from\":2,\"next\":null,"));
+        assert!(text(&last).contains("{\"index\":2,\"time\":null,\"kind\":\"occupancy\""));
+        assert!(text(&last).contains("{\"index\":3,"));
+
+        // The total counts what matches, `next` skips what doesn't
+        let networks = get(&mut source, "/api/records?kind=network&limit=2");
+        assert!(text(&networks).starts_with("{\"total\":3,\"generation\":3,\"from\":0,\"next\":3,"));
+        let networks = get(
+            &mut source,
+            "/api/records?kind=network&ssid=Say+%22&offset=0",
+        );
+        assert!(
+            text(&networks).starts_with("{\"total\":1,\"generation\":3,\"from\":0,\"next\":null,")
+        );
+    }
+
+    #[test]
+    fn starts_over_on_a_page_from_before_an_erase() {
+        let mut source = survey();
+        let first = get(&mut source, "/api/records?limit=2");
+        assert!(text(&first).starts_with("{\"total\":4,\"generation\":3,\"from\":0,\"next\":2,"));
+
+        // Erased, then three new records: ID 2 is a different one now
+        source.records = source.records.split_off(1);
+        source.generation += 1;
+
+        let stale = get(&mut source, "/api/records?limit=2&from=2&generation=3");
+        assert!(text(&stale).starts_with("{\"total\":3,\"generation\":4,\"from\":0,\"next\":2,"));
+        assert!(text(&stale).contains("{\"index\":0,"));
+
+        let current = get(&mut source, "/api/records?limit=2&from=2&generation=4");
+        assert!(
+            text(&current).starts_with("{\"total\":3,\"generation\":4,\"from\":2,\"next\":null,")
+        );
+        let bare = get(&mut source, "/api/records?limit=2&from=2");
+        assert_eq!(text(&bare), text(&current));
+
+        assert_eq!(get(&mut source, "/api/records?generation=new").status, 400);
+    }
+
+    #[test]
+    fn serves_settings_stats_and_battery_as_json() {
+        let mut source = survey();
+        assert_eq!(
+            text(&get(&mut source, "/api/config")),
+            "{\"brightness\":\"20\",\"password\":\"***\"}"
+        );
+        assert_eq!(
+            text(&get(&mut source, "/api/stats")),
+            "{\"channels\":[],\"recommended\":null}"
+        );
+        assert_eq!(text(&get(&mut source, "/api/battery")), "null");
+
+        let set = request(
+            &mut source,
+            "POST /api/config?key=brightness&value=40 HTTP/1.1\r\n\r\n",
+        );
+        assert_eq!(set.status, 200);
+        assert_eq!(text(&set), "{\"brightness\":\"40\",\"password\":\"***\"}");
+
+        let bad = request(
+            &mut source,
+            "POST /api/config?key=brightness&value=lots HTTP/1.1\r\n\r\n",
+        );
+        assert_eq!(bad.status, 400);
+        assert_eq!(text(&bad), "brightness is 0-100\n");
+        assert_eq!(source.brightness, 40);
+
+        source.open = true;
+        let open = request(
+            &mut source,
+            "POST /api/config?key=brightness&value=60 HTTP/1.1\r\n\r\n",
+        );
+        assert_eq!(open.status, 403);
+        assert!(open.head().starts_with("HTTP/1.1 403 Forbidden\r\n"));
+        assert_eq!(source.brightness, 40);
+    }
+
+    #[test]
+    fn streams_matching_records_as_csv() {
+        let mut source = survey();
+        let response = get(&mut source, "/records.csv?kind=network");
+        assert_eq!(response.status, 200);
+        assert!(response
+            .head()
+            .contains("Content-Disposition: attachment; filename=\"survey.csv\"\r\n"));
+        assert!(!response.head().contains("Content-Length"));
+
+        let Body::Csv(query) = response.body else {
+            panic!("expected a CSV body");
+        };
+        let rows: Vec<String> = csv_rows(&mut source, &query).collect();
+        assert_eq!(
+            rows,
+            [
+                "0,2023-11-14T22:13:20Z,network,\"+ CoffeeShop\"\r\n",
+                "1,2023-11-14T22:13:20Z,network,\"+ Say \"\"hi\"\"\"\r\n",
+                "3,2023-11-14T22:13:20Z,network,\"+ Home-5G\"\r\n",
+            ]
+        );
+    }
+
+    #[test]
+    fn streams_more_than_a_page_of_csv() {
+        let mut source = Fake::default();
+        for count in 0..(2 * MAX_LIMIT + 7) as u16 {
+            source.records.push(
+                Record::Occupancy {
+                    count,
+                    window_s: 300,
+                    min_rssi: -75,
+                }
+                .encode(),
+            );
+        }
+
+        let rows: Vec<String> = csv_rows(&mut source, &Query::default()).collect();
+        assert_eq!(rows.len(), source.records.len());
+        for (index, row) in rows.iter().enumerate() {
+            assert!(row.starts_with(&format!("{index},,occupancy,")), "{row}");
+        }
+    }
+
+    #[test]
+    fn rejects_what_it_cannot_serve() {
+        let mut source = survey();
+        for target in [
+            "/api/records?limit=lots",
+            "/api/records?from=-1",
+            "/api/records?since=yesterday",
+            "/records.csv?rssi=loud",
+        ] {
+            assert_eq!(get(&mut source, target).status, 400, "{target}");
+        }
+        assert_eq!(get(&mut source, "/nowhere").status, 404);
+        assert_eq!(
+            request(&mut source, "DELETE /api/records HTTP/1.1\r\n\r\n").status,
+            405
+        );
+        let missing_value = "POST /api/config?key=brightness HTTP/1.1\r\n\r\n";
+        assert_eq!(request(&mut source, missing_value).status, 400);
+    }
+}
@@ -0,0 +1,156 @@
+use alloc::{collections::btree_set::BTreeSet, vec::Vec};
+use embassy_time::{Instant, Timer};
+use esp_hal::peripherals::{IEEE802154, RADIO_CLK};
+use esp_ieee802154::{Config, Ieee802154};
+use esp_println::println;
+
+use crate::{
+    channels,
+    dot15d4::{Frame, PanSighting},
+    lights::{self, Color},
+    record::Record,
+    spectrum, storage,
+};
+
+/// 2.4 GHz 802.15.4 channels (Zigbee and Thread both live here)
+pub const CHANNELS: core::ops::RangeInclusive<u8> = 11..=26;
+
+/// Beacons only go out when someone asks (or every few seconds at most), so
+/// listen longer than Wi-Fi does
+const DWELL_MS: u64 = 1_000;
+
+/// How often to check the receive queue while dwelling
+const POLL_MS: u64 = 5;
+
+/// Sightings remembered for deduplication; past this only new networks are
+/// stored, not every node
+const MAX_SEEN: usize = 512;
+
+/// Energy detect samples per channel visit, spread over the dwell
+const ED_SAMPLES: u64 = 8;
+
+/// ED integration time, in 16us units (8 symbol periods is what 802.15.4
+/// asks for; take a bit longer to catch bursty Wi-Fi)
+const ED_DURATION: u32 = 64;
+
+/// Radio command opcodes, from the ESP-IDF 802.15.4 low level driver
+const CMD_ED_START: u8 = 0x44;
+const CMD_STOP: u8 = 0x45;
+
+/// Print the spectrum and store it every this many sweeps
+const SUMMARY_EVERY: u32 = 4;
+
+/// How long green and blue stay on for a new network
+const BLINK_MS: u64 = 200;
+
+/// The new network blink's colors
+const BLINK: [Color; 2] = [Color::Green, Color::Blue];
+
+#[embassy_executor::task]
+pub async fn start_ieee802154(radio: IEEE802154, mut radio_clock: RADIO_CLK) {
+    let mut ieee802154 = Ieee802154::new(radio, &mut radio_clock);
+    println!("802.15.4 initialized");
+
+    let mut seen: BTreeSet<Vec<u8>> = BTreeSet::new();
+    let mut sweeps = 0;
+    // This runs on the radio executor, so nothing here waits on storage or
+    // the lights: records are queued with `try_append`, and the blink is
+    // turned off from this loop when it's due
+    let mut blinking: Option<Instant> = None;
+
+    for channel in CHANNELS.cycle() {
+        ieee802154.set_config(Config {
+            channel,
+            promiscuous: true,
+            rx_when_idle: true,
+            auto_ack_rx: false,
+            auto_ack_tx: false,
+            ..Default::default()
+        });
+        ieee802154.start_receive();
+
+        let started = Instant::now();
+        let mut samples = 0;
+        while started.elapsed().as_millis() < DWELL_MS {
+            if started.elapsed().as_millis() >= samples * DWELL_MS / ED_SAMPLES {
+                spectrum::record(channel, energy_detect(&mut ieee802154).await);
+                samples += 1;
+            }
+
+            let mut new_network = false;
+            while let Some(raw) = ieee802154.get_raw_received() {
+                // First byte is the PHY length, FCS included
+                let len = raw.data[0] as usize;
+                let Some(frame) = raw.data.get(1..=len).and_then(Frame::parse) else {
+                    continue;
+                };
+
+                let Some(sighting) = PanSighting::from_frame(raw.channel, &frame) else {
+                    continue;
+                };
+
+                let network = sighting.network.is_some();
+                let bytes = Record::Pan(sighting.clone()).encode();
+                if seen.contains(&bytes) || (seen.len() >= MAX_SEEN && !network) {
+                    continue;
+                }
+
+                println!("{}", Record::Pan(sighting));
+                // If storage is behind, leave it out of `seen` so it's tried
+                // again when it's next heard
+                if storage::try_append(bytes.clone()) {
+                    seen.insert(bytes);
+                    new_network |= network;
+                }
+            }
+
+            if new_network && lights::try_set(&BLINK, 100) {
+                blinking = Some(Instant::now());
+            }
+            if blinking.is_some_and(|at| at.elapsed().as_millis() >= BLINK_MS)
+                && lights::try_set(&BLINK, 0)
+            {
+                blinking = None;
+            }
+
+            Timer::after_millis(POLL_MS).await;
+        }
+
+        if channel == *CHANNELS.end() {
+            sweeps += 1;
+            if sweeps % SUMMARY_EVERY == 0 {
+                let interval = spectrum::take();
+                let wifi = channels::persisted();
+                println!("{}", interval.summary(wifi.as_ref()));
+                storage::try_append(Record::Spectrum(interval.combined(wifi.as_ref())).encode());
+            }
+        }
+    }
+}
+
+/// Energy on the current channel, in dBm. The driver doesn't expose energy
+/// detect, so run the radio's ED command directly and go back to receiving
+/// afterwards.
+async fn energy_detect(ieee802154: &mut Ieee802154<'_>) -> i8 {
+    registers()
+        .command()
+        .write(|w| unsafe { w.opcode().bits(CMD_STOP) });
+    registers()
+        .ed_scan_duration()
+        .write(|w| unsafe { w.ed_scan_duration().bits(ED_DURATION) });
+    registers()
+        .command()
+        .write(|w| unsafe { w.opcode().bits(CMD_ED_START) });
+
+    Timer::after_micros(ED_DURATION as u64 * 16 + 200).await;
+    let rssi = registers().ed_scan_cfg().read().ed_rss().bits() as i8;
+
+    ieee802154.start_receive();
+    rssi
+}
+
+/// The radio's registers. Not kept across an await, so the task stays
+/// `Send` for the radio executor.
+fn registers() -> &'static esp32c6::ieee802154::RegisterBlock {
+    unsafe { &*esp32c6::IEEE802154::ptr() }
+}
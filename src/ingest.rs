@@ -0,0 +1,186 @@
+//! Everything done with a sniffed Wi-Fi frame, away from the driver that
+//! caught it, so the simulator can feed it recorded ones.
+
+use core::{
+    cell::RefCell,
+    sync::atomic::{AtomicU32, Ordering},
+};
+
+use alloc::{
+    collections::{btree_map::BTreeMap, btree_set::BTreeSet},
+    string::{String, ToString},
+    vec::Vec,
+};
+use critical_section::Mutex;
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, channel::Channel};
+use embassy_time::Instant;
+use ieee80211::{match_frames, mgmt_frame::BeaconFrame};
+
+use crate::{
+    alerts, allowlist, channels, fingerprint, foxhunt,
+    frame::{self, MacAddress, Management},
+    occupancy,
+    record::Record,
+    storage,
+    wids::Wids,
+};
+
+static KNOWN_SSIDS: Mutex<RefCell<BTreeSet<String>>> = Mutex::new(RefCell::new(BTreeSet::new()));
+
+static WIDS: Mutex<RefCell<Wids>> = Mutex::new(RefCell::new(Wids::new()));
+
+/// Rogue BSSIDs to remember having reported. Past this the one heard from
+/// longest ago is forgotten, and alerts again if it comes back.
+const MAX_ROGUES: usize = 32;
+
+/// BSSIDs we've already raised an allowlist alert for, to when we last heard
+/// them
+static REPORTED_ROGUES: Mutex<RefCell<BTreeMap<MacAddress, u64>>> =
+    Mutex::new(RefCell::new(BTreeMap::new()));
+
+/// A sniffed frame, copied out of the driver's buffer for `start_frames`.
+pub struct Sniffed {
+    /// Management frames whole; of the rest, only the header is looked at
+    pub data: Vec<u8>,
+    pub len: u16,
+    pub channel: u8,
+    pub rssi: i8,
+    pub rate: u8,
+    pub phy: channels::Phy,
+    pub wide: bool,
+}
+
+/// Frames waiting for `start_frames`. Bursts queue here rather than in the
+/// driver; past this they're dropped, and counted.
+static FRAMES: Channel<CriticalSectionRawMutex, Sniffed, 16> = Channel::new();
+static DROPPED_FRAMES: AtomicU32 = AtomicU32::new(0);
+
+/// Frames lost because `start_frames` was behind, this boot.
+pub fn dropped_frames() -> u32 {
+    DROPPED_FRAMES.load(Ordering::Relaxed)
+}
+
+/// Hand a frame over without waiting; safe from the sniffer callback.
+pub fn queue(rx: Sniffed) {
+    if FRAMES.try_send(rx).is_err() {
+        DROPPED_FRAMES.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Everything we do with sniffed frames. Runs on the radio executor, ahead
+/// of the lights and storage.
+#[embassy_executor::task]
+pub async fn start_frames() {
+    loop {
+        let rx = FRAMES.receive().await;
+        process(&rx);
+    }
+}
+
+fn process(rx: &Sniffed) {
+    let now = Instant::now().as_millis();
+    let channel = rx.channel;
+    let rssi = rx.rssi;
+    let data = rx.data.as_slice();
+
+    if let Some(kind) = frame::frame_type(data) {
+        channels::record(&channels::Rx {
+            channel,
+            kind,
+            len: rx.len,
+            rate: rx.rate,
+            phy: rx.phy,
+            wide: rx.wide,
+        });
+    }
+
+    if let Some(transmitter) = frame::transmitter(data) {
+        foxhunt::observe_frame(transmitter, rssi, now);
+    }
+
+    if let Some(alert) = critical_section::with(|cs| WIDS.borrow_ref_mut(cs).observe(data, now)) {
+        alerts::raise(alert);
+    }
+
+    if let Some(sighting) = fingerprint::observe(data, now) {
+        storage::try_append(Record::Device(sighting).encode());
+    }
+
+    occupancy::observe(data, rssi, now);
+
+    let _ = match_frames! {
+        data,
+        beacon = BeaconFrame => {
+            let Some(ssid) = beacon.ssid() else {
+                return;
+            };
+
+            if let Some(frame) = Management::parse(data) {
+                if let Some(fields) = frame.beacon() {
+                    let beacon_channel = fields.channel().unwrap_or(channel);
+                    foxhunt::observe_beacon(frame.bssid, ssid, beacon_channel, rssi, now);
+
+                    if let Some((stations, utilization)) = fields.bss_load() {
+                        channels::bss_load(channel, stations, utilization);
+                    }
+
+                    if let Some(alert) = allowlist::check(ssid, frame.bssid, fields.security()) {
+                        if critical_section::with(|cs| {
+                            first_report(&mut REPORTED_ROGUES.borrow_ref_mut(cs), frame.bssid, now)
+                        }) {
+                            alerts::raise(alert);
+                        }
+                    }
+                }
+            }
+
+            critical_section::with(|cs| {
+                // Hidden networks beacon an empty SSID, or one of zeros
+                let hidden = ssid.bytes().all(|byte| byte == 0);
+                if KNOWN_SSIDS.borrow_ref_mut(cs).insert(ssid.to_string()) && !hidden {
+                    storage::try_append(Record::Network(ssid.to_string()).encode());
+                }
+            });
+        }
+    };
+}
+
+/// Note hearing `bssid` at `now`. True unless it's already been reported
+/// and not forgotten since.
+fn first_report(reported: &mut BTreeMap<MacAddress, u64>, bssid: MacAddress, now: u64) -> bool {
+    if reported.len() >= MAX_ROGUES && !reported.contains_key(&bssid) {
+        let oldest = reported
+            .iter()
+            .min_by_key(|(_, heard)| **heard)
+            .map(|(bssid, _)| *bssid);
+        if let Some(oldest) = oldest {
+            reported.remove(&oldest);
+        }
+    }
+
+    reported.insert(bssid, now).is_none()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn forgets_the_rogue_heard_from_longest_ago() {
+        let mut reported = BTreeMap::new();
+        let rogue = |index: usize| [0x02, 0, 0, 0, 0, index as u8];
+
+        for index in 0..MAX_ROGUES {
+            assert!(first_report(&mut reported, rogue(index), index as u64));
+        }
+        // Still about, so it stays reported
+        assert!(!first_report(&mut reported, rogue(0), 100));
+
+        assert!(first_report(&mut reported, rogue(MAX_ROGUES), 101));
+        assert_eq!(reported.len(), MAX_ROGUES);
+        assert!(!reported.contains_key(&rogue(1)));
+        assert!(!first_report(&mut reported, rogue(0), 102));
+        assert!(first_report(&mut reported, rogue(1), 103));
+        assert!(!reported.contains_key(&rogue(2)));
+    }
+}
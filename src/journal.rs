@@ -0,0 +1,745 @@
+//! The record store's on-flash format: CRC-checked entries appended one after
+//! another through a run of sectors, each numbered with an ID that never
+//! changes.
+//!
+//! Every sector in use starts with a 16-byte header: the magic, then (only
+//! meaningful in the first sector) the generation, bumped whenever the log is
+//! erased, and the commit state. Entries follow back to back: length, CRC,
+//! ID, then the payload padded to a word. Erased flash reads as 0xff, so a
+//! length of 0xffff is free space. Nothing is rewritten in place; the log is
+//! only ever appended to or erased as a whole.
+//!
+//! Appended entries are held back until they fill a flash page, so a burst
+//! of small ones goes out in a few page-sized writes; `sync` writes out the
+//! rest. Until then only this `Journal` can see them.
+//!
+//! Generic over the flash so it runs against RAM on a host.
+
+use alloc::vec::Vec;
+use embedded_storage::nor_flash::NorFlash;
+use wif_protocol::crc::crc16;
+
+/// "WIF2"
+const MAGIC: u32 = 0x5749_4632;
+
+pub const SECTOR_SIZE: u32 = 4096;
+const HEADER: u32 = 16;
+/// Flash is programmed a page at a time
+const PAGE: u32 = 256;
+const ENTRY_HEADER: u32 = 8;
+
+const FREE: u16 = 0xffff;
+
+/// Commit states: a log being filled by a migration is open until the last
+/// record is in.
+const OPEN: u32 = 0xffff_ffff;
+const COMMITTED: u32 = 0;
+
+/// Longest payload we'll store
+pub const MAX_ENTRY: usize = 1024;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Error<E> {
+    Full,
+    TooLong,
+    Flash(E),
+}
+
+impl<E> From<E> for Error<E> {
+    fn from(err: E) -> Self {
+        Self::Flash(err)
+    }
+}
+
+enum Slot {
+    Free,
+    /// Something that isn't an entry; the rest of the sector is unusable
+    Broken,
+    /// `bytes` is `None` if the CRC doesn't match, e.g. power failed while
+    /// it was being written
+    Entry {
+        id: u32,
+        bytes: Option<Vec<u8>>,
+        next: u32,
+    },
+}
+
+pub struct Journal<F> {
+    flash: F,
+    start: u32,
+    sectors: u32,
+    formatted: bool,
+    generation: u32,
+    committed: bool,
+    /// ID of the first entry in each sector used so far
+    firsts: Vec<u32>,
+    /// Where the next entry goes
+    end: u32,
+    next_id: u32,
+    /// Entries not written yet, which belong just before `end`
+    pending: Vec<u8>,
+    /// Partway through `clear_step`: the next sector to erase, and how many
+    clearing: Option<(u32, u32)>,
+}
+
+impl<F: NorFlash> Journal<F> {
+    /// The log in `sectors` sectors from `start`, whatever state it's in.
+    pub fn open(flash: F, start: u32, sectors: u32) -> Result<Self, F::Error> {
+        let mut journal = Self {
+            flash,
+            start,
+            sectors,
+            formatted: false,
+            generation: 0,
+            committed: false,
+            firsts: Vec::new(),
+            end: start + HEADER,
+            next_id: 0,
+            pending: Vec::new(),
+            clearing: None,
+        };
+        journal.scan()?;
+        Ok(journal)
+    }
+
+    pub fn flash(&mut self) -> &mut F {
+        &mut self.flash
+    }
+
+    /// Whether the log holds a finished store, as opposed to being blank or
+    /// half way through a migration.
+    pub fn committed(&self) -> bool {
+        self.formatted && self.committed
+    }
+
+    /// One more than the newest ID.
+    pub fn next_id(&self) -> u32 {
+        self.next_id
+    }
+
+    /// Bumped each time the log is erased, when IDs start again from 0.
+    pub fn generation(&self) -> u32 {
+        self.generation
+    }
+
+    /// Erase the whole region and start an empty log, open (for a migration
+    /// to fill) or already committed.
+    pub fn format(&mut self, committed: bool) -> Result<(), F::Error> {
+        self.flash
+            .erase(self.start, self.start + self.sectors * SECTOR_SIZE)?;
+        self.start_log(committed)
+    }
+
+    /// Throw away every entry. Only the sectors in use need erasing, plus
+    /// the next in case an append was cut off just after starting it.
+    pub fn clear(&mut self) -> Result<(), F::Error> {
+        while !self.clear_step()? {}
+        Ok(())
+    }
+
+    /// `clear` a sector at a time, for callers with other things to do in
+    /// between; true once the log is empty. The first sector goes first, so
+    /// from then on the log reads as blank.
+    pub fn clear_step(&mut self) -> Result<bool, F::Error> {
+        let (sector, used) = match self.clearing {
+            Some(clearing) => clearing,
+            None => (0, (self.sector_of(self.end) + 2).min(self.sectors)),
+        };
+        if sector == used {
+            self.start_log(true)?;
+            return Ok(true);
+        }
+
+        self.flash
+            .erase(self.base(sector), self.base(sector) + SECTOR_SIZE)?;
+        self.clearing = Some((sector + 1, used));
+        if sector == 0 {
+            self.formatted = false;
+            self.firsts.clear();
+            self.pending.clear();
+            self.end = self.start + HEADER;
+            self.next_id = 0;
+        }
+        Ok(false)
+    }
+
+    fn start_log(&mut self, committed: bool) -> Result<(), F::Error> {
+        let generation = self.generation.wrapping_add(1);
+        let state = if committed { COMMITTED } else { OPEN };
+
+        let mut header = [0xffu8; HEADER as usize];
+        header[..4].copy_from_slice(&MAGIC.to_le_bytes());
+        header[4..8].copy_from_slice(&generation.to_le_bytes());
+        header[8..12].copy_from_slice(&state.to_le_bytes());
+        self.flash.write(self.start, &header)?;
+
+        self.formatted = true;
+        self.generation = generation;
+        self.committed = committed;
+        self.firsts.clear();
+        self.end = self.start + HEADER;
+        self.next_id = 0;
+        self.pending.clear();
+        self.clearing = None;
+        Ok(())
+    }
+
+    /// Mark an open log finished. Flash bits only go from 1 to 0 without an
+    /// erase, so this is a single write over the erased state.
+    pub fn commit(&mut self) -> Result<(), F::Error> {
+        self.sync()?;
+        self.flash.write(self.start + 8, &COMMITTED.to_le_bytes())?;
+        self.committed = true;
+        Ok(())
+    }
+
+    /// Store `bytes`, returning its ID. It reaches flash once its page is
+    /// full, or on `sync`. If a write fails, the entry isn't taken on, and
+    /// the ones before it are still pending.
+    pub fn append(&mut self, bytes: &[u8]) -> Result<u32, Error<F::Error>> {
+        if bytes.len() > MAX_ENTRY {
+            return Err(Error::TooLong);
+        }
+        if !self.formatted {
+            self.format(true)?;
+        }
+
+        let size = ENTRY_HEADER + padded(bytes.len());
+        let mut sector = self.sector_of(self.end);
+        if self.end + size > self.base(sector) + SECTOR_SIZE {
+            sector += 1;
+            if sector >= self.sectors {
+                return Err(Error::Full);
+            }
+
+            self.sync()?;
+            let mut header = [0xffu8; HEADER as usize];
+            header[..4].copy_from_slice(&MAGIC.to_le_bytes());
+            self.flash.write(self.base(sector), &header)?;
+            self.end = self.base(sector) + HEADER;
+        }
+
+        let id = self.next_id;
+        let mut entry = Vec::with_capacity(size as usize);
+        entry.extend_from_slice(&(bytes.len() as u16).to_le_bytes());
+        entry.extend_from_slice(&checksum(id, bytes).to_le_bytes());
+        entry.extend_from_slice(&id.to_le_bytes());
+        entry.extend_from_slice(bytes);
+        entry.resize(size as usize, 0xff);
+
+        // Write out every page that's now full
+        let from = self.end - self.pending.len() as u32;
+        self.pending.extend_from_slice(&entry);
+        let full = (self.end + size) / PAGE * PAGE;
+        if full > from {
+            let len = (full - from) as usize;
+            if let Err(err) = self.flash.write(from, &self.pending[..len]) {
+                self.pending.truncate(self.pending.len() - entry.len());
+                return Err(err.into());
+            }
+            self.pending.drain(..len);
+        }
+
+        if self.firsts.len() <= sector as usize {
+            self.firsts.resize(sector as usize, id);
+            self.firsts.push(id);
+        }
+        self.end += size;
+        self.next_id = id + 1;
+        Ok(id)
+    }
+
+    /// Write out any appended entries still held back.
+    pub fn sync(&mut self) -> Result<(), F::Error> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+        let from = self.end - self.pending.len() as u32;
+        self.flash.write(from, &self.pending)?;
+        self.pending.clear();
+        Ok(())
+    }
+
+    /// Whether there are appended entries still to write.
+    pub fn pending(&self) -> bool {
+        !self.pending.is_empty()
+    }
+
+    /// How many sectors hold entries; `walk_sector` takes 0 up to this.
+    pub fn used_sectors(&self) -> u32 {
+        self.firsts.len() as u32
+    }
+
+    /// The entry with ID `id`, if it's there and intact.
+    pub fn get(&mut self, id: u32) -> Result<Option<Vec<u8>>, F::Error> {
+        if id >= self.next_id {
+            return Ok(None);
+        }
+
+        // Sectors that lost all their entries share a first ID with a
+        // neighbour, so look through every sector that could hold it
+        let end = self.firsts.partition_point(|first| *first <= id);
+        let Some(&first) = end.checked_sub(1).and_then(|last| self.firsts.get(last)) else {
+            return Ok(None);
+        };
+        let begin = self.firsts.partition_point(|other| *other < first);
+
+        let mut found = None;
+        for sector in begin..end {
+            self.walk_sector(sector as u32, |entry, bytes| {
+                if entry == id {
+                    found = Some(bytes.to_vec());
+                }
+                found.is_none() && entry < id
+            })?;
+            if found.is_some() {
+                break;
+            }
+        }
+        Ok(found)
+    }
+
+    /// Hand every intact entry to `f`, oldest first, until it returns false.
+    pub fn walk(&mut self, f: impl FnMut(u32, &[u8]) -> bool) -> Result<(), F::Error> {
+        self.walk_since(0, f)
+    }
+
+    /// Like `walk`, starting with the first entry whose ID is at least `id`.
+    /// Sectors before the one holding it aren't read.
+    pub fn walk_since(
+        &mut self,
+        id: u32,
+        mut f: impl FnMut(u32, &[u8]) -> bool,
+    ) -> Result<(), F::Error> {
+        // As in `get`, sectors sharing a first ID could all hold it
+        let end = self.firsts.partition_point(|first| *first <= id);
+        let begin = match end.checked_sub(1).and_then(|last| self.firsts.get(last)) {
+            Some(&first) => self.firsts.partition_point(|other| *other < first),
+            None => 0,
+        };
+
+        for sector in begin as u32..self.firsts.len() as u32 {
+            let mut more = true;
+            self.walk_sector(sector, |entry, bytes| {
+                if entry >= id {
+                    more = f(entry, bytes);
+                }
+                more
+            })?;
+            if !more {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    /// Pick up anything another `Journal` on the same flash has done since
+    /// we looked.
+    pub fn refresh(&mut self) -> Result<(), F::Error> {
+        let mut header = [0u8; 12];
+        self.flash.read(self.start, &mut header)?;
+        let magic = u32::from_le_bytes(header[..4].try_into().unwrap());
+        let generation = u32::from_le_bytes(header[4..8].try_into().unwrap());
+
+        if magic != MAGIC || !self.formatted || generation != self.generation {
+            return self.scan();
+        }
+        self.walk_from(self.end)
+    }
+
+    fn scan(&mut self) -> Result<(), F::Error> {
+        let mut header = [0u8; 12];
+        self.flash.read(self.start, &mut header)?;
+        let magic = u32::from_le_bytes(header[..4].try_into().unwrap());
+
+        self.formatted = magic == MAGIC;
+        self.generation = u32::from_le_bytes(header[4..8].try_into().unwrap());
+        self.committed = u32::from_le_bytes(header[8..12].try_into().unwrap()) == COMMITTED;
+        self.firsts.clear();
+        self.end = self.start + HEADER;
+        self.next_id = 0;
+        self.pending.clear();
+        if !self.formatted {
+            return Ok(());
+        }
+
+        let mut used = 1;
+        while used < self.sectors && self.sector_magic(used)? == MAGIC {
+            used += 1;
+        }
+
+        // Full sectors only need their first ID; the last two are walked to
+        // find the end, two in case power went right after starting a sector
+        let walk_from = used.saturating_sub(2);
+        for sector in 0..walk_from {
+            let mut first = None;
+            self.walk_sector(sector, |id, _| {
+                first = Some(id);
+                false
+            })?;
+            let first = first.or(self.firsts.last().copied()).unwrap_or(0);
+            self.firsts.push(first);
+            self.next_id = first;
+        }
+
+        self.walk_from(self.base(walk_from) + HEADER)
+    }
+
+    /// Walk forward from `offset` to the end of the log, noting IDs as we go.
+    fn walk_from(&mut self, mut offset: u32) -> Result<(), F::Error> {
+        let mut sector = self.sector_of(offset);
+
+        loop {
+            let limit = self.base(sector) + SECTOR_SIZE;
+            // Free space is where the log ends, unless the next entry didn't
+            // fit and went on to the next sector
+            let mut free = None;
+            while offset + ENTRY_HEADER <= limit {
+                match self.slot(offset, limit)? {
+                    Slot::Free => {
+                        free = Some(offset);
+                        break;
+                    }
+                    Slot::Broken => break,
+                    Slot::Entry { id, bytes, next } => {
+                        if bytes.is_some() {
+                            if self.firsts.len() <= sector as usize {
+                                self.firsts.resize(sector as usize, id);
+                                self.firsts.push(id);
+                            }
+                            self.next_id = self.next_id.max(id + 1);
+                        }
+                        offset = next;
+                    }
+                }
+            }
+
+            sector += 1;
+            if sector >= self.sectors || self.sector_magic(sector)? != MAGIC {
+                self.end = free.unwrap_or(limit);
+                return Ok(());
+            }
+            offset = self.base(sector) + HEADER;
+        }
+    }
+
+    /// Hand each intact entry in one sector to `f`, oldest first, until it
+    /// returns false.
+    pub fn walk_sector(
+        &mut self,
+        sector: u32,
+        mut f: impl FnMut(u32, &[u8]) -> bool,
+    ) -> Result<(), F::Error> {
+        let limit = self.base(sector) + SECTOR_SIZE;
+        let mut offset = self.base(sector) + HEADER;
+
+        while offset + ENTRY_HEADER <= limit {
+            match self.slot(offset, limit)? {
+                Slot::Free | Slot::Broken => break,
+                Slot::Entry { id, bytes, next } => {
+                    if let Some(bytes) = bytes {
+                        if !f(id, &bytes) {
+                            break;
+                        }
+                    }
+                    offset = next;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn slot(&mut self, offset: u32, limit: u32) -> Result<Slot, F::Error> {
+        let mut header = [0u8; ENTRY_HEADER as usize];
+        self.read(offset, &mut header)?;
+        let len = u16::from_le_bytes([header[0], header[1]]);
+        let crc = u16::from_le_bytes([header[2], header[3]]);
+        let id = u32::from_le_bytes(header[4..].try_into().unwrap());
+
+        if len == FREE {
+            return Ok(Slot::Free);
+        }
+        let next = offset + ENTRY_HEADER + padded(len as usize);
+        if len as usize > MAX_ENTRY || next > limit {
+            return Ok(Slot::Broken);
+        }
+
+        let mut bytes = alloc::vec![0u8; padded(len as usize) as usize];
+        self.read(offset + ENTRY_HEADER, &mut bytes)?;
+        bytes.truncate(len as usize);
+
+        let intact = checksum(id, &bytes) == crc;
+        Ok(Slot::Entry {
+            id,
+            bytes: intact.then_some(bytes),
+            next,
+        })
+    }
+
+    /// Read flash as it will be once everything's written: where it's
+    /// pending, that's erased flash with the pending bytes on top.
+    fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), F::Error> {
+        self.flash.read(offset, bytes)?;
+
+        let from = self.end - self.pending.len() as u32;
+        let begin = offset.max(from);
+        let end = (offset + bytes.len() as u32).min(self.end);
+        if begin < end {
+            bytes[(begin - offset) as usize..(end - offset) as usize]
+                .copy_from_slice(&self.pending[(begin - from) as usize..(end - from) as usize]);
+        }
+        Ok(())
+    }
+
+    fn sector_magic(&mut self, sector: u32) -> Result<u32, F::Error> {
+        let mut magic = [0u8; 4];
+        self.flash.read(self.base(sector), &mut magic)?;
+        Ok(u32::from_le_bytes(magic))
+    }
+
+    fn base(&self, sector: u32) -> u32 {
+        self.start + sector * SECTOR_SIZE
+    }
+
+    /// The sector `offset` is in, counting the very end of a sector as part
+    /// of it.
+    fn sector_of(&self, offset: u32) -> u32 {
+        (offset - self.start - 1) / SECTOR_SIZE
+    }
+}
+
+fn padded(len: usize) -> u32 {
+    (len as u32 + 3) & !3
+}
+
+fn checksum(id: u32, bytes: &[u8]) -> u16 {
+    let mut data = Vec::with_capacity(4 + bytes.len());
+    data.extend_from_slice(&id.to_le_bytes());
+    data.extend_from_slice(bytes);
+    crc16(&data)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use embedded_storage::nor_flash::{ErrorType, NorFlashError, NorFlashErrorKind, ReadNorFlash};
+
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    pub struct PowerCut;
+
+    impl NorFlashError for PowerCut {
+        fn kind(&self) -> NorFlashErrorKind {
+            NorFlashErrorKind::Other
+        }
+    }
+
+    /// NOR flash in RAM that loses power during write or erase number
+    /// `budget`. A write cut off gets only its first half onto the chip, an
+    /// erase none of the way.
+    #[derive(Clone)]
+    pub struct Ram {
+        pub bytes: Vec<u8>,
+        pub budget: Option<usize>,
+    }
+
+    impl Ram {
+        pub fn new(size: u32) -> Self {
+            Self {
+                bytes: alloc::vec![0xff; size as usize],
+                budget: None,
+            }
+        }
+
+        /// The chip as the next boot finds it.
+        pub fn reboot(&self) -> Self {
+            Self {
+                bytes: self.bytes.clone(),
+                budget: None,
+            }
+        }
+
+        fn spend(&mut self) -> bool {
+            match &mut self.budget {
+                Some(0) => false,
+                Some(budget) => {
+                    *budget -= 1;
+                    *budget > 0
+                }
+                None => true,
+            }
+        }
+    }
+
+    impl ErrorType for Ram {
+        type Error = PowerCut;
+    }
+
+    impl ReadNorFlash for Ram {
+        const READ_SIZE: usize = 1;
+
+        fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), PowerCut> {
+            let offset = offset as usize;
+            bytes.copy_from_slice(&self.bytes[offset..offset + bytes.len()]);
+            Ok(())
+        }
+
+        fn capacity(&self) -> usize {
+            self.bytes.len()
+        }
+    }
+
+    impl NorFlash for Ram {
+        const WRITE_SIZE: usize = 4;
+        const ERASE_SIZE: usize = SECTOR_SIZE as usize;
+
+        fn erase(&mut self, from: u32, to: u32) -> Result<(), PowerCut> {
+            assert!(from.is_multiple_of(SECTOR_SIZE) && to.is_multiple_of(SECTOR_SIZE));
+            if self.budget == Some(0) {
+                return Err(PowerCut);
+            }
+            if !self.spend() {
+                return Err(PowerCut);
+            }
+            self.bytes[from as usize..to as usize].fill(0xff);
+            Ok(())
+        }
+
+        fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), PowerCut> {
+            assert!(offset.is_multiple_of(4) && bytes.len().is_multiple_of(4));
+            if self.budget == Some(0) {
+                return Err(PowerCut);
+            }
+            let more = self.spend();
+            let len = if more { bytes.len() } else { bytes.len() / 2 };
+            let offset = offset as usize;
+            for (cell, byte) in self.bytes[offset..offset + len].iter_mut().zip(bytes) {
+                *cell &= byte;
+            }
+            more.then_some(()).ok_or(PowerCut)
+        }
+    }
+
+    const SECTORS: u32 = 4;
+
+    fn journal(flash: Ram) -> Journal<Ram> {
+        Journal::open(flash, 0, SECTORS).unwrap()
+    }
+
+    fn entries(journal: &mut Journal<Ram>) -> Vec<(u32, Vec<u8>)> {
+        let mut entries = Vec::new();
+        journal
+            .walk(|id, bytes| {
+                entries.push((id, bytes.to_vec()));
+                true
+            })
+            .unwrap();
+        entries
+    }
+
+    fn record(id: u32) -> Vec<u8> {
+        // 32 bytes with the entry header, which doesn't divide a page
+        alloc::format!("record {id:017}").into_bytes()
+    }
+
+    #[test]
+    fn reads_back_after_reopening() {
+        let mut log = journal(Ram::new(SECTORS * SECTOR_SIZE));
+        for id in 0..400 {
+            assert_eq!(log.append(&record(id)), Ok(id));
+        }
+        log.sync().unwrap();
+
+        let mut log = journal(log.flash().reboot());
+        assert_eq!(log.next_id(), 400);
+        assert!(log.used_sectors() > 1);
+        assert_eq!(log.get(321).unwrap(), Some(record(321)));
+        let expected: Vec<_> = (0..400).map(|id| (id, record(id))).collect();
+        assert_eq!(entries(&mut log), expected);
+    }
+
+    #[test]
+    fn drops_an_entry_torn_at_a_page_boundary() {
+        let mut log = journal(Ram::new(SECTORS * SECTOR_SIZE));
+        // Entries start at 16 and take 32 bytes, so the 8th straddles the
+        // first page: its append writes out the first half and holds back
+        // the rest, then power goes
+        for id in 0..8 {
+            log.append(&record(id)).unwrap();
+        }
+        assert!(log.pending());
+
+        let mut log = journal(log.flash().reboot());
+        assert_eq!(log.next_id(), 7);
+        assert_eq!(log.get(7).unwrap(), None);
+
+        // New entries go after the torn one and survive the next boot
+        assert_eq!(log.append(b"after"), Ok(7));
+        log.sync().unwrap();
+        let mut log = journal(log.flash().reboot());
+        let mut expected: Vec<_> = (0..7).map(|id| (id, record(id))).collect();
+        expected.push((7, b"after".to_vec()));
+        assert_eq!(entries(&mut log), expected);
+    }
+
+    #[test]
+    fn drops_an_entry_cut_off_mid_write() {
+        let mut flash = Ram::new(SECTORS * SECTOR_SIZE);
+        // Formatting erases and writes the header, then the 8th entry fills
+        // the first page
+        flash.budget = Some(3);
+        let mut log = journal(flash);
+        for id in 0..7 {
+            log.append(&record(id)).unwrap();
+        }
+        assert_eq!(log.append(&record(7)), Err(Error::Flash(PowerCut)));
+
+        // Only the first half of the page made it: the first three entries
+        // and part of the fourth
+        let mut log = journal(log.flash().reboot());
+        let intact = log.next_id();
+        assert_eq!(intact, 3);
+        let expected: Vec<_> = (0..intact).map(|id| (id, record(id))).collect();
+        assert_eq!(entries(&mut log), expected);
+        assert_eq!(log.append(b"after"), Ok(intact));
+    }
+
+    #[test]
+    fn scans_a_freshly_started_sector() {
+        let mut log = journal(Ram::new(SECTORS * SECTOR_SIZE));
+        let mut id = 0;
+        while log.used_sectors() < 2 {
+            log.append(&record(id)).unwrap();
+            id += 1;
+        }
+        // Power goes with the new sector's header written but the entry that
+        // started it still held back
+        assert!(log.pending());
+        let last = id - 1;
+
+        let mut log = journal(log.flash().reboot());
+        assert_eq!(log.next_id(), last);
+        assert_eq!(log.get(last - 1).unwrap(), Some(record(last - 1)));
+
+        assert_eq!(log.append(b"after"), Ok(last));
+        log.sync().unwrap();
+        let mut log = journal(log.flash().reboot());
+        assert_eq!(log.next_id(), last + 1);
+        assert_eq!(log.get(last).unwrap(), Some(b"after".to_vec()));
+        assert_eq!(entries(&mut log).len() as u32, last + 1);
+    }
+
+    #[test]
+    fn starts_over_after_a_clear() {
+        let mut log = journal(Ram::new(SECTORS * SECTOR_SIZE));
+        for id in 0..300 {
+            log.append(&record(id)).unwrap();
+        }
+        log.clear().unwrap();
+        assert_eq!(log.next_id(), 0);
+
+        log.append(b"fresh").unwrap();
+        log.sync().unwrap();
+        let mut log = journal(log.flash().reboot());
+        assert_eq!(entries(&mut log), [(0, b"fresh".to_vec())]);
+    }
+}
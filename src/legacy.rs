@@ -0,0 +1,195 @@
+//! The store layout before `journal`: a u32 count at 0x9000, then a
+//! 256-byte slot per record, a length byte followed by the record.
+//!
+//! `upgrade` moves a legacy store into the journal once, at boot. The legacy
+//! slots aren't touched until the journal holding their copy is committed,
+//! so if power fails part way the next boot simply starts over.
+
+use alloc::vec::Vec;
+use embedded_storage::nor_flash::{NorFlash, ReadNorFlash};
+use esp_println::println;
+
+use crate::journal::{self, Journal};
+
+pub const START: u32 = 0x9000;
+const SLOT: u32 = 256;
+
+/// How many records the legacy store at `START` holds, if there is one.
+/// Slots at or past `limit` can't be legacy data, they belong to whatever
+/// comes after it.
+pub fn count<F: ReadNorFlash>(flash: &mut F, limit: u32) -> Result<Option<u32>, F::Error> {
+    let mut bytes = [0u8; 4];
+    flash.read(START, &mut bytes)?;
+
+    // Erased, or emptied by an erase
+    let count = u32::from_le_bytes(bytes);
+    if count == u32::MAX || count == 0 {
+        return Ok(None);
+    }
+
+    let fits = limit.saturating_sub(START + 4) / SLOT;
+    if count > fits {
+        println!("legacy: {count} records claimed, only {fits} fit");
+    }
+    Ok(Some(count.min(fits)))
+}
+
+/// The record in slot `index`. Any length byte is valid: a slot holds up to
+/// 255 bytes.
+pub fn read<F: ReadNorFlash>(flash: &mut F, index: u32) -> Result<Vec<u8>, F::Error> {
+    let mut bytes = [0u8; SLOT as usize];
+    flash.read(START + 4 + SLOT * index, &mut bytes)?;
+
+    let len = bytes[0] as usize;
+    Ok(bytes[1..len + 1].to_vec())
+}
+
+/// Copy a legacy store into `journal`, which starts at `limit`, and retire
+/// it. Returns how many records came across, `None` if there was nothing to
+/// do.
+///
+/// Records are appended in order to a freshly formatted log, so each keeps
+/// its legacy index as its ID and the upload cursor still points at the
+/// same place.
+pub fn upgrade<F: NorFlash>(
+    journal: &mut Journal<F>,
+    limit: u32,
+) -> Result<Option<u32>, journal::Error<F::Error>> {
+    let Some(count) = count(journal.flash(), limit)? else {
+        return Ok(None);
+    };
+
+    // Power went after the commit last time, only the cleanup is left
+    if journal.committed() {
+        retire(journal.flash())?;
+        return Ok(None);
+    }
+
+    println!("legacy: moving {count} records");
+    journal.format(false)?;
+
+    for index in 0..count {
+        let bytes = read(journal.flash(), index)?;
+        journal.append(&bytes)?;
+    }
+
+    journal.commit()?;
+    retire(journal.flash())?;
+    Ok(Some(count))
+}
+
+/// Erasing the count's sector is enough for `count` to find nothing.
+fn retire<F: NorFlash>(flash: &mut F) -> Result<(), F::Error> {
+    flash.erase(START, START + journal::SECTOR_SIZE)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::journal::{tests::Ram, SECTOR_SIZE};
+
+    /// Where the journal starts, after room for 128 legacy slots
+    const LIMIT: u32 = START + 8 * SECTOR_SIZE;
+    const SECTORS: u32 = 4;
+
+    /// A chip with `records` in the legacy layout, byte for byte what the
+    /// old firmware left: each append wrote the length, the record, then the
+    /// new count.
+    fn image(records: &[Vec<u8>]) -> Ram {
+        let mut flash = Ram::new(LIMIT + SECTORS * SECTOR_SIZE);
+        for (index, record) in records.iter().enumerate() {
+            let slot = (START + 4 + SLOT * index as u32) as usize;
+            flash.bytes[slot] = record.len() as u8;
+            flash.bytes[slot + 1..slot + 1 + record.len()].copy_from_slice(record);
+            flash.bytes[START as usize..START as usize + 4]
+                .copy_from_slice(&(index as u32 + 1).to_le_bytes());
+        }
+        flash
+    }
+
+    /// SSIDs as the old firmware stored them, with the awkward ones: empty,
+    /// a full slot, and bytes that look like erased flash.
+    fn records() -> Vec<Vec<u8>> {
+        let mut records: Vec<Vec<u8>> = (0..30)
+            .map(|index| alloc::format!("Network {index}").into_bytes())
+            .collect();
+        records[3] = Vec::new();
+        records[7] = alloc::vec![b'x'; 255];
+        records[11] = alloc::vec![0xff; 8];
+        records
+    }
+
+    fn boot(flash: Ram) -> Journal<Ram> {
+        Journal::open(flash, LIMIT, SECTORS).unwrap()
+    }
+
+    fn entries(journal: &mut Journal<Ram>) -> Vec<Vec<u8>> {
+        let mut entries = Vec::new();
+        journal
+            .walk(|id, bytes| {
+                assert_eq!(id as usize, entries.len());
+                entries.push(bytes.to_vec());
+                true
+            })
+            .unwrap();
+        entries
+    }
+
+    #[test]
+    fn moves_every_record() {
+        let mut journal = boot(image(&records()));
+        assert_eq!(upgrade(&mut journal, LIMIT), Ok(Some(30)));
+        assert_eq!(count(journal.flash(), LIMIT), Ok(None));
+
+        let mut journal = boot(journal.flash().reboot());
+        assert!(journal.committed());
+        assert_eq!(upgrade(&mut journal, LIMIT), Ok(None));
+        assert_eq!(entries(&mut journal), records());
+    }
+
+    #[test]
+    fn leaves_an_erased_store_alone() {
+        // The old erase wrote zeros over everything, count included
+        let mut flash = image(&records());
+        flash.bytes[START as usize..LIMIT as usize].fill(0);
+
+        let mut journal = boot(flash);
+        assert_eq!(upgrade(&mut journal, LIMIT), Ok(None));
+        assert!(!journal.committed());
+    }
+
+    #[test]
+    fn only_reads_slots_before_the_journal() {
+        let mut flash = image(&records());
+        flash.bytes[START as usize..START as usize + 4].copy_from_slice(&1000u32.to_le_bytes());
+        assert_eq!(count(&mut flash, LIMIT), Ok(Some(127)));
+    }
+
+    #[test]
+    fn survives_a_power_cut_at_every_step() {
+        let mut committed_not_retired = false;
+
+        for budget in 1.. {
+            let mut flash = image(&records());
+            flash.budget = Some(budget);
+            let mut journal = boot(flash);
+            let finished = upgrade(&mut journal, LIMIT).is_ok();
+
+            // Whatever the cut left, the next boot ends up with every
+            // record, once
+            let mut journal = boot(journal.flash().reboot());
+            if journal.committed() && count(journal.flash(), LIMIT).unwrap().is_some() {
+                committed_not_retired = true;
+            }
+            upgrade(&mut journal, LIMIT).unwrap();
+            assert_eq!(count(journal.flash(), LIMIT), Ok(None), "cut at {budget}");
+            assert_eq!(entries(&mut journal), records(), "cut at {budget}");
+
+            if finished {
+                break;
+            }
+        }
+
+        assert!(committed_not_retired);
+    }
+}
@@ -1,17 +1,16 @@
 use core::{
-    borrow::{Borrow, BorrowMut},
-    cell::{RefCell, RefMut},
-    ops::Deref,
+    fmt,
+    sync::atomic::{AtomicU32, Ordering},
 };
 
-use alloc::{borrow::ToOwned, boxed::Box};
 use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, pubsub::PubSubChannel};
-use esp_hal::{
-    gpio::{AnyPin, GpioPin, Level, Output},
-    ledc::{channel::Channel, timer, LSGlobalClkSource, Ledc, LowSpeed},
-    prelude::_esp_hal_ledc_timer_TimerIFace,
+use embassy_time::{Instant, Timer};
+use esp_println::println;
+
+use crate::{
+    config,
+    error::{self, Subsystem},
 };
-use esp_hal::{ledc::channel, prelude::*};
 
 #[derive(Clone, Debug)]
 pub enum Color {
@@ -29,25 +28,21 @@ pub struct LightChange {
 }
 
 pub async fn apply(change: &LightChange) {
-    LIGHTS_CHANNEL
-        .publisher()
-        .unwrap()
-        .publish(change.clone())
-        .await;
+    error::publish(&LIGHTS_CHANNEL, change.clone()).await;
 }
 
 pub async fn change(light: Color, enabled: bool) {
     let light_change = LightChange {
         color: light,
-        brightness: if enabled { 20 } else { 0 },
+        brightness: if enabled {
+            config::with(|config| config.brightness)
+        } else {
+            0
+        },
         duration: 32,
     };
 
-    LIGHTS_CHANNEL
-        .publisher()
-        .unwrap()
-        .publish(light_change)
-        .await;
+    error::publish(&LIGHTS_CHANNEL, light_change).await;
 }
 
 pub async fn on(light: Color) {
@@ -65,26 +60,151 @@ pub async fn all_off() {
     change(Color::Blue, false).await;
 }
 
-struct Light {
-    brightness: u8,
-    channel: Channel<'static, LowSpeed, AnyPin>,
+/// Set `colors` to `brightness` percent without waiting, for the radio
+/// executor. False, with nothing changed, if the lights are too far behind
+/// to take them all.
+pub fn try_set(colors: &[Color], brightness: u8) -> bool {
+    let publisher = LIGHTS_CHANNEL.immediate_publisher();
+    if publisher.free_capacity() < colors.len() {
+        return false;
+    }
+
+    for color in colors {
+        let _ = publisher.try_publish(LightChange {
+            color: color.clone(),
+            brightness,
+            duration: 16,
+        });
+    }
+    true
 }
-impl Light {
-    fn new(
-        mut channel: Channel<'static, LowSpeed, AnyPin>,
-        timer: &'static timer::Timer<'static, LowSpeed>,
-    ) -> Self {
-        channel
-            .configure(channel::config::Config {
-                timer: timer,
-                duty_pct: 24,
-                pin_config: channel::config::PinConfig::PushPull,
+
+/// A frame ending later than this shows as a stutter: one fade's worth.
+const LATE_US: u32 = 16_000;
+
+static LONGEST_LATE_US: AtomicU32 = AtomicU32::new(0);
+static LATE_FRAMES: AtomicU32 = AtomicU32::new(0);
+
+/// How far behind the animations have run this boot, from `hold`.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Lateness {
+    pub longest_us: u32,
+    /// Frames over `LATE_US` late
+    pub late_frames: u32,
+}
+
+impl fmt::Display for Lateness {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "frames up to {} ms late, {} over {} ms",
+            self.longest_us / 1000,
+            self.late_frames,
+            LATE_US / 1000
+        )
+    }
+}
+
+pub fn lateness() -> Lateness {
+    Lateness {
+        longest_us: LONGEST_LATE_US.load(Ordering::Relaxed),
+        late_frames: LATE_FRAMES.load(Ordering::Relaxed),
+    }
+}
+
+/// Keep a frame of an animation up for `ms`, noting how much longer than
+/// that the executor took to come back to it.
+pub async fn hold(ms: u64) {
+    let started = Instant::now();
+    Timer::after_millis(ms).await;
+
+    let late = started.elapsed().as_micros().saturating_sub(ms * 1000);
+    let late = late.min(u32::MAX as u64) as u32;
+    LONGEST_LATE_US.fetch_max(late, Ordering::Relaxed);
+    if late > LATE_US {
+        LATE_FRAMES.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Blink `colors` together at full brightness, `times` times.
+pub async fn flash(colors: &[Color], times: usize, period_ms: u64) {
+    for _ in 0..times {
+        for color in colors {
+            apply(&LightChange {
+                color: color.clone(),
+                brightness: 100,
+                duration: 16,
+            })
+            .await;
+        }
+
+        hold(period_ms).await;
+
+        for color in colors {
+            apply(&LightChange {
+                color: color.clone(),
+                brightness: 0,
+                duration: 16,
             })
-            .unwrap();
+            .await;
+        }
+
+        hold(period_ms).await;
+    }
+}
 
+/// One of our own networks is being impersonated: slow yellow blinks.
+pub async fn warning() {
+    flash(&[Color::Yellow], 6, 250).await;
+}
+
+/// Intrusion detection alert: everything strobes, nothing else does that.
+pub async fn alert() {
+    flash(
+        &[Color::White, Color::Yellow, Color::Green, Color::Blue],
+        5,
+        80,
+    )
+    .await;
+}
+
+/// The last boot crashed: white and yellow, slowly, five times over.
+pub async fn fault() {
+    flash(&[Color::White, Color::Yellow], 5, 500).await;
+}
+
+/// We've given up on part of the device: its color blinks fast.
+pub async fn failed(subsystem: Subsystem) {
+    let color = match subsystem {
+        Subsystem::Storage => Color::Blue,
+        Subsystem::Radio => Color::Green,
+        Subsystem::Battery => Color::Yellow,
+        Subsystem::Lights => return,
+    };
+    flash(&[color], 10, 100).await;
+}
+
+/// An LED that fades by itself: an LEDC channel on the board, or a virtual
+/// one in the simulator.
+pub trait Lamp {
+    type Error: fmt::Debug;
+
+    /// Whether the last fade is still going
+    fn fading(&self) -> bool;
+
+    /// Start fading from one brightness percent to another over `duration` ms.
+    fn fade(&mut self, from: u8, to: u8, duration: u16) -> Result<(), Self::Error>;
+}
+
+struct Light<L> {
+    brightness: u8,
+    lamp: L,
+}
+impl<L: Lamp> Light<L> {
+    fn new(lamp: L) -> Self {
         Self {
             brightness: 0,
-            channel,
+            lamp,
         }
     }
 
@@ -94,57 +214,33 @@ impl Light {
             return;
         }
 
-        if self.channel.is_duty_fade_running() {
-            embassy_time::Timer::after_millis(200).await;
+        if self.lamp.fading() {
+            Timer::after_millis(200).await;
         }
 
-        self.channel
-            .start_duty_fade(self.brightness, change.brightness, change.duration)
-            .unwrap();
+        // A fade it won't do is only a light out of step, try the next one
+        if let Err(err) = self
+            .lamp
+            .fade(self.brightness, change.brightness, change.duration)
+        {
+            println!("lights: {:?}", err);
+            return;
+        }
         self.brightness = change.brightness;
     }
 }
 
-static LIGHTS_CHANNEL: PubSubChannel<CriticalSectionRawMutex, LightChange, 4, 4, 4> =
-    PubSubChannel::<CriticalSectionRawMutex, LightChange, 4, 4, 4>::new();
-
-#[embassy_executor::task]
-pub async fn setup_lights(
-    mut ledc: Ledc<'static>,
-    yellow_pin: GpioPin<1>,
-    green_pin: GpioPin<4>,
-    blue_pin: GpioPin<6>,
-    white_pin: GpioPin<5>,
-) -> ! {
-    let yellow_output = Output::new(yellow_pin, Level::Low);
-    let green_output = Output::new(green_pin, Level::Low);
-    let blue_output = Output::new(blue_pin, Level::Low);
-    let white_output = Output::new(white_pin, Level::Low);
+/// Room for a whole `flash` step of every color, so the radio executor
+/// rarely waits on the lights.
+static LIGHTS_CHANNEL: PubSubChannel<CriticalSectionRawMutex, LightChange, 8, 4, 4> =
+    PubSubChannel::<CriticalSectionRawMutex, LightChange, 8, 4, 4>::new();
 
-    // Setup LEDC
-    ledc.set_global_slow_clock(LSGlobalClkSource::APBClk);
-    let mut lstimer0 = ledc.get_timer::<LowSpeed>(timer::Number::Timer0);
-    lstimer0
-        .configure(timer::config::Config {
-            duty: timer::config::Duty::Duty10Bit,
-            clock_source: timer::LSClockSource::APBClk,
-            frequency: 24.kHz(),
-        })
-        .unwrap();
-
-    let timer = Box::leak(Box::new(lstimer0));
-
-    let yellow_channel = ledc.get_channel(channel::Number::Channel0, yellow_output);
-    let mut yellow = Light::new(yellow_channel, timer);
-
-    let green_channel = ledc.get_channel(channel::Number::Channel1, green_output);
-    let mut green = Light::new(green_channel, timer);
-
-    let blue_channel = ledc.get_channel(channel::Number::Channel2, blue_output);
-    let mut blue = Light::new(blue_channel, timer);
-
-    let white_channel = ledc.get_channel(channel::Number::Channel3, white_output);
-    let mut white = Light::new(white_channel, timer);
+/// Follow the changes published to `LIGHTS_CHANNEL` on the four lamps.
+pub async fn show<L: Lamp>(white: L, yellow: L, green: L, blue: L) -> ! {
+    let mut white = Light::new(white);
+    let mut yellow = Light::new(yellow);
+    let mut green = Light::new(green);
+    let mut blue = Light::new(blue);
 
     let mut subscriber = LIGHTS_CHANNEL.subscriber().unwrap();
 
@@ -159,3 +255,104 @@ pub async fn setup_lights(
         }
     }
 }
+
+#[cfg(target_os = "none")]
+pub use ledc::setup_lights;
+
+/// The board's lights, on the LED PWM controller.
+#[cfg(target_os = "none")]
+mod ledc {
+    use alloc::boxed::Box;
+    use esp_hal::{
+        gpio::{AnyPin, GpioPin, Level, Output},
+        ledc::{
+            channel::{self, Channel},
+            timer, LSGlobalClkSource, Ledc, LowSpeed,
+        },
+        prelude::*,
+    };
+
+    use super::{show, Lamp};
+    use crate::error::{self, Error, Subsystem};
+
+    impl Lamp for Channel<'static, LowSpeed, AnyPin> {
+        type Error = channel::Error;
+
+        fn fading(&self) -> bool {
+            self.is_duty_fade_running()
+        }
+
+        fn fade(&mut self, from: u8, to: u8, duration: u16) -> Result<(), channel::Error> {
+            self.start_duty_fade(from, to, duration)
+        }
+    }
+
+    fn configure(
+        mut channel: Channel<'static, LowSpeed, AnyPin>,
+        timer: &'static timer::Timer<'static, LowSpeed>,
+    ) -> Result<Channel<'static, LowSpeed, AnyPin>, Error> {
+        channel
+            .configure(channel::config::Config {
+                timer: timer,
+                duty_pct: 24,
+                pin_config: channel::config::PinConfig::PushPull,
+            })
+            .map_err(|_| Error::Lights)?;
+
+        Ok(channel)
+    }
+
+    /// Without lights, changes go nowhere: nothing's listening for them.
+    #[embassy_executor::task]
+    pub async fn setup_lights(
+        ledc: Ledc<'static>,
+        yellow_pin: GpioPin<1>,
+        green_pin: GpioPin<4>,
+        blue_pin: GpioPin<6>,
+        white_pin: GpioPin<5>,
+    ) {
+        if let Err(err) = run(ledc, yellow_pin, green_pin, blue_pin, white_pin).await {
+            error::disable(Subsystem::Lights, err).await;
+        }
+    }
+
+    async fn run(
+        mut ledc: Ledc<'static>,
+        yellow_pin: GpioPin<1>,
+        green_pin: GpioPin<4>,
+        blue_pin: GpioPin<6>,
+        white_pin: GpioPin<5>,
+    ) -> Result<(), Error> {
+        let yellow_output = Output::new(yellow_pin, Level::Low);
+        let green_output = Output::new(green_pin, Level::Low);
+        let blue_output = Output::new(blue_pin, Level::Low);
+        let white_output = Output::new(white_pin, Level::Low);
+
+        // Setup LEDC
+        ledc.set_global_slow_clock(LSGlobalClkSource::APBClk);
+        let mut lstimer0 = ledc.get_timer::<LowSpeed>(timer::Number::Timer0);
+        lstimer0
+            .configure(timer::config::Config {
+                duty: timer::config::Duty::Duty10Bit,
+                clock_source: timer::LSClockSource::APBClk,
+                frequency: 24.kHz(),
+            })
+            .map_err(|_| Error::Lights)?;
+
+        let timer = Box::leak(Box::new(lstimer0));
+
+        let yellow_channel = ledc.get_channel(channel::Number::Channel0, yellow_output);
+        let yellow = configure(yellow_channel, timer)?;
+
+        let green_channel = ledc.get_channel(channel::Number::Channel1, green_output);
+        let green = configure(green_channel, timer)?;
+
+        let blue_channel = ledc.get_channel(channel::Number::Channel2, blue_output);
+        let blue = configure(blue_channel, timer)?;
+
+        let white_channel = ledc.get_channel(channel::Number::Channel3, white_output);
+        let white = configure(white_channel, timer)?;
+
+        show(white, yellow, green, blue).await
+    }
+}
@@ -1,6 +1,35 @@
-//! WiFi sniffer example
+//! A pocket Wi-Fi and 802.15.4 survey tool.
 //!
-//! Sniffs for beacon frames.
+//! Work is split over three executors by how long it can wait:
+//!
+//! - radio, an interrupt executor at priority 3: frames from the sniffer
+//!   (`ingest::start_frames`) and the 802.15.4 receiver
+//! - UI, an interrupt executor at priority 2: lights, scenes and the button
+//! - this thread: storage, the console and telemetry, the radios' own
+//!   control loops, exports, and the supervisor
+//!
+//! The interrupt executors preempt lower priority ones at any instruction,
+//! not only at an await, so anything shared between them is a
+//! `critical_section` mutex, an atomic, or an embassy-sync channel on
+//! `CriticalSectionRawMutex`. Who owns which channel:
+//!
+//! - `storage::REQUESTS` is received only by the storage task; anyone may
+//!   send. The radio executor only ever uses `try_append`, which never
+//!   waits, so frames don't stall behind flash.
+//! - `storage::REPLIES` goes back to whoever holds `storage::IN_FLIGHT`.
+//! - `storage::APPENDED` is published by the storage task, for export.
+//! - `lights::LIGHTS_CHANNEL` has one subscriber, the lights task, and
+//!   publishers on every executor. The radio executor only uses
+//!   `lights::try_set`, which never waits on the UI.
+//! - `scene::SCENE_CHANNEL` and `button::BUTTON_CHANNEL` stay within the UI
+//!   executor.
+//! - `alerts::ALERT_CHANNEL` is published without waiting from frame
+//!   processing and read by the alerts task on this thread.
+//! - `ingest::FRAMES` is filled by the sniffer callback and drained by
+//!   `ingest::start_frames`.
+//!
+//! Tasks whose futures hold a driver that isn't `Send` start from a task on
+//! their executor instead of being spawned there from here; see `start_ui`.
 
 //% FEATURES: esp-wifi esp-wifi/wifi-default esp-wifi/wifi esp-wifi/utils esp-wifi/sniffer
 //% CHIPS: esp32 esp32s2 esp32s3 esp32c2 esp32c3 esp32c6
@@ -16,41 +45,93 @@
     generic_const_exprs
 )]
 
+mod alerts;
+mod allowlist;
 mod battery;
 mod bluetooth;
 mod button;
+mod channels;
+mod clock;
+mod config;
+mod config_format;
+mod console;
+mod crash;
+mod dhcp;
+mod dot15d4;
+mod error;
+mod export;
+mod fingerprint;
+mod foxhunt;
+mod frame;
+mod http;
+mod ieee802154;
+mod ingest;
+mod journal;
+mod legacy;
 mod lights;
+mod mode;
+mod mqtt;
+mod occupancy;
+mod oui;
+mod oui_format;
+mod query;
+mod record;
 mod scene;
+mod serial;
+mod share;
+mod spectrum;
+mod station;
 mod storage;
+mod supervisor;
+mod telemetry;
+mod upload;
+mod wids;
 mod wifi;
 
 extern crate alloc;
 
 use button::button_task;
+use embassy_executor::{SendSpawner, SpawnToken, Spawner};
 use embassy_time::{Duration, Timer};
 use esp_alloc as _;
 use esp_backtrace as _;
-use esp_hal::gpio::{Input, Io, Output, Pull};
+use esp_hal::gpio::{GpioPin, Input, Io, Output, Pull};
 use esp_hal::i2c::I2c;
+use esp_hal::interrupt::{software::SoftwareInterruptControl, Priority};
 use esp_hal::ledc::Ledc;
+use esp_hal::peripherals::LEDC;
 use esp_hal::prelude::*;
 use esp_hal::rng::Rng;
+use esp_hal::rtc_cntl::Rtc;
 use esp_hal::timer::timg::TimerGroup;
 use esp_hal::timer::AnyTimer;
+use esp_hal_embassy::InterruptExecutor;
 use esp_println::println;
 use lights::setup_lights;
+use mode::Mode;
 use scene::setup_scene_manager;
+use static_cell::make_static;
 use wifi::start_wifi;
 
 #[esp_hal_embassy::main]
-async fn main(spawner: embassy_executor::Spawner) {
-    esp_alloc::heap_allocator!(64 * 1024);
+async fn main(spawner: Spawner) {
+    supervisor::paint_stack();
+    esp_alloc::heap_allocator!(supervisor::BOOT_HEAP);
     esp_println::logger::init_logger_from_env();
 
     let peripherals = esp_hal::init(esp_hal::Config::default());
 
+    crash::init();
+    clock::init(Rtc::new(peripherals.LPWR));
+    config::load();
+    supervisor::grow_heap(
+        config::with(|config| config.heap_kb as usize * 1024) - supervisor::BOOT_HEAP,
+    );
+    storage::init();
+
     let timg0 = TimerGroup::new(peripherals.TIMG0);
     let timer: AnyTimer = timg0.timer0.into();
+    let watchdog = timg0.wdt;
 
     let timg1 = TimerGroup::new(peripherals.TIMG1);
     esp_hal_embassy::init(timg1.timer0);
@@ -58,58 +139,152 @@ async fn main(spawner: embassy_executor::Spawner) {
     let io = Io::new(peripherals.GPIO, peripherals.IO_MUX);
     _ = Output::new(io.pins.gpio20, esp_hal::gpio::Level::High);
 
-    let ledc = Ledc::new(peripherals.LEDC);
+    let interrupts = SoftwareInterruptControl::new(peripherals.SW_INTERRUPT);
+    let radio = make_static!(InterruptExecutor::new(interrupts.software_interrupt1))
+        .start(Priority::Priority3);
+    let ui = make_static!(InterruptExecutor::new(interrupts.software_interrupt2))
+        .start(Priority::Priority2);
 
-    spawner
-        .spawn(setup_lights(
-            ledc,
+    let button = Input::new_typed(io.pins.gpio17, Pull::Down);
+    let button_is_high = button.is_high();
+    send_spawn(
+        ui,
+        start_ui(
+            peripherals.LEDC,
             io.pins.gpio1,
             io.pins.gpio4,
             io.pins.gpio6,
             io.pins.gpio5,
-        ))
-        .ok();
+            button,
+        ),
+    );
+    spawn(spawner, crash::start_crash_report());
 
     // storage::Store::reset();
 
-    // MARK: Light task
-
-    // MARK -- Scene manager (UI as it were)
-    spawner.spawn(setup_scene_manager()).ok();
-
     let i2c0 = I2c::new_async(peripherals.I2C0, io.pins.gpio19, io.pins.gpio18, 400.kHz());
     println!("spawning battery task");
-    spawner
-        .spawn(battery::start_battery(i2c0, io.pins.gpio16))
-        .unwrap();
+    spawn(spawner, battery::start_battery(i2c0, io.pins.gpio16));
 
-    let button = Input::new_typed(io.pins.gpio17, Pull::Down);
-    let button_is_high = button.is_high();
-    spawner.spawn(button_task(button)).unwrap();
-
-    if button_is_high {
-        spawner
-            .spawn(bluetooth::start_bluetooth(
-                timer,
-                Rng::new(peripherals.RNG),
-                peripherals.RADIO_CLK,
-                peripherals.BT,
-            ))
-            .unwrap();
+    let mode = mode::take_next().unwrap_or(if button_is_high {
+        Mode::Bluetooth
     } else {
-        spawner
-            .spawn(start_wifi(
-                timer,
-                Rng::new(peripherals.RNG),
-                peripherals.RADIO_CLK,
-                peripherals.WIFI,
-            ))
-            .unwrap();
+        Mode::Wifi
+    });
+    mode::set_current(mode);
+    println!("starting in {:?} mode", mode);
+
+    match mode {
+        Mode::Bluetooth => {
+            spawn(
+                spawner,
+                bluetooth::start_bluetooth(
+                    timer,
+                    Rng::new(peripherals.RNG),
+                    peripherals.RADIO_CLK,
+                    peripherals.BT,
+                ),
+            );
+        }
+        Mode::Wifi => {
+            spawn(
+                spawner,
+                start_wifi(
+                    timer,
+                    Rng::new(peripherals.RNG),
+                    peripherals.RADIO_CLK,
+                    peripherals.WIFI,
+                ),
+            );
+            send_spawn(radio, ingest::start_frames());
+            spawn(spawner, alerts::start_alerts());
+        }
+        Mode::Ieee802154 => {
+            send_spawn(
+                radio,
+                ieee802154::start_ieee802154(peripherals.IEEE802154, peripherals.RADIO_CLK),
+            );
+        }
+        Mode::Share => {
+            spawn(
+                spawner,
+                share::start_share(
+                    spawner,
+                    timer,
+                    Rng::new(peripherals.RNG),
+                    peripherals.RADIO_CLK,
+                    peripherals.WIFI,
+                ),
+            );
+        }
+        Mode::Upload => {
+            spawn(
+                spawner,
+                upload::start_upload(
+                    spawner,
+                    timer,
+                    Rng::new(peripherals.RNG),
+                    peripherals.RADIO_CLK,
+                    peripherals.WIFI,
+                ),
+            );
+        }
+        Mode::Live => {
+            spawn(
+                spawner,
+                mqtt::start_mqtt(
+                    spawner,
+                    timer,
+                    Rng::new(peripherals.RNG),
+                    peripherals.RADIO_CLK,
+                    peripherals.WIFI,
+                ),
+            );
+            send_spawn(radio, ingest::start_frames());
+            spawn(spawner, alerts::start_alerts());
+        }
     }
 
-    spawner.spawn(storage::start_storage()).ok();
+    spawn(spawner, storage::start_storage());
+    spawn(spawner, serial::start_serial(peripherals.USB_DEVICE));
+    spawn(spawner, telemetry::start_telemetry());
+    spawn(spawner, supervisor::start_supervisor(watchdog));
 
     loop {
         Timer::after(Duration::from_secs(10)).await;
     }
 }
+
+/// A task that didn't start is only logged; `health` shows it not running.
+fn spawn<S>(spawner: Spawner, token: SpawnToken<S>) {
+    if let Err(err) = spawner.spawn(token) {
+        println!("couldn't start a task: {:?}", err);
+    }
+}
+
+/// `spawn` onto one of the interrupt executors.
+fn send_spawn<S: Send>(spawner: SendSpawner, token: SpawnToken<S>) {
+    if let Err(err) = spawner.spawn(token) {
+        println!("couldn't start a task: {:?}", err);
+    }
+}
+
+/// The UI executor's tasks. The LEDC driver isn't `Send`, so it's made here,
+/// on the executor that keeps it, from the peripheral.
+#[embassy_executor::task]
+async fn start_ui(
+    ledc: LEDC,
+    yellow_pin: GpioPin<1>,
+    green_pin: GpioPin<4>,
+    blue_pin: GpioPin<6>,
+    white_pin: GpioPin<5>,
+    button: Input<'static, GpioPin<17>>,
+) {
+    let spawner = Spawner::for_current_executor().await;
+    spawn(
+        spawner,
+        setup_lights(Ledc::new(ledc), yellow_pin, green_pin, blue_pin, white_pin),
+    );
+    spawn(spawner, setup_scene_manager());
+    spawn(spawner, button_task(button));
+}
@@ -0,0 +1,109 @@
+//! Which radio the firmware runs this boot.
+//!
+//! Only one of these runs at a time. Switching from the menu stashes the next
+//! mode in RTC memory and resets; holding the button while powering on still
+//! picks Bluetooth like it always has.
+
+use core::sync::atomic::{AtomicU8, Ordering};
+
+#[cfg(target_os = "none")]
+use esp_hal::{macros::ram, reset::software_reset};
+use esp_println::println;
+
+use crate::storage;
+#[cfg(target_os = "none")]
+use crate::wifi;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[repr(u8)]
+pub enum Mode {
+    Wifi = 1,
+    Bluetooth = 2,
+    Ieee802154 = 3,
+    /// Access point serving the survey over HTTP
+    Share = 4,
+    /// Station on the home network, posting new records to the collection
+    /// server
+    Upload = 5,
+    /// Station on the home network, sniffing its channel and publishing
+    /// detections over MQTT
+    Live = 6,
+}
+
+impl Mode {
+    fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            1 => Some(Self::Wifi),
+            2 => Some(Self::Bluetooth),
+            3 => Some(Self::Ieee802154),
+            4 => Some(Self::Share),
+            5 => Some(Self::Upload),
+            6 => Some(Self::Live),
+            _ => None,
+        }
+    }
+}
+
+/// Upper bytes mark the value as ours rather than whatever RAM held at power on
+const NEXT_MAGIC: u32 = 0x4d4f_4400;
+
+#[cfg_attr(target_os = "none", ram(rtc_fast, persistent))]
+static mut NEXT: u32 = 0;
+
+static CURRENT: AtomicU8 = AtomicU8::new(Mode::Wifi as u8);
+
+/// The mode requested before the last reset, if any. Clears the request so
+/// a power cycle goes back to the default.
+pub fn take_next() -> Option<Mode> {
+    let next = critical_section::with(|_| unsafe {
+        let next = NEXT;
+        NEXT = 0;
+        next
+    });
+
+    if next & !0xff != NEXT_MAGIC {
+        return None;
+    }
+
+    Mode::from_u8(next as u8)
+}
+
+pub fn set_current(mode: Mode) {
+    CURRENT.store(mode as u8, Ordering::Relaxed);
+}
+
+pub fn current() -> Mode {
+    Mode::from_u8(CURRENT.load(Ordering::Relaxed)).unwrap()
+}
+
+/// Reboot into `mode`. Wi-Fi gets to shut down cleanly (and save its
+/// channel survey) first.
+pub async fn switch(mode: Mode) {
+    critical_section::with(|_| unsafe { NEXT = NEXT_MAGIC | mode as u32 });
+
+    // Records still queued would be lost to the reset
+    if let Err(err) = storage::flush().await {
+        println!("switching without storing everything: {err}");
+    }
+
+    reset().await;
+}
+
+#[cfg(target_os = "none")]
+async fn reset() {
+    if current() == Mode::Wifi {
+        wifi::restart().await;
+    } else {
+        software_reset();
+    }
+}
+
+/// The simulator has no other firmware to come back up as, so it stops
+/// where the board would reset.
+#[cfg(not(target_os = "none"))]
+async fn reset() {
+    if let Some(next) = take_next() {
+        println!("mode: the board resets into {:?} mode here", next);
+    }
+    std::process::exit(0);
+}
@@ -0,0 +1,434 @@
+//! Publish detections to an MQTT broker as they happen.
+//!
+//! Every record handed to storage goes out on `<prefix>/<kind>` as the same
+//! JSON the exports use, and battery readings on `<prefix>/battery` when
+//! they change. `<prefix>/status` is a retained "online", with a last will
+//! of "offline" so dashboards notice when we drop off.
+//!
+//! Only the bits of MQTT 3.1.1 a publisher needs are here: CONNECT, PUBLISH
+//! at QoS 0 or 1, PUBACK and pings.
+
+use core::cell::RefCell;
+
+use alloc::{format, string::String, vec::Vec};
+use critical_section::Mutex;
+use embassy_futures::select::{select, Either};
+use embassy_time::{with_timeout, Duration, Instant, Timer};
+use embedded_io_async::{Error as _, ErrorKind, Read, ReadExactError, Write};
+use esp_println::println;
+
+use crate::{battery, export, record::Record, storage};
+
+#[cfg(target_os = "none")]
+pub use tcp::start_mqtt;
+
+const KEEP_ALIVE_SECS: u16 = 60;
+
+/// Ping when we've been quiet this long
+const PING_SECS: u64 = 30;
+
+/// Wait this long for a CONNACK, PUBACK or PINGRESP
+const REPLY_SECS: u64 = 10;
+
+const CONNECT: u8 = 0x10;
+const CONNACK: u8 = 0x20;
+const PUBLISH: u8 = 0x30;
+const PUBACK: u8 = 0x40;
+const PINGREQ: u8 = 0xc0;
+const PINGRESP: u8 = 0xd0;
+
+const CLEAN_SESSION: u8 = 0x02;
+const WILL: u8 = 0x04;
+const WILL_RETAIN: u8 = 0x20;
+const DUP: u8 = 0x08;
+const RETAIN: u8 = 0x01;
+
+#[derive(Clone, Debug)]
+pub struct Settings {
+    pub broker: [u8; 4],
+    pub port: u16,
+    /// Topics start with this; defaults to `wifblink/<mac>`
+    pub prefix: String,
+    /// 0 or 1
+    pub qos: u8,
+    /// How often to check the battery for a change worth publishing
+    pub battery_secs: u32,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Settings {
+    pub const fn new() -> Self {
+        Self {
+            broker: [0; 4],
+            port: 1883,
+            prefix: String::new(),
+            qos: 0,
+            battery_secs: 30,
+        }
+    }
+
+    fn prefix(&self, device: &str) -> String {
+        if self.prefix.is_empty() {
+            format!("wifblink/{device}")
+        } else {
+            self.prefix.clone()
+        }
+    }
+}
+
+static SETTINGS: Mutex<RefCell<Settings>> = Mutex::new(RefCell::new(Settings::new()));
+
+pub fn settings() -> Settings {
+    critical_section::with(|cs| SETTINGS.borrow_ref(cs).clone())
+}
+
+pub fn configure(settings: Settings) {
+    critical_section::with(|cs| *SETTINGS.borrow_ref_mut(cs) = settings);
+}
+
+/// A will to leave with the broker.
+pub struct Will<'a> {
+    pub topic: &'a str,
+    pub payload: &'a [u8],
+    pub retain: bool,
+}
+
+pub fn connect(client_id: &str, keep_alive: u16, will: Option<&Will>) -> Vec<u8> {
+    let mut body = Vec::new();
+    put_str(&mut body, "MQTT");
+    body.push(4); // protocol level 3.1.1
+
+    let mut flags = CLEAN_SESSION;
+    if let Some(will) = will {
+        flags |= WILL;
+        if will.retain {
+            flags |= WILL_RETAIN;
+        }
+    }
+    body.push(flags);
+    body.extend_from_slice(&keep_alive.to_be_bytes());
+
+    put_str(&mut body, client_id);
+    if let Some(will) = will {
+        put_str(&mut body, will.topic);
+        body.extend_from_slice(&(will.payload.len() as u16).to_be_bytes());
+        body.extend_from_slice(will.payload);
+    }
+
+    packet(CONNECT, &body)
+}
+
+/// A PUBLISH; `packet_id` only goes on the wire for QoS 1.
+pub fn publish(
+    topic: &str,
+    payload: &[u8],
+    qos: u8,
+    packet_id: u16,
+    retain: bool,
+    dup: bool,
+) -> Vec<u8> {
+    let mut body = Vec::new();
+    put_str(&mut body, topic);
+    if qos > 0 {
+        body.extend_from_slice(&packet_id.to_be_bytes());
+    }
+    body.extend_from_slice(payload);
+
+    let mut kind = PUBLISH | (qos.min(1) << 1);
+    if retain {
+        kind |= RETAIN;
+    }
+    if dup {
+        kind |= DUP;
+    }
+    packet(kind, &body)
+}
+
+pub fn ping() -> Vec<u8> {
+    packet(PINGREQ, &[])
+}
+
+/// Whether a CONNACK body says we're in.
+pub fn accepted(connack: &[u8]) -> bool {
+    connack.get(1) == Some(&0)
+}
+
+/// The packet ID a PUBACK body acknowledges.
+pub fn acknowledged(puback: &[u8]) -> Option<u16> {
+    Some(u16::from_be_bytes(puback.get(..2)?.try_into().ok()?))
+}
+
+fn packet(kind: u8, body: &[u8]) -> Vec<u8> {
+    let mut packet = alloc::vec![kind];
+
+    // Remaining length, seven bits at a time
+    let mut len = body.len();
+    loop {
+        let mut byte = (len % 128) as u8;
+        len /= 128;
+        if len > 0 {
+            byte |= 0x80;
+        }
+        packet.push(byte);
+        if len == 0 {
+            break;
+        }
+    }
+
+    packet.extend_from_slice(body);
+    packet
+}
+
+fn put_str(bytes: &mut Vec<u8>, value: &str) {
+    bytes.extend_from_slice(&(value.len() as u16).to_be_bytes());
+    bytes.extend_from_slice(value.as_bytes());
+}
+
+#[derive(Debug)]
+pub enum MqttError {
+    Connect(ErrorKind),
+    Io(ErrorKind),
+    Closed,
+    Timeout,
+    Refused,
+    Unexpected(u8),
+}
+
+impl<E: embedded_io_async::Error> From<ReadExactError<E>> for MqttError {
+    fn from(err: ReadExactError<E>) -> Self {
+        match err {
+            ReadExactError::UnexpectedEof => Self::Closed,
+            ReadExactError::Other(err) => Self::Io(err.kind()),
+        }
+    }
+}
+
+/// One connection to the broker: log in, then publish until something goes
+/// wrong. `device` is the client ID and names the default topic prefix.
+pub async fn session(connection: &mut (impl Read + Write), device: &str) -> Result<(), MqttError> {
+    let settings = settings();
+    let prefix = settings.prefix(device);
+    let status = format!("{prefix}/status");
+
+    let will = Will {
+        topic: &status,
+        payload: b"offline",
+        retain: true,
+    };
+    write(connection, &connect(device, KEEP_ALIVE_SECS, Some(&will))).await?;
+    let (kind, body) = reply(connection).await?;
+    if kind != CONNACK {
+        return Err(MqttError::Unexpected(kind));
+    }
+    if !accepted(&body) {
+        return Err(MqttError::Refused);
+    }
+
+    write(connection, &publish(&status, b"online", 0, 0, true, false)).await?;
+    println!("mqtt: connected, publishing under {}", prefix);
+
+    let mut records = storage::APPENDED.subscriber().unwrap();
+    let mut packet_id: u16 = 0;
+    let mut last_sent = Instant::now();
+    let mut last_battery = None;
+    // A deadline rather than a timeout, so records coming in faster than
+    // this can't put the battery off for good
+    let battery_every = Duration::from_secs(settings.battery_secs as u64);
+    let mut next_battery = Instant::now() + battery_every;
+
+    loop {
+        let ping_at = last_sent + Duration::from_secs(PING_SECS);
+        let wait = Timer::at(next_battery.min(ping_at));
+        let (topic, payload) = match select(records.next_message_pure(), wait).await {
+            Either::First(bytes) => {
+                let kind = Record::decode(&bytes).map_or("unknown", |record| record.kind());
+                (format!("{prefix}/{kind}"), export::json_record(0, &bytes))
+            }
+            Either::Second(_) if Instant::now() >= next_battery => {
+                next_battery = Instant::now() + battery_every;
+                let level = battery::level();
+                let changed = level.map(|level| (level.percent, level.usb)) != last_battery;
+
+                match level {
+                    Some(level) if changed => {
+                        last_battery = Some((level.percent, level.usb));
+                        (format!("{prefix}/battery"), export::battery_json(&level))
+                    }
+                    _ => continue,
+                }
+            }
+            Either::Second(_) => {
+                write(connection, &ping()).await?;
+                let (kind, _) = reply(connection).await?;
+                if kind != PINGRESP {
+                    return Err(MqttError::Unexpected(kind));
+                }
+                last_sent = Instant::now();
+                continue;
+            }
+        };
+
+        packet_id = packet_id.wrapping_add(1).max(1);
+        let payload = payload.as_bytes();
+        send(connection, &topic, payload, settings.qos, packet_id).await?;
+        last_sent = Instant::now();
+    }
+}
+
+/// Publish, and for QoS 1 wait for the PUBACK, resending once as a duplicate
+/// if it doesn't come.
+async fn send(
+    connection: &mut (impl Read + Write),
+    topic: &str,
+    payload: &[u8],
+    qos: u8,
+    packet_id: u16,
+) -> Result<(), MqttError> {
+    let packet = publish(topic, payload, qos, packet_id, false, false);
+    write(connection, &packet).await?;
+    if qos == 0 {
+        return Ok(());
+    }
+
+    for retry in [false, true] {
+        if retry {
+            let duplicate = publish(topic, payload, qos, packet_id, false, true);
+            write(connection, &duplicate).await?;
+        }
+
+        match reply(connection).await {
+            Ok((PUBACK, body)) if acknowledged(&body) == Some(packet_id) => return Ok(()),
+            Ok((kind, _)) if kind != PUBACK => return Err(MqttError::Unexpected(kind)),
+            Ok(_) | Err(MqttError::Timeout) => continue,
+            Err(err) => return Err(err),
+        }
+    }
+
+    Err(MqttError::Timeout)
+}
+
+async fn write(connection: &mut impl Write, packet: &[u8]) -> Result<(), MqttError> {
+    connection
+        .write_all(packet)
+        .await
+        .map_err(|err| MqttError::Io(err.kind()))
+}
+
+/// Read one packet: its type and body.
+async fn reply(connection: &mut impl Read) -> Result<(u8, Vec<u8>), MqttError> {
+    with_timeout(Duration::from_secs(REPLY_SECS), async {
+        let mut byte = [0u8];
+        connection.read_exact(&mut byte).await?;
+        let kind = byte[0] & 0xf0;
+
+        let mut len = 0usize;
+        for shift in 0..4 {
+            connection.read_exact(&mut byte).await?;
+            len |= ((byte[0] & 0x7f) as usize) << (7 * shift);
+            if byte[0] & 0x80 == 0 {
+                break;
+            }
+        }
+
+        let mut body = alloc::vec![0u8; len];
+        connection.read_exact(&mut body).await?;
+        Ok((kind, body))
+    })
+    .await
+    .map_err(|_| MqttError::Timeout)?
+}
+
+/// Publishing over the board's Wi-Fi station, sniffing alongside.
+#[cfg(target_os = "none")]
+mod tcp {
+    use embassy_executor::Spawner;
+    use embassy_net::{tcp::TcpSocket, Stack};
+    use embassy_time::{Duration, Timer};
+    use esp_hal::{
+        peripherals::{RADIO_CLK, WIFI},
+        rng::Rng,
+        timer::AnyTimer,
+    };
+    use esp_println::println;
+    use esp_wifi::wifi::WifiController;
+
+    use super::{session, settings, MqttError, KEEP_ALIVE_SECS};
+    use crate::{
+        allowlist,
+        error::{self, Error, Subsystem},
+        station::{self, Device},
+    };
+
+    /// Back off between reconnects up to this long
+    const MAX_BACKOFF_SECS: u64 = 60;
+
+    /// Live mode: join the home network, keep sniffing on its channel, and
+    /// publish what turns up.
+    #[embassy_executor::task]
+    pub async fn start_mqtt(
+        spawner: Spawner,
+        timer: AnyTimer,
+        rng: Rng,
+        radio_clock: RADIO_CLK,
+        wifi: WIFI,
+    ) {
+        let (stack, mut controller) =
+            match station::connect(spawner, timer, rng, radio_clock, wifi).await {
+                Ok(connected) => connected,
+                Err(err) => return error::disable(Subsystem::Radio, err).await,
+            };
+
+        allowlist::load();
+        // Battery readings are still worth publishing without detections
+        if let Err(err) = sniff(&mut controller) {
+            println!("mqtt: not sniffing: {err}");
+        }
+
+        spawner.spawn(station::stay_connected(controller)).unwrap();
+        run(stack).await;
+    }
+
+    fn sniff(controller: &mut WifiController<'static>) -> Result<(), Error> {
+        let mut sniffer = controller.take_sniffer().ok_or(Error::Radio)?;
+        sniffer.set_promiscuous_mode(true)?;
+        sniffer.set_receive_cb(crate::wifi::sniffed);
+        Ok(())
+    }
+
+    /// Stay connected to the broker and publish until the end of time.
+    async fn run(stack: &'static Stack<Device>) {
+        let mut backoff = 1;
+
+        loop {
+            stack.wait_config_up().await;
+
+            match connection(stack).await {
+                Ok(()) => backoff = 1,
+                Err(err) => {
+                    println!("mqtt: {:?}", err);
+                    backoff = (backoff * 2).min(MAX_BACKOFF_SECS);
+                }
+            }
+
+            Timer::after_secs(backoff).await;
+        }
+    }
+
+    async fn connection(stack: &'static Stack<Device>) -> Result<(), MqttError> {
+        let settings = settings();
+        let mut rx_buffer = [0u8; 256];
+        let mut tx_buffer = [0u8; 1024];
+        let mut socket = TcpSocket::new(stack, &mut rx_buffer, &mut tx_buffer);
+        socket.set_timeout(Some(Duration::from_secs(KEEP_ALIVE_SECS as u64 * 2)));
+
+        station::connect_tcp(&mut socket, settings.broker, settings.port)
+            .await
+            .map_err(MqttError::Connect)?;
+        session(&mut socket, &station::device_id()).await
+    }
+}
@@ -0,0 +1,143 @@
+//! Estimate how many people are around from how many Wi-Fi clients are.
+//!
+//! Every probe request and every station-to-AP data frame above the RSSI
+//! threshold marks its sender as present. A sender counts until it's been
+//! quiet for the length of the window. Randomized MACs are counted by the
+//! pseudo-device `fingerprint` clustered them into, so a phone rotating its
+//! address isn't counted twice.
+
+use core::cell::RefCell;
+
+use alloc::collections::btree_map::BTreeMap;
+use critical_section::Mutex;
+
+use crate::{
+    fingerprint,
+    frame::{self, fnv1a, FrameType, Management, ManagementKind},
+};
+
+/// Cap on tracked clients, the heap is only 64K
+const MAX_CLIENTS: usize = 256;
+
+const TO_DS: u8 = 0x01;
+const FROM_DS: u8 = 0x02;
+
+#[derive(Clone, Copy, Debug)]
+pub struct Settings {
+    pub window_ms: u64,
+    /// Ignore anything weaker than this, i.e. outside the room
+    pub min_rssi: i8,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Settings {
+    pub const fn new() -> Self {
+        Self {
+            window_ms: 5 * 60 * 1000,
+            min_rssi: -75,
+        }
+    }
+}
+
+pub struct Occupancy {
+    settings: Settings,
+    /// Client key (pseudo-device ID or MAC hash) to when we last heard it
+    seen: BTreeMap<u32, u64>,
+}
+
+impl Default for Occupancy {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Occupancy {
+    pub const fn new() -> Self {
+        Self {
+            settings: Settings::new(),
+            seen: BTreeMap::new(),
+        }
+    }
+
+    pub fn settings(&self) -> Settings {
+        self.settings
+    }
+
+    pub fn configure(&mut self, settings: Settings) {
+        self.settings = settings;
+    }
+
+    pub fn observe(&mut self, client: u32, rssi: i8, now: u64) {
+        if rssi < self.settings.min_rssi {
+            return;
+        }
+
+        if self.seen.len() >= MAX_CLIENTS && !self.seen.contains_key(&client) {
+            self.prune(now);
+            if self.seen.len() >= MAX_CLIENTS {
+                return;
+            }
+        }
+
+        self.seen.insert(client, now);
+    }
+
+    /// Distinct clients heard within the window.
+    pub fn count(&mut self, now: u64) -> usize {
+        self.prune(now);
+        self.seen.len()
+    }
+
+    fn prune(&mut self, now: u64) {
+        let window = self.settings.window_ms;
+        self.seen
+            .retain(|_, last_seen| now.saturating_sub(*last_seen) <= window);
+    }
+}
+
+static OCCUPANCY: Mutex<RefCell<Occupancy>> = Mutex::new(RefCell::new(Occupancy::new()));
+
+/// Feed a frame from the sniffer.
+pub fn observe(data: &[u8], rssi: i8, now: u64) {
+    let Some(mac) = client(data) else {
+        return;
+    };
+
+    let key = fingerprint::device_for(&mac).unwrap_or_else(|| fnv1a(&mac));
+    critical_section::with(|cs| OCCUPANCY.borrow_ref_mut(cs).observe(key, rssi, now));
+}
+
+pub fn count(now: u64) -> usize {
+    critical_section::with(|cs| OCCUPANCY.borrow_ref_mut(cs).count(now))
+}
+
+pub fn settings() -> Settings {
+    critical_section::with(|cs| OCCUPANCY.borrow_ref(cs).settings())
+}
+
+pub fn configure(settings: Settings) {
+    critical_section::with(|cs| OCCUPANCY.borrow_ref_mut(cs).configure(settings));
+}
+
+/// The sending client of a probe request or a station-to-AP data frame.
+fn client(data: &[u8]) -> Option<frame::MacAddress> {
+    match frame::frame_type(data)? {
+        FrameType::Management => {
+            let frame = Management::parse(data)?;
+            (frame.kind == ManagementKind::ProbeRequest).then_some(frame.transmitter)
+        }
+        FrameType::Data => {
+            let flags = *data.get(1)?;
+            if flags & (TO_DS | FROM_DS) != TO_DS {
+                return None;
+            }
+            frame::transmitter(data)
+        }
+        _ => None,
+    }
+}
@@ -0,0 +1,191 @@
+//! MAC address vendor lookup against the IEEE OUI table `build.rs` compiles in.
+
+use core::fmt;
+
+use crate::{
+    frame::{Mac, MacAddress},
+    oui_format::{self, Table},
+};
+
+static TABLE: Table<'static> = Table {
+    buckets: include_bytes!(concat!(env!("OUT_DIR"), "/oui_buckets.bin")),
+    keys: include_bytes!(concat!(env!("OUT_DIR"), "/oui_keys.bin")),
+    vendors: include_bytes!(concat!(env!("OUT_DIR"), "/oui_vendors.bin")),
+    offsets: include_bytes!(concat!(env!("OUT_DIR"), "/oui_offsets.bin")),
+    names: include_bytes!(concat!(env!("OUT_DIR"), "/oui_names.bin")),
+};
+
+const MULTICAST_BIT: u8 = 0x01;
+const LOCAL_BIT: u8 = 0x02;
+
+#[derive(Clone, Copy)]
+pub enum Lookup {
+    Vendor(Vendor),
+    /// Group address, no vendor behind it
+    Multicast,
+    /// Locally administered: randomized by a phone, or made up by an AP for
+    /// one of its extra SSIDs. The upper octets mean nothing.
+    Local,
+    /// Universally administered but not in our copy of the registry
+    Unknown,
+}
+
+pub fn lookup(address: &MacAddress) -> Lookup {
+    if address[0] & MULTICAST_BIT != 0 {
+        return Lookup::Multicast;
+    }
+
+    if address[0] & LOCAL_BIT != 0 {
+        return Lookup::Local;
+    }
+
+    match TABLE.find([address[0], address[1], address[2]]) {
+        Some(name) => Lookup::Vendor(Vendor(name)),
+        None => Lookup::Unknown,
+    }
+}
+
+/// A vendor name, expanded as it's formatted.
+#[derive(Clone, Copy)]
+pub struct Vendor(&'static [u8]);
+
+impl fmt::Display for Vendor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        oui_format::expand(self.0, f)
+    }
+}
+
+impl fmt::Display for Lookup {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Vendor(vendor) => vendor.fmt(f),
+            Self::Multicast => f.write_str("multicast"),
+            Self::Local => f.write_str("randomized"),
+            Self::Unknown => f.write_str("unknown vendor"),
+        }
+    }
+}
+
+/// An address followed by who made it, for logs and exports.
+pub struct Named<'a>(pub &'a MacAddress);
+
+impl fmt::Display for Named<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ({})", Mac(self.0), lookup(self.0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::*;
+
+    const REGISTRY: &str = include_str!("../data/oui.csv");
+
+    /// The MA-L assignments in the registry snapshot, by OUI.
+    fn assignments() -> BTreeMap<[u8; 3], String> {
+        let mut assignments = BTreeMap::new();
+        for line in REGISTRY.lines().skip(1) {
+            let Some(rest) = line.strip_prefix("MA-L,") else {
+                continue;
+            };
+            let (assignment, rest) = rest.split_once(',').unwrap();
+            let name = match rest.strip_prefix('"') {
+                Some(quoted) => quoted.split_once('"').unwrap().0,
+                None => rest.split_once(',').map_or(rest, |(name, _)| name),
+            };
+            let oui = u32::from_str_radix(assignment, 16).unwrap().to_be_bytes();
+            assignments.insert([oui[1], oui[2], oui[3]], name.trim().replace("\"\"", "\""));
+        }
+        assignments
+    }
+
+    fn address(oui: [u8; 3]) -> MacAddress {
+        [oui[0], oui[1], oui[2], 0x12, 0x34, 0x56]
+    }
+
+    #[test]
+    fn names_every_vendor_in_the_registry() {
+        let assignments = assignments();
+        assert!(!assignments.is_empty());
+
+        for (oui, name) in &assignments {
+            if oui[0] & (MULTICAST_BIT | LOCAL_BIT) != 0 {
+                // A few early assignments have these bits set; they read as such
+                continue;
+            }
+            assert_eq!(&lookup(&address(*oui)).to_string(), name, "{oui:02x?}");
+        }
+    }
+
+    #[test]
+    fn leaves_unassigned_ouis_unknown() {
+        let assignments = assignments();
+        let unassigned = (0..=0xff_ffffu32)
+            .map(|oui| {
+                let [_, a, b, c] = oui.to_be_bytes();
+                [a & !(MULTICAST_BIT | LOCAL_BIT), b, c]
+            })
+            .find(|oui| !assignments.contains_key(oui))
+            .unwrap();
+
+        assert!(matches!(lookup(&address(unassigned)), Lookup::Unknown));
+    }
+
+    #[test]
+    fn never_names_a_vendor_for_randomized_addresses() {
+        // Phones randomize by setting the local bit on otherwise random octets,
+        // which can land on a real vendor's OUI once the bit is cleared
+        for oui in assignments().keys() {
+            let randomized = address([oui[0] | LOCAL_BIT, oui[1], oui[2]]);
+            assert!(matches!(lookup(&randomized), Lookup::Local), "{oui:02x?}");
+        }
+        for randomized in [
+            [0xda, 0xa1, 0x19, 0x3c, 0x5e, 0x01],
+            [0x7a, 0x2b, 0x00, 0x91, 0x44, 0xfe],
+            [0x02, 0x00, 0x00, 0x00, 0x00, 0x01],
+        ] {
+            assert_eq!(
+                Named(&randomized).to_string(),
+                format!("{} (randomized)", Mac(&randomized))
+            );
+        }
+    }
+
+    #[test]
+    fn tells_group_addresses_from_randomized_ones() {
+        assert!(matches!(lookup(&[0xff; 6]), Lookup::Multicast));
+        // IPv6 multicast, which also has the local bit set
+        assert!(matches!(
+            lookup(&[0x33, 0x33, 0x00, 0x00, 0x00, 0x01]),
+            Lookup::Multicast
+        ));
+        assert!(matches!(
+            lookup(&[0x01, 0x00, 0x5e, 0x00, 0x00, 0xfb]),
+            Lookup::Multicast
+        ));
+    }
+
+    /// MA-L assignments in the full IEEE registry, rounded up.
+    const FULL_REGISTRY: usize = 40_000;
+
+    #[test]
+    fn fits_its_flash_budget() {
+        let budget: usize = env!("OUI_BUDGET").parse().unwrap();
+        assert_eq!(TABLE.entries(), assignments().len());
+        assert!(TABLE.size() <= budget);
+
+        // The checked-in snapshot may be a sample, so size the full registry
+        // from it as if no vendor had two assignments: a key, a vendor index,
+        // an offset and an average name for every entry
+        let vendors = TABLE.offsets.len() / 4 - 1;
+        let name = TABLE.names.len().div_ceil(vendors);
+        let entries = TABLE.entries().max(FULL_REGISTRY);
+        let full = TABLE.buckets.len() + entries * (2 + 2 + 4 + name);
+        assert!(
+            full <= budget,
+            "{full} bytes for {entries} entries, over the {budget} byte budget"
+        );
+    }
+}
@@ -0,0 +1,136 @@
+//! Layout of the OUI vendor table generated by `build.rs`.
+//!
+//! This file is compiled twice: into the firmware, where `oui.rs` reads the
+//! table, and into the build script, which uses the same code to check every
+//! entry of the table it just wrote. Keep it free of crate imports.
+//!
+//! All tables are little-endian byte strings so they can be `include_bytes!`d
+//! without caring about alignment:
+//!
+//! - `buckets`: 257 `u16`s. Entries whose OUI starts with octet `n` are
+//!   `buckets[n]..buckets[n + 1]`, so only the low two octets are stored.
+//! - `keys`: the low two octets of each OUI, big-endian, sorted per bucket.
+//! - `vendors`: a `u16` vendor index per entry; vendors share one name.
+//! - `offsets`: `u32` start of each vendor's name in `names`, plus the end.
+//! - `names`: vendor names with common words swapped for a single byte below
+//!   0x20, see [`TOKENS`].
+
+use core::fmt;
+
+/// Byte `n + 1` in a name stands for `TOKENS[n]`. Longest first, the encoder
+/// replaces greedily in this order.
+pub const TOKENS: [&str; 24] = [
+    "TECHNOLOGIES CO.,LTD.",
+    "Technologies Co.,Ltd",
+    "Communications",
+    "International",
+    "Semiconductor",
+    "Technologies",
+    "Electronics",
+    "Corporation",
+    "Information",
+    "Technology",
+    "Industrial",
+    "Enterprise",
+    "Corporate",
+    "Networks",
+    "Co., Ltd.",
+    "Co.,Ltd.",
+    "Co.,Ltd",
+    "Limited",
+    "Systems",
+    "Company",
+    "Inc.",
+    "GmbH",
+    "Ltd",
+    "Inc",
+];
+
+/// Flash kept for everything else in the firmware image: code, the radio
+/// blobs and both network stacks, with room to grow.
+pub const FIRMWARE_RESERVE: usize = 1536 * 1024;
+
+/// Most flash the table may take in an app partition of `partition` bytes;
+/// the build fails rather than go over.
+pub const fn budget(partition: usize) -> usize {
+    partition.saturating_sub(FIRMWARE_RESERVE)
+}
+
+pub struct Table<'a> {
+    pub buckets: &'a [u8],
+    pub keys: &'a [u8],
+    pub vendors: &'a [u8],
+    pub offsets: &'a [u8],
+    pub names: &'a [u8],
+}
+
+impl<'a> Table<'a> {
+    /// The encoded vendor name for `oui`, see [`expand`].
+    pub fn find(&self, oui: [u8; 3]) -> Option<&'a [u8]> {
+        let start = u16_at(self.buckets, oui[0] as usize) as usize;
+        let end = u16_at(self.buckets, oui[0] as usize + 1) as usize;
+        let low = [oui[1], oui[2]];
+
+        let (mut lo, mut hi) = (start, end);
+        while lo < hi {
+            let mid = (lo + hi) / 2;
+            let key = [self.keys[2 * mid], self.keys[2 * mid + 1]];
+
+            match key.cmp(&low) {
+                core::cmp::Ordering::Less => lo = mid + 1,
+                core::cmp::Ordering::Greater => hi = mid,
+                core::cmp::Ordering::Equal => {
+                    let vendor = u16_at(self.vendors, mid) as usize;
+                    let from = u32_at(self.offsets, vendor) as usize;
+                    let to = u32_at(self.offsets, vendor + 1) as usize;
+                    return Some(&self.names[from..to]);
+                }
+            }
+        }
+
+        None
+    }
+
+    pub fn entries(&self) -> usize {
+        u16_at(self.buckets, 256) as usize
+    }
+
+    pub fn size(&self) -> usize {
+        self.buckets.len()
+            + self.keys.len()
+            + self.vendors.len()
+            + self.offsets.len()
+            + self.names.len()
+    }
+}
+
+/// Write out an encoded name, expanding tokens.
+pub fn expand(encoded: &[u8], f: &mut impl fmt::Write) -> fmt::Result {
+    let mut rest = encoded;
+
+    while !rest.is_empty() {
+        let run = rest.iter().position(|byte| *byte < 0x20).unwrap_or(rest.len());
+
+        if run > 0 {
+            // Tokens are ASCII, so splitting around them keeps UTF-8 intact
+            f.write_str(core::str::from_utf8(&rest[..run]).map_err(|_| fmt::Error)?)?;
+            rest = &rest[run..];
+            continue;
+        }
+
+        let token = TOKENS.get(rest[0] as usize - 1).ok_or(fmt::Error)?;
+        f.write_str(token)?;
+        rest = &rest[1..];
+    }
+
+    Ok(())
+}
+
+fn u16_at(bytes: &[u8], index: usize) -> u16 {
+    u16::from_le_bytes([bytes[2 * index], bytes[2 * index + 1]])
+}
+
+fn u32_at(bytes: &[u8], index: usize) -> u32 {
+    let at = 4 * index;
+    u32::from_le_bytes([bytes[at], bytes[at + 1], bytes[at + 2], bytes[at + 3]])
+}
@@ -0,0 +1,197 @@
+//! Picking records out of the store.
+//!
+//! A `Query` says which records are wanted. The store walks the log with it
+//! a few entries at a time, starting from any record ID, so a page of a big
+//! survey costs about a page of heap rather than the whole thing.
+
+use alloc::{format, string::String, vec::Vec};
+
+use crate::{
+    alerts::Alert,
+    record::{self, Record},
+};
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Query {
+    /// `Record::kind`
+    pub kind: Option<String>,
+    /// Unix seconds, both inclusive. Records from before the clock was set
+    /// don't match a time range.
+    pub since: Option<u32>,
+    pub until: Option<u32>,
+    /// Wi-Fi or 802.15.4, whichever the record is about. Only PANs, spectrum
+    /// sweeps and channel switch alerts are stored with a channel; networks
+    /// and devices aren't, so never match.
+    pub channel: Option<u8>,
+    /// A signal level at least this strong, in dBm. Only spectrum sweeps are
+    /// stored with one heard; an occupancy count's floor is a setting, not a
+    /// level, so like networks and devices it never matches.
+    pub min_rssi: Option<i8>,
+    pub ssid_prefix: Option<String>,
+    /// Somewhere in the record as printed, stamp included
+    pub text: Option<String>,
+}
+
+impl Query {
+    /// Whether the stored entry `bytes` is wanted. Entries that don't decode
+    /// only turn up when nothing's filtered.
+    pub fn matches(&self, bytes: &[u8]) -> bool {
+        let Some(record) = Record::decode(bytes) else {
+            return *self == Self::default();
+        };
+        let stamp = record::unstamp(bytes).0;
+
+        if self
+            .kind
+            .as_deref()
+            .is_some_and(|kind| kind != record.kind())
+        {
+            return false;
+        }
+
+        if self.since.is_some() || self.until.is_some() {
+            let Some(unix_s) = stamp.and_then(|stamp| stamp.unix_s) else {
+                return false;
+            };
+            if self.since.is_some_and(|since| unix_s < since)
+                || self.until.is_some_and(|until| unix_s > until)
+            {
+                return false;
+            }
+        }
+
+        if self
+            .channel
+            .is_some_and(|channel| !on_channel(&record, channel))
+        {
+            return false;
+        }
+
+        if self
+            .min_rssi
+            .is_some_and(|min_rssi| strongest(&record).is_none_or(|rssi| rssi < min_rssi))
+        {
+            return false;
+        }
+
+        if let Some(prefix) = &self.ssid_prefix {
+            if !ssid(&record).is_some_and(|ssid| ssid.starts_with(prefix.as_str())) {
+                return false;
+            }
+        }
+
+        if let Some(text) = &self.text {
+            let line = match stamp {
+                Some(stamp) => format!("{stamp} {record}"),
+                None => format!("{record}"),
+            };
+            if !line.contains(text.as_str()) {
+                return false;
+            }
+        }
+
+        true
+    }
+}
+
+fn on_channel(record: &Record, channel: u8) -> bool {
+    match record {
+        Record::Pan(sighting) => sighting.channel == channel,
+        Record::Alert(Alert::ChannelSwitchSpoof { channel: other, .. }) => *other == channel,
+        Record::Spectrum(channels) => channels.iter().any(|other| other.channel == channel),
+        _ => false,
+    }
+}
+
+/// The strongest level a record heard: a spectrum peak.
+fn strongest(record: &Record) -> Option<i8> {
+    match record {
+        Record::Spectrum(channels) => channels.iter().map(|channel| channel.peak_dbm).max(),
+        _ => None,
+    }
+}
+
+fn ssid(record: &Record) -> Option<&str> {
+    match record {
+        Record::Network(ssid) => Some(ssid.as_str()),
+        Record::Alert(Alert::EvilTwin { ssid, .. })
+        | Record::Alert(Alert::SecurityDowngrade { ssid, .. }) => Some(ssid.as_str()),
+        _ => None,
+    }
+}
+
+/// Where a page of records starts: a record ID, and the generation of the
+/// log it counts in. The log numbers records from 0 again each time it's
+/// erased, so an ID from before then would skip what's been stored since.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Cursor {
+    /// None for an ID on its own, taken as counting in the log as it is now
+    pub generation: Option<u32>,
+    pub id: u32,
+}
+
+/// Up to a page of matching records, with their IDs.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Page {
+    pub records: Vec<(u32, Vec<u8>)>,
+    /// Of the log the IDs here count in
+    pub generation: u32,
+    /// Where the next page starts, if there's more
+    pub next: Option<u32>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{fingerprint::Sighting, spectrum::SpectrumChannel};
+
+    fn matching(query: &Query, records: &[Record]) -> Vec<&'static str> {
+        let matched = records
+            .iter()
+            .filter(|record| query.matches(&record.encode()));
+        matched.map(Record::kind).collect()
+    }
+
+    #[test]
+    fn only_filters_by_levels_and_channels_records_have() {
+        let records = [
+            Record::Network("CoffeeShop".into()),
+            Record::Device(Sighting {
+                device: 7,
+                fingerprint: 0x1234_5678,
+                mac: [0x02, 0, 0, 0, 0, 1],
+                linked: false,
+            }),
+            Record::Occupancy {
+                count: 4,
+                window_s: 300,
+                min_rssi: -75,
+            },
+            Record::Spectrum(alloc::vec![SpectrumChannel {
+                channel: 15,
+                mean_dbm: -90,
+                peak_dbm: -60,
+                wifi_percent: 10,
+            }]),
+            Record::Alert(Alert::ChannelSwitchSpoof {
+                bssid: [0x3c, 0x84, 0x6a, 0x10, 0x20, 0x30],
+                channel: 6,
+            }),
+        ];
+
+        let loud = |min_rssi| Query {
+            min_rssi: Some(min_rssi),
+            ..Query::default()
+        };
+        assert_eq!(matching(&loud(-80), &records), ["spectrum"]);
+        assert!(matching(&loud(-50), &records).is_empty());
+
+        let on = |channel| Query {
+            channel: Some(channel),
+            ..Query::default()
+        };
+        assert_eq!(matching(&on(15), &records), ["spectrum"]);
+        assert_eq!(matching(&on(6), &records), ["alert"]);
+        assert!(matching(&on(1), &records).is_empty());
+    }
+}
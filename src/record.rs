@@ -0,0 +1,355 @@
+//! What actually goes into a storage slot.
+//!
+//! Every record starts with a tag byte below 0x20. The store used to keep
+//! networks as their raw SSID bytes, and those still read back as networks
+//! as long as they don't start with a byte that could be a tag; an SSID can
+//! start with anything, so new ones are tagged like everything else.
+//!
+//! Storage wraps each record in a stamp saying when it was made. Entries from
+//! before stamps existed simply don't have one.
+
+use alloc::{string::String, vec::Vec};
+use core::fmt;
+
+use crate::{
+    alerts::Alert, clock::Stamp, dot15d4::PanSighting, fingerprint::Sighting, oui::Named,
+    spectrum::SpectrumChannel,
+};
+
+const TAG_ALERT: u8 = 0x01;
+const TAG_DEVICE: u8 = 0x02;
+const TAG_OCCUPANCY: u8 = 0x03;
+const TAG_PAN: u8 = 0x04;
+const TAG_SPECTRUM: u8 = 0x05;
+const TAG_STAMPED: u8 = 0x06;
+const TAG_NETWORK: u8 = 0x07;
+
+#[derive(Clone, Debug)]
+pub enum Record {
+    Network(String),
+    Alert(Alert),
+    /// A client MAC and the pseudo-device its probes cluster into
+    Device(Sighting),
+    /// Distinct clients seen within `window_s` above `min_rssi`. One is
+    /// stored per reading, equal or not, so the stamps on them make up the
+    /// time series.
+    Occupancy {
+        count: u16,
+        window_s: u16,
+        min_rssi: i8,
+    },
+    /// An 802.15.4 node, and the network it advertised if it was a beacon or
+    /// Thread discovery response
+    Pan(PanSighting),
+    /// 802.15.4 energy levels per channel next to the Wi-Fi busy time
+    /// overlapping each
+    Spectrum(Vec<SpectrumChannel>),
+}
+
+impl Record {
+    /// Short name for the kind of record, for exports.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Self::Network(_) => "network",
+            Self::Alert(_) => "alert",
+            Self::Device(_) => "device",
+            Self::Occupancy { .. } => "occupancy",
+            Self::Pan(_) => "pan",
+            Self::Spectrum(_) => "spectrum",
+        }
+    }
+
+    /// Whether each one is a reading at a point in time. Those are all
+    /// stored; networks, devices and PANs only the first time they're seen.
+    pub fn is_reading(&self) -> bool {
+        matches!(
+            self,
+            Self::Alert(_) | Self::Occupancy { .. } | Self::Spectrum(_)
+        )
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+
+        match self {
+            Self::Network(ssid) => {
+                bytes.push(TAG_NETWORK);
+                bytes.extend_from_slice(ssid.as_bytes());
+            }
+            Self::Alert(alert) => {
+                bytes.push(TAG_ALERT);
+                alert.encode(&mut bytes);
+            }
+            Self::Device(sighting) => {
+                bytes.push(TAG_DEVICE);
+                bytes.extend_from_slice(&sighting.device.to_le_bytes());
+                bytes.extend_from_slice(&sighting.fingerprint.to_le_bytes());
+                bytes.extend_from_slice(&sighting.mac);
+                bytes.push(sighting.linked as u8);
+            }
+            Self::Occupancy {
+                count,
+                window_s,
+                min_rssi,
+            } => {
+                bytes.push(TAG_OCCUPANCY);
+                bytes.extend_from_slice(&count.to_le_bytes());
+                bytes.extend_from_slice(&window_s.to_le_bytes());
+                bytes.push(*min_rssi as u8);
+            }
+            Self::Pan(sighting) => {
+                bytes.push(TAG_PAN);
+                sighting.encode(&mut bytes);
+            }
+            Self::Spectrum(channels) => {
+                bytes.push(TAG_SPECTRUM);
+                bytes.push(channels.len() as u8);
+                for channel in channels {
+                    bytes.push(channel.channel);
+                    bytes.push(channel.mean_dbm as u8);
+                    bytes.push(channel.peak_dbm as u8);
+                    bytes.push(channel.wifi_percent);
+                }
+            }
+        }
+
+        bytes
+    }
+
+    /// Decode a record, stamped or not.
+    pub fn decode(bytes: &[u8]) -> Option<Self> {
+        let (_, bytes) = unstamp(bytes);
+
+        match bytes.first()? {
+            &TAG_ALERT => Alert::decode(&bytes[1..]).map(Self::Alert),
+            &TAG_DEVICE => {
+                let mut reader = Reader(&bytes[1..]);
+                Some(Self::Device(Sighting {
+                    device: reader.u32()?,
+                    fingerprint: reader.u32()?,
+                    mac: reader.array::<6>()?,
+                    linked: reader.u8()? != 0,
+                }))
+            }
+            &TAG_OCCUPANCY => {
+                let mut reader = Reader(&bytes[1..]);
+                Some(Self::Occupancy {
+                    count: reader.u16()?,
+                    window_s: reader.u16()?,
+                    min_rssi: reader.u8()? as i8,
+                })
+            }
+            &TAG_PAN => PanSighting::decode(&bytes[1..]).map(Self::Pan),
+            &TAG_SPECTRUM => {
+                let mut reader = Reader(&bytes[1..]);
+                let count = reader.u8()?;
+                (0..count)
+                    .map(|_| {
+                        Some(SpectrumChannel {
+                            channel: reader.u8()?,
+                            mean_dbm: reader.u8()? as i8,
+                            peak_dbm: reader.u8()? as i8,
+                            wifi_percent: reader.u8()?,
+                        })
+                    })
+                    .collect::<Option<Vec<_>>>()
+                    .map(Self::Spectrum)
+            }
+            &TAG_NETWORK => String::from_utf8(bytes[1..].to_vec())
+                .ok()
+                .map(Self::Network),
+            tag if *tag < 0x20 => None,
+            // From before networks were tagged
+            _ => String::from_utf8(bytes.to_vec()).ok().map(Self::Network),
+        }
+    }
+}
+
+impl fmt::Display for Record {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Network(ssid) => write!(f, "+ {}", Escaped(ssid)),
+            Self::Alert(alert) => write!(f, "! {alert}"),
+            Self::Device(sighting) => write!(
+                f,
+                "@ device {:08x} fingerprint {:08x} {}{}",
+                sighting.device,
+                sighting.fingerprint,
+                Named(&sighting.mac),
+                if sighting.linked { " linked" } else { "" }
+            ),
+            Self::Occupancy {
+                count,
+                window_s,
+                min_rssi,
+            } => write!(
+                f,
+                "# {count} clients in {window_s}s above {min_rssi} dBm"
+            ),
+            Self::Pan(sighting) => write!(f, "~ {sighting}"),
+            Self::Spectrum(channels) => {
+                f.write_str("= spectrum")?;
+                for channel in channels {
+                    write!(
+                        f,
+                        " {}:{}/{}dBm w{}%{}",
+                        channel.channel,
+                        channel.mean_dbm,
+                        channel.peak_dbm,
+                        channel.wifi_percent,
+                        if channel.unexplained() { "*" } else { "" }
+                    )?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Text from the air, printed with control characters escaped: the log
+/// never has a zero in it, and a line break or an escape sequence in an
+/// SSID shouldn't do anything to a terminal either.
+pub struct Escaped<'a>(pub &'a str);
+
+impl fmt::Display for Escaped<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for c in self.0.chars() {
+            if c.is_control() {
+                write!(f, "{}", c.escape_default())?;
+            } else {
+                write!(f, "{c}")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Wrap encoded record bytes with when they were recorded.
+pub fn stamp(stamp: &Stamp, bytes: &[u8]) -> Vec<u8> {
+    let mut stamped = alloc::vec![TAG_STAMPED];
+    stamp.encode(&mut stamped);
+    stamped.extend_from_slice(bytes);
+    stamped
+}
+
+/// Split a stored entry into its stamp, if it has one, and the record bytes.
+pub fn unstamp(bytes: &[u8]) -> (Option<Stamp>, &[u8]) {
+    if bytes.first() != Some(&TAG_STAMPED) {
+        return (None, bytes);
+    }
+
+    let mut reader = Reader(&bytes[1..]);
+    match Stamp::decode(&mut reader) {
+        Some(stamp) => (Some(stamp), reader.0),
+        None => (None, bytes),
+    }
+}
+
+/// Little helpers for the fixed-width fields records are made of.
+pub(crate) struct Reader<'a>(pub &'a [u8]);
+
+impl<'a> Reader<'a> {
+    pub fn u8(&mut self) -> Option<u8> {
+        let (first, rest) = self.0.split_first()?;
+        self.0 = rest;
+        Some(*first)
+    }
+
+    pub fn u16(&mut self) -> Option<u16> {
+        Some(u16::from_le_bytes(self.array()?))
+    }
+
+    pub fn u32(&mut self) -> Option<u32> {
+        Some(u32::from_le_bytes(self.array()?))
+    }
+
+    pub fn array<const N: usize>(&mut self) -> Option<[u8; N]> {
+        let bytes = self.bytes(N)?;
+        let mut array = [0u8; N];
+        array.copy_from_slice(bytes);
+        Some(array)
+    }
+
+    pub fn bytes(&mut self, len: usize) -> Option<&'a [u8]> {
+        if self.0.len() < len {
+            return None;
+        }
+
+        let (bytes, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Some(bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn equal_occupancy_readings_keep_their_own_stamps() {
+        let reading = Record::Occupancy {
+            count: 3,
+            window_s: 300,
+            min_rssi: -75,
+        };
+        assert!(reading.is_reading());
+
+        let series: Vec<Vec<u8>> = (0..3)
+            .map(|minute| {
+                let at = Stamp {
+                    boot: 2,
+                    uptime_s: 60 * minute,
+                    unix_s: None,
+                };
+                stamp(&at, &reading.encode())
+            })
+            .collect();
+
+        for (minute, bytes) in series.iter().enumerate() {
+            let (at, _) = unstamp(bytes);
+            assert_eq!(at.map(|at| at.uptime_s), Some(60 * minute as u32));
+            assert!(matches!(
+                Record::decode(bytes),
+                Some(Record::Occupancy { count: 3, .. })
+            ));
+        }
+        assert!(series[0] != series[1] && series[1] != series[2]);
+    }
+
+    #[test]
+    fn reads_ssids_starting_with_a_tag_as_networks() {
+        let at = Stamp {
+            boot: 1,
+            uptime_s: 5,
+            unix_s: None,
+        };
+        for tag in 0..0x20u8 {
+            let ssid = String::from_utf8(alloc::vec![tag, b'x', 0, 0, 0, 0, 0, 0, 0, 0]).unwrap();
+            let bytes = Record::Network(ssid.clone()).encode();
+
+            assert!(matches!(Record::decode(&bytes), Some(Record::Network(read)) if read == ssid));
+            let stamped = stamp(&at, &bytes);
+            assert_eq!(unstamp(&stamped), (Some(at), bytes.as_slice()));
+            assert!(
+                matches!(Record::decode(&stamped), Some(Record::Network(read)) if read == ssid)
+            );
+        }
+    }
+
+    #[test]
+    fn still_reads_untagged_networks() {
+        assert!(matches!(
+            Record::decode(b"CoffeeShop"),
+            Some(Record::Network(ssid)) if ssid == "CoffeeShop"
+        ));
+        assert!(Record::decode(b"").is_none());
+    }
+
+    #[test]
+    fn escapes_control_characters_in_ssids() {
+        let record = Record::Network("Cafe\0\r\n\x1b[2Jé".into());
+        let printed = alloc::format!("{record}");
+        assert_eq!(printed, "+ Cafe\\u{0}\\r\\n\\u{1b}[2Jé");
+        assert!(!printed.bytes().any(|byte| byte < 0x20));
+    }
+}
@@ -1,26 +1,40 @@
-use core::cell::RefCell;
-
+use alloc::vec::Vec;
 use embassy_futures::select::{select3, Either3};
 use embassy_sync::{
     blocking_mutex::raw::CriticalSectionRawMutex,
     pubsub::{PubSubChannel, Subscriber},
 };
-use embassy_time::Timer;
+use embassy_time::Instant;
 use esp_println::println;
 
 use crate::{
     button::{ButtonPress, BUTTON_CHANNEL},
-    lights::{self, Color},
+    clock, error,
+    foxhunt::{self, Candidate, Meter},
+    lights::{self, Color, LightChange},
+    mode::{self, Mode},
+    occupancy,
+    oui::Named,
+    record::Record,
+    storage,
 };
 
+/// The four lights, bottom to top, when they're used as a bar
+const BAR: [Color; 4] = [Color::White, Color::Yellow, Color::Green, Color::Blue];
+
+/// Client counts at which each light of the occupancy bar comes on
+const OCCUPANCY_BUCKETS: [usize; 4] = [1, 5, 10, 20];
+
+/// How often the occupancy scene writes the current count to storage
+const OCCUPANCY_LOG_MS: u64 = 60_000;
+
 static SCENE_CHANNEL: PubSubChannel<CriticalSectionRawMutex, CurrentScene, 4, 4, 4> =
     PubSubChannel::<CriticalSectionRawMutex, CurrentScene, 4, 4, 4>::new();
 
 type SceneSubscriber = Subscriber<'static, CriticalSectionRawMutex, CurrentScene, 4, 4, 4>;
-type ButtonSubscriber = Subscriber<'static, CriticalSectionRawMutex, ButtonPress, 4, 4, 4>;
 
 pub async fn enter(scene: CurrentScene) {
-    SCENE_CHANNEL.publisher().unwrap().publish(scene).await;
+    error::publish(&SCENE_CHANNEL, scene).await;
 }
 
 trait Scene {
@@ -38,6 +52,8 @@ pub enum CurrentScene {
     Startup(StartupScene),
     Sniffing(SniffingScene),
     Menu(MenuScene),
+    FoxHunt(FoxHuntScene),
+    Occupancy(OccupancyScene),
 }
 
 impl CurrentScene {
@@ -52,6 +68,12 @@ impl CurrentScene {
             Self::Menu(scene) => {
                 scene.tick().await;
             }
+            Self::FoxHunt(scene) => {
+                scene.tick().await;
+            }
+            Self::Occupancy(scene) => {
+                scene.tick().await;
+            }
         }
     }
 
@@ -66,6 +88,12 @@ impl CurrentScene {
             Self::Menu(scene) => {
                 scene.button_press().await;
             }
+            Self::FoxHunt(scene) => {
+                scene.button_press().await;
+            }
+            Self::Occupancy(scene) => {
+                scene.button_press().await;
+            }
         }
     }
 
@@ -80,6 +108,12 @@ impl CurrentScene {
             Self::Menu(scene) => {
                 scene.button_down().await;
             }
+            Self::FoxHunt(scene) => {
+                scene.button_down().await;
+            }
+            Self::Occupancy(scene) => {
+                scene.button_down().await;
+            }
         }
     }
 
@@ -94,6 +128,12 @@ impl CurrentScene {
             Self::Menu(scene) => {
                 scene.button_up().await;
             }
+            Self::FoxHunt(scene) => {
+                scene.button_up().await;
+            }
+            Self::Occupancy(scene) => {
+                scene.button_up().await;
+            }
         }
     }
 
@@ -108,6 +148,12 @@ impl CurrentScene {
             Self::Menu(scene) => {
                 scene.long_press().await;
             }
+            Self::FoxHunt(scene) => {
+                scene.long_press().await;
+            }
+            Self::Occupancy(scene) => {
+                scene.long_press().await;
+            }
         }
     }
 
@@ -116,6 +162,8 @@ impl CurrentScene {
             Self::Startup(scene) => scene.enter().await,
             Self::Sniffing(scene) => scene.enter().await,
             Self::Menu(scene) => scene.enter().await,
+            Self::FoxHunt(scene) => scene.enter().await,
+            Self::Occupancy(scene) => scene.enter().await,
         }
     }
 
@@ -124,6 +172,8 @@ impl CurrentScene {
             Self::Startup(scene) => scene.leave().await,
             Self::Sniffing(scene) => scene.leave().await,
             Self::Menu(scene) => scene.leave().await,
+            Self::FoxHunt(scene) => scene.leave().await,
+            Self::Occupancy(scene) => scene.leave().await,
         }
     }
 }
@@ -134,14 +184,14 @@ async fn update_current_scene(subscriber: &mut SceneSubscriber) -> CurrentScene
 
 #[embassy_executor::task]
 pub async fn setup_scene_manager() {
-    let current_scene: RefCell<CurrentScene> = RefCell::new(CurrentScene::Startup(StartupScene {}));
-    current_scene.borrow_mut().enter().await;
+    let mut current_scene = CurrentScene::Startup(StartupScene {});
+    current_scene.enter().await;
     let mut subscriber = SCENE_CHANNEL.subscriber().unwrap();
     let mut button = BUTTON_CHANNEL.subscriber().unwrap();
 
     loop {
         let result = select3(
-            current_scene.borrow_mut().tick(),
+            current_scene.tick(),
             update_current_scene(&mut subscriber),
             button.next_message_pure(),
         )
@@ -151,21 +201,22 @@ pub async fn setup_scene_manager() {
             Either3::First(_) => (),
             Either3::Second(next_scene) => {
                 println!("Scene change: {:?}", next_scene);
-                current_scene.borrow().leave().await;
-                *current_scene.borrow_mut() = next_scene
+                current_scene.leave().await;
+                current_scene = next_scene;
+                current_scene.enter().await;
             }
             Either3::Third(button_press) => match button_press {
                 ButtonPress::Single => {
-                    current_scene.borrow_mut().button_press().await;
+                    current_scene.button_press().await;
                 }
                 ButtonPress::Long => {
-                    current_scene.borrow_mut().long_press().await;
+                    current_scene.long_press().await;
                 }
                 ButtonPress::Down => {
-                    current_scene.borrow_mut().button_down().await;
+                    current_scene.button_down().await;
                 }
                 ButtonPress::Up => {
-                    current_scene.borrow_mut().button_up().await;
+                    current_scene.button_up().await;
                 }
             },
         }
@@ -181,15 +232,15 @@ impl Scene for StartupScene {
     }
 
     async fn tick(&mut self) {
-        Timer::after_millis(100).await;
+        lights::hold(100).await;
         lights::on(Color::White).await;
-        Timer::after_millis(200).await;
+        lights::hold(200).await;
         lights::on(Color::Yellow).await;
-        Timer::after_millis(200).await;
+        lights::hold(200).await;
         lights::on(Color::Green).await;
-        Timer::after_millis(200).await;
+        lights::hold(200).await;
         lights::on(Color::Blue).await;
-        Timer::after_millis(400).await;
+        lights::hold(400).await;
         lights::all_off().await;
 
         enter(CurrentScene::Sniffing(SniffingScene {})).await;
@@ -221,7 +272,7 @@ impl Scene for SniffingScene {
     }
 
     async fn tick(&mut self) {
-        Timer::after_millis(2).await;
+        lights::hold(2).await;
     }
 }
 
@@ -231,6 +282,29 @@ pub enum MenuOption {
     Sleep,
     Erase,
     Sniff,
+    FoxHunt,
+    Occupancy,
+    Ieee802154,
+    Share,
+    Upload,
+    Live,
+}
+
+impl MenuOption {
+    fn colors(&self) -> &'static [Color] {
+        match self {
+            MenuOption::Sniff => &[Color::White],
+            MenuOption::Erase => &[Color::Yellow],
+            MenuOption::Sleep => &[Color::Green],
+            MenuOption::Bluetooth => &[Color::Blue],
+            MenuOption::FoxHunt => &[Color::White, Color::Blue],
+            MenuOption::Occupancy => &[Color::Yellow, Color::Green],
+            MenuOption::Ieee802154 => &[Color::Green, Color::Blue],
+            MenuOption::Share => &[Color::White, Color::Green],
+            MenuOption::Upload => &[Color::Yellow, Color::Blue],
+            MenuOption::Live => &[Color::White, Color::Yellow],
+        }
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -245,39 +319,20 @@ impl Scene for MenuScene {
     }
 
     async fn long_press(&mut self) {
-        let on = lights::LightChange {
-            color: match self.current {
-                MenuOption::Sniff => Color::White,
-                MenuOption::Erase => Color::Yellow,
-                MenuOption::Sleep => Color::Green,
-                MenuOption::Bluetooth => Color::Blue,
-            },
-            brightness: 100,
-            duration: 16,
-        };
-
-        let off = lights::LightChange {
-            color: match self.current {
-                MenuOption::Sniff => Color::White,
-                MenuOption::Erase => Color::Yellow,
-                MenuOption::Sleep => Color::Green,
-                MenuOption::Bluetooth => Color::Blue,
-            },
-            brightness: 0,
-            duration: 16,
-        };
+        lights::flash(self.current.colors(), 3, 100).await;
 
-        for i in 0..=5 {
-            if i % 2 == 0 {
-                lights::apply(&on).await;
-            } else {
-                lights::apply(&off).await;
+        match self.current {
+            MenuOption::FoxHunt => enter(CurrentScene::FoxHunt(FoxHuntScene::new())).await,
+            MenuOption::Occupancy => {
+                enter(CurrentScene::Occupancy(OccupancyScene { last_logged: None })).await
             }
-
-            Timer::after_millis(100).await;
+            MenuOption::Bluetooth => mode::switch(Mode::Bluetooth).await,
+            MenuOption::Ieee802154 => mode::switch(Mode::Ieee802154).await,
+            MenuOption::Share => mode::switch(Mode::Share).await,
+            MenuOption::Upload => mode::switch(Mode::Upload).await,
+            MenuOption::Live => mode::switch(Mode::Live).await,
+            _ => enter(CurrentScene::Sniffing(SniffingScene {})).await,
         }
-
-        enter(CurrentScene::Sniffing(SniffingScene {})).await;
     }
 
     async fn button_press(&mut self) {
@@ -293,23 +348,221 @@ impl Scene for MenuScene {
             MenuOption::Sleep => {
                 self.current = MenuOption::Bluetooth;
             }
+            MenuOption::Bluetooth => {
+                self.current = MenuOption::FoxHunt;
+            }
+            MenuOption::FoxHunt => {
+                self.current = MenuOption::Occupancy;
+            }
 
-            MenuOption::Bluetooth => self.current = MenuOption::Sniff,
+            MenuOption::Occupancy => {
+                self.current = MenuOption::Ieee802154;
+            }
+            MenuOption::Ieee802154 => {
+                self.current = MenuOption::Share;
+            }
+            MenuOption::Share => {
+                self.current = MenuOption::Upload;
+            }
+            MenuOption::Upload => {
+                self.current = MenuOption::Live;
+            }
+            MenuOption::Live => self.current = MenuOption::Sniff,
         }
     }
 
     async fn tick(&mut self) {
         lights::all_off().await;
 
-        match self.current {
-            MenuOption::Sniff => lights::change(Color::White, self.is_on).await,
-            MenuOption::Erase => lights::change(Color::Yellow, self.is_on).await,
-            MenuOption::Sleep => lights::change(Color::Green, self.is_on).await,
-            MenuOption::Bluetooth => lights::change(Color::Blue, self.is_on).await,
+        for color in self.current.colors() {
+            lights::change(color.clone(), self.is_on).await;
         }
 
         self.is_on = !self.is_on;
 
-        Timer::after_millis(400).await;
+        lights::hold(400).await;
+    }
+}
+
+/// Pick one of the strongest nearby networks with single presses (its number
+/// is shown in binary on the lights), lock onto it with a long press, then
+/// follow the bar: more lights, brighter and faster blinking means closer.
+/// Another long press gives up and goes back to sniffing.
+#[derive(Clone, Debug)]
+pub struct FoxHuntScene {
+    candidates: Vec<Candidate>,
+    selected: usize,
+    hunting: bool,
+    is_on: bool,
+}
+
+impl Default for FoxHuntScene {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FoxHuntScene {
+    pub fn new() -> Self {
+        Self {
+            candidates: foxhunt::strongest(8, Instant::now().as_millis()),
+            selected: 0,
+            hunting: false,
+            is_on: true,
+        }
+    }
+
+    fn print_selected(&self) {
+        if let Some(candidate) = self.candidates.get(self.selected) {
+            println!(
+                "Fox hunt target {}: {} {} ch {} {} dBm",
+                self.selected + 1,
+                candidate.ssid,
+                Named(&candidate.bssid),
+                candidate.channel,
+                candidate.rssi
+            );
+        }
+    }
+}
+
+impl Scene for FoxHuntScene {
+    async fn enter(&self) {
+        lights::all_off().await;
+        self.print_selected();
+    }
+
+    async fn button_press(&mut self) {
+        if self.hunting || self.candidates.is_empty() {
+            return;
+        }
+
+        self.selected = (self.selected + 1) % self.candidates.len();
+        self.print_selected();
+    }
+
+    async fn long_press(&mut self) {
+        match self.candidates.get(self.selected) {
+            Some(candidate) if !self.hunting => {
+                foxhunt::hunt(candidate, Instant::now().as_millis());
+                self.hunting = true;
+                lights::all_off().await;
+            }
+            _ => enter(CurrentScene::Sniffing(SniffingScene {})).await,
+        }
+    }
+
+    async fn tick(&mut self) {
+        if !self.hunting {
+            if self.candidates.is_empty() {
+                // Nothing heard yet: slow blink on the bottom light
+                lights::change(BAR[0].clone(), self.is_on).await;
+                self.is_on = !self.is_on;
+                lights::hold(1_000).await;
+                return;
+            }
+
+            let number = self.selected + 1;
+            for (bit, color) in BAR.iter().enumerate() {
+                lights::change(color.clone(), number & (1 << bit) != 0).await;
+            }
+
+            lights::hold(200).await;
+            return;
+        }
+
+        let Some(rssi) = foxhunt::signal(Instant::now().as_millis()) else {
+            // Lost it: everything off except a slow blink on the top light
+            for color in &BAR[..3] {
+                lights::off(color.clone()).await;
+            }
+            lights::change(BAR[3].clone(), self.is_on).await;
+            self.is_on = !self.is_on;
+            lights::hold(1_000).await;
+            return;
+        };
+
+        let meter = Meter::from_rssi(rssi);
+        // The top of the bar blinks, the rest stays on
+        let top = meter.lit.saturating_sub(1);
+
+        for (index, color) in BAR.iter().enumerate() {
+            let brightness = if !self.is_on && index >= top {
+                0
+            } else if index < meter.lit {
+                100
+            } else if index == meter.lit {
+                meter.partial
+            } else {
+                0
+            };
+
+            lights::apply(&LightChange {
+                color: color.clone(),
+                brightness,
+                duration: 16,
+            })
+            .await;
+        }
+
+        self.is_on = !self.is_on;
+        lights::hold(meter.period_ms / 2).await;
+    }
+
+    async fn leave(&self) {
+        foxhunt::stop();
+    }
+}
+
+/// Room occupancy: the bar shows how many distinct clients are around (see
+/// `OCCUPANCY_BUCKETS`), and the count is logged every `OCCUPANCY_LOG_MS`.
+/// Long press goes back to sniffing.
+#[derive(Clone, Debug)]
+pub struct OccupancyScene {
+    last_logged: Option<u64>,
+}
+
+impl Scene for OccupancyScene {
+    async fn enter(&self) {
+        lights::all_off().await;
+    }
+
+    async fn long_press(&mut self) {
+        enter(CurrentScene::Sniffing(SniffingScene {})).await;
+    }
+
+    async fn tick(&mut self) {
+        let now = Instant::now().as_millis();
+        let count = occupancy::count(now);
+
+        let lit = OCCUPANCY_BUCKETS
+            .iter()
+            .filter(|bucket| count >= **bucket)
+            .count();
+        for (index, color) in BAR.iter().enumerate() {
+            lights::change(color.clone(), index < lit).await;
+        }
+
+        if self
+            .last_logged
+            .is_none_or(|at| now.saturating_sub(at) >= OCCUPANCY_LOG_MS)
+        {
+            let settings = occupancy::settings();
+            let record = Record::Occupancy {
+                count: count.min(u16::MAX as usize) as u16,
+                window_s: (settings.window_ms / 1000) as u16,
+                min_rssi: settings.min_rssi,
+            };
+
+            println!("{} {record}", clock::now());
+            // A button press or scene change can cancel this tick at any
+            // await, so don't wait on storage; if it's behind, log again
+            // next tick
+            if storage::try_append(record.encode()) {
+                self.last_logged = Some(now);
+            }
+        }
+
+        lights::hold(1_000).await;
     }
 }
@@ -0,0 +1,284 @@
+//! The console on the USB serial port: read lines, hand them to `console`.
+//!
+//! Output goes out through `println!` like everything else, so it shares
+//! the port with the log. Host tools talk in frames instead, which start
+//! with a zero byte no one types; those go to `telemetry`.
+
+use core::fmt::{self, Write};
+
+use alloc::{format, string::String, vec::Vec};
+use embedded_io_async::Read;
+use esp_hal::{peripherals::USB_DEVICE, usb_serial_jtag::UsbSerialJtag};
+use esp_println::{print, println};
+
+use crate::{
+    allowlist::{self, Trusted},
+    battery::{self, Level},
+    channels,
+    clock::{self, Source, Stamp},
+    config,
+    console::{Console, Device},
+    crash,
+    error::{self, Error},
+    frame::{MacAddress, Security},
+    ingest, lights,
+    mode::{self, Mode},
+    occupancy,
+    query::{Cursor, Page, Query},
+    spectrum,
+    storage::{self, Store},
+    supervisor::{self, Task},
+    telemetry,
+};
+
+const MAX_LINE: usize = 128;
+
+/// Longest frame from the host we'll collect
+const MAX_FRAME: usize = 256;
+
+const BACKSPACE: u8 = 0x08;
+const DELETE: u8 = 0x7f;
+
+/// What to do once the reply's been printed.
+enum Then {
+    Erase,
+    Switch(Mode),
+}
+
+struct Firmware {
+    store: Option<Store>,
+    then: Option<Then>,
+}
+
+impl Firmware {
+    /// The store, opened on first use; if that fails it's tried again next
+    /// time.
+    fn store(&mut self) -> Result<&mut Store, Error> {
+        let store = match self.store.take() {
+            Some(store) => store,
+            None => Store::new()?,
+        };
+        Ok(self.store.insert(store))
+    }
+}
+
+impl Device for Firmware {
+    fn page(&mut self, query: &Query, from: Cursor, limit: usize) -> Page {
+        self.store()
+            .and_then(|store| store.page(query.clone(), from, limit))
+            .unwrap_or_else(|err| {
+                println!("storage: {err}");
+                Page::default()
+            })
+    }
+
+    fn count(&mut self, query: &Query, from: Cursor) -> usize {
+        self.store()
+            .and_then(|store| store.count(query, from))
+            .unwrap_or_else(|err| {
+                println!("storage: {err}");
+                0
+            })
+    }
+
+    fn stats(&mut self) -> String {
+        let now = embassy_time::Instant::now().as_millis();
+        let mut stats = format!("clock {}\n", clock::now());
+
+        match mode::current() {
+            Mode::Wifi | Mode::Live => {
+                stats += &channels::survey().summary();
+                stats += &format!("{} people around\n", occupancy::count(now));
+                stats += &format!("{} frames dropped\n", ingest::dropped_frames());
+            }
+            Mode::Ieee802154 => stats += &spectrum::summary(),
+            _ => {
+                if let Some(survey) = channels::persisted() {
+                    stats += &survey.summary();
+                }
+            }
+        }
+
+        match self.store().and_then(Store::len) {
+            Ok(len) => stats += &format!("{len} records stored\n"),
+            Err(err) => stats += &format!("storage: {err}\n"),
+        }
+        stats += &format!("this boot: {}\n", storage::counters());
+        stats += &format!("lights: {}\n", lights::lateness());
+        for subsystem in error::all_disabled() {
+            stats += &format!("{subsystem:?} disabled\n");
+        }
+        stats
+    }
+
+    fn battery(&self) -> Option<Level> {
+        battery::level()
+    }
+
+    fn erase(&mut self) {
+        self.then = Some(Then::Erase);
+    }
+
+    fn settings(&self) -> Vec<(&'static str, String)> {
+        config::get().shown()
+    }
+
+    fn set(&mut self, key: &str, value: &str) -> Result<(), &'static str> {
+        config::set(key, value)
+    }
+
+    fn reset_settings(&mut self) -> Result<(), &'static str> {
+        config::reset()
+    }
+
+    fn trusted(&self) -> Vec<Trusted> {
+        allowlist::trusted()
+    }
+
+    fn trust(
+        &mut self,
+        ssid: &str,
+        bssid: MacAddress,
+        security: Security,
+    ) -> Result<(), &'static str> {
+        allowlist::trust(ssid, bssid, security)
+    }
+
+    fn forget(&mut self, ssid: &str) -> bool {
+        allowlist::forget(ssid)
+    }
+
+    fn now(&self) -> Stamp {
+        clock::now()
+    }
+
+    fn set_time(&mut self, unix_ms: u64) {
+        clock::set_unix_ms(unix_ms, Source::Serial);
+    }
+
+    fn switch(&mut self, mode: Mode) {
+        self.then = Some(Then::Switch(mode));
+    }
+
+    /// Back into the same mode, which lets Wi-Fi save its survey first
+    fn reboot(&mut self) {
+        self.then = Some(Then::Switch(mode::current()));
+    }
+
+    fn crash(&self) -> Option<String> {
+        crash::last().map(|report| format!("{report}"))
+    }
+
+    fn clear_crash(&mut self) -> Result<(), &'static str> {
+        crash::clear().map_err(|_| "flash failed")
+    }
+
+    fn health(&self) -> String {
+        format!("{}", supervisor::health())
+    }
+}
+
+/// Console output, printed as it comes with the line endings a terminal
+/// wants.
+struct Terminal;
+
+impl Write for Terminal {
+    fn write_str(&mut self, text: &str) -> fmt::Result {
+        for (i, line) in text.split('\n').enumerate() {
+            if i > 0 {
+                print!("\r\n");
+            }
+            print!("{line}");
+        }
+        Ok(())
+    }
+}
+
+#[embassy_executor::task]
+pub async fn start_serial(usb: USB_DEVICE) {
+    // Writing goes through esp-println, which drives the same peripheral
+    let (mut rx, _tx) = UsbSerialJtag::new_async(usb).split();
+
+    let mut console = Console::new();
+    let mut firmware = Firmware {
+        store: None,
+        then: None,
+    };
+
+    let mut line = Vec::with_capacity(MAX_LINE);
+    let mut buffer = [0u8; 32];
+    let mut last = 0u8;
+    // Since the last zero, while that might be a frame
+    let mut frame: Option<Vec<u8>> = None;
+
+    loop {
+        supervisor::wait(Task::Serial);
+        let Ok(len) = rx.read(&mut buffer).await else {
+            continue;
+        };
+        supervisor::beat(Task::Serial);
+
+        for &byte in &buffer[..len] {
+            if let Some(body) = &mut frame {
+                match byte {
+                    0 if body.is_empty() => (),
+                    0 => {
+                        let body = core::mem::take(body);
+                        if let Some(message) = wif_protocol::decode(&body) {
+                            frame = None;
+                            telemetry::received(message).await;
+                        }
+                    }
+                    byte if body.len() < MAX_FRAME => body.push(byte),
+                    _ => frame = None,
+                }
+                continue;
+            }
+            if byte == 0 {
+                frame = Some(Vec::new());
+                continue;
+            }
+
+            // A terminal sending CRLF ends one line, not two
+            let crlf = last == b'\r' && byte == b'\n';
+            last = byte;
+
+            match byte {
+                b'\n' if crlf => (),
+                b'\r' | b'\n' => {
+                    print!("\r\n");
+                    let Ok(text) = core::str::from_utf8(&line) else {
+                        line.clear();
+                        continue;
+                    };
+
+                    // Dumps can be long, so straight out rather than gathered
+                    console.handle(text, &mut firmware, &mut Terminal);
+                    line.clear();
+
+                    match firmware.then.take() {
+                        Some(Then::Erase) => {
+                            if let Err(err) = storage::erase().await {
+                                println!("erase failed: {err}");
+                            }
+                        }
+                        Some(Then::Switch(mode)) => mode::switch(mode).await,
+                        None => (),
+                    }
+                }
+                BACKSPACE | DELETE => {
+                    if line.pop().is_some() {
+                        print!("\x08 \x08");
+                    }
+                }
+                byte if byte.is_ascii_graphic() || byte == b' ' => {
+                    if line.len() < MAX_LINE {
+                        line.push(byte);
+                        print!("{}", byte as char);
+                    }
+                }
+                _ => (),
+            }
+        }
+    }
+}
@@ -0,0 +1,309 @@
+//! Share mode: bring up our own access point and serve the survey over HTTP.
+//!
+//! Join the network and open http://192.168.4.1/ for a status page, the
+//! JSON API or a CSV download of the whole store. The network always has a
+//! password: without one set, a new one is made up each time and printed
+//! over serial.
+
+use core::cell::RefCell;
+
+use alloc::{string::String, vec::Vec};
+use critical_section::Mutex;
+use embassy_executor::Spawner;
+use embassy_net::{
+    tcp::{self, TcpSocket},
+    udp::{PacketMetadata, UdpSocket},
+    Ipv4Address, Ipv4Cidr, Stack, StackResources, StaticConfigV4,
+};
+use embassy_time::{Duration, Timer};
+use embedded_io_async::Write;
+use esp_hal::{
+    peripherals::{RADIO_CLK, WIFI},
+    rng::Rng,
+    timer::AnyTimer,
+};
+use esp_println::println;
+use esp_wifi::{
+    init,
+    wifi::{
+        new_with_mode, AccessPointConfiguration, AuthMethod, Configuration, WifiApDevice,
+        WifiController, WifiDevice,
+    },
+    EspWifiInitFor,
+};
+use static_cell::make_static;
+
+use crate::{
+    battery::{self, Level},
+    channels::{self, Survey},
+    config,
+    dhcp::{self, Leases},
+    error::{self, Error, Subsystem},
+    export,
+    http::{self, Body, Request, Source},
+    query::{Cursor, Page, Query},
+    storage::Store,
+    wifi,
+};
+
+const HTTP_PORT: u16 = 80;
+const DHCP_SERVER_PORT: u16 = 67;
+const DHCP_CLIENT_PORT: u16 = 68;
+
+/// Longest request head we'll read
+const MAX_HEAD: usize = 1024;
+
+/// What made up passwords are made of; nothing that reads like something
+/// else off a serial console
+const PASSWORD_CHARS: &[u8] = b"abcdefghjkmnpqrstuvwxyz23456789";
+const PASSWORD_LEN: usize = 12;
+
+type Device = WifiDevice<'static, WifiApDevice>;
+
+#[derive(Clone, Debug)]
+pub struct Settings {
+    pub ssid: String,
+    /// WPA2, at least 8 characters; empty to have one made up each time
+    pub password: String,
+}
+
+impl Settings {
+    pub const fn new() -> Self {
+        Self {
+            ssid: String::new(),
+            password: String::new(),
+        }
+    }
+
+    fn ssid(&self) -> &str {
+        if self.ssid.is_empty() {
+            "wifblink"
+        } else {
+            &self.ssid
+        }
+    }
+}
+
+static SETTINGS: Mutex<RefCell<Settings>> = Mutex::new(RefCell::new(Settings::new()));
+
+pub fn settings() -> Settings {
+    critical_section::with(|cs| SETTINGS.borrow_ref(cs).clone())
+}
+
+pub fn configure(settings: Settings) {
+    critical_section::with(|cs| *SETTINGS.borrow_ref_mut(cs) = settings);
+}
+
+#[embassy_executor::task]
+pub async fn start_share(
+    spawner: Spawner,
+    timer: AnyTimer,
+    rng: Rng,
+    radio_clock: RADIO_CLK,
+    wifi: WIFI,
+) {
+    // Dropping the controller would stop the radio
+    let (stack, _controller) = match access_point(spawner, timer, rng, radio_clock, wifi).await {
+        Ok(started) => started,
+        Err(err) => return error::disable(Subsystem::Radio, err).await,
+    };
+
+    let mut rx_buffer = [0u8; 1024];
+    let mut tx_buffer = [0u8; 2048];
+
+    loop {
+        let mut socket = TcpSocket::new(stack, &mut rx_buffer, &mut tx_buffer);
+        socket.set_timeout(Some(Duration::from_secs(10)));
+
+        if socket.accept(HTTP_PORT).await.is_err() {
+            continue;
+        }
+
+        if let Err(err) = serve(&mut socket).await {
+            println!("http: {:?}", err);
+        }
+
+        socket.close();
+        let _ = socket.flush().await;
+        Timer::after_millis(50).await;
+        socket.abort();
+    }
+}
+
+/// Bring up our access point, its network and the DHCP server.
+async fn access_point(
+    spawner: Spawner,
+    timer: AnyTimer,
+    mut rng: Rng,
+    radio_clock: RADIO_CLK,
+    wifi: WIFI,
+) -> Result<(&'static Stack<Device>, WifiController<'static>), Error> {
+    let seed = (rng.random() as u64) << 32 | rng.random() as u64;
+    let mut settings = settings();
+    if settings.password.is_empty() {
+        settings.password = made_up_password(&mut rng);
+        println!("share: no password set, using {}", settings.password);
+    }
+
+    let init = make_static!(init(EspWifiInitFor::Wifi, timer, rng, radio_clock)?);
+    let (device, mut controller) = new_with_mode(init, wifi, WifiApDevice)?;
+
+    // The settings check lengths, these can't fail
+    let password = settings.password.as_str();
+    controller.set_configuration(&Configuration::AccessPoint(AccessPointConfiguration {
+        ssid: settings.ssid().try_into().map_err(|_| Error::Radio)?,
+        password: password.try_into().map_err(|_| Error::Radio)?,
+        auth_method: AuthMethod::WPA2Personal,
+        ..Default::default()
+    }))?;
+    wifi::start_controller(&mut controller).await?;
+
+    let [a, b, c, d] = dhcp::SERVER;
+    let address = Ipv4Address::new(a, b, c, d);
+    let config = embassy_net::Config::ipv4_static(StaticConfigV4 {
+        address: Ipv4Cidr::new(address, 24),
+        gateway: None,
+        dns_servers: Default::default(),
+    });
+    let stack = &*make_static!(Stack::new(
+        device,
+        config,
+        make_static!(StackResources::<4>::new()),
+        seed
+    ));
+
+    spawner.spawn(net_task(stack)).unwrap();
+    spawner.spawn(dhcp_task(stack)).unwrap();
+
+    stack.wait_config_up().await;
+    println!("sharing on \"{}\" at http://{}/", settings.ssid(), address);
+
+    Ok((stack, controller))
+}
+
+fn made_up_password(rng: &mut Rng) -> String {
+    (0..PASSWORD_LEN)
+        .map(|_| PASSWORD_CHARS[rng.random() as usize % PASSWORD_CHARS.len()] as char)
+        .collect()
+}
+
+#[embassy_executor::task]
+async fn net_task(stack: &'static Stack<Device>) {
+    stack.run().await
+}
+
+#[embassy_executor::task]
+async fn dhcp_task(stack: &'static Stack<Device>) {
+    let mut rx_meta = [PacketMetadata::EMPTY; 4];
+    let mut rx_buffer = [0u8; 1024];
+    let mut tx_meta = [PacketMetadata::EMPTY; 4];
+    let mut tx_buffer = [0u8; 1024];
+    let mut socket = UdpSocket::new(
+        stack,
+        &mut rx_meta,
+        &mut rx_buffer,
+        &mut tx_meta,
+        &mut tx_buffer,
+    );
+    socket.bind(DHCP_SERVER_PORT).unwrap();
+
+    let mut leases = Leases::new();
+    let mut buffer = [0u8; 576];
+
+    loop {
+        let Ok((len, _)) = socket.recv_from(&mut buffer).await else {
+            continue;
+        };
+
+        if let Some(reply) = dhcp::reply(&buffer[..len], &mut leases) {
+            let _ = socket
+                .send_to(&reply, (Ipv4Address::BROADCAST, DHCP_CLIENT_PORT))
+                .await;
+        }
+    }
+}
+
+/// The store, straight from flash. One that can't be read looks empty.
+struct FlashSource(Option<Store>);
+
+impl Source for FlashSource {
+    fn len(&mut self) -> usize {
+        self.0
+            .as_mut()
+            .and_then(|store| store.len().ok())
+            .unwrap_or(0)
+    }
+
+    fn count(&mut self, query: &Query) -> usize {
+        self.0
+            .as_mut()
+            .and_then(|store| store.count(query, Cursor::default()).ok())
+            .unwrap_or(0)
+    }
+
+    fn page(&mut self, query: &Query, from: Cursor, limit: usize) -> Page {
+        self.0
+            .as_mut()
+            .and_then(|store| store.page(query.clone(), from, limit).ok())
+            .unwrap_or_default()
+    }
+
+    fn battery(&self) -> Option<Level> {
+        battery::level()
+    }
+
+    fn survey(&self) -> Option<Survey> {
+        channels::persisted()
+    }
+
+    fn settings(&self) -> Vec<(&'static str, String)> {
+        config::get().shown()
+    }
+
+    fn set(&mut self, key: &str, value: &str) -> Result<(), &'static str> {
+        config::set(key, value)
+    }
+
+    /// `access_point` never starts one without a password.
+    fn open(&self) -> bool {
+        false
+    }
+}
+
+async fn serve(socket: &mut TcpSocket<'_>) -> Result<(), tcp::Error> {
+    let mut head = [0u8; MAX_HEAD];
+    let mut len = 0;
+
+    loop {
+        let read = socket.read(&mut head[len..]).await?;
+        if read == 0 {
+            return Ok(());
+        }
+        len += read;
+
+        if http::head_end(&head[..len]).is_some() || len == head.len() {
+            break;
+        }
+    }
+
+    let mut source = FlashSource(Store::new().ok());
+    let response = match Request::parse(&head[..len]) {
+        Some(request) => http::handle(&request, &mut source),
+        None => http::bad_request(),
+    };
+
+    socket.write_all(response.head().as_bytes()).await?;
+
+    match response.body {
+        Body::Text(body) => socket.write_all(body.as_bytes()).await?,
+        Body::Csv(query) => {
+            socket.write_all(export::CSV_HEADER.as_bytes()).await?;
+            // A flash error ends the download early
+            for row in http::csv_rows(&mut source, &query) {
+                socket.write_all(row.as_bytes()).await?;
+            }
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,245 @@
+//! 2.4 GHz noise as the 802.15.4 radio sees it.
+//!
+//! While sniffing 802.15.4, each channel is sampled with the radio's energy
+//! detect. Lining those levels up against the Wi-Fi survey from the last
+//! Wi-Fi boot shows which Zigbee/Thread channels are drowned out by Wi-Fi and
+//! which are noisy for some other reason (microwaves, BLE, other meshes).
+
+use core::{cell::RefCell, fmt::Write};
+
+use alloc::{string::String, vec::Vec};
+use critical_section::Mutex;
+
+use crate::channels::{self, Survey};
+
+/// 802.15.4 channels 11 through 26
+pub const CHANNELS: usize = 16;
+const FIRST_CHANNEL: u8 = 11;
+
+/// An ED sample at or above this counts the channel as occupied
+const BUSY_DBM: i8 = -75;
+
+/// A Wi-Fi channel's 20 MHz (plus skirts) reaches this far from its center
+const WIFI_HALF_WIDTH_MHZ: u32 = 11;
+
+/// Noise this far above the floor with Wi-Fi this quiet (per-mille) is
+/// something else
+const UNEXPLAINED_DBM: i8 = -85;
+const QUIET_WIFI: u32 = 100;
+
+#[derive(Clone, Copy, Debug)]
+pub struct EnergyStats {
+    pub samples: u32,
+    sum_dbm: i32,
+    pub peak_dbm: i8,
+    /// Samples at or above `BUSY_DBM`
+    pub busy: u32,
+}
+
+impl EnergyStats {
+    const fn new() -> Self {
+        Self {
+            samples: 0,
+            sum_dbm: 0,
+            peak_dbm: i8::MIN,
+            busy: 0,
+        }
+    }
+
+    pub fn mean_dbm(&self) -> Option<i8> {
+        (self.samples > 0).then(|| (self.sum_dbm / self.samples as i32) as i8)
+    }
+
+    /// Share of samples over the busy threshold, in per-mille.
+    pub fn occupancy(&self) -> u32 {
+        if self.samples == 0 {
+            return 0;
+        }
+        self.busy * 1000 / self.samples
+    }
+}
+
+/// One channel of the combined summary, as it's stored.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SpectrumChannel {
+    pub channel: u8,
+    pub mean_dbm: i8,
+    pub peak_dbm: i8,
+    /// Overlapping Wi-Fi busy time, in percent
+    pub wifi_percent: u8,
+}
+
+impl SpectrumChannel {
+    /// Noisy with hardly any Wi-Fi on top of it.
+    pub fn unexplained(&self) -> bool {
+        self.mean_dbm >= UNEXPLAINED_DBM && (self.wifi_percent as u32) * 10 < QUIET_WIFI
+    }
+}
+
+pub struct Spectrum {
+    channels: [EnergyStats; CHANNELS],
+}
+
+impl Default for Spectrum {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Spectrum {
+    pub const fn new() -> Self {
+        Self {
+            channels: [EnergyStats::new(); CHANNELS],
+        }
+    }
+
+    pub fn get(&self, channel: u8) -> Option<&EnergyStats> {
+        self.channels
+            .get((channel as usize).checked_sub(FIRST_CHANNEL as usize)?)
+    }
+
+    pub fn record(&mut self, channel: u8, dbm: i8) {
+        let Some(index) = (channel as usize).checked_sub(FIRST_CHANNEL as usize) else {
+            return;
+        };
+        let Some(stats) = self.channels.get_mut(index) else {
+            return;
+        };
+
+        stats.samples += 1;
+        stats.sum_dbm += dbm as i32;
+        stats.peak_dbm = stats.peak_dbm.max(dbm);
+        if dbm >= BUSY_DBM {
+            stats.busy += 1;
+        }
+    }
+
+    /// Every sampled channel with the Wi-Fi busy time that overlaps it.
+    pub fn combined(&self, wifi: Option<&Survey>) -> Vec<SpectrumChannel> {
+        (FIRST_CHANNEL..FIRST_CHANNEL + CHANNELS as u8)
+            .filter_map(|channel| {
+                let stats = self.get(channel)?;
+                Some(SpectrumChannel {
+                    channel,
+                    mean_dbm: stats.mean_dbm()?,
+                    peak_dbm: stats.peak_dbm,
+                    wifi_percent: wifi.map_or(0, |survey| (wifi_busy(survey, channel) / 10) as u8),
+                })
+            })
+            .collect()
+    }
+
+    /// The sampled channel with the least energy on it.
+    pub fn quietest(&self) -> Option<u8> {
+        (FIRST_CHANNEL..FIRST_CHANNEL + CHANNELS as u8)
+            .filter_map(|channel| Some((channel, self.get(channel)?.mean_dbm()?)))
+            .min_by_key(|(_, mean)| *mean)
+            .map(|(channel, _)| channel)
+    }
+
+    pub fn summary(&self, wifi: Option<&Survey>) -> String {
+        let mut summary = String::new();
+        let _ = writeln!(summary, "ch  mean  peak  occ  wifi");
+
+        for channel in self.combined(wifi) {
+            let occupancy = self.get(channel.channel).map_or(0, EnergyStats::occupancy);
+            let _ = writeln!(
+                summary,
+                "{:>2} {:>5} {:>5} {:>3}% {:>4}%{}",
+                channel.channel,
+                channel.mean_dbm,
+                channel.peak_dbm,
+                occupancy / 10,
+                channel.wifi_percent,
+                if channel.unexplained() { "  not wifi" } else { "" },
+            );
+        }
+
+        if wifi.is_none() {
+            let _ = writeln!(summary, "no wifi survey to compare against yet");
+        }
+
+        match self.quietest() {
+            Some(channel) => {
+                let _ = writeln!(summary, "quietest 802.15.4 channel: {channel}");
+            }
+            None => {
+                let _ = writeln!(summary, "quietest 802.15.4 channel: not enough data yet");
+            }
+        }
+
+        summary
+    }
+}
+
+/// Center frequencies in MHz
+fn center_mhz(channel: u8) -> u32 {
+    2405 + 5 * (channel as u32 - FIRST_CHANNEL as u32)
+}
+
+fn wifi_center_mhz(channel: u8) -> u32 {
+    if channel == 14 {
+        2484
+    } else {
+        2407 + 5 * channel as u32
+    }
+}
+
+/// Wi-Fi busy time landing on an 802.15.4 channel, per-mille: each Wi-Fi
+/// channel weighted by how close its center is.
+fn wifi_busy(survey: &Survey, channel: u8) -> u32 {
+    let center = center_mhz(channel);
+
+    (1..=channels::CHANNELS as u8)
+        .filter_map(|wifi| {
+            let distance = wifi_center_mhz(wifi).abs_diff(center);
+            let overlap = (WIFI_HALF_WIDTH_MHZ + 1).checked_sub(distance)?;
+            Some(survey.get(wifi)?.busy() * overlap / (WIFI_HALF_WIDTH_MHZ + 1))
+        })
+        .max()
+        .unwrap_or(0)
+}
+
+/// Samples since the last summary; each summary starts it over, so a noisy
+/// hour doesn't stay averaged into every later one.
+static SPECTRUM: Mutex<RefCell<Spectrum>> = Mutex::new(RefCell::new(Spectrum::new()));
+
+pub fn record(channel: u8, dbm: i8) {
+    critical_section::with(|cs| SPECTRUM.borrow_ref_mut(cs).record(channel, dbm));
+}
+
+/// The summary so far this interval, against the Wi-Fi survey saved before
+/// we switched over to 802.15.4.
+pub fn summary() -> String {
+    let wifi = channels::persisted();
+    critical_section::with(|cs| SPECTRUM.borrow_ref(cs).summary(wifi.as_ref()))
+}
+
+/// This interval's samples, starting the next one empty.
+pub fn take() -> Spectrum {
+    critical_section::with(|cs| SPECTRUM.replace(cs, Spectrum::new()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn starts_each_interval_empty() {
+        record(11, -60);
+        record(11, -80);
+        record(26, -90);
+
+        let interval = take();
+        assert_eq!(interval.get(11).unwrap().samples, 2);
+        assert_eq!(interval.get(11).unwrap().mean_dbm(), Some(-70));
+        assert_eq!(interval.quietest(), Some(26));
+
+        record(11, -95);
+        let interval = take();
+        assert_eq!(interval.get(11).unwrap().samples, 1);
+        assert_eq!(interval.get(11).unwrap().peak_dbm, -95);
+        assert_eq!(interval.get(26).unwrap().mean_dbm(), None);
+        assert_eq!(take().quietest(), None);
+    }
+}
@@ -0,0 +1,228 @@
+//! Joining a configured network as a client, for the modes that talk to
+//! servers.
+
+use core::cell::RefCell;
+
+use alloc::{format, string::String};
+use critical_section::Mutex;
+use embassy_executor::Spawner;
+use embassy_net::{
+    dns::DnsQueryType,
+    tcp::{ConnectError, TcpSocket},
+    udp::{PacketMetadata, UdpSocket},
+    IpAddress, IpEndpoint, Ipv4Address, Stack, StackResources,
+};
+use embassy_time::{with_timeout, Duration, Timer};
+use embedded_io_async::ErrorKind;
+use esp_hal::{
+    efuse::Efuse,
+    peripherals::{RADIO_CLK, WIFI},
+    rng::Rng,
+    timer::AnyTimer,
+};
+use esp_println::println;
+use esp_wifi::{
+    init,
+    wifi::{
+        get_wifi_state, new_with_mode, ClientConfiguration, Configuration, WifiController,
+        WifiDevice, WifiEvent, WifiStaDevice, WifiState,
+    },
+    EspWifiInitFor,
+};
+use static_cell::make_static;
+
+use crate::{
+    clock::{self, Source},
+    error::Error,
+    wifi,
+};
+
+/// Wait this long before trying to associate again
+const RECONNECT_MS: u64 = 5_000;
+
+const NTP_SERVER: &str = "pool.ntp.org";
+const NTP_PORT: u16 = 123;
+
+/// Resync the clock this often, or retry this soon after failing
+const NTP_SYNC_SECS: u64 = 60 * 60;
+const NTP_RETRY_SECS: u64 = 30;
+
+pub type Device = WifiDevice<'static, WifiStaDevice>;
+
+#[derive(Clone, Debug)]
+pub struct Settings {
+    pub ssid: String,
+    pub password: String,
+}
+
+impl Settings {
+    pub const fn new() -> Self {
+        Self {
+            ssid: String::new(),
+            password: String::new(),
+        }
+    }
+}
+
+static SETTINGS: Mutex<RefCell<Settings>> = Mutex::new(RefCell::new(Settings::new()));
+
+pub fn settings() -> Settings {
+    critical_section::with(|cs| SETTINGS.borrow_ref(cs).clone())
+}
+
+pub fn configure(settings: Settings) {
+    critical_section::with(|cs| *SETTINGS.borrow_ref_mut(cs) = settings);
+}
+
+/// Bring up Wi-Fi as a station on the configured network and wait for DHCP.
+/// The association is kept up (and retried) in the background. Fails only
+/// if the radio won't start; a network that isn't there is waited for.
+pub async fn connect(
+    spawner: Spawner,
+    timer: AnyTimer,
+    mut rng: Rng,
+    radio_clock: RADIO_CLK,
+    wifi: WIFI,
+) -> Result<(&'static Stack<Device>, WifiController<'static>), Error> {
+    let seed = (rng.random() as u64) << 32 | rng.random() as u64;
+    let init = make_static!(init(EspWifiInitFor::Wifi, timer, rng, radio_clock)?);
+    let (device, mut controller) = new_with_mode(init, wifi, WifiStaDevice)?;
+
+    // The settings check lengths, these can't fail
+    let settings = settings();
+    let ssid = settings
+        .ssid
+        .as_str()
+        .try_into()
+        .map_err(|_| Error::Radio)?;
+    let password = settings.password.as_str();
+    controller.set_configuration(&Configuration::Client(ClientConfiguration {
+        ssid,
+        password: password.try_into().map_err(|_| Error::Radio)?,
+        ..Default::default()
+    }))?;
+    wifi::start_controller(&mut controller).await?;
+
+    let stack = &*make_static!(Stack::new(
+        device,
+        embassy_net::Config::dhcpv4(Default::default()),
+        make_static!(StackResources::<4>::new()),
+        seed
+    ));
+    spawner.spawn(net_task(stack)).unwrap();
+    spawner.spawn(sntp_task(stack)).unwrap();
+
+    println!("joining \"{}\"", settings.ssid);
+    associate(&mut controller).await;
+
+    stack.wait_config_up().await;
+    if let Some(config) = stack.config_v4() {
+        println!("got {}", config.address);
+    }
+
+    Ok((stack, controller))
+}
+
+/// Associate, retrying until it works.
+pub async fn associate(controller: &mut WifiController<'static>) {
+    loop {
+        match controller.connect().await {
+            Ok(()) => return,
+            Err(err) => {
+                println!("couldn't join: {:?}", err);
+                Timer::after_millis(RECONNECT_MS).await;
+            }
+        }
+    }
+}
+
+/// Rejoin whenever the network drops us.
+#[embassy_executor::task]
+pub async fn stay_connected(mut controller: WifiController<'static>) {
+    loop {
+        if get_wifi_state() == WifiState::StaConnected {
+            controller.wait_for_event(WifiEvent::StaDisconnected).await;
+            println!("disconnected");
+            Timer::after_millis(RECONNECT_MS).await;
+        }
+
+        associate(&mut controller).await;
+    }
+}
+
+/// Connect `socket` to a configured server. A failure is told as the kind of
+/// I/O error it amounts to, the way the socket's reads and writes are.
+pub async fn connect_tcp(
+    socket: &mut TcpSocket<'_>,
+    address: [u8; 4],
+    port: u16,
+) -> Result<(), ErrorKind> {
+    let [a, b, c, d] = address;
+    let server = IpEndpoint::new(IpAddress::Ipv4(Ipv4Address::new(a, b, c, d)), port);
+    socket.connect(server).await.map_err(|err| match err {
+        ConnectError::TimedOut => ErrorKind::TimedOut,
+        ConnectError::ConnectionReset => ErrorKind::ConnectionReset,
+        ConnectError::InvalidState | ConnectError::NoRoute => ErrorKind::NotConnected,
+    })
+}
+
+/// The factory MAC, so servers can tell devices apart.
+pub fn device_id() -> String {
+    let mac = Efuse::get_mac_address();
+    mac.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+/// Keep the clock set from SNTP while we're online.
+#[embassy_executor::task]
+async fn sntp_task(stack: &'static Stack<Device>) {
+    loop {
+        stack.wait_config_up().await;
+
+        let wait = match sntp(stack).await {
+            Some(unix_ms) => {
+                clock::set_unix_ms(unix_ms, Source::Sntp);
+                NTP_SYNC_SECS
+            }
+            None => NTP_RETRY_SECS,
+        };
+        Timer::after_secs(wait).await;
+    }
+}
+
+async fn sntp(stack: &'static Stack<Device>) -> Option<u64> {
+    let server = *stack
+        .dns_query(NTP_SERVER, DnsQueryType::A)
+        .await
+        .ok()?
+        .first()?;
+
+    let mut rx_meta = [PacketMetadata::EMPTY; 1];
+    let mut rx_buffer = [0u8; 128];
+    let mut tx_meta = [PacketMetadata::EMPTY; 1];
+    let mut tx_buffer = [0u8; 128];
+    let mut socket = UdpSocket::new(
+        stack,
+        &mut rx_meta,
+        &mut rx_buffer,
+        &mut tx_meta,
+        &mut tx_buffer,
+    );
+    socket.bind(0).ok()?;
+
+    socket
+        .send_to(&clock::sntp_request(), (server, NTP_PORT))
+        .await
+        .ok()?;
+
+    let mut reply = [0u8; 48];
+    let (len, _) = with_timeout(Duration::from_secs(5), socket.recv_from(&mut reply))
+        .await
+        .ok()?
+        .ok()?;
+    clock::sntp_unix_ms(&reply[..len])
+}
+
+#[embassy_executor::task]
+async fn net_task(stack: &'static Stack<Device>) {
+    stack.run().await
+}
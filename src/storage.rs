@@ -1,170 +1,717 @@
-use alloc::{str, vec::Vec};
+use core::{
+    fmt,
+    sync::atomic::{AtomicU32, Ordering},
+};
+
+use alloc::{collections::VecDeque, vec::Vec};
+use embassy_futures::yield_now;
 use embassy_sync::{
-    blocking_mutex::raw::CriticalSectionRawMutex,
-    pubsub::{PubSubChannel, WaitResult},
+    blocking_mutex::raw::CriticalSectionRawMutex, channel::Channel, mutex::Mutex,
+    pubsub::PubSubChannel,
 };
+use embassy_time::Instant;
 use embedded_storage::{ReadStorage, Storage};
 use esp_backtrace as _;
 use esp_println::println;
 use esp_storage::FlashStorage;
 
-const FLASH_START: u32 = 0x9000;
+use crate::{
+    clock,
+    error::{self, Error, Subsystem},
+    frame::fnv1a,
+    journal::{self, Journal},
+    legacy,
+    query::{Cursor, Page, Query},
+    record::{self, Record},
+    supervisor::{self, Task},
+};
+
+pub const SECTOR_SIZE: u32 = journal::SECTOR_SIZE;
+
+/// Sectors at the very end of flash set aside for `reserved_sector`
+const RESERVED_SECTORS: u32 = 16;
+
+/// The record log sits just below the reserved sectors
+const LOG_SECTORS: u32 = 256;
+
+// Sectors counted back from the end of flash, kept out of the survey
+pub const ALLOWLIST_SECTOR: u32 = 0;
+pub const UPLOAD_SECTOR: u32 = 1;
+pub const BOOT_SECTOR: u32 = 2;
+pub const CONFIG_SECTOR: u32 = 3;
+pub const CRASH_SECTOR: u32 = 4;
+
+const UPLOAD_MAGIC: &[u8; 4] = b"UPL1";
+
+/// What became of an appended record.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Appended {
+    Stored,
+    /// A network, device or PAN already in the store; the first sighting is
+    /// kept
+    Duplicate,
+    Full,
+    Failed(Error),
+}
 
-#[derive(Clone, Debug)]
 enum Command {
-    Append(Vec<u8>),
+    Append(Vec<Vec<u8>>),
     Dump,
+    Erase,
+    Flush,
 }
 
-static STORE_CHANNEL: PubSubChannel<CriticalSectionRawMutex, Command, 4, 4, 4> =
-    PubSubChannel::<CriticalSectionRawMutex, Command, 4, 4, 4>::new();
+struct Request {
+    command: Command,
+    /// Sequence number to answer with, if someone's waiting
+    reply: Option<u32>,
+}
 
-pub async fn append(bytes: Vec<u8>) {
-    STORE_CHANNEL
-        .publisher()
-        .unwrap()
-        .publish(Command::Append(bytes))
-        .await;
+enum Reply {
+    Appended(Vec<Appended>),
+    Done(Result<(), Error>),
 }
 
-pub async fn dump() {
-    STORE_CHANNEL
-        .publisher()
-        .unwrap()
-        .publish(Command::Dump)
+/// Deep enough for a burst of `try_append`s from the radio executor while
+/// this one's busy with flash
+static REQUESTS: Channel<CriticalSectionRawMutex, Request, 32> = Channel::new();
+static REPLIES: Channel<CriticalSectionRawMutex, (u32, Reply), 1> = Channel::new();
+
+/// Held while waiting for a reply, so only one is ever on its way. Holds the
+/// next sequence number: a caller that gave up early leaves its reply
+/// behind, and the next one skips it.
+static IN_FLIGHT: Mutex<CriticalSectionRawMutex, u32> = Mutex::new(0);
+
+/// Every record passed to `append` that's newly stored, for anything that
+/// wants them live; duplicates of what's already there aren't repeated. Slow
+/// subscribers lose the oldest ones rather than holding up storage.
+pub static APPENDED: PubSubChannel<CriticalSectionRawMutex, Vec<u8>, 8, 2, 0> =
+    PubSubChannel::<CriticalSectionRawMutex, Vec<u8>, 8, 2, 0>::new();
+
+static STORED: AtomicU32 = AtomicU32::new(0);
+static DUPLICATES: AtomicU32 = AtomicU32::new(0);
+static FULL: AtomicU32 = AtomicU32::new(0);
+static FAILED: AtomicU32 = AtomicU32::new(0);
+static DROPPED: AtomicU32 = AtomicU32::new(0);
+
+/// Flash calls block the executor, so the storage task yields between
+/// them. A step longer than this holds up a frame of the lights.
+const STEP_BUDGET_US: u32 = 20_000;
+
+static LONGEST_STEP_US: AtomicU32 = AtomicU32::new(0);
+static SLOW_STEPS: AtomicU32 = AtomicU32::new(0);
+
+/// What's happened to appended records this boot.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Counters {
+    pub stored: u32,
+    pub duplicates: u32,
+    pub full: u32,
+    pub failed: u32,
+    /// Never reached the store: the queue was full
+    pub dropped: u32,
+    /// Longest the storage task kept everything else waiting
+    pub longest_step_us: u32,
+    /// Steps over `STEP_BUDGET_US`
+    pub slow_steps: u32,
+}
+
+impl fmt::Display for Counters {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} stored, {} duplicates, {} lost to a full store, {} failed, {} dropped; \
+             longest flash step {} ms, {} over {} ms",
+            self.stored,
+            self.duplicates,
+            self.full,
+            self.failed,
+            self.dropped,
+            self.longest_step_us / 1000,
+            self.slow_steps,
+            STEP_BUDGET_US / 1000
+        )
+    }
+}
+
+pub fn counters() -> Counters {
+    Counters {
+        stored: STORED.load(Ordering::Relaxed),
+        duplicates: DUPLICATES.load(Ordering::Relaxed),
+        full: FULL.load(Ordering::Relaxed),
+        failed: FAILED.load(Ordering::Relaxed),
+        dropped: DROPPED.load(Ordering::Relaxed),
+        longest_step_us: LONGEST_STEP_US.load(Ordering::Relaxed),
+        slow_steps: SLOW_STEPS.load(Ordering::Relaxed),
+    }
+}
+
+/// Let the other tasks run, noting how long they've waited since `started`,
+/// and start timing the next step.
+async fn pause(started: &mut Instant) {
+    let us = started.elapsed().as_micros().min(u32::MAX as u64) as u32;
+    LONGEST_STEP_US.fetch_max(us, Ordering::Relaxed);
+    if us > STEP_BUDGET_US {
+        SLOW_STEPS.fetch_add(1, Ordering::Relaxed);
+    }
+
+    supervisor::beat(Task::Storage);
+    yield_now().await;
+    *started = Instant::now();
+}
+
+fn count(appended: Appended) -> Appended {
+    let counter = match appended {
+        Appended::Stored => &STORED,
+        Appended::Duplicate => &DUPLICATES,
+        Appended::Full => &FULL,
+        Appended::Failed(_) => &FAILED,
+    };
+    counter.fetch_add(1, Ordering::Relaxed);
+    appended
+}
+
+/// Send `command` and wait for the storage task to carry it out.
+async fn request(command: Command) -> Reply {
+    let mut next = IN_FLIGHT.lock().await;
+    let seq = *next;
+    *next = next.wrapping_add(1);
+
+    REQUESTS
+        .send(Request {
+            command,
+            reply: Some(seq),
+        })
         .await;
+    loop {
+        let (answered, reply) = REPLIES.receive().await;
+        if answered == seq {
+            return reply;
+        }
+    }
 }
 
+/// Hand `reply` to whoever's waiting, without waiting on them. Only one
+/// request is ever in flight, so anything still in `REPLIES` was left by a
+/// caller that gave up, and would otherwise hold this task up until the next
+/// request came along.
+fn answer(seq: u32, reply: Reply) {
+    REPLIES.clear();
+    let _ = REPLIES.try_send((seq, reply));
+}
+
+/// Store a record, and say how that went once it's in flash.
+pub async fn append(bytes: Vec<u8>) -> Appended {
+    append_batch(alloc::vec![bytes]).await[0]
+}
+
+/// Store several records in one go: what became of each, in order.
+pub async fn append_batch(records: Vec<Vec<u8>>) -> Vec<Appended> {
+    let len = records.len();
+    match request(Command::Append(records)).await {
+        Reply::Appended(results) => results,
+        // Appends are always answered with results; this is for completeness
+        Reply::Done(result) => {
+            let err = result.err().unwrap_or(Error::Flash);
+            alloc::vec![Appended::Failed(err); len]
+        }
+    }
+}
+
+/// Queue an append without waiting, for the sniffer callback. Returns false
+/// (and the record is counted as dropped) if the storage task is behind.
+pub fn try_append(bytes: Vec<u8>) -> bool {
+    let request = Request {
+        command: Command::Append(alloc::vec![bytes]),
+        reply: None,
+    };
+    let queued = REQUESTS.try_send(request).is_ok();
+    if !queued {
+        DROPPED.fetch_add(1, Ordering::Relaxed);
+    }
+    queued
+}
+
+/// Wait until everything queued so far, `try_append`s included, is in
+/// flash.
+pub async fn flush() -> Result<(), Error> {
+    done(request(Command::Flush).await)
+}
+
+pub async fn dump() -> Result<(), Error> {
+    done(request(Command::Dump).await)
+}
+
+/// Wipe every record, and start uploads over from the beginning.
+pub async fn erase() -> Result<(), Error> {
+    done(request(Command::Erase).await)
+}
+
+fn done(reply: Reply) -> Result<(), Error> {
+    match reply {
+        Reply::Done(result) => result,
+        Reply::Appended(_) => Ok(()),
+    }
+}
+
+/// Once storage is disabled, everything sent its way fails straight away.
 #[embassy_executor::task]
 pub async fn start_storage() {
-    let mut store = Store::new();
-    let mut subscriber = STORE_CHANNEL.subscriber().unwrap();
+    supervisor::beat(Task::Storage);
+    if let Err(err) = run().await {
+        error::disable(Subsystem::Storage, err).await;
+        refuse(err).await;
+    }
+}
+
+async fn run() -> Result<(), Error> {
+    let mut store = Store::new()?;
 
     println!("We know about:");
-    store.dump();
+    store.dump().await?;
 
     loop {
-        let result = subscriber.next_message().await;
+        // Records queued without waiting are written out once the queue
+        // runs dry, so a burst of them goes out a page at a time
+        if REQUESTS.is_empty() && store.journal.pending() {
+            store.journal.sync()?;
+        }
 
-        match result {
-            WaitResult::Lagged(_) => {
-                continue;
-            }
-            WaitResult::Message(command) => match command {
-                Command::Append(bytes) => store.append(&bytes),
-                Command::Dump => {
-                    store.dump();
+        supervisor::wait(Task::Storage);
+        let request = REQUESTS.receive().await;
+        supervisor::beat(Task::Storage);
+
+        let mut fatal = None;
+        let reply = match request.command {
+            Command::Append(records) => {
+                let mut results = Vec::with_capacity(records.len());
+                for bytes in &records {
+                    // The rest of a batch fails with the first bad write
+                    let appended = match fatal {
+                        Some(err) => Appended::Failed(err),
+                        None => append_one(&mut store, bytes).await,
+                    };
+                    if let Appended::Failed(Error::Flash) = appended {
+                        fatal = Some(Error::Flash);
+                    }
+                    results.push(appended);
+                }
+
+                // Whoever's waiting hears once it's all in flash. Before
+                // giving up, what made it into the batch is written too, or
+                // reported as failed.
+                if request.reply.is_some() || fatal.is_some() {
+                    if let Err(err) = store.journal.sync() {
+                        fatal = Some(err.into());
+                        for appended in &mut results {
+                            if *appended == Appended::Stored {
+                                *appended = Appended::Failed(Error::Flash);
+                            }
+                        }
+                    }
                 }
-            },
+                Reply::Appended(results.into_iter().map(count).collect())
+            }
+            Command::Dump => Reply::Done(store.dump().await),
+            Command::Erase => {
+                let result = store.clear().await;
+                Reply::Done(result.and_then(|()| set_upload_cursor(0)))
+            }
+            Command::Flush => Reply::Done(store.journal.sync().map_err(Error::from)),
+        };
+        if let Reply::Done(Err(err)) = reply {
+            fatal = Some(err);
+        }
+
+        if let Some(seq) = request.reply {
+            answer(seq, reply);
+        }
+        if let Some(err) = fatal {
+            return Err(err);
         }
     }
 }
 
-pub struct Store {
-    storage: FlashStorage,
-    capacity: usize,
+async fn append_one(store: &mut Store, bytes: &[u8]) -> Appended {
+    let stamped = record::stamp(&clock::now(), bytes);
+    // Try once more on a freshly opened log before giving up
+    let result = match store.append(&stamped).await {
+        Err(Error::Flash) => match reopen(store) {
+            Ok(()) => store.append(&stamped).await,
+            Err(err) => Err(err),
+        },
+        result => result,
+    };
+
+    let appended = match result {
+        Ok(true) => Appended::Stored,
+        Ok(false) => Appended::Duplicate,
+        Err(Error::Full) => Appended::Full,
+        Err(err) => Appended::Failed(err),
+    };
+    if appended == Appended::Stored {
+        APPENDED.immediate_publisher().publish_immediate(stamped);
+    }
+    appended
 }
 
-impl Store {
-    fn new() -> Self {
-        let storage = FlashStorage::new();
-        let capacity = storage.capacity();
-        Self { storage, capacity }
+/// Open the log afresh. That only sees what's in flash, so what's held back
+/// for the page is written out first.
+fn reopen(store: &mut Store) -> Result<(), Error> {
+    store.journal.sync()?;
+    *store = Store::new()?;
+    Ok(())
+}
+
+/// Answer everything with `err`, so nobody waits on a store that's gone.
+async fn refuse(err: Error) {
+    loop {
+        supervisor::wait(Task::Storage);
+        let request = REQUESTS.receive().await;
+
+        let reply = match request.command {
+            Command::Append(records) => Reply::Appended(
+                records
+                    .iter()
+                    .map(|_| count(Appended::Failed(err)))
+                    .collect(),
+            ),
+            _ => Reply::Done(Err(err)),
+        };
+        if let Some(seq) = request.reply {
+            answer(seq, reply);
+        }
     }
+}
+
+/// Records read from the log per trip through it
+const CHUNK: usize = 16;
+
+/// What `Store::records` hands out: `(id, bytes)` for each match.
+pub struct Records<'a> {
+    store: &'a mut Store,
+    query: Query,
+    /// Where to pick up the walk
+    next: u32,
+    buffer: VecDeque<(u32, Vec<u8>)>,
+    done: bool,
+}
 
-    pub fn reset() -> Self {
-        let storage = FlashStorage::new();
-        let capacity = storage.capacity();
-        let mut store = Self { storage, capacity };
+impl Records<'_> {
+    fn fill(&mut self) -> Result<(), Error> {
+        let Self {
+            store,
+            query,
+            next,
+            buffer,
+            ..
+        } = self;
 
-        store.ireset();
-        store
+        store.journal.walk_since(*next, |id, bytes| {
+            *next = id + 1;
+            if query.matches(bytes) {
+                buffer.push_back((id, bytes.to_vec()));
+            }
+            buffer.len() < CHUNK
+        })?;
+        // Nothing more before the end of the log
+        self.done = buffer.is_empty();
+        Ok(())
     }
+}
 
-    fn ireset(&mut self) {
-        for i in (FLASH_START as usize..self.capacity).step_by(4096) {
-            let bytes: [u8; 4096] = [0; 4096];
-            self.storage.write(i.try_into().unwrap(), &bytes).unwrap();
-            println!("Wrote block {}. {}", i, (i) / self.capacity);
+impl Iterator for Records<'_> {
+    type Item = Result<(u32, Vec<u8>), Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.buffer.is_empty() && !self.done {
+            if let Err(err) = self.fill() {
+                self.done = true;
+                return Some(Err(err));
+            }
         }
+        self.buffer.pop_front().map(Ok)
+    }
+}
+
+/// Move a store left by older firmware into the log. Run once at boot,
+/// before anything opens a `Store`.
+pub fn init() {
+    let result = Journal::open(FlashStorage::new(), log_start(), LOG_SECTORS)
+        .map_err(Error::from)
+        .and_then(|mut journal| Ok(legacy::upgrade(&mut journal, log_start())?));
+
+    match result {
+        Ok(Some(count)) => println!("storage: moved {count} records to the new layout"),
+        Ok(None) => (),
+        // The old records stay where they are, for the next boot to try again
+        Err(err) => println!("storage: couldn't move the old records: {err}"),
     }
+}
+
+fn log_start() -> u32 {
+    reserved_sector(RESERVED_SECTORS - 1) - LOG_SECTORS * SECTOR_SIZE
+}
+
+/// Offset of one of the reserved sectors at the end of flash.
+pub fn reserved_sector(index: u32) -> u32 {
+    FlashStorage::new().capacity() as u32 - (index + 1) * SECTOR_SIZE
+}
+
+/// How many records (oldest first) have made it to the collection server.
+pub fn upload_cursor() -> Result<usize, Error> {
+    let mut bytes = [0u8; 8];
+    FlashStorage::new().read(reserved_sector(UPLOAD_SECTOR), &mut bytes)?;
+
+    if &bytes[..4] != UPLOAD_MAGIC {
+        return Ok(0);
+    }
+    Ok(u32::from_le_bytes(bytes[4..].try_into().unwrap()) as usize)
+}
 
-    fn count(&mut self) -> u32 {
-        let mut bytes: [u8; 4] = [0; 4];
-        self.storage.read(FLASH_START, &mut bytes).unwrap();
-        return u32::from_le_bytes(bytes);
+pub fn set_upload_cursor(cursor: usize) -> Result<(), Error> {
+    let mut bytes = UPLOAD_MAGIC.to_vec();
+    bytes.extend_from_slice(&(cursor as u32).to_le_bytes());
+    FlashStorage::new().write(reserved_sector(UPLOAD_SECTOR), &bytes)?;
+    Ok(())
+}
+
+/// Most identity records `Index` keeps track of, at 8 bytes each
+const MAX_INDEXED: usize = 1024;
+
+/// Where identity records (everything but readings) are in the log, by a
+/// hash of their bytes, so an append can tell whether it's a duplicate
+/// without reading the whole log.
+struct Index {
+    /// Hash and ID, sorted by hash. A hash two records share only has the
+    /// first one's ID.
+    entries: Vec<(u32, u32)>,
+    /// Whether every identity record in the log is in `entries`
+    complete: bool,
+}
+
+impl Index {
+    fn new() -> Self {
+        Self {
+            entries: Vec::new(),
+            complete: true,
+        }
     }
 
-    fn dump(&mut self) {
-        let mut cursor = FLASH_START + 4;
-        for _ in 0..self.count() {
-            let mut bytes: [u8; 256] = [0 as u8; 256];
-            let _ = self.storage.read(cursor, &mut bytes).unwrap();
-            let len = bytes[0] as usize;
-            let real_bytes = bytes[1..(len + 1)].to_vec();
+    fn find(&self, hash: u32) -> Option<u32> {
+        let found = self.entries.binary_search_by_key(&hash, |(hash, _)| *hash);
+        found.ok().map(|at| self.entries[at].1)
+    }
 
-            match str::from_utf8(&real_bytes) {
-                Ok(string) => println!("+ {string}"),
-                Err(err) => println!("! {:?}, {:?}", err, real_bytes),
+    fn insert(&mut self, hash: u32, id: u32) {
+        if let Err(at) = self.entries.binary_search_by_key(&hash, |(hash, _)| *hash) {
+            if self.entries.len() < MAX_INDEXED {
+                self.entries.insert(at, (hash, id));
+            } else {
+                self.complete = false;
             }
+        }
+    }
+}
+
+/// The part of a stored record that makes it a duplicate: all of it but the
+/// stamp. None for readings, which are kept however often they repeat.
+fn identity(bytes: &[u8]) -> Option<&[u8]> {
+    let reading = Record::decode(bytes).is_some_and(|record| record.is_reading());
+    (!reading).then(|| record::unstamp(bytes).1)
+}
+
+pub struct Store {
+    journal: Journal<FlashStorage>,
+    /// Built on the first append
+    index: Option<Index>,
+}
+
+impl Store {
+    pub fn new() -> Result<Self, Error> {
+        let journal = Journal::open(FlashStorage::new(), log_start(), LOG_SECTORS)?;
+        Ok(Self {
+            journal,
+            index: None,
+        })
+    }
+
+    pub fn reset() -> Result<Self, Error> {
+        let mut store = Self::new()?;
+        store.journal.clear()?;
+        Ok(store)
+    }
 
-            cursor += 256;
+    /// Forget every record. Only the part of the log in use gets erased, a
+    /// sector at a time.
+    async fn clear(&mut self) -> Result<(), Error> {
+        let mut started = Instant::now();
+        self.index = None;
+        while !self.journal.clear_step()? {
+            pause(&mut started).await;
         }
+        Ok(())
     }
 
-    fn entries(&mut self) -> Vec<Vec<u8>> {
-        let mut cursor = FLASH_START + 4;
-        let mut results: Vec<Vec<u8>> = Vec::new();
-        for _ in 0..self.count() {
-            let mut bytes: [u8; 256] = [0 as u8; 256];
-            let _ = self.storage.read(cursor, &mut bytes).unwrap();
-            let len = bytes[0] as usize;
-            let real_bytes = bytes[1..(len + 1)].to_vec();
-            results.insert(0, real_bytes);
-            cursor += 256;
+    /// `Journal::walk` a sector at a time, letting everything else run in
+    /// between.
+    async fn walk(&mut self, mut f: impl FnMut(u32, &[u8]) -> bool) -> Result<(), Error> {
+        let mut started = Instant::now();
+        for sector in 0..self.journal.used_sectors() {
+            let mut more = true;
+            self.journal.walk_sector(sector, |id, bytes| {
+                more = f(id, bytes);
+                more
+            })?;
+            if !more {
+                break;
+            }
+            pause(&mut started).await;
         }
+        Ok(())
+    }
 
-        return results;
+    /// Picks up whatever other `Store`s have added or erased.
+    pub fn len(&mut self) -> Result<usize, Error> {
+        self.journal.refresh()?;
+        Ok(self.journal.next_id() as usize)
     }
 
-    fn next_offset(&mut self, new_bytes: &[u8]) -> Option<u32> {
-        let mut cursor = FLASH_START + 4;
+    pub fn is_empty(&mut self) -> Result<bool, Error> {
+        Ok(self.len()? == 0)
+    }
 
-        for _ in 0..self.count() {
-            let mut bytes: [u8; 256] = [0 as u8; 256];
-            let _ = self.storage.read(cursor, &mut bytes).unwrap();
-            let len: usize = bytes[0] as usize;
-            let result = &bytes[1..(len + 1)];
+    /// The record at `index`, oldest first.
+    pub fn get(&mut self, index: usize) -> Result<Option<Vec<u8>>, Error> {
+        if index >= self.len()? {
+            return Ok(None);
+        }
+        Ok(self.journal.get(index as u32)?)
+    }
 
-            if result == new_bytes {
-                // We've already got it
-                return None;
+    async fn dump(&mut self) -> Result<(), Error> {
+        self.walk(|_, bytes| {
+            match (record::unstamp(bytes), Record::decode(bytes)) {
+                ((Some(stamp), _), Some(record)) => println!("{stamp} {record}"),
+                ((None, _), Some(record)) => println!("{record}"),
+                (_, None) => println!("? {:?}", bytes),
             }
+            true
+        })
+        .await
+    }
 
-            cursor += 256;
+    /// Records matching `query` with IDs from `from` on, oldest first, read
+    /// from flash a few at a time.
+    pub fn records(&mut self, query: Query, from: u32) -> Result<Records<'_>, Error> {
+        self.journal.refresh()?;
+        Ok(Records {
+            store: self,
+            query,
+            next: from,
+            buffer: VecDeque::new(),
+            done: false,
+        })
+    }
+
+    /// The record ID `from` points at: the log's beginning, for a cursor
+    /// from before the log was last erased.
+    pub fn resolve(&mut self, from: Cursor) -> Result<u32, Error> {
+        self.journal.refresh()?;
+        Ok(match from.generation {
+            Some(generation) if generation != self.journal.generation() => 0,
+            _ => from.id,
+        })
+    }
+
+    /// Up to `limit` records matching `query` from `from` on, `resolve`d.
+    pub fn page(&mut self, query: Query, from: Cursor, limit: usize) -> Result<Page, Error> {
+        let from = self.resolve(from)?;
+        let mut page = Page {
+            generation: self.journal.generation(),
+            ..Page::default()
+        };
+        for record in self.records(query, from)? {
+            let (id, bytes) = record?;
+            if page.records.len() == limit {
+                page.next = Some(id);
+                break;
+            }
+            page.records.push((id, bytes));
         }
+        Ok(page)
+    }
+
+    /// How many records match `query` from `from` on, `resolve`d.
+    pub fn count(&mut self, query: &Query, from: Cursor) -> Result<usize, Error> {
+        let from = self.resolve(from)?;
+        let mut count = 0;
+        self.journal.walk_since(from, |_, bytes| {
+            if query.matches(bytes) {
+                count += 1;
+            }
+            true
+        })?;
+        Ok(count)
+    }
 
-        return Some(cursor);
+    /// `Index` of the log, walking it for one if need be.
+    async fn index(&mut self) -> Result<&Index, Error> {
+        if self.index.is_none() {
+            let mut index = Index::new();
+            self.walk(|id, bytes| {
+                if let Some(identity) = identity(bytes) {
+                    index.insert(fnv1a(identity), id);
+                }
+                true
+            })
+            .await?;
+            self.index = Some(index);
+        }
+        Ok(self.index.as_ref().unwrap())
     }
 
-    fn append(&mut self, bytes: &[u8]) {
-        if let Some(next_offset) = self.next_offset(bytes) {
-            let old_count = self.count();
+    /// Whether the log already holds `identity`. Only a hash shared with
+    /// another record, or more records than the index holds, takes a walk
+    /// through the log.
+    async fn contains(&mut self, identity: &[u8]) -> Result<bool, Error> {
+        let index = self.index().await?;
+        match (index.find(fnv1a(identity)), index.complete) {
+            (Some(id), _) => {
+                let stored = self.journal.get(id)?;
+                if stored.is_some_and(|bytes| record::unstamp(&bytes).1 == identity) {
+                    return Ok(true);
+                }
+            }
+            (None, true) => return Ok(false),
+            (None, false) => (),
+        }
 
-            // Write the length of the bytes as the first byte
-            let len = bytes.len();
-            self.storage
-                .write(next_offset, [len as u8].as_slice())
-                .unwrap();
+        let mut found = false;
+        self.walk(|_, bytes| {
+            found = record::unstamp(bytes).1 == identity;
+            !found
+        })
+        .await?;
+        Ok(found)
+    }
+
+    /// Whether `bytes` was new and got stored. Readings always are.
+    async fn append(&mut self, bytes: &[u8]) -> Result<bool, Error> {
+        self.journal.refresh()?;
 
-            // Write the bytes after that
-            self.storage.write(next_offset + 1, &bytes).unwrap();
+        // We've already got it, keep the first time we saw it
+        let identity = identity(bytes);
+        if let Some(identity) = identity {
+            if self.contains(identity).await? {
+                return Ok(false);
+            }
+        }
 
-            // Write the new count to the beginning of the storage
-            let new_count_bytes = (old_count + 1).to_le_bytes();
-            self.storage
-                .write(FLASH_START, new_count_bytes.as_slice())
-                .unwrap();
+        let id = self.journal.append(bytes)?;
+        if let (Some(identity), Some(index)) = (identity, &mut self.index) {
+            index.insert(fnv1a(identity), id);
         }
+        Ok(true)
     }
 }
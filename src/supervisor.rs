@@ -0,0 +1,369 @@
+//! Keeping an eye on the tasks that have to keep going.
+//!
+//! Tasks check in with `beat` while they're working and say when they're
+//! only waiting for something to do. The supervisor feeds both hardware
+//! watchdogs once a second, but only while every critical task has checked
+//! in recently. When one hasn't, it notes which in the crash report and
+//! stops feeding, and the watchdog resets us.
+//!
+//! If the whole executor wedges the supervisor can't say anything, so the
+//! last task to check in is kept in RTC memory for the next boot to report.
+
+use core::{
+    arch::asm,
+    cell::RefCell,
+    fmt, ptr,
+    sync::atomic::{AtomicU32, Ordering},
+};
+
+use alloc::{format, vec::Vec};
+use critical_section::Mutex;
+use embassy_time::{Instant, Timer};
+use esp_hal::{
+    macros::ram,
+    peripherals::TIMG0,
+    prelude::*,
+    reset::get_reset_reason,
+    rtc_cntl::{RwdtStage, SocResetReason},
+    timer::timg::{MwdtStage, Wdt},
+};
+use esp_println::println;
+
+use crate::{
+    clock::{self, Stamp},
+    crash,
+};
+
+const HEARD_MAGIC: u32 = 0x4845_5244;
+
+/// How often the supervisor looks, and feeds the watchdogs
+const CHECK_SECS: u64 = 1;
+/// The main system watchdog resets the CPU and peripherals
+const MWDT_SECS: u64 = 5;
+/// The RTC watchdog resets the whole chip, in case that didn't do it
+const RWDT_SECS: u64 = 10;
+
+/// Written below the stack at boot, to see how far down it's been used
+const PAINT: u32 = 0x5354_4b21;
+
+/// Heap set up before the settings can be read; `grow_heap` adds the rest
+pub const BOOT_HEAP: usize = 32 * 1024;
+/// Stack `grow_heap` always leaves behind
+const MIN_STACK: u32 = 32 * 1024;
+
+/// How much of the bottom of the stack has gone to the heap
+static CARVED: AtomicU32 = AtomicU32::new(0);
+
+extern "C" {
+    static _stack_end_cpu0: u32;
+    static _stack_start_cpu0: u32;
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[repr(u8)]
+pub enum Task {
+    Storage = 0,
+    Sniffer = 1,
+    Serial = 2,
+    Battery = 3,
+}
+
+const TASKS: [Task; 4] = [Task::Storage, Task::Sniffer, Task::Serial, Task::Battery];
+
+impl Task {
+    fn from_u32(value: u32) -> Option<Self> {
+        TASKS.into_iter().find(|task| *task as u32 == value)
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Storage => "storage",
+            Self::Sniffer => "sniffer",
+            Self::Serial => "serial",
+            Self::Battery => "battery",
+        }
+    }
+
+    /// Whether a stall is worth a reset
+    fn critical(&self) -> bool {
+        matches!(self, Self::Storage | Self::Sniffer)
+    }
+
+    /// How long it can go between check-ins while working
+    fn timeout_secs(&self) -> u64 {
+        match self {
+            // Erasing a sector or two, and the odd retry
+            Self::Storage => 30,
+            // Hops every 250 ms
+            Self::Sniffer => 5,
+            Self::Serial | Self::Battery => 10,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum State {
+    /// Hasn't checked in this boot; in this mode it might never
+    NotStarted,
+    Working(Instant),
+    Waiting,
+    /// Gone for good, e.g. its subsystem was disabled
+    Stopped,
+}
+
+static STATES: Mutex<RefCell<[State; TASKS.len()]>> =
+    Mutex::new(RefCell::new([State::NotStarted; TASKS.len()]));
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct Heard {
+    magic: u32,
+    task: u32,
+    boot: u32,
+    uptime_s: u32,
+}
+
+#[ram(rtc_fast, persistent)]
+static mut HEARD: Heard = Heard {
+    magic: 0,
+    task: 0,
+    boot: 0,
+    uptime_s: 0,
+};
+
+/// Largest heap use seen, sampled each check
+static HEAP_PEAK: Mutex<RefCell<usize>> = Mutex::new(RefCell::new(0));
+
+/// `task` is alive and working.
+pub fn beat(task: Task) {
+    let now = Instant::now();
+    let stamp = clock::now();
+    critical_section::with(|cs| {
+        STATES.borrow_ref_mut(cs)[task as usize] = State::Working(now);
+        unsafe {
+            HEARD = Heard {
+                magic: HEARD_MAGIC,
+                task: task as u32,
+                boot: stamp.boot,
+                uptime_s: stamp.uptime_s,
+            }
+        };
+    });
+}
+
+/// `task` is idle until something arrives, however long that takes.
+pub fn wait(task: Task) {
+    set(task, State::Waiting);
+}
+
+/// `task` has ended and won't check in again.
+pub fn stop(task: Task) {
+    set(task, State::Stopped);
+}
+
+fn set(task: Task, state: State) {
+    critical_section::with(|cs| STATES.borrow_ref_mut(cs)[task as usize] = state);
+}
+
+/// The task that checked in last before the previous reset, and when.
+pub fn last_heard() -> Option<(Task, Stamp)> {
+    let heard = critical_section::with(|_| unsafe { HEARD });
+    if heard.magic != HEARD_MAGIC {
+        return None;
+    }
+
+    let stamp = Stamp {
+        boot: heard.boot,
+        uptime_s: heard.uptime_s,
+        unix_s: None,
+    };
+    Some((Task::from_u32(heard.task)?, stamp))
+}
+
+/// Whether a watchdog reset us last time.
+pub fn watchdog_reset() -> bool {
+    matches!(
+        get_reset_reason(),
+        Some(
+            SocResetReason::CoreMwdt0
+                | SocResetReason::CoreMwdt1
+                | SocResetReason::CoreRtcWdt
+                | SocResetReason::Cpu0Mwdt0
+                | SocResetReason::Cpu0Mwdt1
+                | SocResetReason::Cpu0RtcWdt
+                | SocResetReason::SysRtcWdt
+                | SocResetReason::SysSuperWdt
+        )
+    )
+}
+
+/// Fill the unused stack with `PAINT`. Run first thing at boot, while
+/// hardly any of it is in use.
+pub fn paint_stack() {
+    let sp: u32;
+    unsafe { asm!("mv {}, sp", out(reg) sp) };
+
+    // Leave room for this function, and for an interrupt if one comes in
+    let top = sp - 512;
+    critical_section::with(|_| {
+        let mut address = stack_bottom();
+        while address < top {
+            unsafe { ptr::write_volatile(address as *mut u32, PAINT) };
+            address += 4;
+        }
+    });
+}
+
+/// Give the heap `size` more bytes from the bottom of the stack, once,
+/// as long as none of them have been used and `MIN_STACK` is left.
+pub fn grow_heap(size: usize) {
+    let size = size as u32 & !3;
+    if size == 0 || CARVED.load(Ordering::Relaxed) != 0 {
+        return;
+    }
+
+    let bottom = stack_bottom();
+    let stack = stack_top() - bottom;
+    let untouched = stack - stack_peak() as u32;
+    if size > untouched || stack - size < MIN_STACK {
+        println!(
+            "supervisor: no room for {} KB more heap in {} KB of stack",
+            size / 1024,
+            stack / 1024
+        );
+        return;
+    }
+
+    CARVED.store(size, Ordering::Relaxed);
+    unsafe {
+        esp_alloc::HEAP.add_region(esp_alloc::HeapRegion::new(
+            bottom as *mut u8,
+            size as usize,
+            esp_alloc::MemoryCapability::Internal.into(),
+        ));
+    }
+}
+
+fn stack_bottom() -> u32 {
+    unsafe { ptr::addr_of!(_stack_end_cpu0) as u32 + CARVED.load(Ordering::Relaxed) }
+}
+
+fn stack_top() -> u32 {
+    unsafe { ptr::addr_of!(_stack_start_cpu0) as u32 }
+}
+
+/// The most of the stack that's been used since boot: everything above the
+/// lowest word that isn't paint any more.
+fn stack_peak() -> usize {
+    let mut address = stack_bottom();
+    while address < stack_top() && unsafe { ptr::read_volatile(address as *const u32) } == PAINT {
+        address += 4;
+    }
+    (stack_top() - address) as usize
+}
+
+fn sample_heap() -> usize {
+    let used = esp_alloc::HEAP.used();
+    critical_section::with(|cs| {
+        let mut peak = HEAP_PEAK.borrow_ref_mut(cs);
+        *peak = (*peak).max(used);
+        *peak
+    })
+}
+
+/// Tasks that have been working longer than they're allowed, and for how
+/// long in seconds.
+fn stalled() -> Vec<(Task, u64)> {
+    let states = critical_section::with(|cs| *STATES.borrow_ref(cs));
+    TASKS
+        .into_iter()
+        .filter_map(|task| match states[task as usize] {
+            State::Working(since) => Some((task, since.elapsed().as_secs())),
+            _ => None,
+        })
+        .filter(|(task, secs)| *secs > task.timeout_secs())
+        .collect()
+}
+
+pub struct Health {
+    tasks: Vec<(Task, State)>,
+    stack_peak: usize,
+    stack_size: usize,
+    heap_used: usize,
+    heap_peak: usize,
+    heap_size: usize,
+}
+
+pub fn health() -> Health {
+    let states = critical_section::with(|cs| *STATES.borrow_ref(cs));
+    let heap_peak = sample_heap();
+    let heap_used = esp_alloc::HEAP.used();
+
+    Health {
+        tasks: TASKS
+            .into_iter()
+            .map(|task| (task, states[task as usize]))
+            .collect(),
+        stack_peak: stack_peak(),
+        stack_size: (stack_top() - stack_bottom()) as usize,
+        heap_used,
+        heap_peak,
+        heap_size: heap_used + esp_alloc::HEAP.free(),
+    }
+}
+
+impl fmt::Display for Health {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (task, state) in &self.tasks {
+            write!(f, "{:<10}", task.name())?;
+            match state {
+                State::NotStarted => writeln!(f, "not running")?,
+                State::Working(since) => {
+                    let secs = since.elapsed().as_secs();
+                    if secs > task.timeout_secs() {
+                        writeln!(f, "stalled, {secs} s since it checked in")?
+                    } else {
+                        writeln!(f, "working, checked in {secs} s ago")?
+                    }
+                }
+                State::Waiting => writeln!(f, "waiting")?,
+                State::Stopped => writeln!(f, "stopped")?,
+            }
+        }
+        writeln!(
+            f,
+            "{:<10}peak {} of {} bytes",
+            "stack", self.stack_peak, self.stack_size
+        )?;
+        writeln!(
+            f,
+            "{:<10}{} now, peak {} of {} bytes",
+            "heap", self.heap_used, self.heap_peak, self.heap_size
+        )
+    }
+}
+
+#[embassy_executor::task]
+pub async fn start_supervisor(mut mwdt: Wdt<TIMG0>) {
+    mwdt.set_timeout(MwdtStage::Stage0, MWDT_SECS.secs());
+    mwdt.enable();
+    clock::with_rtc(|rtc| {
+        rtc.rwdt.set_timeout(RwdtStage::Stage0, RWDT_SECS.secs());
+        rtc.rwdt.enable();
+    });
+
+    loop {
+        sample_heap();
+
+        if let Some((task, secs)) = stalled().into_iter().find(|(task, _)| task.critical()) {
+            println!("supervisor: {} stalled for {secs} s", task.name());
+            crash::stalled(&format!("{} stalled for {secs} s", task.name()));
+            // Not fed again; the watchdog resets us from here
+            core::future::pending::<()>().await;
+        }
+
+        mwdt.feed();
+        clock::with_rtc(|rtc| rtc.rwdt.feed());
+        Timer::after_secs(CHECK_SECS).await;
+    }
+}
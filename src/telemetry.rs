@@ -0,0 +1,201 @@
+//! Binary frames on the serial link, for host tools; see `wif-protocol`.
+//!
+//! Nothing is framed until a host says hello, so a plain serial monitor only
+//! ever sees the log.
+
+use core::sync::atomic::{AtomicU16, Ordering};
+
+use alloc::{
+    string::{String, ToString},
+    vec::Vec,
+};
+use esp_println::Printer;
+use wif_protocol::{self as protocol, Channel, Command, Message, Reply};
+
+use crate::{
+    alerts::Alert,
+    battery, channels,
+    clock::{self, Source},
+    error::Error,
+    mode::{self, Mode},
+    occupancy,
+    query::Query,
+    record::{self, Record},
+    storage::{self, Store},
+};
+
+/// The version agreed with the host, 0 until one has said hello
+static VERSION: AtomicU16 = AtomicU16::new(0);
+
+pub fn send(message: &Message) {
+    if VERSION.load(Ordering::Relaxed) != 0 {
+        write(message);
+    }
+}
+
+/// One frame, in one go so log output can't end up inside it.
+fn write(message: &Message) {
+    Printer::write_bytes(&protocol::encode(message));
+}
+
+pub fn alert(alert: &Alert) {
+    send(&Message::Alert(protocol::Alert {
+        rogue: alert.is_rogue(),
+        text: alloc::format!("{alert}"),
+    }));
+}
+
+/// Pass records on to the host as they're stored.
+#[embassy_executor::task]
+pub async fn start_telemetry() {
+    let mut records = storage::APPENDED.subscriber().unwrap();
+
+    loop {
+        let bytes = records.next_message_pure().await;
+        send(&Message::Record(record(None, bytes)));
+    }
+}
+
+/// Deal with a frame from the host.
+pub async fn received(message: Message) {
+    match message {
+        Message::Hello {
+            min_version,
+            version,
+        } => match protocol::negotiate(min_version, version) {
+            Some(version) => {
+                VERSION.store(version, Ordering::Relaxed);
+                write(&Message::Hello {
+                    min_version: version,
+                    version,
+                });
+            }
+            // Tell it what we do speak, and leave it to give up
+            None => write(&Message::Hello {
+                min_version: protocol::MIN_VERSION,
+                version: protocol::VERSION,
+            }),
+        },
+        Message::Command(command) => run(command).await,
+        // Only we send the rest
+        _ => (),
+    }
+}
+
+async fn run(command: Command) {
+    let reply = match command {
+        Command::Dump { from, limit } => match dump(from, limit) {
+            Ok(()) => Reply::Ok,
+            Err(err) => Reply::Error(err.to_string()),
+        },
+        Command::Stats => match Store::new().and_then(|mut store| store.len()) {
+            Ok(records) => {
+                send(&Message::Stats(stats(records)));
+                Reply::Ok
+            }
+            Err(err) => Reply::Error(err.to_string()),
+        },
+        Command::Erase => match storage::erase().await {
+            Ok(()) => Reply::Ok,
+            Err(err) => Reply::Error(err.to_string()),
+        },
+        Command::SetTime { unix_ms } => {
+            clock::set_unix_ms(unix_ms, Source::Serial);
+            Reply::Ok
+        }
+        // Answer before going, there's no answering after
+        Command::Mode(next) => {
+            send(&Message::Reply(Reply::Ok));
+            mode::switch(match next {
+                protocol::Mode::Sniff => Mode::Wifi,
+                protocol::Mode::Bluetooth => Mode::Bluetooth,
+                protocol::Mode::Ieee802154 => Mode::Ieee802154,
+                protocol::Mode::Share => Mode::Share,
+                protocol::Mode::Upload => Mode::Upload,
+                protocol::Mode::Live => Mode::Live,
+            })
+            .await;
+            return;
+        }
+        Command::Reboot => {
+            send(&Message::Reply(Reply::Ok));
+            mode::switch(mode::current()).await;
+            return;
+        }
+    };
+
+    send(&Message::Reply(reply));
+}
+
+fn dump(from: u32, limit: u32) -> Result<(), Error> {
+    let mut store = Store::new()?;
+    for entry in store.records(Query::default(), from)?.take(limit as usize) {
+        let (id, bytes) = entry?;
+        send(&Message::Record(record(Some(id), bytes)));
+    }
+    Ok(())
+}
+
+fn stamp(stamp: clock::Stamp) -> protocol::Stamp {
+    protocol::Stamp {
+        boot: stamp.boot,
+        uptime_s: stamp.uptime_s,
+        unix_s: stamp.unix_s,
+    }
+}
+
+fn record(index: Option<u32>, bytes: Vec<u8>) -> protocol::Record {
+    let (stamped, _) = record::unstamp(&bytes);
+    let (kind, text) = match Record::decode(&bytes) {
+        Some(record) => (record.kind(), alloc::format!("{record}")),
+        None => ("unknown", String::new()),
+    };
+
+    protocol::Record {
+        index,
+        stamp: stamped.map(stamp),
+        kind: kind.into(),
+        text,
+        bytes,
+    }
+}
+
+fn stats(records: usize) -> protocol::Stats {
+    let now = clock::now();
+    let sniffing = matches!(mode::current(), Mode::Wifi | Mode::Live);
+
+    // Sniffing has a survey going; other modes have the one from before
+    let survey = if sniffing {
+        Some(channels::survey())
+    } else {
+        channels::persisted()
+    };
+    let channels = survey.map_or_else(Vec::new, |survey| {
+        (1..=channels::CHANNELS as u8)
+            .filter_map(|channel| {
+                let stats = survey.get(channel).filter(|stats| stats.dwell_ms > 0)?;
+                Some(Channel {
+                    channel,
+                    frames: stats.frames(),
+                    bytes: stats.bytes(),
+                    busy: stats.busy() as u16,
+                    stations: stats.stations,
+                })
+            })
+            .collect()
+    });
+
+    protocol::Stats {
+        stamp: stamp(now),
+        records: records as u32,
+        occupancy: sniffing
+            .then(|| occupancy::count(embassy_time::Instant::now().as_millis()) as u16),
+        battery: battery::level().map(|level| protocol::Battery {
+            percent: level.percent,
+            millivolts: level.millivolts,
+            usb: level.usb,
+        }),
+        channels,
+        recommended_channel: survey.and_then(|survey| survey.recommended()),
+    }
+}
@@ -0,0 +1,451 @@
+//! Upload mode: join the home network and POST everything the collection
+//! server hasn't seen yet, in batches of JSON.
+//!
+//! The cursor into the store only moves once the server has answered 2xx
+//! for a batch, so a failed upload is simply retried on the next round.
+
+use core::{cell::RefCell, future::Future};
+
+use alloc::{format, string::String, vec::Vec};
+use critical_section::Mutex;
+use embassy_time::Timer;
+use embedded_io_async::{ErrorKind, Read, Write};
+use esp_println::println;
+
+use crate::{
+    error::Error,
+    export, http,
+    storage::{self, Store},
+};
+
+#[cfg(target_os = "none")]
+pub use tcp::start_upload;
+
+/// Records per POST
+const BATCH_SIZE: usize = 20;
+
+/// Tries per batch before waiting for the next round
+const MAX_ATTEMPTS: u32 = 4;
+
+#[derive(Clone, Debug)]
+pub struct Settings {
+    pub server: [u8; 4],
+    pub port: u16,
+    pub path: String,
+    /// Wait before retrying a batch; each retry after that waits twice as
+    /// long
+    pub retry_ms: u32,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Settings {
+    pub const fn new() -> Self {
+        Self {
+            server: [0; 4],
+            port: 8080,
+            path: String::new(),
+            retry_ms: 2000,
+        }
+    }
+
+    fn path(&self) -> &str {
+        if self.path.is_empty() {
+            "/surveys"
+        } else {
+            &self.path
+        }
+    }
+}
+
+static SETTINGS: Mutex<RefCell<Settings>> = Mutex::new(RefCell::new(Settings::new()));
+
+pub fn settings() -> Settings {
+    critical_section::with(|cs| SETTINGS.borrow_ref(cs).clone())
+}
+
+pub fn configure(settings: Settings) {
+    critical_section::with(|cs| *SETTINGS.borrow_ref_mut(cs) = settings);
+}
+
+/// Where batches go. Every POST gets a connection of its own.
+pub trait Server {
+    type Connection<'a>: Read + Write
+    where
+        Self: 'a;
+
+    fn connect(&mut self) -> impl Future<Output = Result<Self::Connection<'_>, ErrorKind>>;
+}
+
+#[derive(Debug)]
+pub enum UploadError {
+    Connect(ErrorKind),
+    Io(ErrorKind),
+    Status(u16),
+    BadResponse,
+    Storage(Error),
+}
+
+impl From<Error> for UploadError {
+    fn from(err: Error) -> Self {
+        Self::Storage(err)
+    }
+}
+
+fn io(err: impl embedded_io_async::Error) -> UploadError {
+    UploadError::Io(err.kind())
+}
+
+/// Upload batches until we're caught up or the server stops cooperating.
+/// Only trouble with the store is an error; the server gets another go next
+/// round anyway.
+pub async fn upload_pending(server: &mut impl Server, device: &str) -> Result<(), UploadError> {
+    let settings = settings();
+    let mut store = Store::new()?;
+    let mut cursor = storage::upload_cursor()?;
+
+    while cursor < store.len()? {
+        let mut records: Vec<Vec<u8>> = Vec::new();
+        for index in cursor..store.len()?.min(cursor + BATCH_SIZE) {
+            match store.get(index)? {
+                Some(bytes) => records.push(bytes),
+                None => break,
+            }
+        }
+        let body = export::json_batch(device, cursor, &records);
+
+        let mut attempt = 0;
+        loop {
+            match post(server, &settings, &body).await {
+                Ok(()) => break,
+                Err(err) => {
+                    attempt += 1;
+                    println!("upload failed ({:?}), attempt {}", err, attempt);
+                    if attempt >= MAX_ATTEMPTS {
+                        return Ok(());
+                    }
+                    Timer::after_millis((settings.retry_ms as u64) << (attempt - 1)).await;
+                }
+            }
+        }
+
+        cursor += records.len();
+        storage::set_upload_cursor(cursor)?;
+        println!("uploaded {} of {}", cursor, store.len()?);
+    }
+
+    Ok(())
+}
+
+async fn post(
+    server: &mut impl Server,
+    settings: &Settings,
+    body: &str,
+) -> Result<(), UploadError> {
+    let mut connection = server.connect().await.map_err(UploadError::Connect)?;
+
+    let [a, b, c, d] = settings.server;
+    let host = format!("{a}.{b}.{c}.{d}:{}", settings.port);
+    let request = http::post_head(&host, settings.path(), body.len());
+    connection.write_all(request.as_bytes()).await.map_err(io)?;
+    connection.write_all(body.as_bytes()).await.map_err(io)?;
+    connection.flush().await.map_err(io)?;
+
+    // The status line is all we need
+    let mut head = [0u8; 64];
+    let mut len = 0;
+    while len < head.len() && !head[..len].contains(&b'\n') {
+        let read = connection.read(&mut head[len..]).await.map_err(io)?;
+        if read == 0 {
+            break;
+        }
+        len += read;
+    }
+
+    match http::response_status(&head[..len]) {
+        Some(status) if (200..300).contains(&status) => Ok(()),
+        Some(status) => Err(UploadError::Status(status)),
+        None => Err(UploadError::BadResponse),
+    }
+}
+
+/// Uploading over the board's Wi-Fi station.
+#[cfg(target_os = "none")]
+mod tcp {
+    use embassy_executor::Spawner;
+    use embassy_net::{tcp::TcpSocket, Stack};
+    use embassy_time::{Duration, Timer};
+    use embedded_io_async::ErrorKind;
+    use esp_hal::{
+        peripherals::{RADIO_CLK, WIFI},
+        rng::Rng,
+        timer::AnyTimer,
+    };
+    use esp_println::println;
+
+    use super::{settings, upload_pending, Server};
+    use crate::{
+        error::{self, Subsystem},
+        station::{self, Device},
+    };
+
+    /// How often to look for new records once everything's uploaded
+    const ROUND_SECS: u64 = 60;
+
+    #[embassy_executor::task]
+    pub async fn start_upload(
+        spawner: Spawner,
+        timer: AnyTimer,
+        rng: Rng,
+        radio_clock: RADIO_CLK,
+        wifi: WIFI,
+    ) {
+        let (stack, controller) =
+            match station::connect(spawner, timer, rng, radio_clock, wifi).await {
+                Ok(connected) => connected,
+                Err(err) => return error::disable(Subsystem::Radio, err).await,
+            };
+        spawner.spawn(station::stay_connected(controller)).unwrap();
+
+        let device = station::device_id();
+        let mut server = Tcp {
+            stack,
+            rx_buffer: [0; 512],
+            tx_buffer: [0; 2048],
+        };
+
+        loop {
+            stack.wait_config_up().await;
+            if let Err(err) = upload_pending(&mut server, &device).await {
+                println!("upload stopped: {:?}", err);
+            }
+            Timer::after_secs(ROUND_SECS).await;
+        }
+    }
+
+    /// The collection server from the settings, over TCP.
+    struct Tcp {
+        stack: &'static Stack<Device>,
+        rx_buffer: [u8; 512],
+        tx_buffer: [u8; 2048],
+    }
+
+    impl Server for Tcp {
+        type Connection<'a> = TcpSocket<'a>;
+
+        async fn connect(&mut self) -> Result<TcpSocket<'_>, ErrorKind> {
+            let mut socket = TcpSocket::new(self.stack, &mut self.rx_buffer, &mut self.tx_buffer);
+            socket.set_timeout(Some(Duration::from_secs(10)));
+
+            let settings = settings();
+            station::connect_tcp(&mut socket, settings.server, settings.port).await?;
+            Ok(socket)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::VecDeque,
+        io::{self, BufRead, BufReader, Read as _, Write as _},
+        net::{SocketAddr, TcpListener, TcpStream},
+        sync::{Arc, Mutex},
+        thread,
+    };
+
+    use embassy_executor::Executor;
+    use embassy_futures::block_on;
+    use embedded_io_async::ErrorType;
+
+    use super::*;
+    use crate::record::Record;
+
+    const OK: Option<&str> = Some("HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n");
+    const CREATED: Option<&str> = Some("HTTP/1.1 201 Created\r\n\r\n");
+    const BROKEN: Option<&str> = Some("HTTP/1.1 500 Internal Server Error\r\n\r\n");
+    const GARBLED: Option<&str> = Some("hello\r\n");
+    const HANG_UP: Option<&str> = None;
+
+    /// The collection server, on a loopback port. Each connection gets the
+    /// next of `replies`, `None` hanging up on it unread; every request it
+    /// answers is kept in `posts`.
+    struct StandIn {
+        address: SocketAddr,
+        connects: usize,
+        posts: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl StandIn {
+        fn new(replies: &[Option<&'static str>]) -> Self {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let address = listener.local_addr().unwrap();
+            let posts = Arc::new(Mutex::new(Vec::new()));
+
+            let mut replies = VecDeque::from(replies.to_vec());
+            let kept = posts.clone();
+            thread::spawn(move || {
+                while let Some(reply) = replies.pop_front() {
+                    let (stream, _) = listener.accept().unwrap();
+                    if let Some(reply) = reply {
+                        answer(stream, reply, &kept).unwrap();
+                    }
+                }
+            });
+
+            Self {
+                address,
+                connects: 0,
+                posts,
+            }
+        }
+
+        fn posts(&self) -> Vec<String> {
+            self.posts.lock().unwrap().clone()
+        }
+    }
+
+    /// Keep the POST on `stream`, then send `reply` a few bytes at a time,
+    /// the way replies trickle in.
+    fn answer(stream: TcpStream, reply: &str, posts: &Mutex<Vec<String>>) -> io::Result<()> {
+        let mut reader = BufReader::new(stream);
+        let mut post = String::new();
+        let mut length = 0;
+        loop {
+            let start = post.len();
+            reader.read_line(&mut post)?;
+            let line = &post[start..];
+            if let Some(value) = line.strip_prefix("Content-Length: ") {
+                length = value.trim().parse().unwrap();
+            }
+            if line == "\r\n" || line.is_empty() {
+                break;
+            }
+        }
+        let mut body = vec![0; length];
+        reader.read_exact(&mut body)?;
+        post += &String::from_utf8(body).unwrap();
+        posts.lock().unwrap().push(post);
+
+        let mut stream = reader.into_inner();
+        stream.set_nodelay(true)?;
+        for chunk in reply.as_bytes().chunks(7) {
+            stream.write_all(chunk)?;
+            stream.flush()?;
+        }
+        Ok(())
+    }
+
+    struct Connection(TcpStream);
+
+    fn kind(err: io::Error) -> ErrorKind {
+        match err.kind() {
+            io::ErrorKind::ConnectionRefused => ErrorKind::ConnectionRefused,
+            io::ErrorKind::ConnectionReset => ErrorKind::ConnectionReset,
+            io::ErrorKind::BrokenPipe => ErrorKind::BrokenPipe,
+            _ => ErrorKind::Other,
+        }
+    }
+
+    impl Server for StandIn {
+        type Connection<'a> = Connection;
+
+        async fn connect(&mut self) -> Result<Connection, ErrorKind> {
+            self.connects += 1;
+            TcpStream::connect(self.address)
+                .map(Connection)
+                .map_err(kind)
+        }
+    }
+
+    impl ErrorType for Connection {
+        type Error = ErrorKind;
+    }
+
+    impl Write for Connection {
+        async fn write(&mut self, buf: &[u8]) -> Result<usize, ErrorKind> {
+            self.0.write(buf).map_err(kind)
+        }
+
+        async fn flush(&mut self) -> Result<(), ErrorKind> {
+            self.0.flush().map_err(kind)
+        }
+    }
+
+    impl Read for Connection {
+        async fn read(&mut self, buf: &mut [u8]) -> Result<usize, ErrorKind> {
+            self.0.read(buf).map_err(kind)
+        }
+    }
+
+    /// The records in a batch, by index.
+    fn indexes(post: &str) -> Vec<usize> {
+        post.split("{\"index\":")
+            .skip(1)
+            .map(|rest| rest.split(',').next().unwrap().parse().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn moves_the_cursor_only_past_what_the_server_took() {
+        storage::init();
+        thread::spawn(|| {
+            let executor: &'static mut Executor = Box::leak(Box::new(Executor::new()));
+            executor.run(|spawner| spawner.must_spawn(storage::start_storage()));
+        });
+        for network in 0..2 * BATCH_SIZE + 5 {
+            let record = Record::Network(format!("network {network}"));
+            block_on(storage::append(record.encode()));
+        }
+        block_on(storage::flush()).unwrap();
+        configure(Settings {
+            server: [10, 0, 0, 2],
+            retry_ms: 1,
+            ..Settings::new()
+        });
+
+        // The first batch goes through, the second on a retry, and the
+        // third not at all this round
+        let mut server = StandIn::new(&[OK, BROKEN, CREATED, HANG_UP, BROKEN, GARBLED, BROKEN]);
+        block_on(upload_pending(&mut server, "board")).unwrap();
+        assert_eq!(server.connects, 7);
+        assert_eq!(storage::upload_cursor().unwrap(), 2 * BATCH_SIZE);
+
+        let posts = &server.posts();
+        assert_eq!(posts.len(), 6);
+        for post in posts {
+            assert!(post.starts_with("POST /surveys HTTP/1.1\r\nHost: 10.0.0.2:8080\r\n"));
+            let (head, body) = post.split_once("\r\n\r\n").unwrap();
+            assert!(head.contains(&format!("Content-Length: {}\r\n", body.len())));
+            assert!(body.starts_with("{\"device\":\"board\",\"records\":["));
+        }
+        assert_eq!(indexes(&posts[0]), (0..BATCH_SIZE).collect::<Vec<_>>());
+        assert_eq!(posts[1], posts[2]);
+        assert_eq!(
+            indexes(&posts[1]),
+            (BATCH_SIZE..2 * BATCH_SIZE).collect::<Vec<_>>()
+        );
+        assert!(posts[3..].iter().all(|post| post == &posts[3]));
+        assert_eq!(
+            indexes(&posts[3]),
+            (2 * BATCH_SIZE..2 * BATCH_SIZE + 5).collect::<Vec<_>>()
+        );
+
+        // The next round picks up where that one stopped
+        let mut server = StandIn::new(&[OK]);
+        block_on(upload_pending(&mut server, "board")).unwrap();
+        assert_eq!(storage::upload_cursor().unwrap(), 2 * BATCH_SIZE + 5);
+        let posts = server.posts();
+        assert_eq!(posts.len(), 1);
+        assert_eq!(indexes(&posts[0])[0], 2 * BATCH_SIZE);
+
+        // And with nothing new, doesn't connect at all
+        let mut server = StandIn::new(&[]);
+        block_on(upload_pending(&mut server, "board")).unwrap();
+        assert_eq!(server.connects, 0);
+    }
+}
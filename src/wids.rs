@@ -0,0 +1,368 @@
+//! Wireless intrusion detection.
+//!
+//! Every management frame the sniffer sees is fed through [`Wids::observe`],
+//! which keeps a little bit of state per BSSID/transmitter and returns an
+//! [`Alert`] when something looks like an attack rather than normal traffic.
+
+use alloc::{
+    collections::btree_map::{BTreeMap, Entry},
+    vec::Vec,
+};
+
+use crate::{
+    alerts::Alert,
+    frame::{fnv1a, Management, ManagementKind, MacAddress},
+};
+
+/// Deauth/disassoc frames per BSSID per window before we call it a flood
+const DEAUTH_THRESHOLD: u16 = 20;
+const DEAUTH_WINDOW_MS: u64 = 1_000;
+
+/// Distinct SSIDs from a single transmitter per window
+const FLOOD_THRESHOLD: usize = 6;
+const FLOOD_WINDOW_MS: u64 = 10_000;
+
+/// Sequence/TSF anomalies per BSSID per window
+const SPOOF_THRESHOLD: u16 = 4;
+const SPOOF_WINDOW_MS: u64 = 30_000;
+
+/// A real AP keeps announcing the switch in every beacon until it moves, so a
+/// beacon without the element this soon after one with it is suspicious.
+const CSA_HOLD_MS: u64 = 2_000;
+
+/// Only compare sequence numbers of beacons this close together; while hopping
+/// we can miss enough traffic for a busy AP to legitimately wrap.
+const SEQUENCE_GAP_MS: u64 = 1_000;
+
+/// Don't raise the same alert for the same address more often than this
+const ALERT_COOLDOWN_MS: u64 = 60_000;
+
+/// Cap on the entries of each table, the heap is only 64K
+const MAX_TRACKED: usize = 32;
+
+#[derive(Clone, Copy)]
+struct Counter {
+    started: u64,
+    count: u16,
+}
+
+impl Counter {
+    const fn new(now: u64) -> Self {
+        Self {
+            started: now,
+            count: 0,
+        }
+    }
+
+    fn hit(&mut self, now: u64, window: u64) -> u16 {
+        if now.saturating_sub(self.started) > window {
+            *self = Self::new(now);
+        }
+
+        self.count = self.count.saturating_add(1);
+        self.count
+    }
+}
+
+struct BeaconTrack {
+    sequence: u16,
+    tsf: u64,
+    last_seen: u64,
+    last_switch: Option<(u64, u8)>,
+    anomalies: Counter,
+}
+
+struct Transmitter {
+    started: u64,
+    last_seen: u64,
+    ssids: Vec<u32>,
+}
+
+trait LastSeen {
+    fn last_seen(&self) -> u64;
+}
+
+impl LastSeen for Counter {
+    fn last_seen(&self) -> u64 {
+        self.started
+    }
+}
+
+impl LastSeen for BeaconTrack {
+    fn last_seen(&self) -> u64 {
+        self.last_seen
+    }
+}
+
+impl LastSeen for Transmitter {
+    fn last_seen(&self) -> u64 {
+        self.last_seen
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum AlertKind {
+    Deauth,
+    ChannelSwitch,
+    Flood,
+    Spoof,
+}
+
+pub struct Wids {
+    deauths: BTreeMap<MacAddress, Counter>,
+    beacons: BTreeMap<MacAddress, BeaconTrack>,
+    transmitters: BTreeMap<MacAddress, Transmitter>,
+    raised: BTreeMap<(AlertKind, MacAddress), u64>,
+}
+
+impl Default for Wids {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Wids {
+    pub const fn new() -> Self {
+        Self {
+            deauths: BTreeMap::new(),
+            beacons: BTreeMap::new(),
+            transmitters: BTreeMap::new(),
+            raised: BTreeMap::new(),
+        }
+    }
+
+    /// Look at one frame. `now` is milliseconds on any monotonic clock.
+    pub fn observe(&mut self, data: &[u8], now: u64) -> Option<Alert> {
+        let frame = Management::parse(data)?;
+
+        match frame.kind {
+            ManagementKind::Deauthentication | ManagementKind::Disassociation => {
+                self.deauth(&frame, now)
+            }
+            ManagementKind::Beacon => self
+                .beacon(&frame, now)
+                .or_else(|| self.flood(&frame, now)),
+            ManagementKind::Action => self.action(&frame, now),
+            _ => None,
+        }
+    }
+
+    fn deauth(&mut self, frame: &Management, now: u64) -> Option<Alert> {
+        evict(&mut self.deauths, frame.bssid);
+
+        let frames = self
+            .deauths
+            .entry(frame.bssid)
+            .or_insert(Counter::new(now))
+            .hit(now, DEAUTH_WINDOW_MS);
+
+        if frames < DEAUTH_THRESHOLD {
+            return None;
+        }
+
+        self.raise(
+            AlertKind::Deauth,
+            frame.bssid,
+            now,
+            Alert::DeauthFlood {
+                bssid: frame.bssid,
+                frames,
+            },
+        )
+    }
+
+    fn beacon(&mut self, frame: &Management, now: u64) -> Option<Alert> {
+        let beacon = frame.beacon()?;
+        let switch = beacon.channel_switch();
+
+        evict(&mut self.beacons, frame.bssid);
+
+        if let Entry::Vacant(entry) = self.beacons.entry(frame.bssid) {
+            entry.insert(BeaconTrack {
+                sequence: frame.sequence,
+                tsf: beacon.tsf,
+                last_seen: now,
+                last_switch: switch.map(|channel| (now, channel)),
+                anomalies: Counter::new(now),
+            });
+
+            return match switch {
+                Some(channel) if !valid_channel(channel) => self.raise(
+                    AlertKind::ChannelSwitch,
+                    frame.bssid,
+                    now,
+                    Alert::ChannelSwitchSpoof {
+                        bssid: frame.bssid,
+                        channel,
+                    },
+                ),
+                _ => None,
+            };
+        }
+
+        let track = self.beacons.get_mut(&frame.bssid)?;
+
+        // TSF only goes backwards when the AP restarts, which happens once,
+        // or when somebody else is sending beacons with its BSSID.
+        let tsf_backwards = beacon.tsf < track.tsf;
+        let sequence_backwards = now.saturating_sub(track.last_seen) < SEQUENCE_GAP_MS
+            && frame.sequence.wrapping_sub(track.sequence) & 0x0fff >= 0x0800;
+        let anomalous = tsf_backwards || sequence_backwards;
+
+        let anomalies = if anomalous {
+            track.anomalies.hit(now, SPOOF_WINDOW_MS)
+        } else {
+            0
+        };
+
+        let dropped_switch = match (switch, track.last_switch) {
+            (None, Some((at, channel))) if now.saturating_sub(at) < CSA_HOLD_MS => Some(channel),
+            _ => None,
+        };
+
+        track.sequence = frame.sequence;
+        track.tsf = beacon.tsf;
+        track.last_seen = now;
+        if let Some(channel) = switch {
+            track.last_switch = Some((now, channel));
+        }
+
+        let forged_switch = match switch {
+            Some(channel) => (anomalous || !valid_channel(channel)).then_some(channel),
+            None => dropped_switch,
+        };
+
+        if let Some(channel) = forged_switch {
+            return self.raise(
+                AlertKind::ChannelSwitch,
+                frame.bssid,
+                now,
+                Alert::ChannelSwitchSpoof {
+                    bssid: frame.bssid,
+                    channel,
+                },
+            );
+        }
+
+        if anomalies >= SPOOF_THRESHOLD {
+            return self.raise(
+                AlertKind::Spoof,
+                frame.bssid,
+                now,
+                Alert::BeaconSpoof {
+                    bssid: frame.bssid,
+                    anomalies,
+                },
+            );
+        }
+
+        None
+    }
+
+    fn action(&mut self, frame: &Management, now: u64) -> Option<Alert> {
+        let channel = frame.action_channel_switch()?;
+
+        // An AP announcing a switch in an action frame also announces it in
+        // its beacons. If its latest beacon didn't, the action frame is fake.
+        let forged = !valid_channel(channel)
+            || self.beacons.get(&frame.bssid).is_some_and(|track| {
+                now.saturating_sub(track.last_seen) < CSA_HOLD_MS && track.last_switch.is_none()
+            });
+
+        if !forged {
+            return None;
+        }
+
+        self.raise(
+            AlertKind::ChannelSwitch,
+            frame.bssid,
+            now,
+            Alert::ChannelSwitchSpoof {
+                bssid: frame.bssid,
+                channel,
+            },
+        )
+    }
+
+    fn flood(&mut self, frame: &Management, now: u64) -> Option<Alert> {
+        let ssid = frame.beacon()?.ssid().map(fnv1a)?;
+
+        evict(&mut self.transmitters, frame.transmitter);
+
+        let transmitter = self
+            .transmitters
+            .entry(frame.transmitter)
+            .or_insert_with(|| Transmitter {
+                started: now,
+                last_seen: now,
+                ssids: Vec::new(),
+            });
+
+        if now.saturating_sub(transmitter.started) > FLOOD_WINDOW_MS {
+            transmitter.started = now;
+            transmitter.ssids.clear();
+        }
+
+        transmitter.last_seen = now;
+        if !transmitter.ssids.contains(&ssid) && transmitter.ssids.len() < 2 * FLOOD_THRESHOLD {
+            transmitter.ssids.push(ssid);
+        }
+
+        let ssids = transmitter.ssids.len();
+        if ssids < FLOOD_THRESHOLD {
+            return None;
+        }
+
+        self.raise(
+            AlertKind::Flood,
+            frame.transmitter,
+            now,
+            Alert::BeaconFlood {
+                transmitter: frame.transmitter,
+                ssids: ssids as u16,
+            },
+        )
+    }
+
+    fn raise(
+        &mut self,
+        kind: AlertKind,
+        address: MacAddress,
+        now: u64,
+        alert: Alert,
+    ) -> Option<Alert> {
+        if let Some(at) = self.raised.get(&(kind, address)) {
+            if now.saturating_sub(*at) < ALERT_COOLDOWN_MS {
+                return None;
+            }
+        }
+
+        if self.raised.len() >= MAX_TRACKED {
+            self.raised
+                .retain(|_, at| now.saturating_sub(*at) < ALERT_COOLDOWN_MS);
+        }
+
+        self.raised.insert((kind, address), now);
+        Some(alert)
+    }
+}
+
+/// Make room for `address` by dropping the entry we heard from longest ago.
+fn evict<T: LastSeen>(table: &mut BTreeMap<MacAddress, T>, address: MacAddress) {
+    if table.len() < MAX_TRACKED || table.contains_key(&address) {
+        return;
+    }
+
+    let oldest = table
+        .iter()
+        .min_by_key(|(_, entry)| entry.last_seen())
+        .map(|(address, _)| *address);
+
+    if let Some(oldest) = oldest {
+        table.remove(&oldest);
+    }
+}
+
+fn valid_channel(channel: u8) -> bool {
+    matches!(channel, 1..=14 | 32..=177)
+}
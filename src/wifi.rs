@@ -1,15 +1,7 @@
-use core::cell::RefCell;
-
-use embassy_futures::block_on;
+use embassy_futures::select::{select, Either};
 use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, pubsub::PubSubChannel};
 use esp_alloc as _;
 use esp_backtrace as _;
-
-use alloc::{
-    collections::btree_set::BTreeSet,
-    string::{String, ToString},
-};
-use critical_section::Mutex;
 use esp_hal::{
     peripherals::{RADIO_CLK, WIFI},
     reset::software_reset,
@@ -19,77 +11,172 @@ use esp_hal::{
 use esp_println::println;
 use esp_wifi::{
     init,
-    wifi::{new_with_mode, PromiscuousPkt, WifiApDevice},
+    wifi::{new_with_mode, PromiscuousPkt, WifiApDevice, WifiController},
     EspWifiInitFor,
 };
-use ieee80211::{match_frames, mgmt_frame::BeaconFrame};
+use embassy_time::{Instant, Timer};
+
+use crate::{
+    allowlist,
+    channels::{self, HOP_CHANNELS},
+    error::{self, Error, Subsystem},
+    foxhunt,
+    frame::{self, FrameType},
+    ingest::{self, Sniffed},
+    supervisor::{self, Task},
+};
+
+/// How long to listen on each channel before hopping to the next
+const DWELL_MS: u64 = 250;
 
-use crate::storage;
+/// Print the channel summary every this many sweeps over all channels
+const SUMMARY_EVERY: u32 = 10;
+
+/// Tries to start the radio before giving up on it
+const START_ATTEMPTS: u32 = 3;
 
 #[derive(Clone, PartialEq)]
 enum WifiStatus {
     Sniffing,
-    Bluetooth,
+    Restart,
 }
 
 static WIFI_CHANNEL: PubSubChannel<CriticalSectionRawMutex, WifiStatus, 4, 4, 4> =
     PubSubChannel::<CriticalSectionRawMutex, WifiStatus, 4, 4, 4>::new();
 
-static KNOWN_SSIDS: Mutex<RefCell<BTreeSet<String>>> = Mutex::new(RefCell::new(BTreeSet::new()));
+/// Stop sniffing and reset; the wifi task does the reset once it's let go
+/// of the radio.
+pub async fn restart() {
+    // With the radio given up on, there's no task to hand this to
+    if error::disabled(Subsystem::Radio) {
+        software_reset();
+    }
+    error::publish(&WIFI_CHANNEL, WifiStatus::Restart).await;
+}
+
+/// Enough of a data or control frame for its addresses
+const HEADER_BYTES: usize = 32;
+
+/// The sniffer callback, shared with live mode, which sniffs the channel of
+/// the network it's joined. It runs in the driver, so all it does is queue
+/// the frame.
+pub fn sniffed(packet: PromiscuousPkt<'_>) {
+    let keep = match frame::frame_type(packet.data) {
+        Some(FrameType::Management) => packet.data.len(),
+        _ => packet.data.len().min(HEADER_BYTES),
+    };
+    let (phy, rate) = channels::phy_rate(
+        packet.rx_cntl.cur_bb_format as u32,
+        packet.rx_cntl.rate as u32,
+        packet.rx_cntl.he_siga1 as u32,
+    );
+    ingest::queue(Sniffed {
+        data: packet.data[..keep].to_vec(),
+        len: packet.data.len() as u16,
+        channel: packet.rx_cntl.channel as u8,
+        rssi: packet.rx_cntl.rssi as i8,
+        rate,
+        phy,
+        wide: packet.rx_cntl.second != 0,
+    });
+}
+
+/// Start the radio, giving it a few tries.
+pub async fn start_controller(controller: &mut WifiController<'_>) -> Result<(), Error> {
+    let mut attempt = 1;
+    loop {
+        match controller.start().await {
+            Ok(()) => return Ok(()),
+            Err(err) if attempt < START_ATTEMPTS => {
+                println!("radio didn't start ({:?}), attempt {}", err, attempt);
+                Timer::after_millis(500 * attempt as u64).await;
+                attempt += 1;
+            }
+            Err(err) => return Err(err.into()),
+        }
+    }
+}
 
 #[embassy_executor::task]
 pub async fn start_wifi(timer: AnyTimer, rng: Rng, radio_clock: RADIO_CLK, wifi: WIFI) {
-    let init = init(EspWifiInitFor::Wifi, timer, rng, radio_clock).unwrap();
+    if let Err(err) = sniff(timer, rng, radio_clock, wifi).await {
+        supervisor::stop(Task::Sniffer);
+        error::disable(Subsystem::Radio, err).await;
+    }
+}
+
+async fn sniff(timer: AnyTimer, rng: Rng, radio_clock: RADIO_CLK, wifi: WIFI) -> Result<(), Error> {
+    let init = init(EspWifiInitFor::Wifi, timer, rng, radio_clock)?;
     println!("wifi initialized");
 
+    allowlist::load();
+
     // We must initialize some kind of interface and start it.
-    let (_, mut controller) = new_with_mode(&init, wifi, WifiApDevice).unwrap();
-
-    controller.start().await.unwrap();
-
-    let mut sniffer = controller.take_sniffer().unwrap();
-    sniffer.set_promiscuous_mode(true).unwrap();
-
-    fn callback(packet: PromiscuousPkt<'_>) {
-        let _ = match_frames! {
-            packet.data,
-            beacon = BeaconFrame => {
-                let Some(ssid) = beacon.ssid() else {
-                    return;
-                };
-
-                if critical_section::with(|cs| {
-                    if KNOWN_SSIDS.borrow_ref_mut(cs).insert(ssid.to_string()) && ssid.to_string() != "" {
-                        let byte_string = ssid.to_string();
-                        let bytes = byte_string.as_bytes();
-                        block_on(storage::append(bytes.to_vec()));
-                        return true;
-                    } else {
-                        return false;
-                    }
-                }) {
-                }
-            }
-        };
-    }
+    let (_, mut controller) = new_with_mode(&init, wifi, WifiApDevice)?;
+
+    start_controller(&mut controller).await?;
 
-    sniffer.set_receive_cb(callback);
+    let mut sniffer = controller.take_sniffer().ok_or(Error::Radio)?;
+    sniffer.set_promiscuous_mode(true)?;
+
+    sniffer.set_receive_cb(sniffed);
 
     let mut subscriber = WIFI_CHANNEL.subscriber().unwrap();
+    let mut hops = HOP_CHANNELS.cycle();
+    let mut sweeps = 0;
 
     loop {
-        let status = subscriber.next_message_pure().await;
+        supervisor::beat(Task::Sniffer);
+
+        // Stay put on the target's channel while fox hunting
+        let channel = foxhunt::channel().unwrap_or_else(|| hops.next().unwrap());
+        set_channel(channel);
+
+        let started = Instant::now();
+        let status = match select(
+            Timer::after_millis(DWELL_MS),
+            subscriber.next_message_pure(),
+        )
+        .await
+        {
+            Either::First(_) => WifiStatus::Sniffing,
+            Either::Second(status) => status,
+        };
+
+        channels::dwell(channel, started.elapsed().as_millis() as u32);
+
+        if channel == *HOP_CHANNELS.end() {
+            sweeps += 1;
+            if sweeps % SUMMARY_EVERY == 0 {
+                println!("{}", channels::survey().summary());
+                channels::persist();
+            }
+        }
 
         if status == WifiStatus::Sniffing {
             continue;
         }
 
+        channels::persist();
+
         println!("Shutting down wifi");
-        controller.disconnect().await.unwrap();
+        // Resetting regardless
+        let _ = controller.disconnect().await;
         println!("Done");
         drop(controller);
 
         software_reset();
         break;
     }
+
+    Ok(())
+}
+
+fn set_channel(channel: u8) {
+    unsafe {
+        esp_wifi_sys::include::esp_wifi_set_channel(
+            channel,
+            esp_wifi_sys::include::wifi_second_chan_t_WIFI_SECOND_CHAN_NONE,
+        );
+    }
 }